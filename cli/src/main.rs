@@ -1,16 +1,471 @@
-use clap::Parser;
+mod terminal;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use riscv_lib::benchmark::Measurement;
+use riscv_lib::console::{BufferedInput, ConsoleInput, StdinInput};
+use riscv_lib::cpu::base::MisalignedPolicy;
+#[cfg(feature = "jit")]
+use riscv_lib::cpu::jit::Jit;
+use riscv_lib::cpu::lockstep::{Lockstep, LockstepEnd};
 use riscv_lib::cpu::rv32i::{Cpu, StepState};
-use riscv_lib::memory::Memory;
+use riscv_lib::cpu::trace::{TraceFilter, Tracer};
+use riscv_lib::debugger::gdb::{Connection, GdbStub, SessionEnd};
+use riscv_lib::debugger::monitor::{Monitor, MonitorEnd};
+use riscv_lib::device_tree::{DeviceTree, MachineConfig};
+use riscv_lib::devices::framebuffer::{
+    Framebuffer, ImageFormat, PixelFormat, DEFAULT_FRAMEBUFFER_BASE,
+};
+use riscv_lib::devices::rtc::{Rtc, RtcClock, DEFAULT_RTC_BASE, RTC_SIZE};
+use riscv_lib::devices::test_finisher::{
+    self, TestFinisher, DEFAULT_TEST_FINISHER_BASE, TEST_FINISHER_SIZE,
+};
+use riscv_lib::devices::uart::{Uart, DEFAULT_UART_BASE, UART_SIZE};
+use riscv_lib::devices::virtio::block::{Block, BlockMode};
+use riscv_lib::devices::virtio::console::{Console, ConsolePort};
+use riscv_lib::devices::virtio::net::{self, Net, NetBackend, Pcap, Reflector, UnixSocket};
+use riscv_lib::devices::virtio::rng::Rng;
+use riscv_lib::devices::virtio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+use riscv_lib::devices::{Device, PowerRequest};
+use riscv_lib::elf::{self, Elf, SymbolTable};
+use riscv_lib::instruction::{assembler, disassembler};
+use riscv_lib::machine::{self, BootImages, Virt, RAM_BASE, RTC_INTERRUPT, UART_INTERRUPT};
+use riscv_lib::memory::{Memory, MemoryError, Permissions};
+use std::cell::RefCell;
 use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::Instant;
+use terminal::RawMode;
+
+/// The latest --rtc-epoch, in seconds, whose time in nanoseconds fits the RTC
+const MAX_RTC_EPOCH: u64 = u64::MAX / 1_000_000_000;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DiskMode {
+    /// Guest writes go to the image
+    Rw,
+    /// The disk is read-only
+    Ro,
+    /// Guest writes are kept in memory and the image is never modified
+    Cow,
+}
+
+impl From<DiskMode> for BlockMode {
+    fn from(mode: DiskMode) -> Self {
+        match mode {
+            DiskMode::Rw => BlockMode::ReadWrite,
+            DiskMode::Ro => BlockMode::ReadOnly,
+            DiskMode::Cow => BlockMode::CopyOnWrite,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Misaligned {
+    /// Perform misaligned loads and stores
+    Allow,
+    /// Raise an address-misaligned exception
+    Trap,
+    /// Perform them and report how many there were
+    Count,
+}
+
+impl From<Misaligned> for MisalignedPolicy {
+    fn from(misaligned: Misaligned) -> Self {
+        match misaligned {
+            Misaligned::Allow => MisalignedPolicy::Allow,
+            Misaligned::Trap => MisalignedPolicy::Trap,
+            Misaligned::Count => MisalignedPolicy::Count,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FramebufferFormat {
+    Xrgb8888,
+    Rgb565,
+}
+
+impl From<FramebufferFormat> for PixelFormat {
+    fn from(format: FramebufferFormat) -> Self {
+        match format {
+            FramebufferFormat::Xrgb8888 => PixelFormat::Xrgb8888,
+            FramebufferFormat::Rgb565 => PixelFormat::Rgb565,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SnapshotFormat {
+    Png,
+    Ppm,
+}
+
+impl From<SnapshotFormat> for ImageFormat {
+    fn from(format: SnapshotFormat) -> Self {
+        match format {
+            SnapshotFormat::Png => ImageFormat::Png,
+            SnapshotFormat::Ppm => ImageFormat::Ppm,
+        }
+    }
+}
+
+/// Where the virtio network device's frames go, given as reflect, pcap:<file>,
+/// listen:<socket> or connect:<socket>.
+#[derive(Clone, Debug)]
+enum NetBackendArg {
+    Reflect,
+    Pcap(String),
+    Listen(String),
+    Connect(String),
+}
+
+fn parse_net_backend(backend: &str) -> Result<NetBackendArg, String> {
+    match backend.split_once(':') {
+        None if backend == "reflect" => Ok(NetBackendArg::Reflect),
+        Some(("pcap", path)) => Ok(NetBackendArg::Pcap(path.to_string())),
+        Some(("listen", path)) => Ok(NetBackendArg::Listen(path.to_string())),
+        Some(("connect", path)) => Ok(NetBackendArg::Connect(path.to_string())),
+        _ => Err(format!(
+            "invalid network backend {backend}, expected reflect, pcap:<file>, listen:<socket> or connect:<socket>"
+        )),
+    }
+}
+
+/// Where to wait for GDB, given as a TCP port on localhost or unix:<socket>.
+#[derive(Clone, Debug)]
+enum GdbArg {
+    Tcp(u16),
+    Unix(String),
+}
+
+fn parse_gdb(address: &str) -> Result<GdbArg, String> {
+    match address.strip_prefix("unix:") {
+        Some(path) => Ok(GdbArg::Unix(path.to_string())),
+        None => address.parse().map(GdbArg::Tcp).map_err(|_| {
+            format!("invalid GDB address {address}, expected a port or unix:<socket>")
+        }),
+    }
+}
+
+/// A range of RAM and what the hart may do with it, given as <start>:<end>:<permissions>.
+#[derive(Clone, Debug)]
+struct Protection {
+    range: Range<usize>,
+    permissions: Permissions,
+}
+
+/// Parse a protection whose permissions are any of r, w and x, as in rx, r-x or - for none.
+fn parse_protection(protection: &str) -> Result<Protection, String> {
+    let (range, flags) = protection.rsplit_once(':').ok_or_else(|| {
+        format!("invalid protection {protection}, expected <start>:<end>:<permissions>")
+    })?;
+    let range = parse_range(range, parse_address)?;
+    let mut permissions = Permissions::NONE;
+    for flag in flags.chars() {
+        match flag {
+            'r' => permissions.read = true,
+            'w' => permissions.write = true,
+            'x' => permissions.execute = true,
+            '-' => (),
+            _ => {
+                return Err(format!(
+                    "invalid permissions {flags}, expected any of r, w and x, or -"
+                ))
+            }
+        }
+    }
+    Ok(Protection { range, permissions })
+}
+
+/// Parse a MAC address written as six colon separated hex bytes.
+fn parse_mac(mac: &str) -> Result<[u8; 6], String> {
+    let bytes: Vec<_> = mac
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("invalid MAC address {mac}: {err}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("invalid MAC address {mac}: expected six bytes"))
+}
+
+/// Parse a framebuffer size written as <width>x<height>.
+fn parse_resolution(resolution: &str) -> Result<(usize, usize), String> {
+    let invalid = || format!("invalid resolution {resolution}, expected <width>x<height>");
+    let (width, height) = resolution.split_once('x').ok_or_else(invalid)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the instructions in a flat binary, or in the executable sections of an ELF file,
+    /// like objdump -d
+    Disasm {
+        file: String,
+
+        /// The address a flat binary is loaded at
+        #[arg(long, value_parser = parse_address, default_value_t = 0)]
+        base: usize,
+    },
+    /// Assemble GNU-style RISC-V assembly into a flat binary
+    Asm {
+        file: String,
+
+        /// Where to write the binary
+        #[arg(short, long)]
+        output: String,
+
+        /// The address the binary will be loaded at
+        #[arg(long, value_parser = parse_address, default_value_t = 0)]
+        base: usize,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    program: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A flat binary loaded at address 0 and started there
+    #[arg(short, long, required_unless_present_any = ["kernel", "bios"], conflicts_with_all = ["kernel", "bios"])]
+    program: Option<String>,
+
+    /// Boot the virt machine with this firmware (e.g, OpenSBI's fw_dynamic.bin) at the start of
+    /// RAM (0x80000000). It is passed the kernel, device tree and initrd.
+    #[arg(long)]
+    bios: Option<String>,
+
+    /// Boot the virt machine with this kernel image, after the firmware if one is given.
+    #[arg(long)]
+    kernel: Option<String>,
+
+    /// Load this initrd near the top of RAM and describe it in the device tree
+    #[arg(long, requires = "kernel")]
+    initrd: Option<String>,
 
+    /// The kernel command line
+    #[arg(long, requires = "kernel")]
+    append: Option<String>,
+
+    /// The size of RAM. Its pages are only allocated as the guest writes them, so a large
+    /// address space (e.g, 2 GiB) costs little more than the RAM the guest uses.
     #[arg(short, long, default_value_t = 1 << 17)]
     memory_bytes: usize,
+
+    /// Feed the guest console from a file rather than stdin
+    #[arg(long, conflicts_with = "input")]
+    input_file: Option<String>,
+
+    /// Feed the guest console from a string rather than stdin
+    #[arg(long)]
+    input: Option<String>,
+
+    /// Attach an NS16550A UART at this address (0x10000000 if no address is given). The console
+    /// input is then fed to the UART rather than the getc ecalls.
+    #[arg(long, value_parser = parse_address)]
+    uart: Option<Option<usize>>,
+
+    /// Write UART output to a file rather than stdout
+    #[arg(long, requires = "uart")]
+    uart_output: Option<String>,
+
+    /// Attach a SiFive test finisher at this address (0x100000 if no address is given) so the
+    /// guest can power off the emulator with an exit status.
+    #[arg(long, value_parser = parse_address)]
+    test_finisher: Option<Option<usize>>,
+
+    /// Attach a Goldfish RTC at this address (0x101000 if no address is given). It follows the
+    /// host clock unless --rtc-epoch is given.
+    #[arg(long, value_parser = parse_address)]
+    rtc: Option<Option<usize>>,
+
+    /// Start the RTC at this many seconds since the Unix epoch and advance it a fixed amount
+    /// every instruction, so every run sees the same time. The time is kept in nanoseconds, which
+    /// limits it to about 584 years after the epoch.
+    #[arg(long, requires = "rtc", value_parser = clap::value_parser!(u64).range(..=MAX_RTC_EPOCH))]
+    rtc_epoch: Option<u64>,
+
+    /// Attach a virtio block device backed by this disk image. Virtio devices are placed every
+    /// 0x1000 bytes from 0x10001000.
+    #[arg(long)]
+    virtio_blk: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = DiskMode::Cow, requires = "virtio_blk")]
+    virtio_blk_mode: DiskMode,
+
+    /// Attach a virtio console. Its first port takes the console input unless a UART is
+    /// attached, and writes to stdout.
+    #[arg(long)]
+    virtio_console: bool,
+
+    /// Write the virtio console's first port to a file rather than stdout
+    #[arg(long, requires = "virtio_console")]
+    virtio_console_output: Option<String>,
+
+    /// Add an extra virtio console port (using the multiport feature) that writes to this file.
+    /// Can be given more than once.
+    #[arg(long, requires = "virtio_console")]
+    virtio_console_port: Vec<String>,
+
+    /// Attach a virtio entropy device
+    #[arg(long)]
+    virtio_rng: bool,
+
+    /// The seed for the virtio entropy device. Runs with the same seed see the same bytes.
+    #[arg(long, default_value_t = 0, requires = "virtio_rng")]
+    virtio_rng_seed: u64,
+
+    /// Attach a virtio network device. Frames are reflected back to the guest (reflect),
+    /// written to a pcap file (pcap:<file>), or exchanged with another emulator over a Unix
+    /// socket (listen:<socket> on one side and connect:<socket> on the other).
+    #[arg(long, value_parser = parse_net_backend)]
+    virtio_net: Option<NetBackendArg>,
+
+    /// The MAC address of the virtio network device
+    #[arg(long, value_parser = parse_mac, requires = "virtio_net")]
+    virtio_net_mac: Option<[u8; 6]>,
+
+    /// Generate a device tree describing the machine, place it at the top of memory and pass
+    /// its address to the program in a1 (with the hart ID in a0). This is always done when
+    /// booting with --kernel or --bios.
+    #[arg(long, conflicts_with_all = ["kernel", "bios"])]
+    dtb: bool,
+
+    /// Write the generated device tree to a file
+    #[arg(long)]
+    dtb_dump: Option<String>,
+
+    /// Attach a headless framebuffer of this size (e.g, 640x480)
+    #[arg(long, value_parser = parse_resolution)]
+    framebuffer: Option<(usize, usize)>,
+
+    /// The address of the framebuffer (0x28000000 by default)
+    #[arg(long, value_parser = parse_address, requires = "framebuffer")]
+    framebuffer_base: Option<usize>,
+
+    #[arg(long, value_enum, default_value_t = FramebufferFormat::Xrgb8888, requires = "framebuffer")]
+    framebuffer_format: FramebufferFormat,
+
+    /// Save framebuffer snapshots to files starting with this prefix whenever the guest writes
+    /// the snapshot register.
+    #[arg(long, requires = "framebuffer")]
+    framebuffer_snapshot: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = SnapshotFormat::Png, requires = "framebuffer_snapshot")]
+    framebuffer_snapshot_format: SnapshotFormat,
+
+    /// Also save a framebuffer snapshot every N instructions
+    #[arg(long, requires = "framebuffer_snapshot")]
+    framebuffer_snapshot_every: Option<u64>,
+
+    /// Also save a framebuffer snapshot when the emulator exits
+    #[arg(long, requires = "framebuffer_snapshot")]
+    framebuffer_snapshot_on_exit: bool,
+
+    /// Wait for GDB to connect on this TCP port on localhost (or unix:<socket>) before running,
+    /// and let it control the guest until it detaches.
+    #[arg(long, value_parser = parse_gdb, conflicts_with = "debug")]
+    gdb: Option<GdbArg>,
+
+    /// Start in an interactive monitor that can step, set breakpoints and watchpoints and
+    /// inspect the machine. The guest console gets no input from stdin unless --input or
+    /// --input-file is given, as the monitor reads it.
+    #[arg(long)]
+    debug: bool,
+
+    /// Read symbols for the monitor and --trace-symbol from this ELF file (e.g, the one the
+    /// program was built from)
+    #[arg(long)]
+    symbols: Option<String>,
+
+    /// Write a trace of every retired instruction to this file, in the format of Spike's
+    /// --log-commits
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Only trace instructions in this address range, given as <start>:<end>
+    #[arg(long, value_parser = parse_address_range, requires = "trace")]
+    trace_pc: Option<Range<u32>>,
+
+    /// Only trace instructions in this function (or other symbol)
+    #[arg(long, requires_all = ["trace", "symbols"], conflicts_with = "trace_pc")]
+    trace_symbol: Option<String>,
+
+    /// Only trace the instructions retired in this window, given as <first>:<end> counting from 0
+    #[arg(long, value_parser = parse_window, requires = "trace")]
+    trace_window: Option<Range<u64>>,
+
+    /// Precede each trace line with the instruction's disassembly, as Spike's -l does
+    #[arg(long, requires = "trace")]
+    trace_disassembly: bool,
+
+    /// Compare every retired instruction against this reference commit log (e.g, from Spike's
+    /// --log-commits) and stop at the first difference
+    #[arg(long, conflicts_with_all = ["gdb", "debug"])]
+    lockstep: Option<String>,
+
+    /// Compile hot code to native code rather than interpreting it. Tracing, lockstep testing
+    /// and the debuggers still go an instruction at a time.
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    jit: bool,
+
+    /// Report the instructions retired, the time taken, the MIPS and the RAM allocated when the
+    /// emulator exits
+    #[arg(long)]
+    stats: bool,
+
+    /// What the hart does with loads and stores that aren't aligned to their width
+    #[arg(long, value_enum, default_value_t = Misaligned::Allow)]
+    misaligned: Misaligned,
+
+    /// Restrict what the hart may do with a range of RAM, given as <start>:<end>:<permissions>
+    /// with any of r, w and x, or - for none (e.g, rx for code or - for a guard page). The range
+    /// has to start on a page boundary and is rounded up to whole pages. Can be given more than
+    /// once, later ranges overriding earlier ones.
+    #[arg(long, value_parser = parse_protection)]
+    protect: Vec<Protection>,
+}
+
+/// Print how many instructions the guest retired since start, how fast, and how much of its RAM
+/// has been allocated.
+fn print_stats(cpu: &Cpu, mem: &Memory, start: Instant) {
+    let measurement = Measurement {
+        instructions: cpu.state.registers.csrs.instret,
+        time: start.elapsed(),
+    };
+    println!("Retired {measurement}");
+    let ram = mem.ram();
+    println!(
+        "Allocated {} KiB of {} KiB of RAM",
+        ram.footprint() / 1024,
+        ram.size() / 1024
+    );
+}
+
+/// Print the misaligned loads and stores counted under --misaligned count.
+fn print_misaligned(cpu: &Cpu) {
+    let counts = cpu.state.misaligned;
+    println!(
+        "Made {} misaligned loads and {} misaligned stores",
+        counts.loads, counts.stores
+    );
+}
+
+/// Exit with an error about the arguments, as clap does for the ones it checks itself.
+fn argument_error(message: String) -> ! {
+    Args::command()
+        .error(ErrorKind::ValueValidation, message)
+        .exit()
 }
 
 fn read_file_as_bytes(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -18,36 +473,491 @@ fn read_file_as_bytes(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>>
     Ok(byte_content)
 }
 
+/// Parse an address given in decimal or as hex with a 0x prefix.
+fn parse_address(address: &str) -> Result<usize, String> {
+    let result = match address.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    result.map_err(|err| format!("invalid address {address}: {err}"))
+}
+
+/// Parse a range given as <start>:<end>.
+fn parse_range<T>(
+    range: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Range<T>, String> {
+    let (start, end) = range
+        .split_once(':')
+        .ok_or_else(|| format!("invalid range {range}, expected <start>:<end>"))?;
+    Ok(parse(start)?..parse(end)?)
+}
+
+fn parse_address_range(range: &str) -> Result<Range<u32>, String> {
+    parse_range(range, |address| {
+        parse_address(address).map(|address| address as u32)
+    })
+}
+
+fn parse_window(range: &str) -> Result<Range<u64>, String> {
+    parse_range(range, |count| {
+        count
+            .parse()
+            .map_err(|err| format!("invalid instruction count {count}: {err}"))
+    })
+}
+
+/// Attach a device, connecting its interrupt to the PLIC when running the virt machine.
+fn attach(
+    mem: &mut Memory,
+    virt: Option<&Virt>,
+    base: usize,
+    size: usize,
+    interrupt: Option<u32>,
+    device: Rc<RefCell<dyn Device>>,
+) {
+    match virt {
+        Some(virt) => virt.attach(mem, base, size, interrupt, device),
+        None => mem.attach(base, size, device),
+    }
+    .unwrap();
+}
+
+/// Print a listing of a flat binary or ELF file.
+fn disasm(path: &str, base: usize) {
+    let bytes = read_file_as_bytes(path).unwrap();
+    let mut stdout = std::io::stdout().lock();
+
+    if !elf::is_elf(&bytes) {
+        let symbols = SymbolTable::default();
+        disassembler::listing(&bytes, base as u32, &symbols, &mut stdout).unwrap();
+        return;
+    }
+
+    let elf = Elf::parse(&bytes).unwrap();
+    for section in elf.sections.iter().filter(|section| section.executable) {
+        writeln!(stdout, "\nDisassembly of section {}:", section.name).unwrap();
+        disassembler::listing(&section.data, section.address, &elf.symbols, &mut stdout).unwrap();
+    }
+}
+
+/// Assemble a source file into a flat binary, exiting with an error if it does not assemble.
+fn asm(path: &str, output: &str, base: usize) {
+    let source = fs::read_to_string(path).unwrap();
+    match assembler::assemble(&source, base as u32) {
+        Ok(image) => {
+            fs::write(output, &image.bytes).unwrap();
+            if image.entry != image.base {
+                println!("Entry point is {:#010x}", image.entry);
+            }
+        }
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn debug<C: Connection>(connection: C, cpu: &mut Cpu, mem: &mut Memory) -> SessionEnd {
+    GdbStub::new(connection).run(cpu, mem).unwrap()
+}
+
+/// Wait for GDB and serve it until the session ends.
+fn gdb_session(address: &GdbArg, cpu: &mut Cpu, mem: &mut Memory) -> SessionEnd {
+    match address {
+        GdbArg::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", *port)).unwrap();
+            println!("Waiting for GDB on localhost:{port}");
+            let (stream, _) = listener.accept().unwrap();
+            debug(stream, cpu, mem)
+        }
+        #[cfg(unix)]
+        GdbArg::Unix(path) => {
+            // Remove a socket left behind by an earlier run
+            let _ = fs::remove_file(path);
+            let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+            println!("Waiting for GDB on {path}");
+            let (stream, _) = listener.accept().unwrap();
+            debug(stream, cpu, mem)
+        }
+        #[cfg(not(unix))]
+        GdbArg::Unix(_) => panic!("Unix sockets are not supported on this platform"),
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
-    println!("Loading program");
+    match &args.command {
+        Some(Command::Disasm { file, base }) => return disasm(file, *base),
+        Some(Command::Asm { file, output, base }) => return asm(file, output, *base),
+        None => (),
+    }
 
-    let program = read_file_as_bytes(&args.program).unwrap();
+    // Booting a kernel or firmware uses the virt machine, with RAM at 0x80000000 and its
+    // interrupt controllers and UART always attached. Otherwise the program goes at address 0.
+    let (mut mem, virt) = match &args.program {
+        Some(path) => {
+            println!("Loading program");
 
-    let mut mem = Memory::new(args.memory_bytes);
+            let program = read_file_as_bytes(path).unwrap();
 
-    println!("Writing program into memory from index 0");
+            let mut mem = Memory::new(args.memory_bytes);
 
-    for (index, &byte) in program.iter().enumerate() {
-        mem.set8(index, byte).unwrap();
-    }
+            println!("Writing program into memory from index 0");
 
-    println!("Executing");
+            mem.load_slice(0, &program).unwrap();
+
+            (mem, None)
+        }
+        None => {
+            let mut mem = Memory::with_base(RAM_BASE, args.memory_bytes);
+            let virt = Virt::new(&mut mem);
+            (mem, Some(virt))
+        }
+    };
+    let virt = virt.as_ref();
 
     let mut cpu = Cpu::new();
 
+    // If the console is fed from stdin and stdin is a terminal we switch it to raw mode so the
+    // guest sees each key press as it happens. The terminal is restored when raw_mode drops.
+    let (input, raw_mode): (Box<dyn ConsoleInput>, _) = if let Some(path) = &args.input_file {
+        let bytes = read_file_as_bytes(path).unwrap();
+        (Box::new(BufferedInput::new(&bytes)), None)
+    } else if let Some(input) = &args.input {
+        (Box::new(BufferedInput::new(input.as_bytes())), None)
+    } else if args.debug {
+        (Box::new(BufferedInput::new(&[])), None)
+    } else {
+        (Box::new(StdinInput::new()), RawMode::enter())
+    };
+
+    // The console input goes to the first device that can take it: the UART, then the virtio
+    // console, and finally the getc ecalls.
+    let mut input = Some(input);
+
+    if let Some(base) = args.uart.or(virt.map(|_| None)) {
+        let base = base.unwrap_or(DEFAULT_UART_BASE);
+        let output: Box<dyn Write> = match &args.uart_output {
+            Some(path) => Box::new(fs::File::create(path).unwrap()),
+            None => Box::new(std::io::stdout()),
+        };
+        let uart = Rc::new(RefCell::new(Uart::new(input.take().unwrap(), output)));
+        attach(&mut mem, virt, base, UART_SIZE, Some(UART_INTERRUPT), uart);
+    }
+
+    if let Some(base) = args.test_finisher.or(virt.map(|_| None)) {
+        let base = base.unwrap_or(DEFAULT_TEST_FINISHER_BASE);
+        let finisher = Rc::new(RefCell::new(TestFinisher::new()));
+        mem.attach(base, TEST_FINISHER_SIZE, finisher).unwrap();
+    }
+
+    if let Some(base) = args.rtc {
+        let base = base.unwrap_or(DEFAULT_RTC_BASE);
+        let clock = match args.rtc_epoch {
+            Some(seconds) => RtcClock::Fixed(seconds * 1_000_000_000),
+            None => RtcClock::Host,
+        };
+        let rtc = Rc::new(RefCell::new(Rtc::new(clock)));
+        attach(&mut mem, virt, base, RTC_SIZE, Some(RTC_INTERRUPT), rtc);
+    }
+
+    let framebuffer = args.framebuffer.map(|(width, height)| {
+        let mut framebuffer = Framebuffer::new(width, height, args.framebuffer_format.into());
+        if let Some(prefix) = &args.framebuffer_snapshot {
+            framebuffer.set_snapshots(
+                prefix,
+                args.framebuffer_snapshot_format.into(),
+                args.framebuffer_snapshot_every,
+            );
+        }
+
+        let base = args.framebuffer_base.unwrap_or(DEFAULT_FRAMEBUFFER_BASE);
+        let size = framebuffer.size();
+        let framebuffer = Rc::new(RefCell::new(framebuffer));
+        mem.attach(base, size, framebuffer.clone()).unwrap();
+        framebuffer
+    });
+
+    let mut virtio_count = 0;
+    let mut attach_virtio = |mem: &mut Memory, device: VirtioMmio| {
+        let (base, interrupt) = machine::virtio_slot(virtio_count);
+        let device = Rc::new(RefCell::new(device));
+        attach(mem, virt, base, VIRTIO_MMIO_SIZE, Some(interrupt), device);
+        virtio_count += 1;
+    };
+
+    if let Some(path) = &args.virtio_blk {
+        let block = Block::open(path, args.virtio_blk_mode.into()).unwrap();
+        attach_virtio(&mut mem, VirtioMmio::new(Box::new(block)));
+    }
+
+    if args.virtio_console {
+        let output: Box<dyn Write> = match &args.virtio_console_output {
+            Some(path) => Box::new(fs::File::create(path).unwrap()),
+            None => Box::new(std::io::stdout()),
+        };
+        let console_input = input
+            .take()
+            .unwrap_or_else(|| Box::new(BufferedInput::new(&[])));
+        let mut ports = vec![ConsolePort::new(console_input, output)];
+
+        for path in &args.virtio_console_port {
+            ports.push(ConsolePort::new(
+                Box::new(BufferedInput::new(&[])),
+                Box::new(fs::File::create(path).unwrap()),
+            ));
+        }
+
+        attach_virtio(&mut mem, VirtioMmio::new(Box::new(Console::new(ports))));
+    }
+
+    if args.virtio_rng {
+        let rng = Rng::new(args.virtio_rng_seed);
+        attach_virtio(&mut mem, VirtioMmio::new(Box::new(rng)));
+    }
+
+    if let Some(backend) = &args.virtio_net {
+        let backend: Box<dyn NetBackend> = match backend {
+            NetBackendArg::Reflect => Box::new(Reflector::new()),
+            NetBackendArg::Pcap(path) => {
+                Box::new(Pcap::new(Box::new(fs::File::create(path).unwrap())).unwrap())
+            }
+            NetBackendArg::Listen(path) => {
+                println!("Waiting for a connection on {path}");
+                Box::new(UnixSocket::listen(path).unwrap())
+            }
+            NetBackendArg::Connect(path) => Box::new(UnixSocket::connect(path).unwrap()),
+        };
+        let mac = args.virtio_net_mac.unwrap_or(net::DEFAULT_MAC);
+        attach_virtio(&mut mem, VirtioMmio::new(Box::new(Net::new(mac, backend))));
+    }
+
+    if let Some(input) = input {
+        cpu.input = input;
+    }
+
+    if virt.is_some() {
+        let images = BootImages {
+            bios: args
+                .bios
+                .as_ref()
+                .map(|path| read_file_as_bytes(path).unwrap()),
+            kernel: args
+                .kernel
+                .as_ref()
+                .map(|path| read_file_as_bytes(path).unwrap()),
+            initrd: args
+                .initrd
+                .as_ref()
+                .map(|path| read_file_as_bytes(path).unwrap()),
+            bootargs: args.append.clone(),
+        };
+        let state = machine::boot(&mut mem, &images).unwrap_or_else(|_| {
+            argument_error(format!(
+                "--memory-bytes {} is too small for the boot images and device tree",
+                args.memory_bytes
+            ))
+        });
+
+        if let Some(path) = &args.dtb_dump {
+            // The device tree header holds its big-endian total size
+            let address = state.a1 as usize;
+            let size = mem.get32(address + 4).unwrap().swap_bytes() as usize;
+            let mut dtb = vec![0; size];
+            mem.read_slice(address, &mut dtb).unwrap();
+            fs::write(path, dtb).unwrap();
+        }
+
+        cpu.state.registers.pc = state.pc;
+        cpu.state.registers.set(10, state.a0);
+        cpu.state.registers.set(11, state.a1);
+        cpu.state.registers.set(12, state.a2);
+    } else if args.dtb || args.dtb_dump.is_some() {
+        let config = MachineConfig {
+            isa: machine::ISA.to_string(),
+            harts: 1,
+            bootargs: None,
+            initrd: None,
+        };
+        let dtb = DeviceTree::for_machine(&mem, &config).to_dtb();
+
+        if let Some(path) = &args.dtb_dump {
+            fs::write(path, &dtb).unwrap();
+        }
+
+        if args.dtb {
+            // The device tree has to be 8 byte aligned
+            let Some(top) = args.memory_bytes.checked_sub(dtb.len()) else {
+                argument_error(format!(
+                    "--memory-bytes {} is too small for the {} byte device tree",
+                    args.memory_bytes,
+                    dtb.len()
+                ));
+            };
+            let address = top & !7;
+            mem.load_slice(address, &dtb).unwrap();
+            cpu.state.registers.set(10, 0);
+            cpu.state.registers.set(11, address as u32);
+        }
+    }
+
+    let symbols = match &args.symbols {
+        Some(path) => {
+            Elf::parse(&read_file_as_bytes(path).unwrap())
+                .unwrap()
+                .symbols
+        }
+        None => SymbolTable::default(),
+    };
+
+    if let Some(path) = &args.trace {
+        let pc = match &args.trace_symbol {
+            Some(name) => Some(symbols.range(name).unwrap_or_else(|| {
+                argument_error(format!("--trace-symbol: no symbol named {name}"))
+            })),
+            None => args.trace_pc.clone(),
+        };
+        let filter = TraceFilter {
+            pc,
+            instructions: args.trace_window.clone(),
+        };
+        let output = Box::new(BufWriter::new(fs::File::create(path).unwrap()));
+        cpu.tracer = Some(Tracer::new(output, filter, args.trace_disassembly));
+    }
+
+    cpu.state.misaligned_policy = args.misaligned.into();
+    for protection in &args.protect {
+        let Range { start, end } = protection.range;
+        if let Err(err) = mem.protect(start, end.saturating_sub(start), protection.permissions) {
+            let problem = match err {
+                MemoryError::Misaligned => "doesn't start on a page boundary",
+                _ => "isn't in RAM",
+            };
+            argument_error(format!("--protect {start:#x}:{end:#x} {problem}"));
+        }
+    }
+    #[cfg(feature = "jit")]
+    if args.jit {
+        cpu.jit = Some(Jit::new());
+    }
+
+    println!("Executing");
+    let start = Instant::now();
+
     // It seems like it is etiquette to
     // boot at address 0x200
     //cpu.state.registers.pc = 0x200;
 
-    loop {
-        match cpu.step(&mut mem) {
+    // A GDB session ends with the guest stopped for good, or with GDB detaching and leaving the
+    // guest to carry on
+    let mut finished = match &args.gdb {
+        Some(address) => match gdb_session(address, &mut cpu, &mut mem) {
+            SessionEnd::Guest(state) => Some(state),
+            SessionEnd::Detach => None,
+            SessionEnd::Kill => {
+                println!("Killed by GDB");
+                Some(StepState::Exit)
+            }
+        },
+        None if args.debug => {
+            let stdin = std::io::stdin();
+            let end = Monitor::new(symbols)
+                .run(
+                    &mut cpu,
+                    &mut mem,
+                    &mut stdin.lock(),
+                    &mut std::io::stdout(),
+                )
+                .unwrap();
+            match end {
+                MonitorEnd::Guest(state) => Some(state),
+                MonitorEnd::Quit => Some(StepState::Exit),
+            }
+        }
+        None => match &args.lockstep {
+            Some(path) => {
+                let reference = BufReader::new(fs::File::open(path).unwrap());
+                let mut lockstep = Lockstep::new(reference);
+                let end = lockstep.run(&mut cpu, &mut mem);
+                let compared = lockstep.compared;
+                match end {
+                    Ok(LockstepEnd::Guest(state)) => {
+                        println!("Matched the reference for {compared} instructions");
+                        Some(state)
+                    }
+                    // The guest carries on without a reference to compare against
+                    Ok(LockstepEnd::ReferenceEnd) => {
+                        println!("Matched the whole reference, {compared} instructions");
+                        None
+                    }
+                    Err(err) => {
+                        println!("{}", err.to_string().trim_end());
+                        drop(raw_mode);
+                        if let Some(tracer) = &mut cpu.tracer {
+                            tracer.flush().unwrap();
+                        }
+                        if args.stats {
+                            print_stats(&cpu, &mem, start);
+                        }
+                        if args.misaligned == Misaligned::Count {
+                            print_misaligned(&cpu);
+                        }
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => None,
+        },
+    };
+
+    let status = loop {
+        match finished.take().unwrap_or_else(|| cpu.run(&mut mem)) {
             StepState::Continue => (),
             StepState::Exit => {
                 println!("Program exited");
-                break;
+                break 0;
+            }
+            StepState::Power(PowerRequest::Pass) => {
+                println!("Program passed");
+                break 0;
+            }
+            StepState::Power(PowerRequest::Fail(code)) => {
+                println!("Program failed with status {code}");
+                break test_finisher::exit_status(code) as i32;
+            }
+            StepState::Power(PowerRequest::Reset) => {
+                println!("Program requested a reset, which is not supported");
+                break 1;
+            }
+            StepState::Breakpoint => {
+                let pc = cpu.state.registers.pc;
+                println!("Program hit a breakpoint at {pc:#010x} with no debugger attached");
+                break 1;
+            }
+            StepState::Trap(trap) => {
+                println!("Program stopped with no trap handler for a {trap}");
+                break 1;
             }
         }
+    };
+
+    if let Some(framebuffer) = framebuffer.filter(|_| args.framebuffer_snapshot_on_exit) {
+        framebuffer.borrow().save_snapshot("exit");
+    }
+
+    // process::exit does not run destructors so we restore the terminal and flush the trace
+    // first
+    drop(raw_mode);
+    if let Some(tracer) = &mut cpu.tracer {
+        tracer.flush().unwrap();
+    }
+    if args.stats {
+        print_stats(&cpu, &mem, start);
+    }
+    if args.misaligned == Misaligned::Count {
+        print_misaligned(&cpu);
     }
+    std::process::exit(status);
 }
@@ -0,0 +1,61 @@
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// The terminal settings from before we entered raw mode, kept in a static so the SIGINT handler
+/// can put the terminal back before the process dies.
+static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+
+fn restore() {
+    if let Some(original) = ORIGINAL.get() {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+        }
+    }
+}
+
+extern "C" fn restore_and_exit(_signal: libc::c_int) {
+    restore();
+    unsafe { libc::_exit(130) }
+}
+
+/// Puts the host terminal into raw mode for as long as it is alive so that keystrokes are
+/// delivered to the guest one at a time and are not echoed by the host. We leave ISIG enabled so
+/// Ctrl-C still stops the emulator.
+pub struct RawMode;
+
+impl RawMode {
+    /// Enter raw mode. Returns None if stdin is not a terminal (e.g, input is piped in).
+    pub fn enter() -> Option<Self> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return None;
+        }
+
+        ORIGINAL.get_or_init(|| termios);
+
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                restore_and_exit as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+
+        Some(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore();
+    }
+}
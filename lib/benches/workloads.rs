@@ -0,0 +1,31 @@
+/**
+ * Run each benchmark workload and report how fast the emulator ran it, with the JIT too when it
+ * is built (cargo bench --features jit). Arguments other than cargo's flags pick the workloads to
+ * run by name.
+ */
+use riscv_lib::benchmark::WORKLOADS;
+use riscv_lib::cpu::rv32i::Cpu;
+
+fn main() {
+    let names: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+
+    for workload in &WORKLOADS {
+        if !names.is_empty() && !names.iter().any(|name| name == workload.name) {
+            continue;
+        }
+
+        let measurement = workload.run(&mut Cpu::new(), workload.iterations);
+        println!("{:<10} {:<12} {measurement}", workload.name, "interpreter");
+
+        #[cfg(feature = "jit")]
+        {
+            let mut cpu = Cpu::new();
+            cpu.jit = Some(riscv_lib::cpu::jit::Jit::new());
+            let measurement = workload.run(&mut cpu, workload.iterations);
+            println!("{:<10} {:<12} {measurement}", workload.name, "jit");
+        }
+    }
+}
@@ -0,0 +1,166 @@
+/**
+ * Guest workloads for measuring how fast the emulator runs: a Dhrystone-like integer loop, memcpy
+ * and kernels in the style of CoreMark's linked list, matrix and state machine. Each is RV32I
+ * assembly that repeats its work ITERATIONS times and exits with a checksum of the last
+ * repetition in a1, so a broken emulator fails the benchmark rather than reporting a speed.
+ *
+ * lib/benches/workloads.rs runs them all (cargo bench), and the CLI's --stats reports the same
+ * measurement for any program.
+ */
+use crate::cpu::rv32i::{Cpu, StepState};
+use crate::instruction::assembler;
+use crate::memory::Memory;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub struct Workload {
+    pub name: &'static str,
+    source: &'static str,
+    /// The number of iterations a benchmark runs, enough to take a good fraction of a second
+    pub iterations: u32,
+    checksum: u32,
+}
+
+pub const WORKLOADS: [Workload; 5] = [
+    Workload {
+        name: "dhrystone",
+        source: include_str!("benchmark/dhrystone.s"),
+        iterations: 2000,
+        checksum: 0x4405E64D,
+    },
+    Workload {
+        name: "memcpy",
+        source: include_str!("benchmark/memcpy.s"),
+        iterations: 3000,
+        checksum: 0x4ED8FB8E,
+    },
+    Workload {
+        name: "list",
+        source: include_str!("benchmark/list.s"),
+        iterations: 5000,
+        checksum: 0xC1FA6F47,
+    },
+    Workload {
+        name: "matrix",
+        source: include_str!("benchmark/matrix.s"),
+        iterations: 2000,
+        checksum: 0x50CC2126,
+    },
+    Workload {
+        name: "state",
+        source: include_str!("benchmark/state.s"),
+        iterations: 2000,
+        checksum: 0xA20533A1,
+    },
+];
+
+/// Enough memory for any of the workloads, which are loaded at 0
+const MEMORY_SIZE: usize = 1 << 16;
+
+impl Workload {
+    /// Memory with the workload loaded, set to run this many iterations.
+    fn load(&self, iterations: u32) -> Memory {
+        let source = format!(".equ ITERATIONS, {iterations}\n{}", self.source);
+        let image = assembler::assemble(&source, 0).expect("failed to assemble the workload");
+        let mut memory = Memory::new(MEMORY_SIZE);
+        image.load(&mut memory).unwrap();
+        memory
+    }
+
+    /// Run the workload on cpu for this many iterations (at least one). Panics if the guest
+    /// doesn't exit with the right checksum.
+    pub fn run(&self, cpu: &mut Cpu, iterations: u32) -> Measurement {
+        let mut memory = self.load(iterations);
+        let start = Instant::now();
+        let end = cpu.run(&mut memory);
+        let time = start.elapsed();
+        assert_eq!(end, StepState::Exit, "{} didn't exit", self.name);
+        assert_eq!(
+            cpu.state.registers.get(11),
+            self.checksum,
+            "{} exited with the wrong checksum",
+            self.name
+        );
+        Measurement {
+            instructions: cpu.state.registers.csrs.instret,
+            time,
+        }
+    }
+}
+
+/// The instructions a guest retired and how long it took.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl Measurement {
+    /// Millions of instructions per second
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.time.as_secs_f64() / 1e6
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} instructions in {:.3}s, {:.1} MIPS",
+            self.instructions,
+            self.time.as_secs_f64(),
+            self.mips()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn workloads_exit_with_their_checksums() {
+        for workload in &WORKLOADS {
+            for iterations in [1, 2] {
+                let measurement = workload.run(&mut Cpu::new(), iterations);
+                assert!(measurement.instructions > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn stepping_retires_as_many_instructions_as_running() {
+        // run executes blocks, so check it counts them the same as stepping one at a time
+        for workload in &WORKLOADS {
+            let run = workload.run(&mut Cpu::new(), 1);
+            let mut memory = workload.load(1);
+            let mut cpu = Cpu::new();
+            while cpu.step(&mut memory) == StepState::Continue {}
+            assert_eq!(cpu.state.registers.get(11), workload.checksum);
+            assert_eq!(cpu.state.registers.csrs.instret, run.instructions);
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn workloads_exit_with_their_checksums_under_the_jit() {
+        for workload in &WORKLOADS {
+            let mut cpu = Cpu::new();
+            cpu.jit = Some(crate::cpu::jit::Jit::new());
+            workload.run(&mut cpu, 20);
+        }
+    }
+
+    #[test]
+    fn measurement() {
+        let measurement = Measurement {
+            instructions: 3_000_000,
+            time: Duration::from_millis(1500),
+        };
+        assert_eq!(measurement.mips(), 2.0);
+        assert_eq!(
+            measurement.to_string(),
+            "3000000 instructions in 1.500s, 2.0 MIPS"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+# A Dhrystone-like mix of procedure calls, string copies and comparisons, array updates and
+# branches on small enumerations
+    .equ ROUNDS, 100
+
+    li s0, ITERATIONS
+iteration:
+    la t0, array
+    li t1, 33
+clear:
+    sw zero, 0(t0)
+    addi t0, t0, 4
+    addi t1, t1, -1
+    bnez t1, clear
+    li s1, 0
+    li s2, ROUNDS
+round:
+    # Proc_7: add two integers and a constant
+    mv a0, s2
+    li a1, 10
+    call proc7
+    add s1, s1, a0
+
+    # Copy one string into a record and compare it with another
+    la a0, buffer
+    la a1, string2
+    call strcpy
+    la a0, buffer
+    la a1, string1
+    call strcmp
+    add s1, s1, a0
+
+    # Proc_8: store to an array and read back a neighbour
+    andi t0, s2, 31
+    slli t0, t0, 2
+    la t1, array
+    add t1, t1, t0
+    sw s2, 0(t1)
+    lw t2, 4(t1)
+    add s1, s1, t2
+
+    # Proc_6: branch on an enumeration
+    andi t0, s2, 3
+    beqz t0, case0
+    li t1, 2
+    blt t0, t1, case1
+    beq t0, t1, case2
+    addi s1, s1, 7
+    j cases_done
+case0:
+    xori s1, s1, 0x55
+    j cases_done
+case1:
+    slli t1, s1, 1
+    srli t2, s1, 31
+    or s1, t1, t2
+    j cases_done
+case2:
+    sub s1, s1, s2
+cases_done:
+    addi s2, s2, -1
+    bnez s2, round
+
+    addi s0, s0, -1
+    bnez s0, iteration
+    mv a1, s1
+    li a0, 0
+    ecall
+
+proc7:
+    add a0, a0, a1
+    addi a0, a0, 2
+    ret
+
+# Copy the string at a1 to a0
+strcpy:
+    lbu t0, 0(a1)
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    bnez t0, strcpy
+    ret
+
+# Compare the strings at a0 and a1, giving the difference of the first bytes that differ
+strcmp:
+    lbu t0, 0(a0)
+    lbu t1, 0(a1)
+    bne t0, t1, strcmp_done
+    addi a0, a0, 1
+    addi a1, a1, 1
+    bnez t0, strcmp
+strcmp_done:
+    sub a0, t0, t1
+    ret
+
+    .data
+    # On its own page, as a linker would put it, rather than sharing one with the code
+    .balign 4096
+string1:
+    .asciz "DHRYSTONE PROGRAM, 1'ST STRING"
+string2:
+    .asciz "DHRYSTONE PROGRAM, 2'ND STRING"
+    .align 2
+buffer:
+    .zero 32
+array:
+    .zero 132
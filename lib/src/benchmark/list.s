@@ -0,0 +1,134 @@
+# CoreMark-style linked list processing: build a list, search it, reverse it and insertion sort
+# it. A node is a word pointing to the next node (or 0) followed by a word of data.
+    .equ NODES, 64
+    .equ NODES_SIZE, 512
+
+    li s0, ITERATIONS
+iteration:
+    call build
+    mv s2, a0
+
+    # Count the nodes matching each of four keys
+    li s1, 0
+    li s3, 0
+find_key:
+    mv a0, s2
+    mv a1, s3
+    call find
+    slli t0, s1, 5
+    add s1, s1, t0
+    add s1, s1, a0
+    addi s3, s3, 1
+    li t0, 4
+    bne s3, t0, find_key
+
+    mv a0, s2
+    call reverse
+    call sort
+
+    # Hash the sorted data
+list_hash:
+    beqz a0, hashed
+    lw t0, 4(a0)
+    slli t1, s1, 5
+    add s1, s1, t1
+    xor s1, s1, t0
+    lw a0, 0(a0)
+    j list_hash
+hashed:
+    addi s0, s0, -1
+    bnez s0, iteration
+    mv a1, s1
+    li a0, 0
+    ecall
+
+# Link the nodes in order, giving each data from a xorshift sequence. Returns the head.
+build:
+    la a0, nodes
+    mv t0, a0
+    li t1, NODES
+    li t2, 0x2545F491
+build_node:
+    slli t3, t2, 13
+    xor t2, t2, t3
+    srli t3, t2, 17
+    xor t2, t2, t3
+    slli t3, t2, 5
+    xor t2, t2, t3
+    sw t2, 4(t0)
+    addi t4, t0, 8
+    addi t1, t1, -1
+    bnez t1, link
+    li t4, 0
+link:
+    sw t4, 0(t0)
+    mv t0, t4
+    bnez t0, build_node
+    ret
+
+# Count the nodes of the list at a0 whose data's low two bits are a1
+find:
+    li t0, 0
+find_node:
+    beqz a0, found
+    lw t1, 4(a0)
+    andi t1, t1, 3
+    bne t1, a1, find_next
+    addi t0, t0, 1
+find_next:
+    lw a0, 0(a0)
+    j find_node
+found:
+    mv a0, t0
+    ret
+
+# Reverse the list at a0 in place, returning the new head
+reverse:
+    li t0, 0
+reverse_node:
+    beqz a0, reversed
+    lw t1, 0(a0)
+    sw t0, 0(a0)
+    mv t0, a0
+    mv a0, t1
+    j reverse_node
+reversed:
+    mv a0, t0
+    ret
+
+# Insertion sort the list at a0 by unsigned data, returning the new head
+sort:
+    li t0, 0
+sort_node:
+    beqz a0, sorted
+    mv t1, a0
+    lw a0, 0(a0)
+    lw t2, 4(t1)
+    beqz t0, insert_front
+    lw t3, 4(t0)
+    bgeu t3, t2, insert_front
+    mv t4, t0
+find_place:
+    lw t5, 0(t4)
+    beqz t5, insert_after
+    lw t6, 4(t5)
+    bgeu t6, t2, insert_after
+    mv t4, t5
+    j find_place
+insert_after:
+    sw t5, 0(t1)
+    sw t1, 0(t4)
+    j sort_node
+insert_front:
+    sw t0, 0(t1)
+    mv t0, t1
+    j sort_node
+sorted:
+    mv a0, t0
+    ret
+
+    .bss
+    # On its own page, as a linker would put it, rather than sharing one with the code
+    .balign 4096
+nodes:
+    .zero NODES_SIZE
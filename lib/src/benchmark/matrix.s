@@ -0,0 +1,106 @@
+# CoreMark-style matrix multiplication of two 8x8 matrices of words. RV32I has no multiply, so
+# products are made by shifting and adding.
+    .equ N, 8
+    .equ ELEMENTS, 64
+    .equ MATRIX_SIZE, 256
+
+    # Fill both matrices with small values from a xorshift sequence
+    la t0, matrix_a
+    li t1, ELEMENTS + ELEMENTS
+    li t2, 0x2545F491
+fill:
+    slli t3, t2, 13
+    xor t2, t2, t3
+    srli t3, t2, 17
+    xor t2, t2, t3
+    slli t3, t2, 5
+    xor t2, t2, t3
+    andi t3, t2, 0xFF
+    sw t3, 0(t0)
+    addi t0, t0, 4
+    addi t1, t1, -1
+    bnez t1, fill
+
+    li s0, ITERATIONS
+iteration:
+    li s1, 0
+row:
+    li s2, 0
+column:
+    li s3, 0
+    li s4, 0
+product:
+    # a0 = A[i][k], a1 = B[k][j]
+    slli t0, s1, 3
+    add t0, t0, s3
+    slli t0, t0, 2
+    la t1, matrix_a
+    add t1, t1, t0
+    lw a0, 0(t1)
+    slli t0, s3, 3
+    add t0, t0, s2
+    slli t0, t0, 2
+    la t1, matrix_b
+    add t1, t1, t0
+    lw a1, 0(t1)
+    call multiply
+    add s4, s4, a0
+    addi s3, s3, 1
+    li t0, N
+    bne s3, t0, product
+
+    # C[i][j] = the sum of the products
+    slli t0, s1, 3
+    add t0, t0, s2
+    slli t0, t0, 2
+    la t1, matrix_c
+    add t1, t1, t0
+    sw s4, 0(t1)
+    addi s2, s2, 1
+    li t0, N
+    bne s2, t0, column
+    addi s1, s1, 1
+    bne s1, t0, row
+
+    # Hash C
+    la t0, matrix_c
+    li t1, ELEMENTS
+    li s1, 0
+hash:
+    lw t2, 0(t0)
+    slli t3, s1, 5
+    add s1, s1, t3
+    xor s1, s1, t2
+    addi t0, t0, 4
+    addi t1, t1, -1
+    bnez t1, hash
+
+    addi s0, s0, -1
+    bnez s0, iteration
+    mv a1, s1
+    li a0, 0
+    ecall
+
+# a0 = a0 * a1
+multiply:
+    li t0, 0
+multiply_bit:
+    andi t1, a1, 1
+    beqz t1, multiply_next
+    add t0, t0, a0
+multiply_next:
+    slli a0, a0, 1
+    srli a1, a1, 1
+    bnez a1, multiply_bit
+    mv a0, t0
+    ret
+
+    .bss
+    # On its own page, as a linker would put it, rather than sharing one with the code
+    .balign 4096
+matrix_a:
+    .zero MATRIX_SIZE
+matrix_b:
+    .zero MATRIX_SIZE
+matrix_c:
+    .zero MATRIX_SIZE
@@ -0,0 +1,99 @@
+# Copy a buffer a word at a time, unrolled, and part of it a byte at a time to an unaligned
+# destination
+    .equ SIZE, 4096
+    .equ WORDS, 1024
+    .equ BYTES, 1024
+    # The words covering the unaligned copy
+    .equ UNALIGNED_WORDS, 257
+
+    # Fill the source with a xorshift sequence
+    la t0, source
+    li t1, WORDS
+    li t2, 0x2545F491
+fill:
+    slli t3, t2, 13
+    xor t2, t2, t3
+    srli t3, t2, 17
+    xor t2, t2, t3
+    slli t3, t2, 5
+    xor t2, t2, t3
+    sw t2, 0(t0)
+    addi t0, t0, 4
+    addi t1, t1, -1
+    bnez t1, fill
+
+    li s0, ITERATIONS
+iteration:
+    la a0, destination
+    la a1, source
+    li a2, SIZE
+    call copy_words
+    la a0, unaligned + 1
+    la a1, source
+    li a2, BYTES
+    call copy_bytes
+
+    la a0, destination
+    li a1, WORDS
+    call checksum
+    mv s1, a0
+    la a0, unaligned
+    li a1, UNALIGNED_WORDS
+    call checksum
+    xor s1, s1, a0
+
+    addi s0, s0, -1
+    bnez s0, iteration
+    mv a1, s1
+    li a0, 0
+    ecall
+
+# Copy a2 bytes, a multiple of 16, from a1 to a0
+copy_words:
+    lw t0, 0(a1)
+    lw t1, 4(a1)
+    lw t2, 8(a1)
+    lw t3, 12(a1)
+    sw t0, 0(a0)
+    sw t1, 4(a0)
+    sw t2, 8(a0)
+    sw t3, 12(a0)
+    addi a0, a0, 16
+    addi a1, a1, 16
+    addi a2, a2, -16
+    bnez a2, copy_words
+    ret
+
+# Copy a2 bytes from a1 to a0
+copy_bytes:
+    lbu t0, 0(a1)
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    bnez a2, copy_bytes
+    ret
+
+# Hash the a1 words at a0
+checksum:
+    li t0, 0
+checksum_word:
+    lw t1, 0(a0)
+    slli t2, t0, 5
+    add t0, t0, t2
+    xor t0, t0, t1
+    addi a0, a0, 4
+    addi a1, a1, -1
+    bnez a1, checksum_word
+    mv a0, t0
+    ret
+
+    .bss
+    # On its own page, as a linker would put it, rather than sharing one with the code
+    .balign 4096
+source:
+    .zero SIZE
+destination:
+    .zero SIZE
+unaligned:
+    .zero BYTES + 4
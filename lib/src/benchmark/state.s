@@ -0,0 +1,111 @@
+# CoreMark-style state machine: classify the comma separated tokens of a string as integers,
+# decimals, numbers with exponents or invalid, with a table of transitions.
+    .equ PASSES, 8
+    .equ START, 0
+    # The separator, which can't be written as a character in an operand list
+    .equ COMMA, 0x2C
+
+    li s0, ITERATIONS
+iteration:
+    # Clear the count of tokens ending in each state
+    la t0, counts
+    sw zero, 0(t0)
+    sw zero, 4(t0)
+    sw zero, 8(t0)
+    sw zero, 12(t0)
+    sw zero, 16(t0)
+    sw zero, 20(t0)
+    li s1, PASSES
+pass:
+    la s2, input
+    li s3, START
+    # The number of transitions to a different state
+    li s4, 0
+character:
+    lbu t0, 0(s2)
+    addi s2, s2, 1
+    beqz t0, token_end
+    li t1, COMMA
+    beq t0, t1, token_end
+
+    # Classify the character as a digit, sign, point, exponent or anything else
+    li t2, 0
+    addi t1, t0, -'0'
+    li t3, 10
+    bltu t1, t3, classified
+    li t2, 1
+    li t1, '+'
+    beq t0, t1, classified
+    li t1, '-'
+    beq t0, t1, classified
+    li t2, 2
+    li t1, '.'
+    beq t0, t1, classified
+    li t2, 3
+    ori t1, t0, 0x20
+    li t3, 'e'
+    beq t1, t3, classified
+    li t2, 4
+classified:
+    # state = transitions[state][class]
+    slli t1, s3, 2
+    add t1, t1, s3
+    add t1, t1, t2
+    la t3, transitions
+    add t3, t3, t1
+    lbu t1, 0(t3)
+    beq t1, s3, character
+    addi s4, s4, 1
+    mv s3, t1
+    j character
+
+token_end:
+    slli t1, s3, 2
+    la t2, counts
+    add t2, t2, t1
+    lw t3, 0(t2)
+    addi t3, t3, 1
+    sw t3, 0(t2)
+    li s3, START
+    bnez t0, character
+    addi s1, s1, -1
+    bnez s1, pass
+
+    # Hash the counts and transitions
+    mv s1, s4
+    la t0, counts
+    li t1, 6
+hash:
+    lw t2, 0(t0)
+    slli t3, s1, 5
+    add s1, s1, t3
+    xor s1, s1, t2
+    addi t0, t0, 4
+    addi t1, t1, -1
+    bnez t1, hash
+
+    addi s0, s0, -1
+    bnez s0, iteration
+    mv a1, s1
+    li a0, 0
+    ecall
+
+    .data
+    # On its own page, as a linker would put it, rather than sharing one with the code
+    .balign 4096
+input:
+    .ascii "5012,1.23,-874,+122,1.5e3,9.0e-2,abc,12x,.5,7e,-.25e+10,0,,3.14159,"
+    .ascii "++1,2.2.2,e5,65535,-0.0001,1e,42,7E-3,18446744073709551615,x,"
+    .asciz "-,.,1.,0.5e1x,314,2718e-3,1.41421,-1.73205,+2.23606e0,end"
+# Indexed by state then class. The states are start, integer, decimal, exponent, scientific and
+# invalid.
+transitions:
+    .byte 1, 1, 2, 5, 5
+    .byte 1, 5, 2, 3, 5
+    .byte 2, 5, 5, 3, 5
+    .byte 4, 4, 5, 5, 5
+    .byte 4, 5, 5, 5, 5
+    .byte 5, 5, 5, 5, 5
+    .align 2
+counts:
+    .zero 24
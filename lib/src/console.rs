@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// A source of bytes for the guest console. Both read functions return None once the input has
+/// been exhausted, and read_nonblocking also returns None if no byte is ready yet.
+pub trait ConsoleInput {
+    fn read_blocking(&mut self) -> Option<u8>;
+    fn read_nonblocking(&mut self) -> Option<u8>;
+}
+
+/// Console input backed by the host's stdin.
+///
+/// Stdin has no portable non-blocking read so we hand it to a reader thread which forwards each
+/// byte over a channel. The thread is only started on the first read so that programs which never
+/// read input do not hold stdin open.
+#[derive(Default)]
+pub struct StdinInput {
+    receiver: Option<Receiver<u8>>,
+}
+
+impl StdinInput {
+    pub fn new() -> Self {
+        Self { receiver: None }
+    }
+
+    fn receiver(&mut self) -> &Receiver<u8> {
+        self.receiver.get_or_insert_with(|| {
+            let (sender, receiver) = channel();
+            thread::spawn(move || {
+                let mut stdin = std::io::stdin();
+                let mut byte = [0u8];
+                while let Ok(1) = stdin.read(&mut byte) {
+                    if sender.send(byte[0]).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        })
+    }
+}
+
+impl ConsoleInput for StdinInput {
+    fn read_blocking(&mut self) -> Option<u8> {
+        self.receiver().recv().ok()
+    }
+
+    fn read_nonblocking(&mut self) -> Option<u8> {
+        match self.receiver().try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+}
+
+/// Console input from a fixed buffer (e.g, the contents of a file or a string given on the
+/// command line). Every byte is immediately available, so blocking and non-blocking reads behave
+/// the same.
+pub struct BufferedInput(VecDeque<u8>);
+
+impl BufferedInput {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self(bytes.iter().copied().collect())
+    }
+}
+
+impl ConsoleInput for BufferedInput {
+    fn read_blocking(&mut self) -> Option<u8> {
+        self.0.pop_front()
+    }
+
+    fn read_nonblocking(&mut self) -> Option<u8> {
+        self.0.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buffered_input() {
+        let mut input = BufferedInput::new(b"ab");
+        assert_eq!(input.read_blocking(), Some(b'a'));
+        assert_eq!(input.read_nonblocking(), Some(b'b'));
+        assert_eq!(input.read_nonblocking(), None);
+        assert_eq!(input.read_blocking(), None);
+    }
+}
@@ -1,14 +1,86 @@
 use crate::cpu::registers::Registers;
+use std::fmt;
+
+/// What the hart does with a load or store whose address isn't a multiple of its width. The spec
+/// allows it to either perform the access or raise an address-misaligned exception, leaving the
+/// trap handler to emulate it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    /// Perform the access as if it were aligned
+    #[default]
+    Allow,
+    /// Raise a load or store address-misaligned exception (cause 4 or 6)
+    Trap,
+    /// Perform the access, counting it in CpuState::misaligned
+    Count,
+}
+
+/// The misaligned accesses the hart has performed under MisalignedPolicy::Count.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MisalignedCounts {
+    pub loads: u64,
+    pub stores: u64,
+}
+
+/// The synchronous exceptions the hart raises, numbered as they are reported in mcause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAccessFault = 1,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+}
+
+/// An exception raised by the instruction at pc. value is what goes in mtval: the address for
+/// a misaligned or faulting access, or pc for a fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub exception: Exception,
+    pub value: u32,
+    pub pc: u32,
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Exception::InstructionAccessFault => "instruction access fault",
+            Exception::LoadAddressMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreAddressMisaligned => "store address misaligned",
+            Exception::StoreAccessFault => "store access fault",
+        };
+        write!(f, "{name} (cause {})", *self as u32)
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#010x} from PC {:#010x}",
+            self.exception, self.value, self.pc
+        )
+    }
+}
 
 #[derive(Debug)]
 pub struct CpuState<T: Default + Copy, const N: usize> {
     pub registers: Registers<T, N>,
+    pub misaligned_policy: MisalignedPolicy,
+    pub misaligned: MisalignedCounts,
+    /// Set by an instruction that raises an exception, which leaves the PC pointing at it, for
+    /// the Cpu to take (see Cpu::take_trap).
+    pub trap: Option<Trap>,
 }
 
 impl<T: Default + Copy, const N: usize> CpuState<T, N> {
     pub fn new() -> Self {
         Self {
             registers: Registers::<T, N>::new(),
+            misaligned_policy: MisalignedPolicy::Allow,
+            misaligned: MisalignedCounts::default(),
+            trap: None,
         }
     }
 }
@@ -8,6 +8,14 @@ pub struct Csrs {
 
     // We denote CSR 0x1 as 'test' in our implementation. Potentially we should flag this on / off.
     pub test: u32,
+
+    // The machine mode trap CSRs. Only direct mode mtvec is supported, and 0 means there is no
+    // trap handler (see Cpu::take_trap).
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
 }
 
 #[derive(Debug)]
@@ -36,12 +44,22 @@ impl Csrs {
             instret: 0,
             rdtime: 0,
             test: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
         }
     }
 
     pub fn get(&self, address: usize) -> Result<u32, IllegalCsrAddress> {
         match address {
             0x1 => Ok(self.test),
+            0x305 => Ok(self.mtvec),
+            0x340 => Ok(self.mscratch),
+            0x341 => Ok(self.mepc),
+            0x342 => Ok(self.mcause),
+            0x343 => Ok(self.mtval),
             0xC00 => Ok(lower(self.rdcycle)),
             0xC80 => Ok(upper(self.rdcycle)),
             0xC01 => Ok(lower(self.rdtime)),
@@ -58,6 +76,28 @@ impl Csrs {
                 self.test = value;
                 Ok(())
             }
+            // The mode bits are WARL, so vectored mode reads back as direct
+            0x305 => {
+                self.mtvec = value & !0b11;
+                Ok(())
+            }
+            0x340 => {
+                self.mscratch = value;
+                Ok(())
+            }
+            // Instructions are always 4 byte aligned
+            0x341 => {
+                self.mepc = value & !0b11;
+                Ok(())
+            }
+            0x342 => {
+                self.mcause = value;
+                Ok(())
+            }
+            0x343 => {
+                self.mtval = value;
+                Ok(())
+            }
             _ => Err(IllegalCsrAddress),
         }
     }
@@ -95,4 +135,18 @@ mod test {
         assert_eq!(csrs.get(0xC02).unwrap(), 250);
         assert_eq!(csrs.get(0xC82).unwrap(), 255);
     }
+
+    #[test]
+    fn test_trap_csrs() {
+        let mut csrs = Csrs::new();
+        for csr in [0x305, 0x340, 0x341, 0x342, 0x343] {
+            assert_eq!(csrs.get(csr).unwrap(), 0);
+            csrs.set(csr, 0x8000_0103).unwrap();
+        }
+        assert_eq!(csrs.get(0x305).unwrap(), 0x8000_0100);
+        assert_eq!(csrs.get(0x340).unwrap(), 0x8000_0103);
+        assert_eq!(csrs.get(0x341).unwrap(), 0x8000_0100);
+        assert_eq!(csrs.get(0x342).unwrap(), 0x8000_0103);
+        assert_eq!(csrs.get(0x343).unwrap(), 0x8000_0103);
+    }
 }
@@ -0,0 +1,151 @@
+/**
+ * Basic blocks: straight runs of decoded instructions ending at the first one that can jump or
+ * needs the environment, so that a block can be executed by calling each instruction's handler in
+ * turn without fetching or decoding anything. Each block remembers the blocks that last followed
+ * it, chaining them together so that loops rarely need the lookup by address.
+ *
+ * Blocks are only made from RAM, where writes are tracked (see Memory::mark_code), and are
+ * invalidated when their code is written.
+ */
+use super::rv32i::{Decoded, Handler, INSTRUCTION_SIZE};
+use crate::memory::{Access, Memory, CODE_PAGE_SIZE};
+use std::collections::HashMap;
+
+/// The most instructions a block holds
+const MAX_BLOCK_LENGTH: usize = 64;
+
+/// How many invalidated blocks are left in the cache before it is cleared out
+const MAX_INVALID_BLOCKS: usize = 1024;
+
+fn executable(memory: &Memory, address: u32) -> bool {
+    memory
+        .check_access(address as usize, INSTRUCTION_SIZE as usize, Access::Execute)
+        .is_ok()
+}
+
+pub(super) struct Block {
+    start: u32,
+    /// Every instruction but the last has a Handler::Execute
+    pub(super) instructions: Vec<Decoded>,
+    /// The start of the blocks last seen to follow this one, and their indices
+    next: [Option<(u32, usize)>; 2],
+    valid: bool,
+}
+
+impl Block {
+    /// Decode the block at pc, or None if pc isn't in RAM the hart may execute.
+    fn translate(memory: &mut Memory, pc: u32) -> Option<Block> {
+        let mut instructions = Vec::new();
+        let mut address = pc;
+        // Check each address is RAM before reading it so we never read from a device. A block
+        // stops short of code that can't be executed, which faults when it's fetched.
+        while executable(memory, address) && memory.mark_code(address as usize) {
+            let Ok(instruction) = memory.get32(address as usize) else {
+                break;
+            };
+            let decoded = Decoded::new(instruction);
+            instructions.push(decoded);
+            let ends = !matches!(decoded.handler, Handler::Execute(_))
+                || decoded.jumps()
+                || instructions.len() == MAX_BLOCK_LENGTH;
+            match address.checked_add(INSTRUCTION_SIZE) {
+                Some(next) if !ends => address = next,
+                _ => break,
+            }
+        }
+
+        (!instructions.is_empty()).then_some(Block {
+            start: pc,
+            instructions,
+            next: [None; 2],
+            valid: true,
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.start as u64 + (self.instructions.len() as u32 * INSTRUCTION_SIZE) as u64
+    }
+}
+
+pub(super) struct BlockCache {
+    blocks: Vec<Block>,
+    starts: HashMap<u32, usize>,
+    /// The block that ran last, to chain the next one to
+    last: Option<usize>,
+    invalid: usize,
+}
+
+impl BlockCache {
+    pub(super) fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            starts: HashMap::new(),
+            last: None,
+            invalid: 0,
+        }
+    }
+
+    pub(super) fn block(&self, index: usize) -> &Block {
+        &self.blocks[index]
+    }
+
+    /// The index of the block at pc, decoding it if need be. None if pc isn't in RAM.
+    pub(super) fn lookup(&mut self, memory: &mut Memory, pc: u32) -> Option<usize> {
+        let last = self.last.take();
+        let chained = last
+            .and_then(|last| {
+                self.blocks[last]
+                    .next
+                    .iter()
+                    .flatten()
+                    .find(|next| next.0 == pc)
+            })
+            .map(|&(_, index)| index)
+            .filter(|&index| self.blocks[index].valid);
+        if chained.is_some() {
+            self.last = chained;
+            return chained;
+        }
+
+        let index = match self.starts.get(&pc) {
+            Some(&index) => index,
+            None => {
+                let block = Block::translate(memory, pc)?;
+                self.blocks.push(block);
+                self.starts.insert(pc, self.blocks.len() - 1);
+                self.blocks.len() - 1
+            }
+        };
+
+        // The newest successor goes first, pushing out the oldest
+        if let Some(last) = last {
+            let next = &mut self.blocks[last].next;
+            next[1] = next[0];
+            next[0] = Some((pc, index));
+        }
+        self.last = Some(index);
+        Some(index)
+    }
+
+    /// Forget the blocks with instructions in the code page at address.
+    pub(super) fn invalidate(&mut self, address: usize) {
+        let (page_start, page_end) = (address as u64, (address + CODE_PAGE_SIZE) as u64);
+        for block in self.blocks.iter_mut().filter(|block| block.valid) {
+            if (block.start as u64) < page_end && page_start < block.end() {
+                block.valid = false;
+                self.starts.remove(&block.start);
+                self.invalid += 1;
+            }
+        }
+
+        // Chains to invalid blocks are skipped, so the blocks themselves only go when the whole
+        // cache is cleared
+        if self.invalid > MAX_INVALID_BLOCKS && self.invalid > self.blocks.len() / 2 {
+            self.clear();
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
@@ -1,3 +1,4 @@
+mod blocks;
 pub mod op_args;
 pub mod rv32i;
 
@@ -1,57 +1,79 @@
 use crate::cpu::base::CpuState;
-use crate::instruction::decoder;
+use crate::instruction::{decoder, opcodes};
 use crate::memory::Memory;
 use std::default::Default;
 use std::marker::Copy;
 
+/// The fields of an instruction, extracted once so it can be executed many times without decoding
+/// it again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Operands {
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub funct3: u8,
+    pub funct7: u8,
+    /// The immediate in the instruction's format (I, S, B, U or J), or 0 for OP instructions
+    pub immediate: i32,
+}
+
+impl Operands {
+    pub const fn new(instruction: u32) -> Self {
+        let immediate = match decoder::opcode(instruction) {
+            opcodes::OP => 0,
+            opcodes::STORE => decoder::s_type_immediate_32(instruction),
+            opcodes::BRANCH => decoder::b_type_immediate_32(instruction),
+            opcodes::LUI | opcodes::AUIPC => decoder::u_type_immediate(instruction),
+            opcodes::JAL => decoder::j_type_immediate_32(instruction),
+            _ => decoder::i_type_immediate_32(instruction),
+        };
+
+        Self {
+            rd: decoder::rd(instruction) as u8,
+            rs1: decoder::rs1(instruction) as u8,
+            rs2: decoder::rs2(instruction) as u8,
+            funct3: decoder::funct3(instruction),
+            funct7: decoder::funct7(instruction),
+            immediate,
+        }
+    }
+}
+
 pub struct OpArgs<'a, 'b, T: Default + Copy, const N: usize> {
     pub state: &'a mut CpuState<T, N>,
     pub memory: &'b mut Memory,
     pub instruction: u32,
+    pub operands: Operands,
 }
 
 impl<'a, 'b, T: Default + Copy, const N: usize> OpArgs<'a, 'b, T, N> {
-    pub fn i_imm(&self) -> i32 {
-        decoder::i_type_immediate_32(self.instruction)
+    /// The immediate, sign extended, in whichever format the instruction uses.
+    pub fn immediate(&self) -> i32 {
+        self.operands.immediate
     }
 
+    /// The CSR address of a SYSTEM instruction (the unsigned I-type immediate).
     pub fn csr(&self) -> u32 {
-        decoder::csr(self.instruction)
-    }
-
-    pub fn b_imm(&self) -> i32 {
-        decoder::b_type_immediate_32(self.instruction)
-    }
-
-    pub fn s_imm(&self) -> i32 {
-        decoder::s_type_immediate_32(self.instruction)
-    }
-
-    pub fn u_imm(&self) -> i32 {
-        decoder::u_type_immediate(self.instruction)
-    }
-
-    pub fn j_imm(&self) -> i32 {
-        decoder::j_type_immediate_32(self.instruction)
+        self.operands.immediate as u32 & 0xFFF
     }
 
     pub fn rd(&self) -> usize {
-        decoder::rd(self.instruction)
+        self.operands.rd as usize
     }
 
     pub fn rs1(&self) -> usize {
-        decoder::rs1(self.instruction)
+        self.operands.rs1 as usize
     }
 
     pub fn rs2(&self) -> usize {
-        decoder::rs2(self.instruction)
+        self.operands.rs2 as usize
     }
 
     pub fn funct3(&self) -> u8 {
-        decoder::funct3(self.instruction)
+        self.operands.funct3
     }
 
     pub fn funct7(&self) -> u8 {
-        decoder::funct7(self.instruction)
+        self.operands.funct7
     }
 }
@@ -1,15 +1,19 @@
+use super::blocks::BlockCache;
+use crate::cpu::base::{Exception, MisalignedPolicy, Trap};
+use crate::devices::PowerRequest;
 use crate::instruction::{
     decoder,
-    funct3::{branch, load, op, op_imm, store, system},
+    funct3::{branch, fence, load, op, op_imm, store, system},
     opcodes,
     util::C_5_BITS,
 };
-use crate::memory::{Memory, MemoryError};
+use crate::memory::{Access, Memory, MemoryError, CODE_PAGE_SIZE};
 use std::time::SystemTime;
 
-const INSTRUCTION_SIZE: u32 = 4;
+pub const INSTRUCTION_SIZE: u32 = 4;
 const FUNCT7_SWITCH: u8 = 0b0100000;
 
+pub use super::op_args::Operands;
 pub type OpArgs<'a, 'b> = super::op_args::OpArgs<'a, 'b, u32, 32>;
 pub type CpuState = crate::cpu::base::CpuState<u32, 32>;
 
@@ -20,10 +24,25 @@ fn trap_opcode(op: &OpArgs) {
     panic!("Illegal opcode trap when handling instruction {instruction:02x} {op:08b} {state:?}")
 }
 
-fn trap_memory_access(address: u32, op: &OpArgs) {
-    let instruction = op.instruction;
-    let state = &op.state;
-    panic!("Illegal memory access trap when accessing address {instruction:032b} {address:016x} {state:?}")
+/// Raise exception for the instruction being executed, with value for mtval. The instruction
+/// doesn't retire, and the PC is left pointing at it for the Cpu to take the trap.
+fn raise(op: &mut OpArgs, exception: Exception, value: u32) {
+    let pc = op.state.registers.pc;
+    op.state.trap = Some(Trap {
+        exception,
+        value,
+        pc,
+    });
+}
+
+/// Raise an instruction access fault for the instruction at pc, which can't be fetched, e.g,
+/// from memory that isn't executable.
+pub(crate) fn raise_instruction_access_fault(cpu_state: &mut CpuState, pc: u32) {
+    cpu_state.trap = Some(Trap {
+        exception: Exception::InstructionAccessFault,
+        value: pc,
+        pc,
+    });
 }
 
 fn trap_illegal_csr_operation(csr_address: usize, op: &OpArgs, is_write: bool) -> ! {
@@ -74,7 +93,7 @@ fn apply_op_with_funct7_switch<F1: Fn(i32, i32) -> i32, F2: Fn(i32, i32) -> i32>
 fn apply_op_imm<F: Fn(i32, i32) -> i32>(op: &mut OpArgs, f: F) {
     let source_register = op.rs1();
     let destination_register = op.rd();
-    let immediate = op.i_imm();
+    let immediate = op.immediate();
     let source_value = op.state.registers.get(source_register) as i32;
     let new_value = f(source_value, immediate);
     op.state
@@ -174,7 +193,7 @@ fn op(op: &mut OpArgs) {
 fn apply_branch<F: Fn(i32, i32) -> bool>(op: &mut OpArgs, f: F) {
     let source_one = op.rs1();
     let source_two = op.rs2();
-    let offset = op.b_imm();
+    let offset = op.immediate();
 
     if f(
         op.state.registers.geti(source_one),
@@ -213,21 +232,55 @@ fn branch(op: &mut OpArgs) {
     };
 }
 
+/// The width of a load or store: 1 << funct3, ignoring the bit that makes a load unsigned.
+fn access_width(op: &OpArgs) -> u32 {
+    1 << (op.funct3() & 0b11)
+}
+
+/// Apply the hart's MisalignedPolicy to a load or store at address, returning whether the access
+/// goes ahead.
+fn check_alignment(address: u32, op: &mut OpArgs, is_store: bool) -> bool {
+    if address.is_multiple_of(access_width(op)) {
+        return true;
+    }
+    match op.state.misaligned_policy {
+        MisalignedPolicy::Allow => (),
+        MisalignedPolicy::Trap if is_store => {
+            raise(op, Exception::StoreAddressMisaligned, address);
+            return false;
+        }
+        MisalignedPolicy::Trap => {
+            raise(op, Exception::LoadAddressMisaligned, address);
+            return false;
+        }
+        MisalignedPolicy::Count if is_store => op.state.misaligned.stores += 1,
+        MisalignedPolicy::Count => op.state.misaligned.loads += 1,
+    }
+    true
+}
+
 /// Apply the load function. This computes the address of the load and then passes the addres to a
 /// custom F that applies the funct3 specific logic. The return is then written to rd.
 fn apply_load<F: Fn(u32, &Memory) -> Result<i32, MemoryError>>(op: &mut OpArgs, f: F) {
     let source = op.rs1();
-    let offset = op.i_imm();
+    let offset = op.immediate();
     let destination = op.rd();
     let source_address = (op.state.registers.geti(source) + offset) as u32;
-    let result = f(source_address, op.memory);
+    if !check_alignment(source_address, op, false) {
+        return;
+    }
+    let width = access_width(op) as usize;
+    let result = op
+        .memory
+        .check_access(source_address as usize, width, Access::Read)
+        .and_then(|()| f(source_address, op.memory));
 
     match result {
         Ok(result) => {
             op.state.registers.seti(destination, result);
             op.state.registers.pc += INSTRUCTION_SIZE
         }
-        Err(_) => trap_memory_access(source_address, op),
+        Err(_) => raise(op, Exception::LoadAccessFault, source_address),
     }
 }
 
@@ -271,12 +324,20 @@ fn load(op: &mut OpArgs) {
 /// source register, then hand off to a user supplied f to apply the funct3 behaviour.
 fn apply_store<F: Fn(u32, u32, &mut Memory) -> Result<(), MemoryError>>(op: &mut OpArgs, f: F) {
     let destination = op.rs1();
-    let offset = op.s_imm();
+    let offset = op.immediate();
     let destination_address = (op.state.registers.geti(destination) + offset) as u32;
+    if !check_alignment(destination_address, op, true) {
+        return;
+    }
     let source_value = op.state.registers.get(op.rs2());
-    match f(destination_address, source_value, op.memory) {
+    let width = access_width(op) as usize;
+    let result = op
+        .memory
+        .check_access(destination_address as usize, width, Access::Write)
+        .and_then(|()| f(destination_address, source_value, op.memory));
+    match result {
         Ok(()) => op.state.registers.pc += INSTRUCTION_SIZE,
-        Err(_) => trap_memory_access(destination_address, op),
+        Err(_) => raise(op, Exception::StoreAccessFault, destination_address),
     }
 }
 
@@ -301,7 +362,7 @@ fn store(op: &mut OpArgs) {
 /// into rd. All other bits are set to zero)
 fn lui(op: &mut OpArgs) {
     let destination_register = op.rd();
-    let immediate = op.u_imm();
+    let immediate = op.immediate();
     op.state
         .registers
         .set(destination_register, immediate as u32);
@@ -312,7 +373,7 @@ fn lui(op: &mut OpArgs) {
 /// and places it in RD. This can be used to compute addresses for JALR instructions.
 fn auipc(op: &mut OpArgs) {
     let destination_register = op.rd();
-    let immediate = op.u_imm();
+    let immediate = op.immediate();
     op.state.registers.set(
         destination_register,
         op.state.registers.pc + (immediate as u32),
@@ -324,7 +385,7 @@ fn auipc(op: &mut OpArgs) {
 /// current PC + 4 in the destination register.
 fn jal(op: &mut OpArgs) {
     let destination_register = op.rd();
-    let imm_value = op.j_imm();
+    let imm_value = op.immediate();
     let new_pc = ((op.state.registers.pc as i32) + imm_value) as u32;
 
     op.state
@@ -346,7 +407,7 @@ fn jalr(op: &mut OpArgs) {
     let source_register = op.rs1();
     let destination_register = op.rd();
     let source_value = op.state.registers.geti(source_register);
-    let imm_value = op.i_imm();
+    let imm_value = op.immediate();
     let new_pc = (source_value + imm_value) as u32;
     let new_pc = new_pc & !1;
     op.state
@@ -356,20 +417,18 @@ fn jalr(op: &mut OpArgs) {
 }
 
 fn fence(op: &mut OpArgs) {
-    // Fence is implement as a no-op as we only execute a single hart and do not pre-cache
-    // instruction implementations.
+    // Fence is implement as a no-op as we only execute a single hart. FENCE.I is handled by the
+    // InstructionSet, which flushes its decoded instructions.
     op.state.registers.pc += INSTRUCTION_SIZE;
 }
 
+/// ECALL and EBREAK are both handed to the environment, which can tell them apart with
+/// op.immediate() (ECALL is 0 and EBREAK is 1).
 fn ecall_or_ebreak<F: FnOnce(&mut OpArgs) -> ()>(op: &mut OpArgs, ecall: F) {
     const ECALL: i32 = 0;
     const EBREAK: i32 = 1;
-    match op.i_imm() {
-        ECALL => {
-            /* ECALL */
-            ecall(op)
-        }
-        EBREAK => unsafe { std::intrinsics::breakpoint() },
+    match op.immediate() {
+        ECALL | EBREAK => ecall(op),
         _ =>
         /* Illegal parameter */
         {
@@ -507,8 +566,15 @@ fn csr_rsi(op: &mut OpArgs) {
     });
 }
 
+/// MRET returns from a trap handler to the instruction at mepc.
+fn mret(op: &mut OpArgs) {
+    op.state.registers.pc = op.state.registers.csrs.mepc;
+}
+
 fn system<F: FnOnce(&mut OpArgs) -> ()>(op: &mut OpArgs, ecall: F) {
+    const MRET: i32 = 0x302;
     match op.funct3() {
+        system::ECALL_OR_EBREAK if op.immediate() == MRET => return mret(op),
         system::ECALL_OR_EBREAK => ecall_or_ebreak(op, ecall),
         system::CSRRW => csr_rw(op),
         system::CSRRS => csr_rs(op),
@@ -526,47 +592,264 @@ fn system<F: FnOnce(&mut OpArgs) -> ()>(op: &mut OpArgs, ecall: F) {
     op.state.registers.pc += INSTRUCTION_SIZE;
 }
 
-pub struct InstructionSet {}
+/// What executing an instruction involves.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Handler {
+    Execute(fn(&mut OpArgs)),
+    /// ECALL, EBREAK, MRET and the CSR instructions, which may need the environment
+    System,
+    FenceI,
+}
+
+/// An instruction decoded ready to execute.
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub instruction: u32,
+    pub operands: Operands,
+    pub(super) handler: Handler,
+}
+
+impl Decoded {
+    pub fn new(instruction: u32) -> Self {
+        let operands = Operands::new(instruction);
+        let handler = match decoder::opcode(instruction) {
+            opcodes::OP => Handler::Execute(op),
+            opcodes::OP_IMM => Handler::Execute(op_imm),
+            opcodes::JAL => Handler::Execute(jal),
+            opcodes::JALR => Handler::Execute(jalr),
+            opcodes::LUI => Handler::Execute(lui),
+            opcodes::AUIPC => Handler::Execute(auipc),
+            opcodes::BRANCH => Handler::Execute(branch),
+            opcodes::LOAD => Handler::Execute(load),
+            opcodes::STORE => Handler::Execute(store),
+            opcodes::FENCE if operands.funct3 == fence::FENCE_I => Handler::FenceI,
+            opcodes::FENCE => Handler::Execute(fence),
+            opcodes::SYSTEM => Handler::System,
+            _ => Handler::Execute(|op| trap_opcode(op)),
+        };
+
+        Self {
+            instruction,
+            operands,
+            handler,
+        }
+    }
+
+    /// Whether the instruction can change the PC other than moving on to the next instruction.
+    pub(super) fn jumps(&self) -> bool {
+        matches!(
+            decoder::opcode(self.instruction),
+            opcodes::JAL | opcodes::JALR | opcodes::BRANCH
+        )
+    }
+}
+
+/// The number of instructions the decode cache holds
+const CACHE_ENTRIES: usize = 1 << 16;
+
+/// A direct mapped cache of decoded instructions, keyed by their address.
+struct DecodeCache {
+    entries: Vec<Option<(u32, Decoded)>>,
+}
+
+impl DecodeCache {
+    fn new() -> Self {
+        Self {
+            entries: vec![None; CACHE_ENTRIES],
+        }
+    }
+
+    fn index(pc: u32) -> usize {
+        (pc / INSTRUCTION_SIZE) as usize % CACHE_ENTRIES
+    }
+
+    fn get(&self, pc: u32) -> Option<Decoded> {
+        match self.entries[Self::index(pc)] {
+            Some((address, decoded)) if address == pc => Some(decoded),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, pc: u32, decoded: Decoded) {
+        self.entries[Self::index(pc)] = Some((pc, decoded));
+    }
+
+    /// Forget the instructions in the code page at address.
+    fn invalidate(&mut self, address: usize) {
+        for pc in (address..address + CODE_PAGE_SIZE).step_by(INSTRUCTION_SIZE as usize) {
+            let entry = &mut self.entries[Self::index(pc as u32)];
+            if matches!(entry, Some((address, _)) if *address == pc as u32) {
+                *entry = None;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+}
+
+/// Executes instructions, keeping those it has fetched decoded so that they run faster the next
+/// time, either one at a time or a block at a time. Decoded instructions are forgotten when memory
+/// they came from is written, or on a FENCE.I.
+pub struct InstructionSet {
+    cache: DecodeCache,
+    blocks: BlockCache,
+    fences: u64,
+}
 
 impl InstructionSet {
-    pub const fn new() -> Self {
-        Self {}
+    pub fn new() -> Self {
+        Self {
+            cache: DecodeCache::new(),
+            blocks: BlockCache::new(),
+            fences: 0,
+        }
+    }
+
+    /// Forget the instructions decoded from the code page at address (see
+    /// Memory::take_written_code).
+    pub fn invalidate(&mut self, address: usize) {
+        self.cache.invalidate(address);
+        self.blocks.invalidate(address);
+    }
+
+    fn invalidate_written_code(&mut self, memory: &mut Memory) {
+        for page in memory.take_written_code() {
+            self.invalidate(page);
+        }
+    }
+
+    /// The number of FENCE.Is executed, for anything else keeping code that they should flush.
+    pub fn fences(&self) -> u64 {
+        self.fences
+    }
+
+    /// Fetch and decode the instruction at pc. Only instructions the hart may execute are cached,
+    /// and protecting memory invalidates them (see Memory::protect).
+    pub fn fetch(&mut self, memory: &mut Memory, pc: u32) -> Result<Decoded, MemoryError> {
+        self.invalidate_written_code(memory);
+
+        if let Some(decoded) = self.cache.get(pc) {
+            return Ok(decoded);
+        }
+
+        memory.check_access(pc as usize, INSTRUCTION_SIZE as usize, Access::Execute)?;
+        let decoded = Decoded::new(memory.get32(pc as usize)?);
+        // Instructions outside RAM (e.g, in a device) aren't cached, as writes to them aren't
+        // tracked
+        if memory.mark_code(pc as usize) {
+            self.cache.insert(pc, decoded);
+        }
+        Ok(decoded)
     }
 
+    /// Decode and execute an instruction.
     pub fn step<F: FnOnce(&mut OpArgs) -> ()>(
-        &self,
+        &mut self,
         cpu_state: &mut CpuState,
         memory: &mut Memory,
         instruction: u32,
         ecall: F,
     ) {
-        cpu_state.registers.csrs.rdtime = (SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()) as u64;
+        self.execute(cpu_state, memory, &Decoded::new(instruction), ecall)
+    }
 
+    pub fn execute<F: FnOnce(&mut OpArgs)>(
+        &mut self,
+        cpu_state: &mut CpuState,
+        memory: &mut Memory,
+        decoded: &Decoded,
+        ecall: F,
+    ) {
         let op_arg = &mut OpArgs {
             state: cpu_state,
-            memory: memory,
-            instruction: instruction,
+            memory,
+            instruction: decoded.instruction,
+            operands: decoded.operands,
+        };
+
+        match decoded.handler {
+            Handler::Execute(handler) => handler(op_arg),
+            Handler::System => {
+                // The time is only visible to CSR reads, and reading the clock on every step is
+                // as slow as the rest of the step put together
+                op_arg.state.registers.csrs.rdtime = (SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()) as u64;
+                system(op_arg, ecall)
+            }
+            Handler::FenceI => {
+                self.cache.clear();
+                self.blocks.clear();
+                self.fences += 1;
+                fence(op_arg)
+            }
+        }
+
+        if cpu_state.trap.is_none() {
+            retire(cpu_state);
+        }
+    }
+
+    /// Execute the block of instructions at the PC, up to and including the first that can jump
+    /// or needs the environment. Devices are ticked before each instruction, as Cpu::step does,
+    /// and the block stops early if one of them makes a power request, an instruction raises an
+    /// exception or code is written. Code outside RAM runs an instruction at a time.
+    pub fn run_block<F: FnOnce(&mut OpArgs)>(
+        &mut self,
+        cpu_state: &mut CpuState,
+        memory: &mut Memory,
+        ecall: F,
+    ) -> Option<PowerRequest> {
+        self.invalidate_written_code(memory);
+        let pc = cpu_state.registers.pc;
+        let Some(index) = self.blocks.lookup(memory, pc) else {
+            let request = memory.tick();
+            if request.is_none() {
+                match self.fetch(memory, pc) {
+                    Ok(decoded) => self.execute(cpu_state, memory, &decoded, ecall),
+                    Err(_) => raise_instruction_access_fault(cpu_state, pc),
+                }
+            }
+            return request;
         };
 
-        match decoder::opcode(instruction) {
-            opcodes::OP => op(op_arg),
-            opcodes::OP_IMM => op_imm(op_arg),
-            opcodes::JAL => jal(op_arg),
-            opcodes::JALR => jalr(op_arg),
-            opcodes::LUI => lui(op_arg),
-            opcodes::AUIPC => auipc(op_arg),
-            opcodes::BRANCH => branch(op_arg),
-            opcodes::LOAD => load(op_arg),
-            opcodes::STORE => store(op_arg),
-            opcodes::FENCE => fence(op_arg),
-            opcodes::SYSTEM => system(op_arg, ecall),
-            _ => trap_opcode(op_arg),
+        let (&last, body) = self.blocks.block(index).instructions.split_last().unwrap();
+        for decoded in body {
+            if let Some(request) = memory.tick() {
+                return Some(request);
+            }
+            let Handler::Execute(handler) = decoded.handler else {
+                unreachable!("only the last instruction of a block can need more than a handler")
+            };
+            handler(&mut OpArgs {
+                state: cpu_state,
+                memory,
+                instruction: decoded.instruction,
+                operands: decoded.operands,
+            });
+            if cpu_state.trap.is_some() {
+                return None;
+            }
+            retire(cpu_state);
+
+            // The block may have overwritten itself
+            if memory.code_written() {
+                return None;
+            }
         }
 
-        cpu_state.registers.csrs.rdcycle += 1;
-        cpu_state.registers.csrs.instret += 1;
+        let request = memory.tick();
+        if request.is_none() {
+            self.execute(cpu_state, memory, &last, ecall);
+        }
+        request
     }
 }
+
+fn retire(cpu_state: &mut CpuState) {
+    cpu_state.registers.csrs.rdcycle += 1;
+    cpu_state.registers.csrs.instret += 1;
+}
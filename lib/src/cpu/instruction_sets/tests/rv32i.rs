@@ -1,3 +1,4 @@
+use crate::cpu::base::{Exception, MisalignedCounts, MisalignedPolicy, Trap};
 use crate::cpu::instruction_sets::rv32i::{CpuState, InstructionSet, OpArgs};
 use crate::instruction::encoder::{self, Instruction};
 use crate::memory::Memory;
@@ -844,6 +845,164 @@ fn execute_sw() {
     assert_eq!(test.memory.get32(504), Ok(0xDEADBEFF));
 }
 
+/// A load or store of more than a byte, with x1 as its base and x2 as its destination or source
+struct WideAccess {
+    instruction: Instruction,
+    width: u32,
+    store: bool,
+    /// Read back what it accessed, as it would be loaded into a register
+    read: fn(&Memory, usize) -> u32,
+}
+
+fn wide_accesses() -> [WideAccess; 5] {
+    let half = |memory: &Memory, address| memory.get16(address).unwrap() as u32;
+    let word = |memory: &Memory, address| memory.get32(address).unwrap();
+    [
+        WideAccess {
+            instruction: encoder::lh(1, 2, 0),
+            width: 2,
+            store: false,
+            read: |memory, address| memory.get16(address).unwrap() as i16 as u32,
+        },
+        WideAccess {
+            instruction: encoder::lhu(1, 2, 0),
+            width: 2,
+            store: false,
+            read: half,
+        },
+        WideAccess {
+            instruction: encoder::lw(1, 2, 0),
+            width: 4,
+            store: false,
+            read: word,
+        },
+        WideAccess {
+            instruction: encoder::sh(1, 2, 0),
+            width: 2,
+            store: true,
+            read: half,
+        },
+        WideAccess {
+            instruction: encoder::sw(1, 2, 0),
+            width: 4,
+            store: true,
+            read: word,
+        },
+    ]
+}
+
+/// Execute access at address under policy, checking it loads or stores what an aligned access
+/// would and nothing else. Returns the misaligned accesses counted, or the trap it raised, in
+/// which case it must not have retired or changed anything.
+fn execute_at(
+    access: &WideAccess,
+    address: u32,
+    policy: MisalignedPolicy,
+) -> Result<MisalignedCounts, Trap> {
+    const START: usize = 496;
+    let original = |byte: usize| 0xF0 + (byte - START) as u8;
+    let mut test = init();
+    test.state.misaligned_policy = policy;
+    for byte in START..START + 16 {
+        test.memory.set8(byte, original(byte)).unwrap();
+    }
+    test.set_register(1, address as i32);
+    test.set_register(2, 0x1234_5678);
+
+    // Whether the bytes outside accessed still hold what they started with
+    let untouched = |test: &TestEnvironment, accessed: std::ops::Range<usize>| {
+        (START..START + 16)
+            .filter(|byte| !accessed.contains(byte))
+            .all(|byte| test.memory.get8(byte) == Ok(original(byte)))
+    };
+    test.step(&access.instruction);
+    if let Some(trap) = test.state.trap {
+        assert_eq!(test.state.registers.pc, 0);
+        assert_eq!(test.state.registers.csrs.instret, 0);
+        assert_eq!(test.get_register(2), 0x1234_5678);
+        assert!(untouched(&test, 0..0));
+        return Err(trap);
+    }
+    assert_eq!(test.state.registers.pc, 4);
+
+    let value = (access.read)(&test.memory, address as usize);
+    match access.store {
+        true => assert_eq!(value, 0x1234_5678 & (u32::MAX >> (32 - 8 * access.width))),
+        false => assert_eq!(test.get_register(2) as u32, value),
+    }
+    let accessed = address as usize..(address + access.width) as usize;
+    assert!(untouched(&test, accessed));
+    Ok(test.state.misaligned)
+}
+
+#[test]
+fn misaligned_accesses_are_allowed() {
+    assert_eq!(CpuState::new().misaligned_policy, MisalignedPolicy::Allow);
+    for access in &wide_accesses() {
+        for address in 500..504 {
+            assert_eq!(
+                execute_at(access, address, MisalignedPolicy::Allow),
+                Ok(MisalignedCounts::default())
+            );
+        }
+    }
+}
+
+#[test]
+fn misaligned_accesses_trap() {
+    for access in &wide_accesses() {
+        for address in 500..504 {
+            let exception = match access.store {
+                true => Exception::StoreAddressMisaligned,
+                false => Exception::LoadAddressMisaligned,
+            };
+            let expected = match address % access.width {
+                0 => Ok(MisalignedCounts::default()),
+                _ => Err(Trap {
+                    exception,
+                    value: address,
+                    pc: 0,
+                }),
+            };
+            assert_eq!(
+                execute_at(access, address, MisalignedPolicy::Trap),
+                expected,
+                "at {address}"
+            );
+        }
+    }
+}
+
+#[test]
+fn misaligned_accesses_are_counted() {
+    for access in &wide_accesses() {
+        for address in 500..504 {
+            let misaligned = (address % access.width != 0) as u64;
+            let expected = match access.store {
+                true => MisalignedCounts {
+                    loads: 0,
+                    stores: misaligned,
+                },
+                false => MisalignedCounts {
+                    loads: misaligned,
+                    stores: 0,
+                },
+            };
+            assert_eq!(
+                execute_at(access, address, MisalignedPolicy::Count),
+                Ok(expected)
+            );
+        }
+    }
+}
+
+#[test]
+fn mret() {
+    let mut test = init();
+    test.state.registers.csrs.mepc = 0x100;
+    test.dbg_step_jmp(&encoder::mret(), 0x100);
+}
+
 #[test]
 fn execute_fence() {
     let mut test = init();
@@ -869,9 +1028,13 @@ fn ecall() {
 
 #[test]
 fn ebreak() {
-    // TODO: I tested this and observed a breakpoint trap. I'm not sure how to test it
-    // systematically. Maybe we could set a flag debug_breakpoint to true rather than actually
-    // breaking in the core.
+    let mut test = init();
+    let mut immediate = None;
+    test.step_with_ecall(&encoder::ebreak(), |op| {
+        immediate = Some(op.immediate());
+    });
+    assert_eq!(immediate, Some(1));
+    assert_eq!(4, test.state.registers.pc);
 }
 
 #[test]
@@ -0,0 +1,405 @@
+/**
+ * Compiles a basic block of guest code to x86-64. Each instruction loads its sources from the
+ * context and stores its result straight back, so that the context is up to date whenever the
+ * block leaves: at its end, after a slow path access, or at an instruction it can't run.
+ */
+use super::x86::{Alu, Assembler, Condition, Extend, Label, Register::*, Shift};
+use super::{slow_load, slow_store, Context};
+use super::{CODE_PAGES, EXIT_CONTINUE, EXIT_INTERPRET, PAGES, PC, RAM_BASE, RAM_BOUNDS};
+use super::{REGISTERS, RETIRED};
+use crate::instruction::decoder;
+use crate::instruction::encoder::Instruction;
+use crate::instruction::funct3::{branch, load, op, op_imm};
+use crate::memory::{Access, Memory, CODE_PAGE_SIZE, PAGE_SIZE};
+
+/// The most instructions a block holds
+const MAX_BLOCK_LENGTH: u32 = 64;
+
+pub(super) struct Compiled {
+    pub(super) code: Vec<u8>,
+    /// The number of instructions in the block
+    pub(super) length: u32,
+}
+
+/// The context offset of a guest register.
+const fn register(index: usize) -> usize {
+    REGISTERS + 4 * index
+}
+
+struct Compiler {
+    assembler: Assembler,
+    /// The address of the instruction being compiled
+    pc: u32,
+    /// The number of instructions before it in the block
+    index: u32,
+    /// Whether misaligned accesses are left to the interpreter, which applies the hart's
+    /// MisalignedPolicy
+    check_alignment: bool,
+}
+
+impl Compiler {
+    /// Leave the block with the PC set to pc, or to eax if None.
+    fn exit(&mut self, pc: Option<u32>, retired: u32, reason: u32) {
+        let assembler = &mut self.assembler;
+        match pc {
+            Some(pc) => assembler.store_immediate(PC, pc),
+            None => assembler.store(PC, Rax),
+        }
+        assembler.store_immediate(RETIRED, retired);
+        assembler.mov_immediate(Rax, reason);
+        assembler.pop_rbx();
+        assembler.ret();
+    }
+
+    /// Leave the block after the current instruction, continuing at pc.
+    fn exit_to(&mut self, pc: u32) {
+        self.exit(Some(pc), self.index + 1, EXIT_CONTINUE);
+    }
+
+    fn next_pc(&self) -> u32 {
+        self.pc.wrapping_add(4)
+    }
+
+    /// Store eax to register rd, unless it's x0.
+    fn write(&mut self, rd: usize) {
+        if rd != 0 {
+            self.assembler.store(register(rd), Rax);
+        }
+    }
+
+    fn write_immediate(&mut self, rd: usize, value: u32) {
+        if rd != 0 {
+            self.assembler.store_immediate(register(rd), value);
+        }
+    }
+
+    /// Compile an instruction, returning whether it ended the block or None if it can't be
+    /// compiled, in which case nothing was emitted.
+    fn instruction(&mut self, instruction: Instruction) -> Option<bool> {
+        match instruction {
+            Instruction::OpImm {
+                destination_register,
+                source_register,
+                funct3,
+                immediate,
+            } => self.op_imm(destination_register, source_register, funct3, immediate),
+            Instruction::Op {
+                destination_register,
+                source_register1,
+                source_register2,
+                funct3,
+                funct7,
+            } => self.op(
+                destination_register,
+                source_register1,
+                source_register2,
+                funct3,
+                funct7,
+            ),
+            Instruction::Lui {
+                destination_register,
+                value,
+            } => self.write_immediate(destination_register, value),
+            Instruction::Auipc {
+                destination_register,
+                value,
+            } => self.write_immediate(destination_register, self.pc.wrapping_add(value)),
+            Instruction::Jal {
+                destination_register,
+                address_offset,
+            } => {
+                let target = self.pc.wrapping_add(address_offset as u32);
+                // A misaligned target traps, which is left to the interpreter
+                if !target.is_multiple_of(4) {
+                    return None;
+                }
+                self.write_immediate(destination_register, self.next_pc());
+                self.exit_to(target);
+                return Some(true);
+            }
+            Instruction::Jalr {
+                destination_register,
+                source_register,
+                address_offset,
+            } => {
+                let assembler = &mut self.assembler;
+                assembler.load(Rax, register(source_register));
+                assembler.alu_immediate(Alu::Add, Rax, address_offset as i32 as u32);
+                assembler.alu_immediate(Alu::And, Rax, !1);
+                self.write_immediate(destination_register, self.next_pc());
+                self.exit(None, self.index + 1, EXIT_CONTINUE);
+                return Some(true);
+            }
+            Instruction::Branch {
+                funct3,
+                source_register1,
+                source_register2,
+                branch_offset,
+            } => {
+                let target = self.pc.wrapping_add(branch_offset as i32 as u32);
+                if !target.is_multiple_of(4) {
+                    return None;
+                }
+                self.branch(funct3, source_register1, source_register2, target);
+                return Some(true);
+            }
+            Instruction::Load {
+                funct3,
+                source_register,
+                destination_register,
+                offset,
+            } => self.load(funct3, destination_register, source_register, offset),
+            Instruction::Store {
+                funct3,
+                source_register1,
+                source_register2,
+                offset,
+            } => self.store(funct3, source_register1, source_register2, offset),
+            // We only execute a single hart, so FENCE does nothing
+            Instruction::Fence { .. } => (),
+            _ => return None,
+        }
+        Some(false)
+    }
+
+    fn op_imm(&mut self, rd: usize, rs1: usize, funct3: u8, immediate: i16) {
+        if rd == 0 {
+            return;
+        }
+        let immediate = immediate as i32 as u32;
+        let assembler = &mut self.assembler;
+        assembler.load(Rax, register(rs1));
+        match funct3 {
+            op_imm::ADDI => assembler.alu_immediate(Alu::Add, Rax, immediate),
+            op_imm::SLTI | op_imm::SLTIU => {
+                assembler.alu_immediate(Alu::Cmp, Rax, immediate);
+                assembler.set_eax(match funct3 {
+                    op_imm::SLTI => Condition::Less,
+                    _ => Condition::Below,
+                });
+            }
+            op_imm::XORI => assembler.alu_immediate(Alu::Xor, Rax, immediate),
+            op_imm::ORI => assembler.alu_immediate(Alu::Or, Rax, immediate),
+            op_imm::ANDI => assembler.alu_immediate(Alu::And, Rax, immediate),
+            op_imm::SLLI => assembler.shift_immediate(Shift::Left, Rax, immediate as u8 & 31),
+            _ => {
+                // SRAI is marked by bit 10 of the immediate (the top of funct7)
+                let shift = match immediate & 0x400 {
+                    0 => Shift::RightLogical,
+                    _ => Shift::RightArithmetic,
+                };
+                assembler.shift_immediate(shift, Rax, immediate as u8 & 31)
+            }
+        }
+        self.write(rd);
+    }
+
+    fn op(&mut self, rd: usize, rs1: usize, rs2: usize, funct3: u8, funct7: u8) {
+        if rd == 0 {
+            return;
+        }
+        let assembler = &mut self.assembler;
+        assembler.load(Rax, register(rs1));
+        let rs2 = register(rs2);
+        match funct3 {
+            op::ADD_OR_SUB if funct7 != 0 => assembler.alu(Alu::Sub, Rax, rs2),
+            op::ADD_OR_SUB => assembler.alu(Alu::Add, Rax, rs2),
+            op::SLT | op::SLTU => {
+                assembler.alu(Alu::Cmp, Rax, rs2);
+                assembler.set_eax(match funct3 {
+                    op::SLT => Condition::Less,
+                    _ => Condition::Below,
+                });
+            }
+            op::XOR => assembler.alu(Alu::Xor, Rax, rs2),
+            op::OR => assembler.alu(Alu::Or, Rax, rs2),
+            op::AND => assembler.alu(Alu::And, Rax, rs2),
+            // x86 masks shift amounts to five bits, as RISC-V does
+            _ => {
+                let shift = match funct3 {
+                    op::SLL => Shift::Left,
+                    _ if funct7 != 0 => Shift::RightArithmetic,
+                    _ => Shift::RightLogical,
+                };
+                assembler.load(Rcx, rs2);
+                assembler.shift_cl(shift, Rax);
+            }
+        }
+        self.write(rd);
+    }
+
+    fn branch(&mut self, funct3: u8, rs1: usize, rs2: usize, target: u32) {
+        let condition = match funct3 {
+            branch::BEQ => Condition::Equal,
+            branch::BNE => Condition::NotEqual,
+            branch::BLT => Condition::Less,
+            branch::BGE => Condition::GreaterOrEqual,
+            branch::BLTU => Condition::Below,
+            _ => Condition::AboveOrEqual,
+        };
+        let taken = self.assembler.label();
+        self.assembler.load(Rax, register(rs1));
+        self.assembler.alu(Alu::Cmp, Rax, register(rs2));
+        self.assembler.jump_if(condition, taken);
+        self.exit_to(self.next_pc());
+        self.assembler.bind(taken);
+        self.exit_to(target);
+    }
+
+    /// Compute the address rs1 + offset into eax and its RAM offset into edx, jumping to slow
+    /// unless an access of width bytes there can go straight to RAM.
+    fn address(&mut self, rs1: usize, offset: i16, width: u32, slow: Label) {
+        let assembler = &mut self.assembler;
+        assembler.load(Rax, register(rs1));
+        assembler.alu_immediate(Alu::Add, Rax, offset as i32 as u32);
+        if self.check_alignment && width > 1 {
+            let aligned = assembler.label();
+            assembler.test_immediate(Rax, width - 1);
+            assembler.jump_if(Condition::Equal, aligned);
+            self.exit(Some(self.pc), self.index, EXIT_INTERPRET);
+            self.assembler.bind(aligned);
+        }
+        let assembler = &mut self.assembler;
+        assembler.mov(Rdx, Rax);
+        assembler.alu(Alu::Sub, Rdx, RAM_BASE);
+        let bound = RAM_BOUNDS + 4 * width.trailing_zeros() as usize;
+        assembler.alu(Alu::Cmp, Rdx, bound);
+        assembler.jump_if(Condition::AboveOrEqual, slow);
+    }
+
+    /// Point rsi + rdx at the RAM at offset edx, jumping to slow unless its page can be accessed
+    /// in place and holds all width bytes of the access.
+    fn translate(&mut self, width: u32, slow: Label) {
+        let assembler = &mut self.assembler;
+        assembler.mov(Rcx, Rdx);
+        assembler.shift_immediate(Shift::RightLogical, Rcx, PAGE_SIZE.trailing_zeros() as u8);
+        assembler.load64(Rsi, PAGES);
+        assembler.load_pointer();
+        assembler.test64(Rsi);
+        assembler.jump_if(Condition::Equal, slow);
+        assembler.alu_immediate(Alu::And, Rdx, PAGE_SIZE as u32 - 1);
+        if width > 1 {
+            assembler.alu_immediate(Alu::Cmp, Rdx, PAGE_SIZE as u32 - width);
+            assembler.jump_if(Condition::Above, slow);
+        }
+    }
+
+    /// Call a slow path with the context and the arguments already in esi, edx and ecx, then
+    /// leave the block: at this instruction if the access faulted, otherwise after it.
+    fn slow_path(&mut self, function: usize) {
+        let assembler = &mut self.assembler;
+        assembler.mov64(Rdi, Rbx);
+        assembler.mov_immediate64(Rax, function as u64);
+        assembler.call(Rax);
+        let done = assembler.label();
+        assembler.test(Rax);
+        assembler.jump_if(Condition::Equal, done);
+        self.exit(Some(self.pc), self.index, EXIT_INTERPRET);
+        self.assembler.bind(done);
+        self.exit_to(self.next_pc());
+    }
+
+    fn load(&mut self, funct3: u8, rd: usize, rs1: usize, offset: i16) {
+        let (width, extend) = match funct3 {
+            load::LB => (1, Extend::SignedByte),
+            load::LBU => (1, Extend::UnsignedByte),
+            load::LH => (2, Extend::SignedHalf),
+            load::LHU => (2, Extend::UnsignedHalf),
+            _ => (4, Extend::Word),
+        };
+        let (slow, done) = (self.assembler.label(), self.assembler.label());
+        self.address(rs1, offset, width, slow);
+        self.translate(width, slow);
+        self.assembler.load_ram(extend, Rax);
+        self.write(rd);
+        self.assembler.jump(done);
+
+        self.assembler.bind(slow);
+        self.assembler.mov(Rsi, Rax);
+        self.assembler.mov_immediate(Rdx, funct3 as u32);
+        self.assembler.mov_immediate(Rcx, rd as u32);
+        let function: extern "C" fn(&mut Context, u32, u32, u32) -> u32 = slow_load;
+        self.slow_path(function as usize);
+        self.assembler.bind(done);
+    }
+
+    fn store(&mut self, funct3: u8, rs1: usize, rs2: usize, offset: i16) {
+        let width = 1 << funct3;
+        let (slow, done) = (self.assembler.label(), self.assembler.label());
+        self.address(rs1, offset, width, slow);
+
+        // Stores to code take the slow path, which notes that the code was written. An access
+        // can span two pages, so the pages of its first and last bytes are checked.
+        let assembler = &mut self.assembler;
+        assembler.load64(Rsi, CODE_PAGES);
+        for byte in [0, width - 1] {
+            assembler.mov(Rcx, Rdx);
+            assembler.alu_immediate(Alu::Add, Rcx, byte);
+            assembler.shift_immediate(
+                Shift::RightLogical,
+                Rcx,
+                CODE_PAGE_SIZE.trailing_zeros() as u8,
+            );
+            assembler.compare_flag();
+            assembler.jump_if(Condition::NotEqual, slow);
+        }
+        self.translate(width, slow);
+        let assembler = &mut self.assembler;
+        assembler.load(Rcx, register(rs2));
+        assembler.store_ram(width, Rcx);
+        assembler.jump(done);
+
+        assembler.bind(slow);
+        assembler.mov(Rsi, Rax);
+        assembler.load(Rdx, register(rs2));
+        assembler.mov_immediate(Rcx, funct3 as u32);
+        let function: extern "C" fn(&mut Context, u32, u32, u32) -> u32 = slow_store;
+        self.slow_path(function as usize);
+        self.assembler.bind(done);
+    }
+}
+
+/// Compile the block at pc, or None if the instruction there can't be compiled or isn't in RAM.
+/// With check_alignment, misaligned loads and stores leave the block for the interpreter.
+pub(super) fn compile(memory: &mut Memory, pc: u32, check_alignment: bool) -> Option<Compiled> {
+    let mut compiler = Compiler {
+        assembler: Assembler::new(),
+        pc,
+        index: 0,
+        check_alignment,
+    };
+    compiler.assembler.push_rbx();
+    compiler.assembler.mov64(Rbx, Rdi);
+
+    let length = loop {
+        // Check the address is RAM before reading it so we never read from a device. Code the
+        // hart can't execute is left to the interpreter, which faults on it.
+        let address = compiler.pc as usize;
+        let executable = memory.check_access(address, 4, Access::Execute).is_ok();
+        let instruction = (executable && memory.mark_code(address))
+            .then(|| memory.get32(address).ok())
+            .flatten()
+            .and_then(|instruction| decoder::decode(instruction).ok());
+        match instruction.and_then(|instruction| compiler.instruction(instruction)) {
+            Some(true) => break compiler.index + 1,
+            Some(false) => (),
+            None => {
+                compiler.exit(Some(compiler.pc), compiler.index, EXIT_INTERPRET);
+                break compiler.index;
+            }
+        }
+
+        compiler.index += 1;
+        compiler.pc = compiler.next_pc();
+        // Blocks don't wrap around the top of the address space
+        if compiler.index == MAX_BLOCK_LENGTH || compiler.pc == 0 {
+            compiler.exit(Some(compiler.pc), compiler.index, EXIT_CONTINUE);
+            break compiler.index;
+        }
+    };
+
+    (length > 0).then(|| Compiled {
+        code: compiler.assembler.finish(),
+        length,
+    })
+}
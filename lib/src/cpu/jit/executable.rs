@@ -0,0 +1,77 @@
+/**
+ * Executable memory for compiled code. The buffer is never writable and executable at once: it's
+ * made writable to add code and then executable again.
+ */
+use std::ptr;
+
+pub(super) struct CodeBuffer {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+/// Code is added at this alignment, which suits the start of a function
+const ALIGNMENT: usize = 16;
+
+impl CodeBuffer {
+    /// Map size bytes (a multiple of the page size) of memory for code.
+    pub(super) fn new(size: usize) -> Self {
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            panic!(
+                "failed to map memory for the JIT: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Self {
+            base: base as *mut u8,
+            size,
+            used: 0,
+        }
+    }
+
+    fn protect(&mut self, protection: libc::c_int) {
+        let result =
+            unsafe { libc::mprotect(self.base as *mut libc::c_void, self.size, protection) };
+        if result != 0 {
+            panic!(
+                "failed to protect the JIT's code: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Copy code into the buffer, returning where it starts, or None if the buffer is full.
+    pub(super) fn add(&mut self, code: &[u8]) -> Option<*const u8> {
+        let start = self.used.next_multiple_of(ALIGNMENT);
+        if code.len() > self.size - start.min(self.size) {
+            return None;
+        }
+
+        self.protect(libc::PROT_READ | libc::PROT_WRITE);
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.base.add(start), code.len()) };
+        self.protect(libc::PROT_READ | libc::PROT_EXEC);
+        self.used = start + code.len();
+        Some(unsafe { self.base.add(start) })
+    }
+
+    /// Forget every piece of code in the buffer, so that its space can be reused.
+    pub(super) fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
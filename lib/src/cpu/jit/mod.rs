@@ -0,0 +1,747 @@
+/**
+ * An x86-64 JIT, built with the jit feature. Blocks of guest code that run often are compiled to
+ * native code, which keeps the guest registers in a Context and reads and writes RAM directly.
+ * Anything else, such as a device access, a store to a page holding code or an access to a page
+ * of RAM that is shared (see memory::Ram) or protected, goes through a call back into Memory.
+ * Instructions the JIT doesn't compile (ECALL, EBREAK, CSR accesses and FENCE.I), accesses that
+ * fault and, unless the hart's MisalignedPolicy allows them, misaligned accesses are left to the
+ * interpreter, which remains the reference: a block stops with the PC at the instruction, so the
+ * interpreter sees exactly the state it would have had it run everything itself.
+ *
+ * Devices are ticked once for each instruction a block retired after the block finishes, so a
+ * power request made by a tick stops the guest at the end of a block rather than inside it.
+ * Accesses that go through Memory always end the block, so one made by an access is seen before
+ * the next instruction as usual.
+ */
+mod compiler;
+mod executable;
+mod x86;
+
+use crate::cpu::base::MisalignedPolicy;
+use crate::cpu::instruction_sets::rv32i::CpuState;
+use crate::devices::PowerRequest;
+use crate::instruction::funct3::{load, store};
+use crate::memory::{Access, Memory, CODE_PAGE_SIZE};
+use executable::CodeBuffer;
+use std::collections::HashMap;
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature needs an x86-64 Unix host");
+
+/// The state compiled code runs on, at the offsets the code expects.
+#[repr(C)]
+struct Context {
+    registers: [u32; 32],
+    pc: u32,
+    /// How many instructions the last block retired
+    retired: u32,
+    /// The RAM offset a 1, 2 or 4 byte access has to be below to take the fast path
+    ram_bounds: [u32; 3],
+    ram_base: u32,
+    /// The data of each page of RAM that can be accessed in place, or null (see Memory::direct_ram)
+    pages: *const *mut u8,
+    code_pages: *const bool,
+    memory: *mut Memory,
+}
+
+const REGISTERS: usize = 0;
+const PC: usize = 128;
+const RETIRED: usize = 132;
+const RAM_BOUNDS: usize = 136;
+const RAM_BASE: usize = 148;
+const PAGES: usize = 152;
+const CODE_PAGES: usize = 160;
+
+/// Returned by a block that ran to its end
+const EXIT_CONTINUE: u32 = 0;
+/// Returned by a block that stopped at an instruction for the interpreter
+const EXIT_INTERPRET: u32 = 1;
+
+/// Returned by the slow paths when the access completed
+const ACCESS_DONE: u32 = 0;
+/// Returned by the slow paths when the access faulted
+const ACCESS_FAULT: u32 = 1;
+
+/// A compiled block: a function of the context, returning one of the EXIT_ codes
+type BlockFn = unsafe extern "C" fn(*mut Context) -> u32;
+
+/// The slow path for loads: load into register rd (if it isn't x0) with the load's funct3.
+extern "C" fn slow_load(context: &mut Context, address: u32, funct3: u32, rd: u32) -> u32 {
+    let memory = unsafe { &mut *context.memory };
+    let address = address as usize;
+    if memory
+        .check_access(address, 1 << (funct3 & 0b11), Access::Read)
+        .is_err()
+    {
+        return ACCESS_FAULT;
+    }
+    let value = match funct3 as u8 {
+        load::LB => memory.get8(address).map(|value| value as i8 as u32),
+        load::LH => memory.get16(address).map(|value| value as i16 as u32),
+        load::LBU => memory.get8(address).map(u32::from),
+        load::LHU => memory.get16(address).map(u32::from),
+        _ => memory.get32(address),
+    };
+    match value {
+        Ok(value) => {
+            if rd != 0 {
+                context.registers[rd as usize] = value;
+            }
+            ACCESS_DONE
+        }
+        Err(_) => ACCESS_FAULT,
+    }
+}
+
+/// The slow path for stores, with the store's funct3.
+extern "C" fn slow_store(context: &mut Context, address: u32, value: u32, funct3: u32) -> u32 {
+    let memory = unsafe { &mut *context.memory };
+    let address = address as usize;
+    if memory
+        .check_access(address, 1 << funct3, Access::Write)
+        .is_err()
+    {
+        return ACCESS_FAULT;
+    }
+    let result = match funct3 as u8 {
+        store::SB => memory.set8(address, value as u8),
+        store::SH => memory.set16(address, value as u16),
+        _ => memory.set32(address, value),
+    };
+    match result {
+        Ok(()) => ACCESS_DONE,
+        Err(_) => ACCESS_FAULT,
+    }
+}
+
+/// How many times a block is interpreted before it's compiled
+const HOT_THRESHOLD: u32 = 16;
+
+/// The space for compiled code. The JIT starts over when it's full.
+const CODE_SIZE: usize = 16 << 20;
+
+/// How many invalidated blocks are kept before the JIT starts over
+const MAX_INVALID_BLOCKS: usize = 1024;
+
+/// The number of compiled blocks found by address without a hash lookup
+const TABLE_ENTRIES: usize = 1 << 12;
+
+struct Block {
+    start: u32,
+    end: u64,
+    /// None if the instruction at start can't be compiled
+    entry: Option<BlockFn>,
+    valid: bool,
+}
+
+/// Why Jit::run stopped.
+#[derive(Debug, PartialEq)]
+pub enum JitExit {
+    /// The instruction at the PC has to be interpreted: it's cold, can't be compiled or faults
+    Interpret,
+    /// Code was written, and the caller has to invalidate it (see Memory::take_written_code)
+    CodeWritten,
+    /// A device made a power request
+    Power(PowerRequest),
+}
+
+pub struct Jit {
+    context: Context,
+    code: CodeBuffer,
+    blocks: Vec<Block>,
+    starts: HashMap<u32, usize>,
+    /// A direct mapped cache of the compiled blocks' entries, keyed by their start
+    table: Vec<Option<(u32, BlockFn)>>,
+    /// How many times the blocks not yet compiled have been reached
+    counts: HashMap<u32, u32>,
+    invalid: usize,
+    /// The FENCE.Is the interpreter had executed when the JIT last ran
+    fences: u64,
+    /// Whether the blocks were compiled to leave misaligned accesses to the interpreter
+    check_alignment: bool,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Self {
+            context: Context {
+                registers: [0; 32],
+                pc: 0,
+                retired: 0,
+                ram_bounds: [0; 3],
+                ram_base: 0,
+                pages: std::ptr::null(),
+                code_pages: std::ptr::null(),
+                memory: std::ptr::null_mut(),
+            },
+            code: CodeBuffer::new(CODE_SIZE),
+            blocks: Vec::new(),
+            starts: HashMap::new(),
+            table: vec![None; TABLE_ENTRIES],
+            counts: HashMap::new(),
+            invalid: 0,
+            fences: 0,
+            check_alignment: false,
+        }
+    }
+
+    /// Forget the blocks with instructions in the code page at address.
+    pub fn invalidate(&mut self, address: usize) {
+        let (page_start, page_end) = (address as u64, (address + CODE_PAGE_SIZE) as u64);
+        for block in self.blocks.iter_mut().filter(|block| block.valid) {
+            if (block.start as u64) < page_end && page_start < block.end {
+                block.valid = false;
+                self.starts.remove(&block.start);
+                let entry = &mut self.table[Self::table_index(block.start)];
+                if matches!(entry, Some((start, _)) if *start == block.start) {
+                    *entry = None;
+                }
+                self.invalid += 1;
+            }
+        }
+
+        // Invalid blocks keep their code until the JIT starts over
+        if self.invalid > MAX_INVALID_BLOCKS && self.invalid > self.blocks.len() / 2 {
+            self.clear();
+        }
+    }
+
+    /// Forget every compiled block.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.starts.clear();
+        self.table.fill(None);
+        self.code.clear();
+        self.invalid = 0;
+    }
+
+    fn table_index(pc: u32) -> usize {
+        (pc / 4) as usize % TABLE_ENTRIES
+    }
+
+    /// The block starting at pc, compiling it if it has become hot. None if it has to be
+    /// interpreted.
+    fn lookup(&mut self, memory: &mut Memory, pc: u32) -> Option<BlockFn> {
+        let table_index = Self::table_index(pc);
+        match self.table[table_index] {
+            Some((start, entry)) if start == pc => return Some(entry),
+            _ => (),
+        }
+        if let Some(&index) = self.starts.get(&pc) {
+            let entry = self.blocks[index].entry;
+            self.table[table_index] = entry.map(|entry| (pc, entry));
+            return entry;
+        }
+
+        let count = self.counts.entry(pc).or_insert(0);
+        *count += 1;
+        if *count < HOT_THRESHOLD {
+            return None;
+        }
+        self.counts.remove(&pc);
+
+        let (entry, length) = match compiler::compile(memory, pc, self.check_alignment) {
+            Some(compiled) => {
+                let entry = match self.code.add(&compiled.code) {
+                    Some(entry) => entry,
+                    None => {
+                        self.clear();
+                        self.code
+                            .add(&compiled.code)
+                            .expect("block too big for the JIT")
+                    }
+                };
+                let entry = unsafe { std::mem::transmute::<*const u8, BlockFn>(entry) };
+                (Some(entry), compiled.length)
+            }
+            None => (None, 1),
+        };
+        self.blocks.push(Block {
+            start: pc,
+            end: pc as u64 + 4 * length as u64,
+            entry,
+            valid: true,
+        });
+        self.starts.insert(pc, self.blocks.len() - 1);
+        entry
+    }
+
+    fn enter(&mut self, state: &CpuState, memory: &mut Memory) {
+        for register in 0..32 {
+            self.context.registers[register] = state.registers.get(register);
+        }
+        self.context.pc = state.registers.pc;
+
+        // RAM past the top of the 32-bit address space can't be reached
+        let (pages, size) = memory.direct_ram();
+        let size = match u32::try_from(memory.ram_base()) {
+            Ok(base) => size.min((1 << 32) - base as usize),
+            Err(_) => 0,
+        };
+        for (bound, width) in self.context.ram_bounds.iter_mut().zip([1, 2, 4]) {
+            *bound = (size + 1).saturating_sub(width).min(u32::MAX as usize) as u32;
+        }
+        self.context.ram_base = memory.ram_base() as u32;
+        self.context.pages = pages;
+        self.context.code_pages = memory.code_pages();
+        self.context.memory = memory;
+    }
+
+    fn leave(&self, state: &mut CpuState) {
+        for register in 1..32 {
+            state
+                .registers
+                .set(register, self.context.registers[register]);
+        }
+        state.registers.pc = self.context.pc;
+    }
+
+    /// Run compiled blocks from the PC until one has to be interpreted, code is written or a
+    /// device makes a power request. fences is the number of FENCE.Is the interpreter has
+    /// executed, as they flush compiled code too. Changing the hart's MisalignedPolicy to or
+    /// from Allow does as well.
+    pub fn run(&mut self, state: &mut CpuState, memory: &mut Memory, fences: u64) -> JitExit {
+        let check_alignment = state.misaligned_policy != MisalignedPolicy::Allow;
+        if fences != self.fences || check_alignment != self.check_alignment {
+            self.fences = fences;
+            self.check_alignment = check_alignment;
+            self.clear();
+        }
+
+        self.enter(state, memory);
+        let exit = loop {
+            let Some(entry) = self.lookup(memory, self.context.pc) else {
+                break JitExit::Interpret;
+            };
+            let reason = unsafe { entry(&mut self.context) };
+
+            let retired = self.context.retired;
+            state.registers.csrs.rdcycle += retired as u64;
+            state.registers.csrs.instret += retired as u64;
+            if let Some(request) = (0..retired).find_map(|_| memory.tick()) {
+                break JitExit::Power(request);
+            }
+            if reason == EXIT_INTERPRET {
+                break JitExit::Interpret;
+            }
+            if memory.code_written() {
+                break JitExit::CodeWritten;
+            }
+        };
+        self.leave(state);
+        exit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::base::{Exception, MisalignedCounts, Trap};
+    use crate::cpu::rv32i::{Cpu, StepState};
+    use crate::devices::test_finisher::{TestFinisher, TEST_FINISHER_SIZE};
+    use crate::instruction::assembler;
+    use crate::memory::{Permissions, PAGE_SIZE};
+    use std::cell::RefCell;
+    use std::ptr::addr_of;
+    use std::rc::Rc;
+
+    const MEMORY_SIZE: usize = 0x1000;
+
+    #[test]
+    fn context_layout() {
+        let context = Jit::new().context;
+        let base = addr_of!(context) as usize;
+        let offset = |field: usize| field - base;
+        assert_eq!(offset(addr_of!(context.registers) as usize), REGISTERS);
+        assert_eq!(offset(addr_of!(context.pc) as usize), PC);
+        assert_eq!(offset(addr_of!(context.retired) as usize), RETIRED);
+        assert_eq!(offset(addr_of!(context.ram_bounds) as usize), RAM_BOUNDS);
+        assert_eq!(offset(addr_of!(context.ram_base) as usize), RAM_BASE);
+        assert_eq!(offset(addr_of!(context.pages) as usize), PAGES);
+        assert_eq!(offset(addr_of!(context.code_pages) as usize), CODE_PAGES);
+    }
+
+    /// Run source to the end, or until it traps, with or without the JIT.
+    fn run(source: &str, jit: bool, policy: MisalignedPolicy) -> (Cpu, Memory, StepState) {
+        let image = assembler::assemble(source, 0).unwrap();
+        let mut memory = Memory::new(MEMORY_SIZE);
+        image.load(&mut memory).unwrap();
+        // Leave a test finisher in the middle of RAM
+        let finisher = Rc::new(RefCell::new(TestFinisher::new()));
+        memory.attach(0xF00, TEST_FINISHER_SIZE, finisher).unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.state.misaligned_policy = policy;
+        cpu.jit = jit.then(Jit::new);
+        let end = cpu.run(&mut memory);
+        (cpu, memory, end)
+    }
+
+    /// Check running source with the JIT leaves the same state as interpreting it.
+    fn compare(source: &str) {
+        compare_with_policy(source, MisalignedPolicy::Allow);
+    }
+
+    fn compare_with_policy(source: &str, policy: MisalignedPolicy) {
+        let (interpreted, interpreted_memory, interpreted_end) = run(source, false, policy);
+        let (compiled, compiled_memory, compiled_end) = run(source, true, policy);
+        assert_eq!(compiled_end, interpreted_end, "{source}");
+        for register in 0..32 {
+            assert_eq!(
+                compiled.state.registers.get(register),
+                interpreted.state.registers.get(register),
+                "x{register} differs for {source}"
+            );
+        }
+        assert_eq!(compiled.state.registers.pc, interpreted.state.registers.pc);
+        let instret = |cpu: &Cpu| cpu.state.registers.csrs.instret;
+        assert_eq!(instret(&compiled), instret(&interpreted));
+        assert_eq!(compiled.state.misaligned, interpreted.state.misaligned);
+        for address in 0..MEMORY_SIZE {
+            assert_eq!(
+                compiled_memory.get8(address),
+                interpreted_memory.get8(address),
+                "memory at {address:#x} differs for {source}"
+            );
+        }
+
+        // Make sure the JIT wasn't left out
+        let jit = compiled.jit.unwrap();
+        assert!(jit.blocks.iter().any(|block| block.entry.is_some()));
+    }
+
+    /// A xorshift generator, for random programs that are the same every run.
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self, below: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % below
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.next(choices.len() as u32) as usize]
+        }
+    }
+
+    /// A loop of random arithmetic, loads, stores and branches. x16 points at the data the loads
+    /// and stores use and x17 counts the loop down, and neither is written by the random code.
+    fn random_program(random: &mut Random) -> String {
+        let mut source = "li x16, 0x800\n li x17, 40\n".to_string();
+        for register in 1..16 {
+            source += &format!("li x{register}, {}\n", random.next(u32::MAX) as i32);
+        }
+        source += "loop:\n";
+        for label in 0..40 {
+            let (rd, rs1, rs2) = (random.next(16), random.next(18), random.next(18));
+            let immediate = random.next(4096) as i32 - 2048;
+            source += &match random.next(6) {
+                0 => {
+                    let op = random.pick(&[
+                        "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and",
+                    ]);
+                    format!("{op} x{rd}, x{rs1}, x{rs2}\n")
+                }
+                1 => {
+                    let op = random.pick(&["addi", "slti", "sltiu", "xori", "ori", "andi"]);
+                    format!("{op} x{rd}, x{rs1}, {immediate}\n")
+                }
+                2 => {
+                    let op = random.pick(&["slli", "srli", "srai", "lui", "auipc"]);
+                    match op {
+                        "lui" | "auipc" => format!("{op} x{rd}, {}\n", random.next(1 << 20)),
+                        _ => format!("{op} x{rd}, x{rs1}, {}\n", random.next(32)),
+                    }
+                }
+                3 => {
+                    let op = random.pick(&["lb", "lh", "lw", "lbu", "lhu"]);
+                    format!("{op} x{rd}, {}(x16)\n", random.next(252))
+                }
+                4 => {
+                    let op = random.pick(&["sb", "sh", "sw"]);
+                    format!("{op} x{rs2}, {}(x16)\n", random.next(252))
+                }
+                _ => {
+                    let op = random.pick(&["beq", "bne", "blt", "bge", "bltu", "bgeu"]);
+                    format!(
+                        "{op} x{rs1}, x{rs2}, skip{label}\n addi x{rd}, x{rd}, 1\n skip{label}:\n"
+                    )
+                }
+            };
+        }
+        source + "addi x17, x17, -1\n bnez x17, loop\n li a0, 0\n ecall\n"
+    }
+
+    #[test]
+    fn random_programs_match_the_interpreter() {
+        let mut random = Random(0x1234_5678);
+        for _ in 0..20 {
+            compare(&random_program(&mut random));
+        }
+    }
+
+    #[test]
+    fn calls_match_the_interpreter() {
+        // Sum a string a hundred times, through a function call
+        compare(
+            "
+                li s0, 100
+            again:
+                la a0, string
+                call sum
+                add s1, s1, a0
+                addi s0, s0, -1
+                bnez s0, again
+                sw s1, 0x700(zero)
+                li a0, 0
+                ecall
+            sum:
+                li t0, 0
+            next:
+                lbu t1, 0(a0)
+                beqz t1, done
+                add t0, t0, t1
+                addi a0, a0, 1
+                j next
+            done:
+                mv a0, t0
+                ret
+            string:
+                .asciz \"abcdefghij\"
+            ",
+        );
+    }
+
+    #[test]
+    fn devices_and_faults_match_the_interpreter() {
+        // A store to a device makes a power request after the block has become hot
+        compare(
+            "
+                li t0, 0xF00
+                li t1, 100
+            loop:
+                addi t1, t1, -1
+                bnez t1, loop
+                li t2, 0x5555
+                sw t2, 0(t0)
+                li a0, 7
+                li a0, 0
+                ecall
+            ",
+        );
+
+        // A load past the end of RAM traps at the load
+        compare(
+            "
+                li t0, 0xE00
+            loop:
+                lw t1, 0x100(t0)
+                addi t2, t2, 1
+                addi t0, t0, 4
+                j loop
+            ",
+        );
+    }
+
+    #[test]
+    fn self_modifying_code_matches_the_interpreter() {
+        for fence in ["", "fence.i"] {
+            // The ADDI at patch is replaced half way through, once it has been compiled
+            compare(&format!(
+                "
+                    li t0, 0
+                    li t2, 80
+                patch:
+                    addi t0, t0, 1
+                    addi t2, t2, -1
+                    li t1, 40
+                    bne t2, t1, skip
+                    li t1, 0x00a28293 # addi t0, t0, 10
+                    sw t1, 8(zero)
+                    {fence}
+                skip:
+                    bnez t2, patch
+                    li a0, 0
+                    ecall
+                "
+            ));
+        }
+    }
+
+    #[test]
+    fn misaligned_accesses_match_the_interpreter() {
+        // The loop's accesses are aligned until it has been compiled, then step a byte at a time
+        let source = "
+                li t0, 0x800
+                li t1, 40
+                li t3, 4
+            loop:
+                lw t2, 0(t0)
+                sh t2, 0x100(t0)
+                add t0, t0, t3
+                addi t1, t1, -1
+                li t4, 20
+                bne t1, t4, next
+                li t3, 1
+            next:
+                bnez t1, loop
+                li a0, 0
+                ecall
+            ";
+        for policy in [
+            MisalignedPolicy::Allow,
+            MisalignedPolicy::Trap,
+            MisalignedPolicy::Count,
+        ] {
+            compare_with_policy(source, policy);
+        }
+
+        // Of the last 20 loads and stores, the words are misaligned three times in four and the
+        // halves every other time
+        let (cpu, _, end) = run(source, true, MisalignedPolicy::Count);
+        assert_eq!(end, StepState::Exit);
+        let expected = MisalignedCounts {
+            loads: 15,
+            stores: 10,
+        };
+        assert_eq!(cpu.state.misaligned, expected);
+
+        // Trapping stops at the first misaligned access, the compiled load's
+        let (cpu, _, end) = run(source, true, MisalignedPolicy::Trap);
+        let trap = Trap {
+            exception: Exception::LoadAddressMisaligned,
+            value: 0x851,
+            pc: 16,
+        };
+        assert_eq!(end, StepState::Trap(trap));
+        assert_eq!(cpu.state.registers.pc, 16);
+    }
+
+    #[test]
+    fn protection_faults_match_the_interpreter() {
+        // Each access is compiled before it faults: a push into the guard page, a load from it
+        // and a store to read-only code
+        let sources = [
+            "
+                li t0, 0x4000
+            loop:
+                addi t0, t0, -4
+                sw t0, 0(t0)
+                j loop
+            ",
+            "
+                li t0, 0x4000
+            loop:
+                addi t0, t0, -4
+                lw t1, 0(t0)
+                j loop
+            ",
+            "
+                li t0, 0x3000
+                li t1, 100
+            loop:
+                sw t1, 0(t0)
+                addi t1, t1, -1
+                li t2, 50
+                bne t1, t2, next
+                li t0, 0x800
+            next:
+                bnez t1, loop
+                li a0, 0
+                ecall
+            ",
+        ];
+        for source in sources {
+            let run = |jit: bool| {
+                let mut memory = Memory::new(4 * PAGE_SIZE);
+                let image = assembler::assemble(source, 0).unwrap();
+                image.load(&mut memory).unwrap();
+                // The guard page has been written, so it's there to be accessed in place
+                memory.set32(2 * PAGE_SIZE - 4, 1).unwrap();
+                memory
+                    .protect(0, PAGE_SIZE, Permissions::READ_EXECUTE)
+                    .unwrap();
+                memory
+                    .protect(PAGE_SIZE, PAGE_SIZE, Permissions::NONE)
+                    .unwrap();
+                memory
+                    .protect(2 * PAGE_SIZE, 2 * PAGE_SIZE, Permissions::READ_WRITE)
+                    .unwrap();
+
+                let mut cpu = Cpu::new();
+                cpu.jit = jit.then(Jit::new);
+                let end = cpu.run(&mut memory);
+                assert!(matches!(end, StepState::Trap(_)), "{source} didn't fault");
+                (cpu, memory, end)
+            };
+            let (interpreted, interpreted_memory, interpreted_end) = run(false);
+            let (compiled, compiled_memory, compiled_end) = run(true);
+            assert_eq!(compiled_end, interpreted_end, "{source}");
+            for register in 0..32 {
+                assert_eq!(
+                    compiled.state.registers.get(register),
+                    interpreted.state.registers.get(register),
+                    "x{register} differs for {source}"
+                );
+            }
+            assert_eq!(compiled.state.registers.pc, interpreted.state.registers.pc);
+            let instret = |cpu: &Cpu| cpu.state.registers.csrs.instret;
+            assert_eq!(instret(&compiled), instret(&interpreted));
+            assert_eq!(compiled_memory.get32(2 * PAGE_SIZE - 4), Ok(1));
+            assert_eq!(
+                compiled_memory.get32(0x800),
+                interpreted_memory.get32(0x800)
+            );
+            assert!(compiled
+                .jit
+                .unwrap()
+                .blocks
+                .iter()
+                .any(|block| block.entry.is_some()));
+        }
+    }
+
+    #[test]
+    fn compiled_code_leaves_snapshots_alone() {
+        let source = "
+                li t0, 0
+                li t1, 0x700
+            loop:
+                addi t0, t0, 1
+                add t2, t0, s1
+                sw t2, 0(t1)
+                li t3, 100
+                bne t0, t3, loop
+                li a0, 0
+                ecall
+            ";
+        let mut memory = Memory::new(MEMORY_SIZE);
+        assembler::assemble(source, 0)
+            .unwrap()
+            .load(&mut memory)
+            .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.jit = Some(Jit::new());
+        assert_eq!(cpu.run(&mut memory), StepState::Exit);
+        assert_eq!(memory.get32(0x700), Ok(100));
+
+        // Run the compiled loop again, storing something else, after taking a snapshot. Its
+        // first store is compiled code's, so it has to see that the page is shared.
+        let snapshot = memory.ram().clone();
+        cpu.state.registers.pc = 8;
+        cpu.state.registers.set(5, 0);
+        cpu.state.registers.set(9, 1000);
+        assert_eq!(cpu.run(&mut memory), StepState::Exit);
+        assert_eq!(memory.get32(0x700), Ok(1100));
+        memory.restore_ram(snapshot);
+        assert_eq!(memory.get32(0x700), Ok(100));
+    }
+}
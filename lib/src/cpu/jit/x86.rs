@@ -0,0 +1,356 @@
+/*
+ * Just enough of an x86-64 assembler for the JIT. Operations are on 32-bit registers unless they
+ * say otherwise, and memory operands are either a field of the JIT context, addressed from rbx,
+ * a byte of RAM, addressed as rsi + rdx (or rsi + rcx), or an entry of a table of pointers.
+ */
+
+/// The general purpose registers the JIT uses, by their 64-bit names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Register {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsi = 6,
+    Rdi = 7,
+}
+
+/// Conditions for jumps and flag setting, as encoded in Jcc and SETcc.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Condition {
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    Above = 0x7,
+    Less = 0xC,
+    GreaterOrEqual = 0xD,
+}
+
+/// Two operand arithmetic, as encoded in the reg field of the 0x81 group.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+/// Shifts, as encoded in the reg field of the 0xC1 and 0xD3 groups.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Shift {
+    Left = 4,
+    RightLogical = 5,
+    RightArithmetic = 7,
+}
+
+/// How a load from RAM extends its value to 32 bits.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Extend {
+    SignedByte,
+    UnsignedByte,
+    SignedHalf,
+    UnsignedHalf,
+    Word,
+}
+
+/// A position in the code, bound once it's known. Jumps to it can be made before then.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Label(usize);
+
+pub(super) struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// The positions of rel32 operands, and the labels they jump to
+    fixups: Vec<(usize, Label)>,
+}
+
+const REX_W: u8 = 0x48;
+
+const fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    mode << 6 | (reg & 7) << 3 | rm & 7
+}
+
+impl Assembler {
+    pub(super) fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    /// The code, with every jump resolved. Panics if a label was jumped to but never bound.
+    pub(super) fn finish(mut self) -> Vec<u8> {
+        for (position, Label(label)) in self.fixups {
+            let target = self.labels[label].expect("jump to an unbound label");
+            let relative = target as i32 - (position + 4) as i32;
+            self.code[position..position + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+        self.code
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// A [rbx + offset] operand.
+    fn context(&mut self, reg: u8, offset: usize) {
+        self.emit(&[modrm(0b10, reg, Register::Rbx as u8)]);
+        self.emit32(offset as u32);
+    }
+
+    /// A [base + index] operand. base can't be rbp or r13, which this assembler doesn't use.
+    fn indexed(&mut self, reg: u8, base: Register, index: Register) {
+        self.emit(&[
+            modrm(0b00, reg, 0b100),
+            modrm(0b00, index as u8, base as u8),
+        ]);
+    }
+
+    pub(super) fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub(super) fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.emit32(0);
+    }
+
+    pub(super) fn jump(&mut self, label: Label) {
+        self.emit(&[0xE9]);
+        self.rel32(label);
+    }
+
+    pub(super) fn jump_if(&mut self, condition: Condition, label: Label) {
+        self.emit(&[0x0F, 0x80 | condition as u8]);
+        self.rel32(label);
+    }
+
+    /// mov reg, [rbx + offset]
+    pub(super) fn load(&mut self, reg: Register, offset: usize) {
+        self.emit(&[0x8B]);
+        self.context(reg as u8, offset);
+    }
+
+    /// mov reg64, [rbx + offset]
+    pub(super) fn load64(&mut self, reg: Register, offset: usize) {
+        self.emit(&[REX_W, 0x8B]);
+        self.context(reg as u8, offset);
+    }
+
+    /// mov [rbx + offset], reg
+    pub(super) fn store(&mut self, offset: usize, reg: Register) {
+        self.emit(&[0x89]);
+        self.context(reg as u8, offset);
+    }
+
+    /// mov dword [rbx + offset], value
+    pub(super) fn store_immediate(&mut self, offset: usize, value: u32) {
+        self.emit(&[0xC7]);
+        self.context(0, offset);
+        self.emit32(value);
+    }
+
+    /// op reg, [rbx + offset]
+    pub(super) fn alu(&mut self, op: Alu, reg: Register, offset: usize) {
+        self.emit(&[(op as u8) << 3 | 0x03]);
+        self.context(reg as u8, offset);
+    }
+
+    /// op reg, value
+    pub(super) fn alu_immediate(&mut self, op: Alu, reg: Register, value: u32) {
+        self.emit(&[0x81, modrm(0b11, op as u8, reg as u8)]);
+        self.emit32(value);
+    }
+
+    /// shift reg, amount
+    pub(super) fn shift_immediate(&mut self, shift: Shift, reg: Register, amount: u8) {
+        self.emit(&[0xC1, modrm(0b11, shift as u8, reg as u8), amount]);
+    }
+
+    /// shift reg, cl
+    pub(super) fn shift_cl(&mut self, shift: Shift, reg: Register) {
+        self.emit(&[0xD3, modrm(0b11, shift as u8, reg as u8)]);
+    }
+
+    /// Set eax to 1 if condition holds, otherwise 0: setcc al; movzx eax, al
+    pub(super) fn set_eax(&mut self, condition: Condition) {
+        self.emit(&[0x0F, 0x90 | condition as u8, modrm(0b11, 0, 0)]);
+        self.emit(&[0x0F, 0xB6, modrm(0b11, 0, 0)]);
+    }
+
+    /// mov destination, source
+    pub(super) fn mov(&mut self, destination: Register, source: Register) {
+        self.emit(&[0x89, modrm(0b11, source as u8, destination as u8)]);
+    }
+
+    /// mov destination64, source64
+    pub(super) fn mov64(&mut self, destination: Register, source: Register) {
+        self.emit(&[REX_W, 0x89, modrm(0b11, source as u8, destination as u8)]);
+    }
+
+    /// mov reg, value
+    pub(super) fn mov_immediate(&mut self, reg: Register, value: u32) {
+        self.emit(&[0xB8 | reg as u8]);
+        self.emit32(value);
+    }
+
+    /// mov reg64, value
+    pub(super) fn mov_immediate64(&mut self, reg: Register, value: u64) {
+        self.emit(&[REX_W, 0xB8 | reg as u8]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// test reg, reg
+    pub(super) fn test(&mut self, reg: Register) {
+        self.emit(&[0x85, modrm(0b11, reg as u8, reg as u8)]);
+    }
+
+    /// test reg, value
+    pub(super) fn test_immediate(&mut self, reg: Register, value: u32) {
+        self.emit(&[0xF7, modrm(0b11, 0, reg as u8)]);
+        self.emit32(value);
+    }
+
+    /// test reg64, reg64
+    pub(super) fn test64(&mut self, reg: Register) {
+        self.emit(&[REX_W, 0x85, modrm(0b11, reg as u8, reg as u8)]);
+    }
+
+    /// mov rsi, [rsi + rcx * 8]
+    pub(super) fn load_pointer(&mut self) {
+        self.emit(&[REX_W, 0x8B, modrm(0b00, Register::Rsi as u8, 0b100)]);
+        self.emit(&[modrm(0b11, Register::Rcx as u8, Register::Rsi as u8)]);
+    }
+
+    /// Load reg from [rsi + rdx], extending it to 32 bits.
+    pub(super) fn load_ram(&mut self, extend: Extend, reg: Register) {
+        match extend {
+            Extend::SignedByte => self.emit(&[0x0F, 0xBE]),
+            Extend::UnsignedByte => self.emit(&[0x0F, 0xB6]),
+            Extend::SignedHalf => self.emit(&[0x0F, 0xBF]),
+            Extend::UnsignedHalf => self.emit(&[0x0F, 0xB7]),
+            Extend::Word => self.emit(&[0x8B]),
+        }
+        self.indexed(reg as u8, Register::Rsi, Register::Rdx);
+    }
+
+    /// Store the low width bytes of reg to [rsi + rdx]. Byte stores can only be made from rax,
+    /// rcx, rdx or rbx.
+    pub(super) fn store_ram(&mut self, width: u32, reg: Register) {
+        match width {
+            1 => self.emit(&[0x88]),
+            2 => self.emit(&[0x66, 0x89]),
+            _ => self.emit(&[0x89]),
+        }
+        self.indexed(reg as u8, Register::Rsi, Register::Rdx);
+    }
+
+    /// cmp byte [rsi + rcx], 0
+    pub(super) fn compare_flag(&mut self) {
+        self.emit(&[0x80]);
+        self.indexed(Alu::Cmp as u8, Register::Rsi, Register::Rcx);
+        self.emit(&[0]);
+    }
+
+    /// call reg64
+    pub(super) fn call(&mut self, reg: Register) {
+        self.emit(&[0xFF, modrm(0b11, 2, reg as u8)]);
+    }
+
+    pub(super) fn push_rbx(&mut self) {
+        self.emit(&[0x53]);
+    }
+
+    pub(super) fn pop_rbx(&mut self) {
+        self.emit(&[0x5B]);
+    }
+
+    pub(super) fn ret(&mut self) {
+        self.emit(&[0xC3]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Register::*;
+    use super::*;
+
+    fn assemble(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut assembler = Assembler::new();
+        f(&mut assembler);
+        assembler.finish()
+    }
+
+    /// Something to assemble, and the code it should make
+    type Case = (fn(&mut Assembler), &'static [u8]);
+
+    #[test]
+    fn encodings() {
+        // Checked against the output of an assembler
+        let cases: [Case; 15] = [
+            (|a| a.load(Rax, 0x84), &[0x8B, 0x83, 0x84, 0, 0, 0]),
+            (|a| a.load64(Rsi, 0x98), &[0x48, 0x8B, 0xB3, 0x98, 0, 0, 0]),
+            (|a| a.store(4, Rcx), &[0x89, 0x8B, 4, 0, 0, 0]),
+            (
+                |a| a.store_immediate(8, 0x1234),
+                &[0xC7, 0x83, 8, 0, 0, 0, 0x34, 0x12, 0, 0],
+            ),
+            (|a| a.alu(Alu::Sub, Rax, 8), &[0x2B, 0x83, 8, 0, 0, 0]),
+            (
+                |a| a.alu_immediate(Alu::And, Rax, !1),
+                &[0x81, 0xE0, 0xFE, 0xFF, 0xFF, 0xFF],
+            ),
+            (
+                |a| a.shift_immediate(Shift::RightArithmetic, Rax, 3),
+                &[0xC1, 0xF8, 3],
+            ),
+            (
+                |a| a.set_eax(Condition::Less),
+                &[0x0F, 0x9C, 0xC0, 0x0F, 0xB6, 0xC0],
+            ),
+            (|a| a.mov64(Rdi, Rbx), &[0x48, 0x89, 0xDF]),
+            (
+                |a| a.load_ram(Extend::SignedHalf, Rax),
+                &[0x0F, 0xBF, 0x04, 0x16],
+            ),
+            (|a| a.store_ram(2, Rcx), &[0x66, 0x89, 0x0C, 0x16]),
+            (|a| a.compare_flag(), &[0x80, 0x3C, 0x0E, 0]),
+            (|a| a.test64(Rsi), &[0x48, 0x85, 0xF6]),
+            (|a| a.test_immediate(Rax, 3), &[0xF7, 0xC0, 3, 0, 0, 0]),
+            (|a| a.load_pointer(), &[0x48, 0x8B, 0x34, 0xCE]),
+        ];
+        for (f, expected) in cases {
+            assert_eq!(assemble(f), expected);
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let code = assemble(|a| {
+            let forward = a.label();
+            let backward = a.label();
+            a.bind(backward);
+            a.jump_if(Condition::Equal, forward);
+            a.ret();
+            a.bind(forward);
+            a.jump(backward);
+        });
+        assert_eq!(
+            code,
+            [0x0F, 0x84, 1, 0, 0, 0, 0xC3, 0xE9, 0xF4, 0xFF, 0xFF, 0xFF]
+        );
+    }
+}
@@ -0,0 +1,378 @@
+/**
+ * Lockstep differential testing: run a program and compare each instruction it retires against
+ * a reference commit log, in the format of Spike's --log-commits (see trace). The first
+ * instruction whose PC, encoding, register or CSR writes or memory access differ from the
+ * reference is reported.
+ *
+ * Reference logs usually start with a boot ROM the program doesn't run, so the reference is
+ * synchronised at its first commit at the CPU's starting PC.
+ */
+use crate::cpu::rv32i::{Cpu, StepState};
+use crate::cpu::trace::{self, Commit, MemoryCommit};
+use crate::instruction::disassembler;
+use crate::memory::Memory;
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// How a run in lockstep with the reference ended, when it didn't diverge.
+#[derive(Debug, PartialEq)]
+pub enum LockstepEnd {
+    /// The guest exited or made a power request
+    Guest(StepState),
+    /// The reference ended with the guest still running
+    ReferenceEnd,
+}
+
+/// The first instruction that didn't match the reference.
+#[derive(Debug)]
+pub struct Divergence {
+    /// The number of instructions that matched before it
+    pub index: u64,
+    /// The line of the reference it was compared with, counting from 1
+    pub line: usize,
+    pub expected: Commit,
+    pub actual: Commit,
+}
+
+#[derive(Debug)]
+pub enum LockstepError {
+    Io(io::Error),
+    Divergence(Box<Divergence>),
+}
+
+impl From<io::Error> for LockstepError {
+    fn from(err: io::Error) -> Self {
+        LockstepError::Io(err)
+    }
+}
+
+/// Runs a Cpu in lockstep with a reference log.
+pub struct Lockstep<R> {
+    reference: R,
+    line: usize,
+    /// The number of instructions that have matched the reference
+    pub compared: u64,
+}
+
+impl<R: BufRead> Lockstep<R> {
+    pub fn new(reference: R) -> Self {
+        Self {
+            reference,
+            line: 0,
+            compared: 0,
+        }
+    }
+
+    /// The next commit in the reference, if any.
+    fn next_commit(&mut self) -> io::Result<Option<Commit>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reference.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if let Some(commit) = trace::parse_commit(&line) {
+                return Ok(Some(commit));
+            }
+        }
+    }
+
+    /// Run until the guest stops, the reference ends or the two diverge.
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+    ) -> Result<LockstepEnd, LockstepError> {
+        let start = cpu.state.registers.pc;
+        let mut synchronised = false;
+        loop {
+            let Some(expected) = self.next_commit()? else {
+                return Ok(LockstepEnd::ReferenceEnd);
+            };
+            synchronised |= expected.pc == start;
+            if !synchronised {
+                continue;
+            }
+
+            // A trap taken by the guest's handler retires nothing, as in the reference, so step
+            // on to the handler's first instruction. Breakpoints, power requests and unhandled
+            // traps stop the guest without retiring an instruction.
+            let (state, actual) = loop {
+                match cpu.step_commit(memory) {
+                    (StepState::Continue, None) => continue,
+                    (state, Some(actual)) => break (state, actual),
+                    (state, None) => return Ok(LockstepEnd::Guest(state)),
+                }
+            };
+            if actual != expected {
+                return Err(LockstepError::Divergence(Box::new(Divergence {
+                    index: self.compared,
+                    line: self.line,
+                    expected,
+                    actual,
+                })));
+            }
+
+            self.compared += 1;
+            if state != StepState::Continue {
+                return Ok(LockstepEnd::Guest(state));
+            }
+        }
+    }
+}
+
+fn format_memory(memory: Option<MemoryCommit>) -> String {
+    match memory {
+        Some(MemoryCommit::Load { address, length }) => {
+            format!("load of {length} bytes from {address:#010x}")
+        }
+        Some(MemoryCommit::Store {
+            address,
+            length,
+            value,
+        }) => format!("store of {value:#x} ({length} bytes) to {address:#010x}"),
+        None => "no access".to_string(),
+    }
+}
+
+fn format_csrs(csrs: &[(usize, u32)]) -> String {
+    match csrs {
+        [] => "no write".to_string(),
+        _ => csrs
+            .iter()
+            .map(|&(csr, value)| format!("{csr:#x} = {value:#010x}"))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (expected, actual) = (&self.expected, &self.actual);
+        writeln!(
+            f,
+            "diverged from the reference after {} instructions, at line {}",
+            self.index, self.line
+        )?;
+        writeln!(
+            f,
+            "  {:#010x}: {:08x}  {}",
+            actual.pc,
+            actual.instruction,
+            disassembler::disassemble(actual.instruction, actual.pc)
+        )?;
+        writeln!(f, "  expected: {}", trace::format_commit(expected))?;
+        writeln!(f, "  actual:   {}", trace::format_commit(actual))?;
+
+        if expected.pc != actual.pc {
+            writeln!(
+                f,
+                "  pc: expected {:#010x}, actual {:#010x}",
+                expected.pc, actual.pc
+            )?;
+        }
+        if expected.instruction != actual.instruction {
+            writeln!(
+                f,
+                "  instruction: expected {:#010x}, actual {:#010x}",
+                expected.instruction, actual.instruction
+            )?;
+        }
+        let written = |commit: &Commit, register: usize| {
+            let value = commit
+                .registers
+                .iter()
+                .find(|&&(index, _)| index == register);
+            value.map_or("unwritten".to_string(), |(_, value)| {
+                format!("{value:#010x}")
+            })
+        };
+        for register in 1..32 {
+            let (expected, actual) = (written(expected, register), written(actual, register));
+            if expected != actual {
+                writeln!(f, "  x{register}: expected {expected}, actual {actual}")?;
+            }
+        }
+        if expected.csrs != actual.csrs {
+            writeln!(
+                f,
+                "  csrs: expected {}, actual {}",
+                format_csrs(&expected.csrs),
+                format_csrs(&actual.csrs)
+            )?;
+        }
+        if expected.memory != actual.memory {
+            writeln!(
+                f,
+                "  memory: expected {}, actual {}",
+                format_memory(expected.memory),
+                format_memory(actual.memory)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockstepError::Io(err) => write!(f, "failed to read the reference: {err}"),
+            LockstepError::Divergence(divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LockstepError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::assembler;
+
+    const PROGRAM: &str = "
+        li a0, 0x100
+        li t0, 0x1234
+        sh t0, 2(a0)
+        lw t1, 0(a0)
+        addi t1, t1, 1
+        li a0, 0
+        ecall
+    ";
+
+    fn load(source: &str) -> (Cpu, Memory) {
+        let image = assembler::assemble(source, 0).unwrap();
+        let mut memory = Memory::new(0x200);
+        image.load(&mut memory).unwrap();
+        (Cpu::new(), memory)
+    }
+
+    /// The commit log of running source, as the reference.
+    fn reference_log(source: &str) -> String {
+        let (mut cpu, mut memory) = load(source);
+        let mut log = String::new();
+        loop {
+            let (state, commit) = cpu.step_commit(&mut memory);
+            if let Some(commit) = commit {
+                log += &trace::format_commit(&commit);
+                log += "\n";
+            }
+            if state != StepState::Continue {
+                return log;
+            }
+        }
+    }
+
+    fn run(reference: &str) -> (u64, Result<LockstepEnd, LockstepError>) {
+        run_program(PROGRAM, reference)
+    }
+
+    fn run_program(source: &str, reference: &str) -> (u64, Result<LockstepEnd, LockstepError>) {
+        let (mut cpu, mut memory) = load(source);
+        let mut lockstep = Lockstep::new(reference.as_bytes());
+        let end = lockstep.run(&mut cpu, &mut memory);
+        (lockstep.compared, end)
+    }
+
+    #[test]
+    fn matches_reference() {
+        let (compared, end) = run(&reference_log(PROGRAM));
+        assert_eq!(end.unwrap(), LockstepEnd::Guest(StepState::Exit));
+        assert_eq!(compared, 8);
+
+        // Lines before the program starts and disassembly lines are skipped
+        let reference = format!(
+            "core   0: 3 0x00001000 (0x00000297) x5  0x00001000\n\
+             core   0: 0x0000000000000000 (0x10000513) li a0, 256\n\
+             {}",
+            reference_log(PROGRAM)
+        );
+        let (compared, end) = run(&reference);
+        assert_eq!(end.unwrap(), LockstepEnd::Guest(StepState::Exit));
+        assert_eq!(compared, 8);
+
+        let reference: String = reference
+            .lines()
+            .take(5)
+            .map(|line| line.to_owned() + "\n")
+            .collect();
+        let (compared, end) = run(&reference);
+        assert_eq!(end.unwrap(), LockstepEnd::ReferenceEnd);
+        assert_eq!(compared, 3);
+    }
+
+    #[test]
+    fn continues_through_handled_traps() {
+        // The load is past the end of memory and faults to the handler, which skips it
+        let source = "
+            la t0, handler
+            csrw mtvec, t0
+            li a0, 0x1000
+            lw t1, 0(a0)
+            li a0, 0
+            ecall
+        handler:
+            csrr t2, mepc
+            addi t2, t2, 4
+            csrw mepc, t2
+            mret
+        ";
+        let reference = reference_log(source);
+        assert!(!reference.contains("(0x00052303)"));
+        let (compared, end) = run_program(source, &reference);
+        assert_eq!(end.unwrap(), LockstepEnd::Guest(StepState::Exit));
+        assert_eq!(compared, 10);
+    }
+
+    #[test]
+    fn reports_divergence() {
+        // The reference stores a byte and so loads a different value
+        let reference = reference_log(&PROGRAM.replace("sh t0", "sb t0"));
+        let (_, end) = run(&reference);
+        let Err(LockstepError::Divergence(divergence)) = end else {
+            panic!("expected a divergence, got {end:?}");
+        };
+        assert_eq!(divergence.index, 3);
+        assert_eq!(divergence.line, 4);
+        assert_eq!(
+            divergence.to_string(),
+            "\
+diverged from the reference after 3 instructions, at line 4
+  0x0000000c: 00551123  sh t0, 2(a0)
+  expected: core   0: 3 0x0000000c (0x00550123) mem 0x00000102 0x34
+  actual:   core   0: 3 0x0000000c (0x00551123) mem 0x00000102 0x1234
+  instruction: expected 0x00550123, actual 0x00551123
+  memory: expected store of 0x34 (1 bytes) to 0x00000102, actual store of 0x1234 (2 bytes) to 0x00000102
+"
+        );
+
+        // A register write with the wrong value
+        let reference = reference_log(&PROGRAM.replace("addi t1, t1, 1", "addi t1, t1, 2"));
+        let (_, end) = run(&reference.replace("(0x00230313)", "(0x00130313)"));
+        let Err(LockstepError::Divergence(divergence)) = end else {
+            panic!("expected a divergence, got {end:?}");
+        };
+        assert_eq!(divergence.index, 5);
+        assert!(divergence
+            .to_string()
+            .ends_with("  x6: expected 0x12340002, actual 0x12340001\n"));
+
+        // A CSR write with the wrong value
+        let source = "
+            li t0, 0x20
+            csrw mscratch, t0
+            li a0, 0
+            ecall
+        ";
+        let reference =
+            reference_log(source).replace("c832_mscratch 0x00000020", "c832_mscratch 0x00000021");
+        let (_, end) = run_program(source, &reference);
+        let Err(LockstepError::Divergence(divergence)) = end else {
+            panic!("expected a divergence, got {end:?}");
+        };
+        assert_eq!(divergence.index, 1);
+        assert!(divergence
+            .to_string()
+            .ends_with("  csrs: expected 0x340 = 0x00000021, actual 0x340 = 0x00000020\n"));
+    }
+}
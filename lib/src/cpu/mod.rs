@@ -2,4 +2,8 @@ pub mod base;
 pub mod rv32i;
 pub mod csrs;
 pub mod instruction_sets;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod lockstep;
 pub mod registers;
+pub mod trace;
@@ -3,18 +3,141 @@
  * ECall behaviour:
  * x10 = 0: exit
  * x10 = 1: print x11 to stdout
+ * x10 = 2: read a byte from the console into x10, blocking until one is available. x10 is set to
+ *          -1 once the input is exhausted.
+ * x10 = 3: as above but without blocking. x10 is set to -1 if no byte is ready.
+ * x10 = 4: read up to x12 bytes from the console into memory at x11, blocking until at least one
+ *          byte is available. x10 is set to the number of bytes read (zero once the input is
+ *          exhausted). A buffer running out of memory, or into memory that isn't writable, ends
+ *          the read at the last byte stored, and x10 is set to -1 if not even the first byte can
+ *          be stored. Bytes are only taken from the input once there is somewhere to store them.
+ *
+ * EBREAK stops the hart with the PC left on the EBREAK so a debugger can take over.
+ *
+ * Exceptions are taken in machine mode: mepc, mcause and mtval are set and the hart jumps to the
+ * handler at mtvec, which can return with MRET. With no handler installed (mtvec is 0, as it is
+ * at reset) the hart stops instead.
  */
-use crate::cpu::instruction_sets::rv32i::CpuState;
-use crate::memory::Memory;
-use crate::cpu::instruction_sets::rv32i::InstructionSet;
+use crate::console::{ConsoleInput, StdinInput};
+use crate::cpu::base::Trap;
+use crate::cpu::instruction_sets::rv32i::{
+    raise_instruction_access_fault, InstructionSet, INSTRUCTION_SIZE,
+};
+use crate::cpu::instruction_sets::rv32i::{CpuState, OpArgs};
+#[cfg(feature = "jit")]
+use crate::cpu::jit::{Jit, JitExit};
+use crate::cpu::trace::{Commit, PendingCommit, Tracer};
+use crate::devices::PowerRequest;
+use crate::memory::{Access, Memory};
+use std::io::Write;
 
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum StepState {
     Exit,
     Continue,
+    /// A device (e.g, the test finisher) asked to stop or reset the machine. The instruction at
+    /// the PC has not been executed.
+    Power(PowerRequest),
+    /// The guest executed an EBREAK. The PC is left pointing at it.
+    Breakpoint,
+    /// The guest raised an exception with no trap handler installed. The PC is left pointing at
+    /// the instruction that raised it.
+    Trap(Trap),
+}
+
+const END_OF_INPUT: u32 = u32::MAX;
+const BAD_BUFFER: u32 = u32::MAX;
+
+fn console_byte(byte: Option<u8>) -> u32 {
+    match byte {
+        Some(byte) => byte as u32,
+        None => END_OF_INPUT,
+    }
+}
+
+fn console_read(op: &mut OpArgs, input: &mut dyn ConsoleInput) -> u32 {
+    let address = op.state.registers.get(11) as usize;
+    let length = op.state.registers.get(12) as usize;
+
+    if length == 0 {
+        return 0;
+    }
+
+    // Block for the first byte and then take whatever else is immediately available.
+    let mut count = 0;
+    while count < length {
+        let destination = address + count;
+        let storable = op.memory.is_mapped(destination)
+            && op
+                .memory
+                .check_access(destination, 1, Access::Write)
+                .is_ok();
+        if !storable {
+            return match count {
+                0 => BAD_BUFFER,
+                _ => count as u32,
+            };
+        }
+
+        let next = match count {
+            0 => input.read_blocking(),
+            _ => input.read_nonblocking(),
+        };
+        match next.map(|byte| op.memory.set8(destination, byte)) {
+            Some(Ok(())) => count += 1,
+            _ => break,
+        }
+    }
+
+    count as u32
+}
+
+fn ecall(op: &mut OpArgs, input: &mut dyn ConsoleInput) -> StepState {
+    match op.state.registers.get(10) {
+        0 => return StepState::Exit,
+        1 => print!("{}", op.state.registers.get(11) as u8 as char),
+        2 => {
+            // Make sure any prompt the guest printed is visible before we wait on input.
+            std::io::stdout().flush().unwrap();
+            let byte = console_byte(input.read_blocking());
+            op.state.registers.set(10, byte)
+        }
+        3 => {
+            let byte = console_byte(input.read_nonblocking());
+            op.state.registers.set(10, byte)
+        }
+        4 => {
+            std::io::stdout().flush().unwrap();
+            let count = console_read(op, input);
+            op.state.registers.set(10, count)
+        }
+        _ => panic!("illegal ecall"),
+    }
+    StepState::Continue
+}
+
+/// Handle the ECALLs and EBREAKs handed to the environment, setting step_state to how the step
+/// ended.
+fn environment<'a>(
+    step_state: &'a mut StepState,
+    input: &'a mut dyn ConsoleInput,
+) -> impl FnOnce(&mut OpArgs) + 'a {
+    |op| {
+        *step_state = match op.immediate() {
+            0 => ecall(op, input),
+            _ => StepState::Breakpoint,
+        };
+    }
 }
 
 pub struct Cpu {
     pub state: CpuState,
+    pub input: Box<dyn ConsoleInput>,
+    /// Set to log every retired instruction
+    pub tracer: Option<Tracer>,
+    /// Set to run the blocks run uses often as native code
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
     tbl: InstructionSet,
 }
 
@@ -22,31 +145,132 @@ impl Cpu {
     pub fn new() -> Self {
         Self {
             state: CpuState::new(),
+            input: Box::new(StdinInput::new()),
+            tracer: None,
+            #[cfg(feature = "jit")]
+            jit: None,
             tbl: InstructionSet::new(),
         }
     }
 
     pub fn step(&mut self, memory: &mut Memory) -> StepState {
-        let next_instruction = memory
-            .get32(self.state.registers.pc as usize)
-            .expect("PC out of range");
+        match self.tracer {
+            Some(_) => self.step_commit(memory).0,
+            None => self.execute(memory, false).0,
+        }
+    }
+
+    /// Run until the guest stops. Unless tracing, this executes a block of instructions at a time,
+    /// which is much faster than stepping.
+    pub fn run(&mut self, memory: &mut Memory) -> StepState {
+        loop {
+            let step_state = match self.tracer {
+                Some(_) => self.step(memory),
+                None => self.run_block(memory),
+            };
+            if step_state != StepState::Continue {
+                return step_state;
+            }
+        }
+    }
+
+    fn run_block(&mut self, memory: &mut Memory) -> StepState {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            for page in memory.take_written_code() {
+                self.tbl.invalidate(page);
+                jit.invalidate(page);
+            }
+            match jit.run(&mut self.state, memory, self.tbl.fences()) {
+                JitExit::Interpret => (),
+                JitExit::CodeWritten => return StepState::Continue,
+                JitExit::Power(request) => return StepState::Power(request),
+            }
+        }
+
         let mut step_state = StepState::Continue;
-        self.tbl
-            .step(&mut self.state, memory, next_instruction, |op| {
-                match op.state.registers.get(10) {
-                    0 => step_state = StepState::Exit,
-                    1 => print!("{}", op.state.registers.get(11) as u8 as char),
-                    _ => panic!("illegal ecall"),
-                }
-            });
+        let environment = environment(&mut step_state, self.input.as_mut());
+        if let Some(request) = self.tbl.run_block(&mut self.state, memory, environment) {
+            return StepState::Power(request);
+        }
+        if let Some(step_state) = self.take_trap() {
+            return step_state;
+        }
+
+        // An EBREAK, always the last instruction of its block, is left at the PC as step does
+        if let StepState::Breakpoint = step_state {
+            self.state.registers.pc -= INSTRUCTION_SIZE;
+        }
         step_state
     }
+
+    /// Execute one instruction, returning its effects if it retired, for tools that want the
+    /// effects themselves. They are traced as step traces them.
+    pub fn step_commit(&mut self, memory: &mut Memory) -> (StepState, Option<Commit>) {
+        let (step_state, commit) = self.execute(memory, true);
+        if let (Some(tracer), Some(commit)) = (&mut self.tracer, &commit) {
+            tracer.record(commit).expect("failed to write the trace");
+        }
+        (step_state, commit)
+    }
+
+    fn execute(&mut self, memory: &mut Memory, commit: bool) -> (StepState, Option<Commit>) {
+        if let Some(request) = memory.tick() {
+            return (StepState::Power(request), None);
+        }
+
+        let pc = self.state.registers.pc;
+        let Ok(decoded) = self.tbl.fetch(memory, pc) else {
+            raise_instruction_access_fault(&mut self.state, pc);
+            return (self.take_trap().unwrap(), None);
+        };
+        let pending = commit.then(|| PendingCommit::new(&self.state, decoded.instruction));
+        let mut step_state = StepState::Continue;
+        let environment = environment(&mut step_state, self.input.as_mut());
+        self.tbl
+            .execute(&mut self.state, memory, &decoded, environment);
+        if let Some(step_state) = self.take_trap() {
+            return (step_state, None);
+        }
+
+        // An EBREAK hands over to the debugger without retiring
+        if let StepState::Breakpoint = step_state {
+            self.state.registers.pc = pc;
+            return (step_state, None);
+        }
+        (
+            step_state,
+            pending.map(|pending| pending.finish(&self.state)),
+        )
+    }
+
+    /// Take the trap raised by the last instruction executed, if there was one, entering the
+    /// handler at mtvec or stopping the hart if there isn't one.
+    fn take_trap(&mut self) -> Option<StepState> {
+        let trap = self.state.trap.take()?;
+        let csrs = &mut self.state.registers.csrs;
+        if csrs.mtvec == 0 {
+            return Some(StepState::Trap(trap));
+        }
+        csrs.mepc = trap.pc;
+        csrs.mcause = trap.exception as u32;
+        csrs.mtval = trap.value;
+        self.state.registers.pc = csrs.mtvec;
+        Some(StepState::Continue)
+    }
 }
 
 #[cfg(test)]
 mod basic_tests {
     use super::*;
+    use crate::console::BufferedInput;
+    use crate::cpu::base::{Exception, MisalignedPolicy};
+    use crate::devices::test_finisher::{TestFinisher, TEST_FINISHER_SIZE};
+    use crate::elf::SymbolTable;
     use crate::instruction::encoder;
+    use crate::memory::{Permissions, PAGE_SIZE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_create() {
@@ -61,4 +285,394 @@ mod basic_tests {
         cpu.step(&mut memory);
         assert_eq!(cpu.state.registers.pc, 4);
     }
+
+    fn load(source: &str) -> (Cpu, Memory) {
+        let image = crate::instruction::assembler::assemble(source, 0).unwrap();
+        let mut memory = Memory::new(0x100);
+        image.load(&mut memory).unwrap();
+        (Cpu::new(), memory)
+    }
+
+    /// Run a program to the end, a block at a time or an instruction at a time.
+    fn run(source: &str, blocks: bool) -> (Cpu, Memory, StepState) {
+        let (mut cpu, mut memory) = load(source);
+        let step_state = finish(&mut cpu, &mut memory, blocks);
+        (cpu, memory, step_state)
+    }
+
+    fn finish(cpu: &mut Cpu, memory: &mut Memory, blocks: bool) -> StepState {
+        match blocks {
+            true => cpu.run(memory),
+            false => loop {
+                match cpu.step(memory) {
+                    StepState::Continue => (),
+                    step_state => break step_state,
+                }
+            },
+        }
+    }
+
+    #[test]
+    fn blocks_match_steps() {
+        // Sum the bytes of a string, through a function call, and store the sum and count
+        let source = "
+                la a0, string
+                call sum
+                sw a0, 0x80(zero)
+                sw a1, 0x84(zero)
+                li a0, 0
+                ecall
+            sum:
+                li t0, 0
+                li a1, 0
+            loop:
+                lbu t1, 0(a0)
+                beqz t1, done
+                add t0, t0, t1
+                addi a0, a0, 1
+                addi a1, a1, 1
+                j loop
+            done:
+                mv a0, t0
+                ret
+            .data
+            string:
+                .asciz \"abcdefghij\"
+        ";
+        let (stepped, stepped_memory, step_state) = run(source, false);
+        assert_eq!(step_state, StepState::Exit);
+        assert_eq!(stepped_memory.get32(0x80), Ok(1015));
+        assert_eq!(stepped_memory.get32(0x84), Ok(10));
+
+        let (blocks, blocks_memory, step_state) = run(source, true);
+        assert_eq!(step_state, StepState::Exit);
+        for register in 0..32 {
+            assert_eq!(
+                blocks.state.registers.get(register),
+                stepped.state.registers.get(register)
+            );
+        }
+        assert_eq!(blocks.state.registers.pc, stepped.state.registers.pc);
+        let instret = |cpu: &Cpu| cpu.state.registers.csrs.instret;
+        assert_eq!(instret(&blocks), instret(&stepped));
+        for address in 0..0x100 {
+            assert_eq!(blocks_memory.get8(address), stepped_memory.get8(address));
+        }
+    }
+
+    #[test]
+    fn blocks_stop_for_the_environment() {
+        // A power request made in the middle of a block stops it before the next instruction
+        let (mut cpu, mut memory) = load(
+            "
+                li t0, 0x1000
+                li t1, 0x5555
+                sw t1, 0(t0)
+                li a0, 7
+                li a0, 0
+                ecall
+            ",
+        );
+        let finisher = Rc::new(RefCell::new(TestFinisher::new()));
+        memory.attach(0x1000, TEST_FINISHER_SIZE, finisher).unwrap();
+        assert_eq!(cpu.run(&mut memory), StepState::Power(PowerRequest::Pass));
+        assert_eq!(cpu.state.registers.pc, 16);
+        assert_eq!(cpu.state.registers.get(10), 0);
+
+        // An EBREAK is left at the PC, as when stepping
+        let (cpu, _, step_state) = run("li a0, 7\n ebreak", true);
+        assert_eq!(step_state, StepState::Breakpoint);
+        assert_eq!(cpu.state.registers.pc, 4);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        for blocks in [false, true] {
+            // The ADDI at patch runs twice, the second time after being replaced
+            let (cpu, _, _) = run(
+                "
+                    li t0, 0
+                    li t2, 2
+                patch:
+                    addi t0, t0, 1
+                    li t1, 0x00a28293 # addi t0, t0, 10
+                    sw t1, 8(zero)
+                    fence.i
+                    addi t2, t2, -1
+                    bnez t2, patch
+                    li a0, 0
+                    ecall
+                ",
+                blocks,
+            );
+            assert_eq!(cpu.state.registers.get(5), 11);
+
+            // As above, but with no FENCE.I and the store in the same block as the patched
+            // instruction, just before it
+            let (cpu, _, _) = run(
+                "
+                    li t0, 0
+                    li t2, 2
+                    li t1, 0x00a28293 # addi t0, t0, 10
+                loop:
+                    addi t2, t2, -1
+                    sw t1, 24(zero)
+                patch:
+                    addi t0, t0, 1
+                    bnez t2, loop
+                    li a0, 0
+                    ecall
+                ",
+                blocks,
+            );
+            assert_eq!(cpu.state.registers.get(5), 20);
+        }
+    }
+
+    #[test]
+    fn code_written_between_steps() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new(8);
+        memory.set32(0, encoder::addi(1, 0, 1).encode()).unwrap();
+        cpu.step(&mut memory);
+        assert_eq!(cpu.state.registers.get(1), 1);
+
+        // e.g, a device writing to memory
+        memory.set32(0, encoder::addi(1, 0, 2).encode()).unwrap();
+        cpu.state.registers.pc = 0;
+        cpu.step(&mut memory);
+        assert_eq!(cpu.state.registers.get(1), 2);
+    }
+
+    #[test]
+    fn exceptions_are_taken_by_the_trap_handler() {
+        // The handler records why it was entered and skips the faulting instruction
+        let source = "
+                la t0, handler
+                csrw mtvec, t0
+                li t1, 0x81
+            fault:
+                lw t2, 0(t1)
+                li a0, 0
+                ecall
+            handler:
+                csrr s0, mcause
+                csrr s1, mtval
+                csrr s2, mepc
+                addi t0, s2, 4
+                csrw mepc, t0
+                mret
+            ";
+        let fault = crate::instruction::assembler::assemble(source, 0)
+            .unwrap()
+            .symbols
+            .lookup("fault")
+            .unwrap()
+            .address;
+        for blocks in [false, true] {
+            let (mut cpu, mut memory) = load(source);
+            cpu.state.misaligned_policy = MisalignedPolicy::Trap;
+            assert_eq!(finish(&mut cpu, &mut memory, blocks), StepState::Exit);
+            assert_eq!(cpu.state.registers.get(8), 4);
+            assert_eq!(cpu.state.registers.get(9), 0x81);
+            assert_eq!(cpu.state.registers.get(18), fault);
+            assert_eq!(cpu.state.registers.get(7), 0);
+            // Everything but the load retired
+            assert_eq!(cpu.state.registers.csrs.instret, 12);
+
+            // Without a handler the hart stops at the load
+            let (mut cpu, mut memory) = load(&source.replace("csrw mtvec, t0", "nop"));
+            cpu.state.misaligned_policy = MisalignedPolicy::Trap;
+            let trap = Trap {
+                exception: Exception::LoadAddressMisaligned,
+                value: 0x81,
+                pc: fault,
+            };
+            assert_eq!(finish(&mut cpu, &mut memory, blocks), StepState::Trap(trap));
+            assert_eq!(cpu.state.registers.pc, fault);
+            assert_eq!(cpu.state.registers.csrs.instret, 4);
+        }
+    }
+
+    /// Run source with its code (the first page) read-only and executable, a guard page after
+    /// it and its data and stack in the pages after that, a block or an instruction at a time.
+    /// Returns the trap it stops with, and its symbols.
+    fn protection_fault(source: &str, blocks: bool) -> (Trap, SymbolTable) {
+        let image = crate::instruction::assembler::assemble(source, 0).unwrap();
+        let mut memory = Memory::new(4 * PAGE_SIZE);
+        image.load(&mut memory).unwrap();
+        memory
+            .protect(0, PAGE_SIZE, Permissions::READ_EXECUTE)
+            .unwrap();
+        memory
+            .protect(PAGE_SIZE, PAGE_SIZE, Permissions::NONE)
+            .unwrap();
+        memory
+            .protect(2 * PAGE_SIZE, 2 * PAGE_SIZE, Permissions::READ_WRITE)
+            .unwrap();
+
+        let mut cpu = Cpu::new();
+        let StepState::Trap(trap) = finish(&mut cpu, &mut memory, blocks) else {
+            panic!("{source} didn't fault");
+        };
+        assert_eq!(cpu.state.registers.pc, trap.pc);
+        (trap, image.symbols)
+    }
+
+    #[test]
+    fn protection_faults() {
+        // The stack overflows into the guard page
+        let overflow = "
+                li sp, 0x3000
+            loop:
+                addi sp, sp, -4
+            fault:
+                sw zero, 0(sp)
+                j loop
+            ";
+        // Code is written
+        let write = "
+                la t0, fault
+            fault:
+                sw zero, 0(t0)
+            ";
+        // Data is executed
+        let execute = "
+                li t0, 0x2000
+                li t1, 0x13 # nop
+                sw t1, 0(t0)
+                jr t0
+            ";
+        let fault = |symbols: &SymbolTable| symbols.lookup("fault").unwrap().address;
+        for blocks in [false, true] {
+            let (trap, symbols) = protection_fault(overflow, blocks);
+            let expected = Trap {
+                exception: Exception::StoreAccessFault,
+                value: 0x1ffc,
+                pc: fault(&symbols),
+            };
+            assert_eq!(trap, expected);
+
+            let (trap, symbols) = protection_fault(write, blocks);
+            let expected = Trap {
+                exception: Exception::StoreAccessFault,
+                value: fault(&symbols),
+                pc: fault(&symbols),
+            };
+            assert_eq!(trap, expected);
+
+            let (trap, _) = protection_fault(execute, blocks);
+            let expected = Trap {
+                exception: Exception::InstructionAccessFault,
+                value: 0x2000,
+                pc: 0x2000,
+            };
+            assert_eq!(trap, expected);
+        }
+    }
+
+    fn run_ecall(cpu: &mut Cpu, memory: &mut Memory, call: i16) {
+        memory
+            .set32(0, encoder::addi(10, 0, call).encode())
+            .unwrap();
+        memory.set32(4, encoder::ecall().encode()).unwrap();
+        cpu.state.registers.pc = 0;
+        cpu.step(memory);
+        cpu.step(memory);
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let mut cpu = Cpu::new();
+        let mut memory = Memory::new(8);
+        memory.set32(0, encoder::ebreak().encode()).unwrap();
+        assert!(matches!(cpu.step(&mut memory), StepState::Breakpoint));
+        assert_eq!(cpu.state.registers.pc, 0);
+    }
+
+    #[test]
+    fn test_getc() {
+        let mut cpu = Cpu::new();
+        cpu.input = Box::new(BufferedInput::new(b"hi"));
+        let mut memory = Memory::new(8);
+
+        run_ecall(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.state.registers.get(10), b'h' as u32);
+        run_ecall(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.state.registers.get(10), b'i' as u32);
+        run_ecall(&mut cpu, &mut memory, 3);
+        assert_eq!(cpu.state.registers.geti(10), -1);
+        run_ecall(&mut cpu, &mut memory, 2);
+        assert_eq!(cpu.state.registers.geti(10), -1);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut cpu = Cpu::new();
+        cpu.input = Box::new(BufferedInput::new(b"hello"));
+        let mut memory = Memory::new(64);
+        cpu.state.registers.set(11, 32);
+        cpu.state.registers.set(12, 4);
+
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 4);
+        assert_eq!(memory.get32(32), Ok(u32::from_le_bytes(*b"hell")));
+
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 1);
+        assert_eq!(memory.get8(32), Ok(b'o'));
+
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 0);
+    }
+
+    #[test]
+    fn test_read_past_the_end_of_memory() {
+        let mut cpu = Cpu::new();
+        cpu.input = Box::new(BufferedInput::new(b"hello"));
+        let mut memory = Memory::new(64);
+        cpu.state.registers.set(11, 62);
+        cpu.state.registers.set(12, 4);
+
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 2);
+        assert_eq!(memory.get16(62), Ok(u16::from_le_bytes(*b"he")));
+
+        cpu.state.registers.set(11, 0x1000);
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.geti(10), -1);
+
+        // No input was lost to the bytes that couldn't be stored
+        cpu.state.registers.set(11, 32);
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 3);
+        assert_eq!(memory.get16(32), Ok(u16::from_le_bytes(*b"ll")));
+    }
+
+    #[test]
+    fn test_read_into_read_only_memory() {
+        let mut cpu = Cpu::new();
+        cpu.input = Box::new(BufferedInput::new(b"hello"));
+        let mut memory = Memory::new(2 * PAGE_SIZE);
+        memory
+            .protect(PAGE_SIZE, PAGE_SIZE, Permissions::READ_ONLY)
+            .unwrap();
+
+        // The read stops at the read-only page like a store would
+        cpu.state.registers.set(11, PAGE_SIZE as u32 - 2);
+        cpu.state.registers.set(12, 4);
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 2);
+        assert_eq!(memory.get8(PAGE_SIZE), Ok(0));
+
+        cpu.state.registers.set(11, PAGE_SIZE as u32);
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.geti(10), -1);
+        assert_eq!(memory.get8(PAGE_SIZE), Ok(0));
+
+        cpu.state.registers.set(11, 0x100);
+        run_ecall(&mut cpu, &mut memory, 4);
+        assert_eq!(cpu.state.registers.get(10), 3);
+        assert_eq!(memory.get8(0x100), Ok(b'l'));
+    }
 }
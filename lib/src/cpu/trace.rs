@@ -0,0 +1,510 @@
+/**
+ * Execution traces in the format of Spike's --log-commits, so they can be diffed against Spike
+ * directly. Each retired instruction gets a line like
+ *
+ * core   0: 3 0x80000004 (0x02028593) x11 0x80000020
+ * core   0: 3 0x8000000c (0x00b2a023) mem 0x80001000 0x00000020
+ *
+ * core   0: 3 0x80000010 (0x30529073) c773_mtvec 0x80000020
+ *
+ * giving the privilege level (always machine mode here), the PC, the instruction and then the
+ * registers and CSRs (by decimal number and name) it wrote and the memory it loaded (address) or
+ * stored (address and value). With disassembly on, each is preceded by the line Spike's -l
+ * gives:
+ *
+ * core   0: 0xffffffff80000004 (0x02028593) addi a1, t0, 32
+ *
+ * parse_commit reads the lines back, e.g, from a reference log to compare against.
+ */
+use crate::cpu::csrs::Csrs;
+use crate::cpu::instruction_sets::rv32i::CpuState;
+use crate::debugger;
+use crate::instruction::decoder;
+use crate::instruction::disassembler;
+use crate::instruction::encoder::Instruction;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// The memory an instruction accessed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryCommit {
+    Load {
+        address: u32,
+        length: u32,
+    },
+    Store {
+        address: u32,
+        length: u32,
+        value: u32,
+    },
+}
+
+/// The architectural effects of one retired instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub pc: u32,
+    pub instruction: u32,
+    /// The registers written, other than x0, and their new values
+    pub registers: Vec<(usize, u32)>,
+    /// The CSRs written and their new values
+    pub csrs: Vec<(usize, u32)>,
+    pub memory: Option<MemoryCommit>,
+}
+
+/// The register an instruction writes, if any.
+fn destination(instruction: u32) -> Option<usize> {
+    let writes = !matches!(
+        decoder::decode(instruction).ok()?,
+        Instruction::Branch { .. }
+            | Instruction::Store { .. }
+            | Instruction::Fence { .. }
+            | Instruction::FenceI {}
+            | Instruction::ECall
+            | Instruction::EBreak
+            | Instruction::MRet
+    );
+    Some(decoder::rd(instruction)).filter(|&rd| writes && rd != 0)
+}
+
+/// The CSR an instruction writes, if any. CSRRS and CSRRC only write with a nonzero mask.
+fn csr_destination(instruction: u32) -> Option<usize> {
+    match decoder::decode(instruction).ok()? {
+        Instruction::CsrRw { csr, .. } | Instruction::CsrRwi { csr, .. } => Some(csr),
+        Instruction::CsrRs {
+            source_register,
+            csr,
+            ..
+        }
+        | Instruction::CsrRc {
+            source_register,
+            csr,
+            ..
+        } => (source_register != 0).then_some(csr),
+        Instruction::CsrRsi {
+            source_value, csr, ..
+        }
+        | Instruction::CsrRci {
+            source_value, csr, ..
+        } => (source_value != 0).then_some(csr),
+        _ => None,
+    }
+}
+
+/// What is known about an instruction before it executes, to be turned into a Commit after.
+pub(crate) struct PendingCommit {
+    pc: u32,
+    instruction: u32,
+    registers: [u32; 32],
+    memory: Option<MemoryCommit>,
+}
+
+impl PendingCommit {
+    pub(crate) fn new(state: &CpuState, instruction: u32) -> Self {
+        let registers = std::array::from_fn(|index| state.registers.get(index));
+        let memory = debugger::memory_access(state, instruction).map(|access| match access.write {
+            true => {
+                let value = state.registers.get(decoder::rs2(instruction));
+                let mask = u32::MAX >> (32 - access.length * 8);
+                MemoryCommit::Store {
+                    address: access.address,
+                    length: access.length,
+                    value: value & mask,
+                }
+            }
+            false => MemoryCommit::Load {
+                address: access.address,
+                length: access.length,
+            },
+        });
+
+        Self {
+            pc: state.registers.pc,
+            instruction,
+            registers,
+            memory,
+        }
+    }
+
+    /// The commit for the instruction, given the state after it executed.
+    pub(crate) fn finish(self, state: &CpuState) -> Commit {
+        let registers = match destination(self.instruction) {
+            Some(rd) => vec![(rd, state.registers.get(rd))],
+            // ECALLs are handled by the environment, which may write any register
+            None => (1..32)
+                .filter(|&index| state.registers.get(index) != self.registers[index])
+                .map(|index| (index, state.registers.get(index)))
+                .collect(),
+        };
+        let csrs = csr_destination(self.instruction)
+            .and_then(|csr| Some((csr, state.registers.csrs.get(csr).ok()?)))
+            .into_iter()
+            .collect();
+
+        Commit {
+            pc: self.pc,
+            instruction: self.instruction,
+            registers,
+            csrs,
+            memory: self.memory,
+        }
+    }
+}
+
+/// Format a commit as Spike's --log-commits would, without the newline.
+pub fn format_commit(commit: &Commit) -> String {
+    let mut line = format!(
+        "core   0: 3 {:#010x} ({:#010x})",
+        commit.pc, commit.instruction
+    );
+    for &(register, value) in &commit.registers {
+        line += &format!(" x{register:<2} {value:#010x}");
+    }
+    for &(csr, value) in &commit.csrs {
+        let name = disassembler::csr_name(csr);
+        line += &format!(" c{csr}_{name} {value:#010x}");
+    }
+    match commit.memory {
+        Some(MemoryCommit::Load { address, .. }) => line += &format!(" mem {address:#010x}"),
+        Some(MemoryCommit::Store {
+            address,
+            length,
+            value,
+        }) => {
+            let width = length as usize * 2 + 2;
+            line += &format!(" mem {address:#010x} {value:#0width$x}");
+        }
+        None => (),
+    }
+    line
+}
+
+/// Parse a hex number as Spike logs them, e.g, 0x00001234.
+fn parse_hex(token: &str) -> Option<u32> {
+    u32::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+}
+
+/// Parse a line of Spike's --log-commits output. Lines that are not commits, such as the
+/// disassembly lines of -l, give None. Floating point writes and writes to CSRs we don't have,
+/// such as the mstatus write Spike logs for MRET, are skipped as they are not logged here.
+pub fn parse_commit(line: &str) -> Option<Commit> {
+    let mut tokens = line.split_whitespace().peekable();
+    if tokens.next()? != "core" || !tokens.next()?.ends_with(':') {
+        return None;
+    }
+    // Disassembly lines have the PC where commits have the privilege level
+    if tokens.next()?.starts_with("0x") {
+        return None;
+    }
+    let pc = parse_hex(tokens.next()?)?;
+    let instruction = tokens.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let instruction = parse_hex(instruction)?;
+
+    let mut registers = Vec::new();
+    let mut csrs = Vec::new();
+    let mut memory = None;
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let address = parse_hex(tokens.next()?)?;
+            // Stores give the value, with as many digits as the store has bytes
+            memory = Some(match tokens.next_if(|token| token.starts_with("0x")) {
+                Some(value) => MemoryCommit::Store {
+                    address,
+                    length: (value.len() as u32 - 2) / 2,
+                    value: parse_hex(value)?,
+                },
+                None => MemoryCommit::Load {
+                    address,
+                    length: 1 << (decoder::funct3(instruction) & 0b11),
+                },
+            });
+        } else if let Some(register) = token.strip_prefix('x') {
+            registers.push((register.parse().ok()?, parse_hex(tokens.next()?)?));
+        } else if let Some((csr, _)) = token.strip_prefix('c').and_then(|csr| csr.split_once('_')) {
+            let (csr, value) = (csr.parse().ok()?, parse_hex(tokens.next()?)?);
+            if Csrs::new().get(csr).is_ok() {
+                csrs.push((csr, value));
+            }
+        } else {
+            tokens.next()?;
+        }
+    }
+
+    Some(Commit {
+        pc,
+        instruction,
+        registers,
+        csrs,
+        memory,
+    })
+}
+
+/// Which instructions to trace. An instruction is traced if it passes every filter given.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// Only trace instructions at these addresses, e.g, those of a symbol
+    pub pc: Option<Range<u32>>,
+    /// Only trace the instructions retired in this window, counting from 0
+    pub instructions: Option<Range<u64>>,
+}
+
+/// Writes a trace of the instructions a Cpu retires.
+pub struct Tracer {
+    output: Box<dyn Write>,
+    filter: TraceFilter,
+    disassembly: bool,
+    retired: u64,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, filter: TraceFilter, disassembly: bool) -> Self {
+        Self {
+            output,
+            filter,
+            disassembly,
+            retired: 0,
+        }
+    }
+
+    /// Log a retired instruction if it passes the filter.
+    pub fn record(&mut self, commit: &Commit) -> io::Result<()> {
+        let index = self.retired;
+        self.retired += 1;
+
+        let pc = self.filter.pc.as_ref();
+        let window = self.filter.instructions.as_ref();
+        if !pc.is_none_or(|range| range.contains(&commit.pc))
+            || !window.is_none_or(|range| range.contains(&index))
+        {
+            return Ok(());
+        }
+
+        if self.disassembly {
+            // Spike shows the PC sign extended to 64 bits here
+            writeln!(
+                self.output,
+                "core   0: {:#018x} ({:#010x}) {}",
+                commit.pc as i32 as i64,
+                commit.instruction,
+                disassembler::disassemble(commit.instruction, commit.pc)
+            )?;
+        }
+        writeln!(self.output, "{}", format_commit(commit))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::rv32i::{Cpu, StepState};
+    use crate::instruction::assembler;
+    use crate::memory::Memory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn commit_format() {
+        let commit = Commit {
+            pc: 0x8000_0004,
+            instruction: 0x0202_8593,
+            registers: vec![(11, 0x8000_0020)],
+            csrs: vec![],
+            memory: None,
+        };
+        assert_eq!(
+            format_commit(&commit),
+            "core   0: 3 0x80000004 (0x02028593) x11 0x80000020"
+        );
+
+        let commit = Commit {
+            pc: 0x8000_0008,
+            instruction: 0x0002_a283,
+            registers: vec![(5, 7)],
+            csrs: vec![],
+            memory: Some(MemoryCommit::Load {
+                address: 0x8000_1000,
+                length: 4,
+            }),
+        };
+        assert_eq!(
+            format_commit(&commit),
+            "core   0: 3 0x80000008 (0x0002a283) x5  0x00000007 mem 0x80001000"
+        );
+
+        let commit = Commit {
+            pc: 0x8000_000c,
+            instruction: 0x00b2_9023,
+            registers: vec![],
+            csrs: vec![],
+            memory: Some(MemoryCommit::Store {
+                address: 0x8000_1000,
+                length: 2,
+                value: 0x20,
+            }),
+        };
+        assert_eq!(
+            format_commit(&commit),
+            "core   0: 3 0x8000000c (0x00b29023) mem 0x80001000 0x0020"
+        );
+
+        let commit = Commit {
+            pc: 0x8000_0010,
+            instruction: 0x3405_1073,
+            registers: vec![],
+            csrs: vec![(0x340, 0x20)],
+            memory: None,
+        };
+        assert_eq!(
+            format_commit(&commit),
+            "core   0: 3 0x80000010 (0x34051073) c832_mscratch 0x00000020"
+        );
+    }
+
+    #[test]
+    fn parses_commits() {
+        let commits = [
+            Commit {
+                pc: 0x8000_0004,
+                instruction: 0x0202_8593,
+                registers: vec![(11, 0x8000_0020)],
+                csrs: vec![],
+                memory: None,
+            },
+            Commit {
+                pc: 0x8000_0008,
+                instruction: 0x0002_c283,
+                registers: vec![(5, 7)],
+                csrs: vec![],
+                memory: Some(MemoryCommit::Load {
+                    address: 0x8000_1000,
+                    length: 1,
+                }),
+            },
+            Commit {
+                pc: 0x8000_000c,
+                instruction: 0x00b2_9023,
+                registers: vec![],
+                csrs: vec![],
+                memory: Some(MemoryCommit::Store {
+                    address: 0x8000_1000,
+                    length: 2,
+                    value: 0x20,
+                }),
+            },
+            Commit {
+                pc: 0x8000_0010,
+                instruction: 0x3405_1073,
+                registers: vec![],
+                csrs: vec![(0x340, 0x20)],
+                memory: None,
+            },
+        ];
+        for commit in commits {
+            assert_eq!(parse_commit(&format_commit(&commit)), Some(commit));
+        }
+
+        let spike = "core   0: 3 0x80000010 (0x30529073) x1  0x00000004 c773_mtvec 0x80000020";
+        assert_eq!(
+            parse_commit(spike),
+            Some(Commit {
+                pc: 0x8000_0010,
+                instruction: 0x3052_9073,
+                registers: vec![(1, 4)],
+                csrs: vec![(0x305, 0x8000_0020)],
+                memory: None,
+            })
+        );
+        // MRET's write of mstatus, which we don't have
+        let spike = "core   0: 3 0x80000014 (0x30200073) c768_mstatus 0x00000080";
+        assert_eq!(parse_commit(spike).unwrap().csrs, []);
+
+        assert_eq!(
+            parse_commit("core   0: 0xffffffff80000004 (0x02028593) addi a1, t0, 32"),
+            None
+        );
+        assert_eq!(parse_commit("core   0: 3 0x80000004"), None);
+        assert_eq!(parse_commit("bbl loader"), None);
+        assert_eq!(parse_commit(""), None);
+    }
+
+    /// A Write that can be inspected after it has been handed to the tracer.
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(filter: TraceFilter, disassembly: bool) -> String {
+        let source = "
+            li a0, 0x100
+            li t0, 0x1234
+            sh t0, 2(a0)
+            lw t1, 0(a0)
+            beqz zero, done
+            nop
+        done:
+            li a0, 0
+            ecall
+        ";
+        let image = assembler::assemble(source, 0).unwrap();
+        let mut memory = Memory::new(0x200);
+        image.load(&mut memory).unwrap();
+
+        let output = SharedOutput(Rc::new(RefCell::new(Vec::new())));
+        let mut cpu = Cpu::new();
+        cpu.tracer = Some(Tracer::new(Box::new(output.clone()), filter, disassembly));
+        while cpu.step(&mut memory) == StepState::Continue {}
+
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    #[test]
+    fn traces_retired_instructions() {
+        assert_eq!(
+            trace(TraceFilter::default(), false),
+            "\
+core   0: 3 0x00000000 (0x10000513) x10 0x00000100
+core   0: 3 0x00000004 (0x000012b7) x5  0x00001000
+core   0: 3 0x00000008 (0x23428293) x5  0x00001234
+core   0: 3 0x0000000c (0x00551123) mem 0x00000102 0x1234
+core   0: 3 0x00000010 (0x00052303) x6  0x12340000 mem 0x00000100
+core   0: 3 0x00000014 (0x00000463)
+core   0: 3 0x0000001c (0x00000513) x10 0x00000000
+core   0: 3 0x00000020 (0x00000073)
+"
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let filter = TraceFilter {
+            pc: Some(0x8..0x20),
+            instructions: Some(3..10),
+        };
+        assert_eq!(
+            trace(filter, true),
+            "\
+core   0: 0x000000000000000c (0x00551123) sh t0, 2(a0)
+core   0: 3 0x0000000c (0x00551123) mem 0x00000102 0x1234
+core   0: 0x0000000000000010 (0x00052303) lw t1, 0(a0)
+core   0: 3 0x00000010 (0x00052303) x6  0x12340000 mem 0x00000100
+core   0: 0x0000000000000014 (0x00000463) beqz zero, 0x1c
+core   0: 3 0x00000014 (0x00000463)
+core   0: 0x000000000000001c (0x00000513) li a0, 0
+core   0: 3 0x0000001c (0x00000513) x10 0x00000000
+"
+        );
+    }
+}
@@ -0,0 +1,707 @@
+/**
+ * A GDB remote serial protocol stub, so guests can be debugged with e.g,
+ * `riscv64-unknown-elf-gdb -ex 'target remote localhost:1234'`.
+ *
+ * Registers (g/G/p/P), memory (m/M), continue and single-step (c/s/vCont), software and hardware
+ * breakpoints (Z0/Z1) and write, read and access watchpoints (Z2/Z3/Z4) are supported. GDB is
+ * told about the RV32 register set, including the counter CSRs, with target XML. Binary memory
+ * writes (X) are left to fall back to M. An exception with no trap handler stops the guest
+ * with the signal GDB expects for it, as on a Unix host.
+ */
+use crate::cpu::base::Exception;
+use crate::cpu::registers::ABI_NAMES;
+use crate::cpu::rv32i::{Cpu, StepState};
+use crate::debugger::{Debugger, Stop, WatchKind, Watchpoint};
+use crate::devices::{test_finisher, PowerRequest};
+use crate::memory::Memory;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// The byte GDB sends to interrupt a running target (Ctrl-C).
+const INTERRUPT: u8 = 0x03;
+
+/// GDB numbers the PC after the 32 general purpose registers and the CSRs from 65.
+const PC_REGISTER: usize = 32;
+const CSR_REGISTER_BASE: usize = 65;
+
+/// The CSRs we describe to GDB.
+const CSRS: [(&str, usize); 11] = [
+    ("mtvec", 0x305),
+    ("mscratch", 0x340),
+    ("mepc", 0x341),
+    ("mcause", 0x342),
+    ("mtval", 0x343),
+    ("cycle", 0xC00),
+    ("time", 0xC01),
+    ("instret", 0xC02),
+    ("cycleh", 0xC80),
+    ("timeh", 0xC81),
+    ("instreth", 0xC82),
+];
+
+/// A connection to GDB.
+pub trait Connection: Read + Write {
+    /// Check, without blocking, whether GDB has sent an interrupt (Ctrl-C).
+    fn interrupted(&mut self) -> bool;
+}
+
+fn read_interrupt(stream: &mut impl Read) -> bool {
+    let mut byte = [0];
+    matches!(stream.read(&mut byte), Ok(1) if byte[0] == INTERRUPT)
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        self.set_nonblocking(true).unwrap();
+        let interrupted = read_interrupt(self);
+        self.set_nonblocking(false).unwrap();
+        interrupted
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn interrupted(&mut self) -> bool {
+        self.set_nonblocking(true).unwrap();
+        let interrupted = read_interrupt(self);
+        self.set_nonblocking(false).unwrap();
+        interrupted
+    }
+}
+
+/// How a debugging session ended.
+#[derive(Debug, PartialEq)]
+pub enum SessionEnd {
+    /// The guest exited or made a power request, which has been reported to GDB
+    Guest(StepState),
+    /// GDB detached and the guest should carry on without it
+    Detach,
+    /// GDB killed the guest or went away
+    Kill,
+}
+
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub<C: Connection> {
+    connection: C,
+    debugger: Debugger,
+    /// Breakpoints GDB asked for as hardware breakpoints, so we can report them as such.
+    hardware_breakpoints: BTreeSet<u32>,
+    no_ack: bool,
+    last_stop: String,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// The signal a Unix host would stop a process with for exception.
+fn signal(exception: Exception) -> u8 {
+    const SIGBUS: u8 = 7;
+    const SIGSEGV: u8 = 11;
+    match exception {
+        Exception::LoadAddressMisaligned | Exception::StoreAddressMisaligned => SIGBUS,
+        Exception::InstructionAccessFault
+        | Exception::LoadAccessFault
+        | Exception::StoreAccessFault => SIGSEGV,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// Parse the addr,length arguments of m, M and Z packets.
+fn address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// The target XML describing our registers.
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "<architecture>riscv:rv32</architecture>\n",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    ));
+
+    for (index, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match index {
+            1 => "code_ptr",
+            2 => "data_ptr",
+            _ => "int",
+        };
+        xml +=
+            &format!("<reg name=\"{name}\" bitsize=\"32\" type=\"{kind}\" regnum=\"{index}\"/>\n");
+    }
+
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC_REGISTER}\"/>\n</feature>\n"
+    );
+    xml += "<feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    for (name, csr) in CSRS {
+        let regnum = CSR_REGISTER_BASE + csr;
+        xml += &format!(
+            "<reg name=\"{name}\" bitsize=\"32\" type=\"int\" regnum=\"{regnum}\" group=\"csr\"/>\n"
+        );
+    }
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+/// Answer a qXfer read of offset,length from data.
+fn transfer(data: &str, request: &str) -> String {
+    let Some((offset, length)) = address_length(request) else {
+        return "E01".to_string();
+    };
+    let start = (offset as usize).min(data.len());
+    let end = (start + length as usize).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{marker}{}", &data[start..end])
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            debugger: Debugger::new(),
+            hardware_breakpoints: BTreeSet::new(),
+            no_ack: false,
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Read the next packet, or None if GDB has gone away.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Acks, naks and interrupts that arrive while we are stopped are ignored
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => (),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = [0; 2];
+            self.connection.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+
+            if !self.no_ack {
+                self.connection.write_all(if valid { b"+" } else { b"-" })?;
+            }
+
+            if valid || self.no_ack {
+                // Unescape }x, which stands for x ^ 0x20
+                let mut unescaped = Vec::with_capacity(data.len());
+                let mut bytes = data.into_iter();
+                while let Some(byte) = bytes.next() {
+                    match byte {
+                        b'}' => unescaped.push(bytes.next().unwrap_or(0) ^ 0x20),
+                        byte => unescaped.push(byte),
+                    }
+                }
+                return Ok(Some(String::from_utf8_lossy(&unescaped).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for &byte in data.as_bytes() {
+            match byte {
+                b'#' | b'$' | b'}' | b'*' => escaped.extend([b'}', byte ^ 0x20]),
+                byte => escaped.push(byte),
+            }
+        }
+
+        let mut packet = vec![b'$'];
+        packet.extend(&escaped);
+        packet.extend(format!("#{:02x}", checksum(&escaped)).as_bytes());
+
+        loop {
+            self.connection.write_all(&packet)?;
+            self.connection.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            // Resend until GDB acknowledges the packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => (),
+                    None => return Ok(()),
+                }
+            }
+        }
+    }
+
+    fn read_register(cpu: &Cpu, register: usize) -> Option<u32> {
+        match register {
+            0..32 => Some(cpu.state.registers.get(register)),
+            PC_REGISTER => Some(cpu.state.registers.pc),
+            CSR_REGISTER_BASE.. => cpu
+                .state
+                .registers
+                .csrs
+                .get(register - CSR_REGISTER_BASE)
+                .ok(),
+            _ => None,
+        }
+    }
+
+    fn write_register(cpu: &mut Cpu, register: usize, value: u32) -> bool {
+        match register {
+            0..32 => cpu.state.registers.set(register, value),
+            PC_REGISTER => cpu.state.registers.pc = value,
+            CSR_REGISTER_BASE.. => {
+                let csr = register - CSR_REGISTER_BASE;
+                return cpu.state.registers.csrs.set(csr, value).is_ok();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_memory(memory: &Memory, address: u32, length: u32) -> String {
+        let bytes: Vec<u8> = (0..length)
+            .map_while(|offset| memory.get8(address.wrapping_add(offset) as usize).ok())
+            .collect();
+        match bytes.is_empty() && length != 0 {
+            true => "E01".to_string(),
+            false => hex(&bytes),
+        }
+    }
+
+    fn write_memory(memory: &mut Memory, address: u32, bytes: &[u8]) -> bool {
+        bytes.iter().enumerate().all(|(offset, &byte)| {
+            let address = address.wrapping_add(offset as u32) as usize;
+            memory.set8(address, byte).is_ok()
+        })
+    }
+
+    /// Handle a Z or z packet.
+    fn breakpoint(&mut self, arguments: &str, insert: bool) -> String {
+        let mut parts = arguments.splitn(3, ',');
+        let (Some(kind), Some(address), Some(length)) = (
+            parts.next(),
+            parts.next().and_then(parse_hex),
+            parts.next().and_then(parse_hex),
+        ) else {
+            return "E01".to_string();
+        };
+
+        let watch = |kind| Watchpoint {
+            address,
+            length,
+            kind,
+        };
+        match (kind, insert) {
+            ("0", true) => self.debugger.add_breakpoint(address),
+            ("1", true) => {
+                self.hardware_breakpoints.insert(address);
+                self.debugger.add_breakpoint(address);
+            }
+            ("0" | "1", false) => {
+                self.hardware_breakpoints.remove(&address);
+                self.debugger.remove_breakpoint(address);
+            }
+            ("2", true) => self.debugger.add_watchpoint(watch(WatchKind::Write)),
+            ("3", true) => self.debugger.add_watchpoint(watch(WatchKind::Read)),
+            ("4", true) => self.debugger.add_watchpoint(watch(WatchKind::Access)),
+            ("2", false) => _ = self.debugger.remove_watchpoint(watch(WatchKind::Write)),
+            ("3", false) => _ = self.debugger.remove_watchpoint(watch(WatchKind::Read)),
+            ("4", false) => _ = self.debugger.remove_watchpoint(watch(WatchKind::Access)),
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn query(&mut self, query: &str) -> String {
+        if let Some(request) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            return transfer(&target_xml(), request);
+        }
+
+        match query.split(':').next().unwrap_or("") {
+            "Supported" => concat!(
+                "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;",
+                "swbreak+;hwbreak+;vContSupported+"
+            )
+            .to_string(),
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn handle(&mut self, packet: &str, cpu: &mut Cpu, memory: &mut Memory) -> Action {
+        let command_len = packet.chars().next().map_or(0, char::len_utf8);
+        let (command, arguments) = packet.split_at(command_len);
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => (0..=PC_REGISTER)
+                .map(|register| hex(&Self::read_register(cpu, register).unwrap().to_le_bytes()))
+                .collect(),
+            "G" => match unhex(arguments) {
+                Some(bytes) => {
+                    for (register, value) in bytes.chunks_exact(4).enumerate() {
+                        let value = u32::from_le_bytes(value.try_into().unwrap());
+                        Self::write_register(cpu, register, value);
+                    }
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|register| Self::read_register(cpu, register))
+            {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let written = arguments.split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok()?;
+                    let value = u32::from_le_bytes(unhex(value)?.try_into().ok()?);
+                    Some(Self::write_register(cpu, register, value))
+                });
+                match written {
+                    Some(true) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match address_length(arguments) {
+                Some((address, length)) => Self::read_memory(memory, address, length),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let written = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = address_length(range)?;
+                    let bytes = unhex(data).filter(|bytes| bytes.len() == length as usize)?;
+                    Some(Self::write_memory(memory, address, &bytes))
+                });
+                match written {
+                    Some(true) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(address) = parse_hex(arguments) {
+                    cpu.state.registers.pc = address;
+                }
+                return Action::Resume {
+                    step: command == "s",
+                };
+            }
+            "Z" => self.breakpoint(arguments, true),
+            "z" => self.breakpoint(arguments, false),
+            "q" => self.query(arguments),
+            "Q" if arguments == "StartNoAckMode" => "OK".to_string(),
+            "H" | "T" => "OK".to_string(),
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            "v" if arguments == "Cont?" => "vCont;c;C;s;S".to_string(),
+            "v" if arguments.starts_with("Cont;") => {
+                // We have a single thread so only the first action matters
+                let action = arguments["Cont;".len()..].chars().next();
+                return Action::Resume {
+                    step: matches!(action, Some('s' | 'S')),
+                };
+            }
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    /// The stop reply for stop, or the exit reply if the guest has finished.
+    fn stop_reply(&self, stop: &Stop) -> String {
+        match stop {
+            Stop::Step | Stop::Guest(StepState::Breakpoint) => "S05".to_string(),
+            Stop::Guest(StepState::Trap(trap)) => format!("S{:02x}", signal(trap.exception)),
+            Stop::Breakpoint(address) if self.hardware_breakpoints.contains(address) => {
+                "T05hwbreak:;".to_string()
+            }
+            Stop::Breakpoint(_) => "T05swbreak:;".to_string(),
+            Stop::Watchpoint(watchpoint, access) => {
+                let kind = match watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                // Report the watched address the access touched
+                let address = access.address.max(watchpoint.address);
+                format!("T05{kind}:{address:x};")
+            }
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Guest(StepState::Power(PowerRequest::Fail(code))) => {
+                format!("W{:02x}", test_finisher::exit_status(*code))
+            }
+            Stop::Guest(_) => "W00".to_string(),
+        }
+    }
+
+    /// Serve GDB until the guest finishes or GDB detaches or kills it. The guest is stopped
+    /// when we start.
+    pub fn run(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> io::Result<SessionEnd> {
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                return Ok(SessionEnd::Kill);
+            };
+
+            match self.handle(&packet, cpu, memory) {
+                Action::Reply(reply) => {
+                    self.send(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                }
+                Action::Resume { step } => {
+                    let stop = match step {
+                        true => self.debugger.step(cpu, memory),
+                        false => {
+                            let connection = &mut self.connection;
+                            self.debugger
+                                .resume(cpu, memory, || connection.interrupted())
+                        }
+                    };
+
+                    let reply = self.stop_reply(&stop);
+                    self.send(&reply)?;
+                    match stop {
+                        Stop::Guest(StepState::Breakpoint | StepState::Trap(_)) => (),
+                        Stop::Guest(state) => return Ok(SessionEnd::Guest(state)),
+                        _ => (),
+                    }
+                    self.last_stop = reply;
+                }
+                Action::Detach => {
+                    self.send("OK")?;
+                    return Ok(SessionEnd::Detach);
+                }
+                Action::Kill => return Ok(SessionEnd::Kill),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::encoder::{self, Instruction};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A scripted GDB, which switches to no-ack mode first.
+    struct Client(TcpStream);
+
+    impl Client {
+        fn connect(address: std::net::SocketAddr) -> Self {
+            let mut client = Self(TcpStream::connect(address).unwrap());
+            client.0.write_all(b"$QStartNoAckMode#b0").unwrap();
+            let mut reply = [0; 7];
+            client.0.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"+$OK#9a");
+            client.0.write_all(b"+").unwrap();
+            client
+        }
+
+        fn packet(&mut self, data: &str) -> String {
+            let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            if data == "k" {
+                return String::new();
+            }
+
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            while byte[0] != b'#' {
+                self.0.read_exact(&mut byte).unwrap();
+                reply.push(byte[0]);
+            }
+            let mut sum = [0; 2];
+            self.0.read_exact(&mut sum).unwrap();
+            String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+        }
+    }
+
+    /// Run program under a stub with script acting as GDB.
+    fn debug(program: &[Instruction], script: impl FnOnce(Client) + Send + 'static) -> SessionEnd {
+        let mut memory = Memory::new(0x200);
+        for (index, instruction) in program.iter().enumerate() {
+            memory.set32(index * 4, instruction.encode()).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let gdb = thread::spawn(move || script(Client::connect(address)));
+
+        let (stream, _) = listener.accept().unwrap();
+        let end = GdbStub::new(stream)
+            .run(&mut Cpu::new(), &mut memory)
+            .unwrap();
+        gdb.join().unwrap();
+        end
+    }
+
+    const PROGRAM: [Instruction; 5] = [
+        encoder::addi(1, 0, 5),
+        encoder::sw(0, 1, 0x100),
+        encoder::lw(0, 2, 0x100),
+        encoder::addi(10, 0, 0),
+        encoder::ecall(),
+    ];
+
+    #[test]
+    fn registers_and_memory() {
+        let end = debug(&PROGRAM, |mut gdb| {
+            assert_eq!(gdb.packet("?"), "S05");
+            assert_eq!(gdb.packet("g"), "00000000".repeat(33));
+            assert_eq!(gdb.packet("P1=78563412"), "OK");
+            assert_eq!(gdb.packet("p1"), "78563412");
+            assert_eq!(gdb.packet("P20=04000000"), "OK");
+            assert_eq!(gdb.packet("p20"), "04000000");
+            assert_eq!(gdb.packet("p0"), "00000000");
+            assert_eq!(gdb.packet("pc41"), "00000000");
+            assert_eq!(gdb.packet("P381=78563412"), "OK");
+            assert_eq!(gdb.packet("p381"), "78563412");
+            assert_eq!(gdb.packet("p21"), "E01");
+
+            let sw = hex(&PROGRAM[1].encode().to_le_bytes());
+            assert_eq!(gdb.packet("m4,4"), sw);
+            assert_eq!(gdb.packet("M1fe,4:deadbeef"), "E01");
+            assert_eq!(gdb.packet("M100,4:deadbeef"), "OK");
+            assert_eq!(gdb.packet("m100,4"), "deadbeef");
+            assert_eq!(gdb.packet("m1fe,4"), "dead");
+            assert_eq!(gdb.packet("m200,4"), "E01");
+            assert_eq!(gdb.packet("vMustReplyEmpty"), "");
+            assert_eq!(gdb.packet("é"), "");
+            gdb.packet("k");
+        });
+        assert_eq!(end, SessionEnd::Kill);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let end = debug(&PROGRAM, |mut gdb| {
+            assert_eq!(gdb.packet("Z0,8,4"), "OK");
+            assert_eq!(gdb.packet("c"), "T05swbreak:;");
+            assert_eq!(gdb.packet("p20"), "08000000");
+            assert_eq!(gdb.packet("p1"), "05000000");
+            assert_eq!(gdb.packet("?"), "T05swbreak:;");
+
+            assert_eq!(gdb.packet("s"), "S05");
+            assert_eq!(gdb.packet("p20"), "0c000000");
+            assert_eq!(gdb.packet("z0,8,4"), "OK");
+            assert_eq!(gdb.packet("Z1,10,4"), "OK");
+            assert_eq!(gdb.packet("vCont;c"), "T05hwbreak:;");
+            assert_eq!(gdb.packet("c"), "W00");
+        });
+        assert_eq!(end, SessionEnd::Guest(StepState::Exit));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let end = debug(&PROGRAM, |mut gdb| {
+            assert_eq!(gdb.packet("Z2,100,4"), "OK");
+            assert_eq!(gdb.packet("Z3,102,1"), "OK");
+            assert_eq!(gdb.packet("c"), "T05watch:100;");
+            assert_eq!(gdb.packet("p20"), "08000000");
+            assert_eq!(gdb.packet("c"), "T05rwatch:102;");
+            assert_eq!(gdb.packet("p2"), "05000000");
+            assert_eq!(gdb.packet("D"), "OK");
+        });
+        assert_eq!(end, SessionEnd::Detach);
+    }
+
+    #[test]
+    fn interrupt() {
+        let end = debug(&[encoder::jal(0, 0)], |mut gdb| {
+            gdb.0.write_all(b"$c#63").unwrap();
+            gdb.0.write_all(&[INTERRUPT]).unwrap();
+            let mut reply = [0; 7];
+            gdb.0.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"$S02#b5");
+            assert_eq!(gdb.packet("p20"), "00000000");
+            gdb.packet("k");
+        });
+        assert_eq!(end, SessionEnd::Kill);
+    }
+
+    #[test]
+    fn faults_stop_with_a_signal() {
+        // The load is past the end of memory and there is no trap handler
+        let program = [encoder::lw(0, 2, 0x300), encoder::ecall()];
+        let end = debug(&program, |mut gdb| {
+            assert_eq!(gdb.packet("c"), "S0b");
+            assert_eq!(gdb.packet("p20"), "00000000");
+            assert_eq!(gdb.packet("?"), "S0b");
+            gdb.packet("k");
+        });
+        assert_eq!(end, SessionEnd::Kill);
+    }
+
+    #[test]
+    fn target_description() {
+        debug(&PROGRAM, |mut gdb| {
+            let supported = gdb.packet("qSupported:multiprocess+;swbreak+");
+            assert!(supported.contains("qXfer:features:read+"));
+
+            let xml = gdb.packet("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains("<architecture>riscv:rv32</architecture>"));
+            assert!(xml.contains("name=\"sp\""));
+            assert!(xml.contains("name=\"instret\" bitsize=\"32\" type=\"int\" regnum=\"3139\""));
+            assert!(xml.contains("name=\"mepc\" bitsize=\"32\" type=\"int\" regnum=\"898\""));
+
+            let start = gdb.packet("qXfer:features:read:target.xml:0,10");
+            assert_eq!(start, "m<?xml version=\"1");
+            gdb.packet("k");
+        });
+    }
+}
@@ -0,0 +1,291 @@
+/**
+ * Breakpoints, watchpoints and stepping for the debugger front ends: the GDB stub and the
+ * monitor.
+ *
+ * Breakpoints are kept here rather than written into guest memory as EBREAKs, so the guest never
+ * sees them and they work on any memory. Watchpoints are checked by decoding each load and store
+ * before it executes, which means accesses made by devices (e.g, virtio DMA) are not seen.
+ */
+pub mod gdb;
+pub mod monitor;
+
+use crate::cpu::instruction_sets::rv32i::CpuState;
+use crate::cpu::rv32i::{Cpu, StepState};
+use crate::instruction::{decoder, opcodes};
+use crate::memory::Memory;
+use std::collections::BTreeSet;
+
+/// How many instructions resume runs between checks for an interrupt from the debugger.
+const POLL_INTERVAL: u64 = 0x1_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+/// A memory access made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub address: u32,
+    pub length: u32,
+    pub write: bool,
+}
+
+/// Why the hart stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// A single step finished
+    Step,
+    /// The PC reached a breakpoint. The instruction there has not been executed.
+    Breakpoint(u32),
+    /// The instruction that just executed made an access covered by the watchpoint
+    Watchpoint(Watchpoint, Access),
+    /// The debugger asked us to stop
+    Interrupted,
+    /// The guest executed an EBREAK, exited or made a power request
+    Guest(StepState),
+}
+
+/// The memory access the instruction would make if executed in state.
+pub fn memory_access(state: &CpuState, instruction: u32) -> Option<Access> {
+    let base = state.registers.get(decoder::rs1(instruction));
+    // LB/SB, LH/SH and LW/SW are 0, 1 and 2 in the low funct3 bits
+    let length = 1 << (decoder::funct3(instruction) & 0b11);
+    let (offset, write) = match decoder::opcode(instruction) {
+        opcodes::LOAD => (decoder::i_type_immediate_32(instruction), false),
+        opcodes::STORE => (decoder::s_type_immediate_32(instruction), true),
+        _ => return None,
+    };
+
+    Some(Access {
+        address: base.wrapping_add(offset as u32),
+        length,
+        write,
+    })
+}
+
+impl Watchpoint {
+    fn hit(&self, access: &Access) -> bool {
+        let kind = match self.kind {
+            WatchKind::Write => access.write,
+            WatchKind::Read => !access.write,
+            WatchKind::Access => true,
+        };
+        let end = self.address as u64 + self.length as u64;
+        let access_end = access.address as u64 + access.length as u64;
+        kind && (access.address as u64) < end && (self.address as u64) < access_end
+    }
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Remove a breakpoint, returning false if there was none at address.
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Remove a watchpoint, returning false if there was no such watchpoint.
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&other| other != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Execute a single instruction, ignoring any breakpoint at the PC.
+    pub fn step(&self, cpu: &mut Cpu, memory: &mut Memory) -> Stop {
+        let access = match self.watchpoints.is_empty() {
+            true => None,
+            false => memory
+                .get32(cpu.state.registers.pc as usize)
+                .ok()
+                .and_then(|instruction| memory_access(&cpu.state, instruction)),
+        };
+        let retired = cpu.state.registers.csrs.instret;
+
+        match cpu.step(memory) {
+            StepState::Continue => (),
+            state => return Stop::Guest(state),
+        }
+
+        // A load or store that trapped to the guest's handler didn't retire or access memory
+        let access = access.filter(|_| cpu.state.registers.csrs.instret != retired);
+        let watchpoint = access.and_then(|access| {
+            self.watchpoints
+                .iter()
+                .find(|watchpoint| watchpoint.hit(&access))
+                .map(|&watchpoint| (watchpoint, access))
+        });
+        match watchpoint {
+            Some((watchpoint, access)) => Stop::Watchpoint(watchpoint, access),
+            None => Stop::Step,
+        }
+    }
+
+    /// Run until a breakpoint or watchpoint is hit or the guest stops. A breakpoint at the PC we
+    /// resume from is stepped over. interrupted is polled every so often and stops the run when
+    /// it returns true.
+    pub fn resume(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Stop {
+        for count in 1.. {
+            match self.step(cpu, memory) {
+                Stop::Step => (),
+                stop => return stop,
+            }
+
+            let pc = cpu.state.registers.pc;
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint(pc);
+            }
+
+            if count % POLL_INTERVAL == 0 && interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::encoder;
+    use crate::memory::{Permissions, PAGE_SIZE};
+
+    /// Store 5 to 0x100, load it back and exit, in size bytes of memory.
+    fn program(size: usize) -> Memory {
+        let mut memory = Memory::new(size);
+        let program = [
+            encoder::addi(1, 0, 5),
+            encoder::sw(0, 1, 0x100),
+            encoder::lh(0, 2, 0x102),
+            encoder::addi(10, 0, 0),
+            encoder::ecall(),
+        ];
+        for (index, instruction) in program.iter().enumerate() {
+            memory.set32(index * 4, instruction.encode()).unwrap();
+        }
+        memory
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut memory = program(0x200);
+        let mut cpu = Cpu::new();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(8);
+        debugger.add_breakpoint(0);
+
+        // We don't stop at the breakpoint we start on
+        assert_eq!(
+            debugger.resume(&mut cpu, &mut memory, || false),
+            Stop::Breakpoint(8)
+        );
+        assert_eq!(debugger.step(&mut cpu, &mut memory), Stop::Step);
+        assert_eq!(cpu.state.registers.pc, 12);
+        assert!(debugger.remove_breakpoint(8));
+        assert!(!debugger.remove_breakpoint(8));
+        assert_eq!(
+            debugger.resume(&mut cpu, &mut memory, || false),
+            Stop::Guest(StepState::Exit)
+        );
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut memory = program(0x200);
+        let mut cpu = Cpu::new();
+        let mut debugger = Debugger::new();
+        let write = Watchpoint {
+            address: 0x103,
+            length: 1,
+            kind: WatchKind::Write,
+        };
+        let read = Watchpoint {
+            address: 0x100,
+            length: 2,
+            kind: WatchKind::Read,
+        };
+        debugger.add_watchpoint(write);
+        debugger.add_watchpoint(read);
+
+        let stop = debugger.resume(&mut cpu, &mut memory, || false);
+        let access = Access {
+            address: 0x100,
+            length: 4,
+            write: true,
+        };
+        assert_eq!(stop, Stop::Watchpoint(write, access));
+        assert_eq!(cpu.state.registers.pc, 8);
+
+        // The halfword load at 0x102 misses the read watchpoint on 0x100 and 0x101
+        assert_eq!(
+            debugger.resume(&mut cpu, &mut memory, || false),
+            Stop::Guest(StepState::Exit)
+        );
+        assert!(debugger.remove_watchpoint(read));
+        assert_eq!(debugger.watchpoints(), &[write]);
+    }
+
+    #[test]
+    fn trapped_accesses_miss_watchpoints() {
+        let mut memory = program(PAGE_SIZE);
+        let mut cpu = Cpu::new();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint {
+            address: 0x100,
+            length: 4,
+            kind: WatchKind::Access,
+        });
+
+        // The store faults to a handler at 0x180 instead of writing the watched word
+        memory
+            .protect(0, PAGE_SIZE, Permissions::READ_EXECUTE)
+            .unwrap();
+        cpu.state.registers.csrs.mtvec = 0x180;
+        assert_eq!(debugger.step(&mut cpu, &mut memory), Stop::Step);
+        assert_eq!(debugger.step(&mut cpu, &mut memory), Stop::Step);
+        assert_eq!(cpu.state.registers.pc, 0x180);
+        assert_eq!(cpu.state.registers.csrs.mcause, 7);
+    }
+}
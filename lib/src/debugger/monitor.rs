@@ -0,0 +1,503 @@
+/**
+ * An interactive monitor for debugging guests from the command line. Commands are read a line
+ * at a time from any BufRead and replies go to any Write, so sessions can be scripted:
+ *
+ * step [n]                       execute n instructions (1 by default)
+ * continue [address]             run until a breakpoint, watchpoint or address
+ * break <address>                set a breakpoint
+ * watch|rwatch|awatch <address> [length]
+ *                                stop after a write, read or any access to length bytes
+ * delete <address>               remove the breakpoints and watchpoints at address
+ * info                           list breakpoints and watchpoints
+ * regs                           dump the general purpose registers and the PC
+ * csrs                           dump the CSRs
+ * x <address> [length]           dump memory
+ * disas [address] [count]        show the instructions around the PC or at address
+ * set <register> <value>         modify a register (x0-x31, an ABI name or pc)
+ * quit
+ *
+ * Addresses and values can be decimal, hex with a 0x prefix or symbols. An empty line repeats
+ * the last command.
+ */
+use crate::cpu::registers::ABI_NAMES;
+use crate::cpu::rv32i::{Cpu, StepState};
+use crate::debugger::{Debugger, Stop, WatchKind, Watchpoint};
+use crate::elf::SymbolTable;
+use crate::instruction::disassembler;
+use crate::memory::Memory;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step [n]                    execute n instructions
+continue [address]          run until a breakpoint, watchpoint or address
+break <address>             set a breakpoint
+watch <address> [length]    stop after a write (rwatch: read, awatch: either)
+delete <address>            remove the breakpoints and watchpoints at address
+info                        list breakpoints and watchpoints
+regs                        dump the registers
+csrs                        dump the CSRs
+x <address> [length]        dump memory
+disas [address] [count]     show instructions
+set <register> <value>      modify a register
+quit";
+
+/// The number of instructions disas shows before and after the PC when no address is given.
+const DISAS_CONTEXT: u32 = 4;
+
+/// How a monitor session ended.
+#[derive(Debug, PartialEq)]
+pub enum MonitorEnd {
+    /// The guest exited or made a power request
+    Guest(StepState),
+    /// The user quit, or the input ended
+    Quit,
+}
+
+pub struct Monitor {
+    debugger: Debugger,
+    symbols: SymbolTable,
+    last_command: String,
+}
+
+/// Parse a register name: x0-x31, an ABI name, fp or pc. The PC is register 32.
+fn parse_register(name: &str) -> Option<usize> {
+    match name {
+        "pc" => Some(32),
+        "fp" => Some(8),
+        _ => match name.strip_prefix('x').and_then(|index| index.parse().ok()) {
+            Some(index) if index < 32 => Some(index),
+            _ => ABI_NAMES.iter().position(|&abi| abi == name),
+        },
+    }
+}
+
+impl Monitor {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Self {
+            debugger: Debugger::new(),
+            symbols,
+            last_command: String::new(),
+        }
+    }
+
+    /// Parse a number (decimal, negative decimal or 0x hex) or a symbol.
+    fn parse_value(&self, text: &str) -> Result<u32, String> {
+        let number = match text.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => text
+                .parse::<u32>()
+                .ok()
+                .or_else(|| text.parse::<i32>().ok().map(|value| value as u32)),
+        };
+        number
+            .or_else(|| self.symbols.lookup(text).map(|symbol| symbol.address))
+            .ok_or_else(|| format!("unknown address or symbol {text}"))
+    }
+
+    /// Format an address along with the symbol it falls in, if any.
+    fn address(&self, address: u32) -> String {
+        match self.symbols.describe(address) {
+            Some(symbol) => format!("{address:#010x} <{symbol}>"),
+            None => format!("{address:#010x}"),
+        }
+    }
+
+    /// Disassemble the instruction at address, naming the symbol a branch or jump goes to.
+    fn instruction(&self, memory: &Memory, address: u32) -> String {
+        let word = match memory.get32(address as usize) {
+            Ok(word) => word,
+            Err(_) => return "(out of range)".to_string(),
+        };
+        let text = disassembler::describe(word, address, &self.symbols);
+        format!("{word:08x}  {text}")
+    }
+
+    fn show_pc(&self, cpu: &Cpu, memory: &Memory, output: &mut impl Write) -> io::Result<()> {
+        let pc = cpu.state.registers.pc;
+        let instruction = self.instruction(memory, pc);
+        writeln!(output, "=> {}: {instruction}", self.address(pc))
+    }
+
+    /// Report why the hart stopped. Returns the guest's state if it has finished.
+    fn report(
+        &self,
+        stop: Stop,
+        cpu: &Cpu,
+        memory: &Memory,
+        output: &mut impl Write,
+    ) -> io::Result<Option<StepState>> {
+        match stop {
+            Stop::Step | Stop::Interrupted => (),
+            Stop::Breakpoint(address) => {
+                writeln!(output, "Breakpoint at {}", self.address(address))?
+            }
+            Stop::Watchpoint(watchpoint, access) => {
+                let kind = if access.write { "write" } else { "read" };
+                writeln!(
+                    output,
+                    "Watchpoint on {}: {kind} of {} bytes at {}",
+                    self.address(watchpoint.address),
+                    access.length,
+                    self.address(access.address)
+                )?
+            }
+            Stop::Guest(StepState::Breakpoint) => writeln!(output, "EBREAK")?,
+            Stop::Guest(StepState::Trap(trap)) => writeln!(output, "No trap handler for {trap}")?,
+            Stop::Guest(state) => return Ok(Some(state)),
+        }
+        self.show_pc(cpu, memory, output)?;
+        Ok(None)
+    }
+
+    fn step(
+        &self,
+        count: u32,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        output: &mut impl Write,
+    ) -> io::Result<Option<StepState>> {
+        for _ in 1..count {
+            match self.debugger.step(cpu, memory) {
+                Stop::Step => (),
+                stop => return self.report(stop, cpu, memory, output),
+            }
+        }
+        let stop = self.debugger.step(cpu, memory);
+        self.report(stop, cpu, memory, output)
+    }
+
+    fn resume(
+        &mut self,
+        until: Option<u32>,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        output: &mut impl Write,
+    ) -> io::Result<Option<StepState>> {
+        // Run to an address with a temporary breakpoint
+        let temporary = until.filter(|&address| !self.debugger.breakpoints().any(|b| b == address));
+        if let Some(address) = temporary {
+            self.debugger.add_breakpoint(address);
+        }
+
+        let stop = self.debugger.resume(cpu, memory, || false);
+
+        if let Some(address) = temporary {
+            self.debugger.remove_breakpoint(address);
+        }
+        match stop {
+            Stop::Breakpoint(address) if temporary == Some(address) => {
+                self.report(Stop::Step, cpu, memory, output)
+            }
+            stop => self.report(stop, cpu, memory, output),
+        }
+    }
+
+    fn registers(&self, cpu: &Cpu, output: &mut impl Write) -> io::Result<()> {
+        for row in (0..32).step_by(4) {
+            let line: Vec<_> = (row..row + 4)
+                .map(|index| {
+                    let value = cpu.state.registers.get(index);
+                    format!("{:>4} {value:#010x}", ABI_NAMES[index])
+                })
+                .collect();
+            writeln!(output, "{}", line.join("  "))?;
+        }
+        writeln!(output, "  pc {}", self.address(cpu.state.registers.pc))
+    }
+
+    fn csrs(&self, cpu: &Cpu, output: &mut impl Write) -> io::Result<()> {
+        let csrs = &cpu.state.registers.csrs;
+        writeln!(output, "cycle    {:#018x}", csrs.rdcycle)?;
+        writeln!(output, "time     {:#018x}", csrs.rdtime)?;
+        writeln!(output, "instret  {:#018x}", csrs.instret)?;
+        writeln!(output, "mtvec    {:#010x}", csrs.mtvec)?;
+        writeln!(output, "mscratch {:#010x}", csrs.mscratch)?;
+        writeln!(output, "mepc     {:#010x}", csrs.mepc)?;
+        writeln!(output, "mcause   {:#010x}", csrs.mcause)?;
+        writeln!(output, "mtval    {:#010x}", csrs.mtval)
+    }
+
+    fn dump(
+        &self,
+        address: u32,
+        length: u32,
+        memory: &Memory,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        for line in (0..length).step_by(16) {
+            let start = address.wrapping_add(line);
+            let bytes: Vec<_> = (0..16.min(length - line))
+                .map(
+                    |offset| match memory.get8(start.wrapping_add(offset) as usize) {
+                        Ok(byte) => format!("{byte:02x}"),
+                        Err(_) => "??".to_string(),
+                    },
+                )
+                .collect();
+            writeln!(output, "{start:#010x}: {}", bytes.join(" "))?;
+        }
+        Ok(())
+    }
+
+    fn disassemble(
+        &self,
+        start: u32,
+        count: u32,
+        cpu: &Cpu,
+        memory: &Memory,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        for index in 0..count {
+            let address = start.wrapping_add(index * 4);
+            let marker = if address == cpu.state.registers.pc {
+                "=>"
+            } else {
+                "  "
+            };
+            let instruction = self.instruction(memory, address);
+            writeln!(output, "{marker} {}: {instruction}", self.address(address))?;
+        }
+        Ok(())
+    }
+
+    fn info(&self, output: &mut impl Write) -> io::Result<()> {
+        for address in self.debugger.breakpoints() {
+            writeln!(output, "break {}", self.address(address))?;
+        }
+        for watchpoint in self.debugger.watchpoints() {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            writeln!(
+                output,
+                "{kind} {} {}",
+                self.address(watchpoint.address),
+                watchpoint.length
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Execute a command line. Returns the guest's state if it has finished.
+    fn execute(
+        &mut self,
+        line: &str,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        output: &mut impl Write,
+    ) -> Result<Option<MonitorEnd>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |index: usize| -> Result<Option<u32>, String> {
+            words
+                .get(index)
+                .map(|word| self.parse_value(word))
+                .transpose()
+        };
+        let required = |index: usize| -> Result<u32, String> {
+            argument(index)?.ok_or_else(|| format!("{} needs an address", words[0]))
+        };
+
+        let io = |result: io::Result<()>| result.map_err(|err| err.to_string());
+        let finished = |result: io::Result<Option<StepState>>| match result {
+            Ok(state) => Ok(state.map(MonitorEnd::Guest)),
+            Err(err) => Err(err.to_string()),
+        };
+
+        match words.first().copied().unwrap_or("") {
+            "s" | "step" => {
+                let count = argument(1)?.unwrap_or(1);
+                if count == 0 {
+                    return Err("step needs a count of at least 1".to_string());
+                }
+                return finished(self.step(count, cpu, memory, output));
+            }
+            "c" | "continue" => {
+                let until = argument(1)?;
+                return finished(self.resume(until, cpu, memory, output));
+            }
+            "b" | "break" => self.debugger.add_breakpoint(required(1)?),
+            kind @ ("watch" | "rwatch" | "awatch") => {
+                let kind = match kind {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let address = required(1)?;
+                let length = argument(2)?.unwrap_or(4);
+                self.debugger.add_watchpoint(Watchpoint {
+                    address,
+                    length,
+                    kind,
+                });
+            }
+            "d" | "delete" => {
+                let address = required(1)?;
+                let mut removed = self.debugger.remove_breakpoint(address);
+                let watchpoints: Vec<_> = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .filter(|watchpoint| watchpoint.address == address)
+                    .copied()
+                    .collect();
+                for watchpoint in watchpoints {
+                    removed |= self.debugger.remove_watchpoint(watchpoint);
+                }
+                if !removed {
+                    return Err(format!("nothing set at {}", self.address(address)));
+                }
+            }
+            "i" | "info" => io(self.info(output))?,
+            "r" | "regs" => io(self.registers(cpu, output))?,
+            "csrs" => io(self.csrs(cpu, output))?,
+            "x" => {
+                let length = argument(2)?.unwrap_or(16);
+                io(self.dump(required(1)?, length, memory, output))?
+            }
+            "disas" => {
+                let pc = cpu.state.registers.pc;
+                let start = argument(1)?.unwrap_or(pc.saturating_sub(DISAS_CONTEXT * 4));
+                let count = argument(2)?.unwrap_or(DISAS_CONTEXT * 2 + 1);
+                io(self.disassemble(start, count, cpu, memory, output))?
+            }
+            "set" => {
+                let (Some(register), Some(_)) = (words.get(1), words.get(2)) else {
+                    return Err("set needs a register and a value".to_string());
+                };
+                let register = parse_register(register)
+                    .ok_or_else(|| format!("unknown register {register}"))?;
+                let value = required(2)?;
+                match register {
+                    32 => cpu.state.registers.pc = value,
+                    _ => cpu.state.registers.set(register, value),
+                }
+            }
+            "h" | "help" => io(writeln!(output, "{HELP}"))?,
+            "q" | "quit" => return Ok(Some(MonitorEnd::Quit)),
+            "" => (),
+            command => return Err(format!("unknown command {command}, try help")),
+        }
+        Ok(None)
+    }
+
+    /// Read and execute commands until the guest finishes, the user quits or the input ends.
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<MonitorEnd> {
+        self.show_pc(cpu, memory, output)?;
+        loop {
+            write!(output, "(monitor) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(MonitorEnd::Quit);
+            }
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+
+            match self.execute(&line, cpu, memory, output) {
+                Ok(Some(end)) => return Ok(end),
+                Ok(None) => (),
+                Err(err) => writeln!(output, "{err}")?,
+            }
+            self.last_command = line;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::Symbol;
+    use crate::instruction::encoder;
+
+    /// Store 5 to 0x100, load it back and exit, with main at 0 and exit at 12.
+    fn session(script: &str) -> (MonitorEnd, String, Cpu) {
+        let mut memory = Memory::new(0x200);
+        let program = [
+            encoder::addi(1, 0, 5),
+            encoder::sw(0, 1, 0x100),
+            encoder::lw(0, 2, 0x100),
+            encoder::addi(10, 0, 0),
+            encoder::ecall(),
+        ];
+        for (index, instruction) in program.iter().enumerate() {
+            memory.set32(index * 4, instruction.encode()).unwrap();
+        }
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "main".to_string(),
+                address: 0,
+                size: 12,
+                function: true,
+            },
+            Symbol {
+                name: "exit".to_string(),
+                address: 12,
+                size: 8,
+                function: true,
+            },
+        ]);
+
+        let mut cpu = Cpu::new();
+        let mut output = Vec::new();
+        let end = Monitor::new(symbols)
+            .run(&mut cpu, &mut memory, &mut script.as_bytes(), &mut output)
+            .unwrap();
+        (end, String::from_utf8(output).unwrap(), cpu)
+    }
+
+    #[test]
+    fn stepping_and_breakpoints() {
+        let (end, output, cpu) =
+            session("step 0\nstep\n\nbreak exit\ninfo\ncontinue\nregs\ncontinue\n");
+        assert_eq!(end, MonitorEnd::Guest(StepState::Exit));
+        assert!(output.contains("step needs a count of at least 1"));
+        assert!(output.contains("=> 0x00000008 <main+0x8>:"));
+        assert!(output.contains("break 0x0000000c <exit>"));
+        assert!(output.contains("Breakpoint at 0x0000000c <exit>"));
+        assert!(output.contains("  ra 0x00000005    sp 0x00000005"));
+        assert!(output.contains("  pc 0x0000000c <exit>"));
+        assert_eq!(cpu.state.registers.get(2), 5);
+    }
+
+    #[test]
+    fn continue_until_and_watchpoints() {
+        let (end, output, cpu) =
+            session("awatch 0x100\ncontinue\ndelete 0x100\ncontinue 0xc\nquit\n");
+        assert_eq!(end, MonitorEnd::Quit);
+        assert!(output.contains(
+            "Watchpoint on 0x00000100: write of 4 bytes at 0x00000100\n=> 0x00000008 <main+0x8>:"
+        ));
+        assert!(!output.contains("read of"));
+        assert_eq!(cpu.state.registers.pc, 12);
+    }
+
+    #[test]
+    fn inspect_and_modify() {
+        let script = "set t0 -1\nset x6 0x10\nset pc exit\nx 0 6\ndisas 0 2\ncsrs\nbogus\nbreak\n";
+        let (end, output, cpu) = session(script);
+        assert_eq!(end, MonitorEnd::Quit);
+        assert_eq!(cpu.state.registers.get(5), u32::MAX);
+        assert_eq!(cpu.state.registers.get(6), 0x10);
+        assert_eq!(cpu.state.registers.pc, 12);
+        assert!(output.contains("0x00000000: 93 00 50 00 23 20"));
+        assert!(output.contains(
+            "   0x00000000 <main>: 00500093  li ra, 5\n   0x00000004 <main+0x4>: 10102023  sw ra, 256(zero)"
+        ));
+        assert!(output.contains("instret  0x0000000000000000"));
+        assert!(output.contains("mcause   0x00000000"));
+        assert!(output.contains("unknown command bogus"));
+        assert!(output.contains("break needs an address"));
+    }
+}
@@ -0,0 +1,459 @@
+/**
+ * Builds flattened device trees (DTBs, see the devicetree specification v0.4) describing the
+ * machine we emulate. Firmware and kernels find RAM, the harts and every device through the tree,
+ * which is placed in memory with its address passed in a1 at boot.
+ *
+ * Devices describe themselves through Device::device_tree so the tree always matches what is
+ * actually attached to Memory.
+ */
+use crate::memory::Memory;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// The size of the header, which is followed by the (empty) memory reservation block.
+const HEADER_SIZE: usize = 40;
+const RESERVATION_SIZE: usize = 16;
+
+/// Every address and size in the tree takes two cells (64 bits), as on QEMU's virt machine, even
+/// on 32 bit harts.
+pub const ADDRESS_CELLS: u32 = 2;
+pub const SIZE_CELLS: u32 = 2;
+
+/// The path of the bus every device sits on.
+pub const SOC_PATH: &str = "/soc";
+
+/// The frequency rdtime counts at (it counts milliseconds).
+pub const TIMEBASE_FREQUENCY: u32 = 1000;
+
+/// A device tree node with its properties in the order they were set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            properties: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set a property to raw bytes, replacing it if it is already set.
+    pub fn set(&mut self, name: &str, value: &[u8]) {
+        match self.properties.iter_mut().find(|(key, _)| key == name) {
+            Some((_, existing)) => *existing = value.to_vec(),
+            None => self.properties.push((name.to_string(), value.to_vec())),
+        }
+    }
+
+    /// Set a property with no value, such as interrupt-controller.
+    pub fn set_empty(&mut self, name: &str) {
+        self.set(name, &[])
+    }
+
+    pub fn set_u32(&mut self, name: &str, value: u32) {
+        self.set_u32s(name, &[value])
+    }
+
+    pub fn set_u32s(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.set(name, &bytes)
+    }
+
+    pub fn set_str(&mut self, name: &str, value: &str) {
+        self.set_strs(name, &[value])
+    }
+
+    /// Set a string list property such as compatible, each string NUL terminated.
+    pub fn set_strs(&mut self, name: &str, values: &[&str]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.bytes().chain(std::iter::once(0)))
+            .collect();
+        self.set(name, &bytes)
+    }
+
+    /// Set reg to a single region of memory.
+    pub fn set_reg(&mut self, base: usize, size: usize) {
+        let (base, size) = (base as u64, size as u64);
+        self.set_u32s(
+            "reg",
+            &[
+                (base >> 32) as u32,
+                base as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        )
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Check whether a string list property (e.g, compatible) contains value.
+    pub fn has_str(&self, name: &str, value: &str) -> bool {
+        self.get(name).is_some_and(|bytes| {
+            bytes
+                .split(|&byte| byte == 0)
+                .any(|string| string == value.as_bytes())
+        })
+    }
+
+    pub fn children(&self) -> &[Node] {
+        &self.children
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Get the child with name, adding an empty one if there isn't one yet.
+    pub fn child_mut(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|child| child.name == name) {
+            Some(index) => &mut self.children[index],
+            None => self.add(Node::new(name)),
+        }
+    }
+
+    pub fn add(&mut self, child: Node) -> &mut Node {
+        self.children.push(child);
+        self.children.last_mut().unwrap()
+    }
+}
+
+/// What the device tree needs to know about the machine beyond what is attached to Memory.
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    /// The ISA string for every hart, e.g rv32i
+    pub isa: String,
+    pub harts: usize,
+    /// The kernel command line
+    pub bootargs: Option<String>,
+    /// The address range [start, end) of the initial ramdisk
+    pub initrd: Option<(usize, usize)>,
+}
+
+pub struct DeviceTree {
+    root: Node,
+    next_phandle: u32,
+    /// The phandle of each hart's interrupt controller.
+    harts: Vec<u32>,
+    /// Interrupts to add to device nodes once every device has been described, as the base
+    /// address of the device, the phandle of its interrupt controller and the interrupt number.
+    interrupts: Vec<(usize, u32, u32)>,
+}
+
+impl Default for DeviceTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTree {
+    /// An empty tree with the root and soc nodes set up.
+    pub fn new() -> Self {
+        let mut root = Node::new("");
+        root.set_u32("#address-cells", ADDRESS_CELLS);
+        root.set_u32("#size-cells", SIZE_CELLS);
+        root.set_str("compatible", "riscv-virtio");
+        root.set_str("model", "riscv-emulator");
+
+        let soc = root.child_mut(&SOC_PATH[1..]);
+        soc.set_u32("#address-cells", ADDRESS_CELLS);
+        soc.set_u32("#size-cells", SIZE_CELLS);
+        soc.set_str("compatible", "simple-bus");
+        soc.set_empty("ranges");
+
+        Self {
+            root,
+            next_phandle: 1,
+            harts: Vec::new(),
+            interrupts: Vec::new(),
+        }
+    }
+
+    /// Describe the harts, RAM and every device attached to memory.
+    pub fn for_machine(memory: &Memory, config: &MachineConfig) -> Self {
+        let mut tree = Self::new();
+
+        let cpus = tree.root.child_mut("cpus");
+        cpus.set_u32("#address-cells", 1);
+        cpus.set_u32("#size-cells", 0);
+        cpus.set_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+
+        for hart in 0..config.harts {
+            let phandle = tree.phandle();
+            let cpu = tree
+                .root
+                .child_mut("cpus")
+                .add(Node::new(&format!("cpu@{hart}")));
+            cpu.set_str("device_type", "cpu");
+            cpu.set_u32("reg", hart as u32);
+            cpu.set_str("status", "okay");
+            cpu.set_str("compatible", "riscv");
+            cpu.set_str("riscv,isa", &config.isa);
+
+            let intc = cpu.add(Node::new("interrupt-controller"));
+            intc.set_u32("#interrupt-cells", 1);
+            intc.set_empty("interrupt-controller");
+            intc.set_str("compatible", "riscv,cpu-intc");
+            intc.set_u32("phandle", phandle);
+            tree.harts.push(phandle);
+        }
+
+        memory.describe(&mut tree);
+
+        for (base, parent, interrupt) in std::mem::take(&mut tree.interrupts) {
+            let suffix = format!("@{base:x}");
+            let soc = tree.soc();
+            if let Some(index) = soc
+                .children()
+                .iter()
+                .position(|node| node.name().ends_with(&suffix))
+            {
+                let node = &mut soc.children[index];
+                node.set_u32("interrupt-parent", parent);
+                node.set_u32("interrupts", interrupt);
+            }
+        }
+
+        let stdout = tree
+            .soc()
+            .children()
+            .iter()
+            .find(|node| node.has_str("compatible", "ns16550a"))
+            .map(|node| format!("{SOC_PATH}/{}", node.name()));
+
+        let chosen = tree.root.child_mut("chosen");
+        if let Some(stdout) = stdout {
+            chosen.set_str("stdout-path", &stdout);
+        }
+        if let Some(bootargs) = &config.bootargs {
+            chosen.set_str("bootargs", bootargs);
+        }
+        if let Some((start, end)) = config.initrd {
+            chosen.set_u32s(
+                "linux,initrd-start",
+                &[(start as u64 >> 32) as u32, start as u32],
+            );
+            chosen.set_u32s("linux,initrd-end", &[(end as u64 >> 32) as u32, end as u32]);
+        }
+
+        tree
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn root_mut(&mut self) -> &mut Node {
+        &mut self.root
+    }
+
+    /// The bus devices are added to.
+    pub fn soc(&mut self) -> &mut Node {
+        self.root.child_mut(&SOC_PATH[1..])
+    }
+
+    /// The phandles of each hart's interrupt controller, for interrupts-extended properties.
+    pub fn hart_interrupt_controllers(&self) -> &[u32] {
+        &self.harts
+    }
+
+    /// Route the interrupt of the device at base to interrupt on the controller with phandle
+    /// parent. This is applied once every device has added its node.
+    pub fn connect_interrupt(&mut self, base: usize, parent: u32, interrupt: u32) {
+        self.interrupts.push((base, parent, interrupt));
+    }
+
+    /// Allocate a phandle for a node that other nodes refer to.
+    pub fn phandle(&mut self) -> u32 {
+        let phandle = self.next_phandle;
+        self.next_phandle += 1;
+        phandle
+    }
+
+    /// Flatten the tree into a DTB.
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        flatten(&self.root, &mut structure, &mut strings);
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let off_dt_struct = HEADER_SIZE + RESERVATION_SIZE;
+        let off_dt_strings = off_dt_struct + structure.len();
+        let total_size = off_dt_strings + strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // The boot hart
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+
+        let mut dtb: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        dtb.extend_from_slice(&[0; RESERVATION_SIZE]);
+        dtb.extend_from_slice(&structure);
+        dtb.extend_from_slice(&strings);
+        dtb
+    }
+}
+
+/// Pad the structure block to the next 4 byte boundary.
+fn align(structure: &mut Vec<u8>) {
+    structure.resize(structure.len().next_multiple_of(4), 0);
+}
+
+/// The offset of name in the strings block, adding it if it isn't there yet.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for string in strings.split(|&byte| byte == 0) {
+        if string == name.as_bytes() && offset < strings.len() {
+            return offset as u32;
+        }
+        offset += string.len() + 1;
+    }
+
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset as u32
+}
+
+fn flatten(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    align(structure);
+
+    for (name, value) in &node.properties {
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        structure.extend_from_slice(&string_offset(strings, name).to_be_bytes());
+        structure.extend_from_slice(value);
+        align(structure);
+    }
+
+    for child in &node.children {
+        flatten(child, structure, strings);
+    }
+
+    structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferedInput;
+    use crate::devices::uart::{Uart, DEFAULT_UART_BASE, UART_SIZE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn word(dtb: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(dtb[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn flatten_tree() {
+        let mut tree = DeviceTree::new();
+        tree.root_mut().child_mut("a").set_u32("x", 7);
+        tree.root_mut().child_mut("b").set_u32("x", 8);
+        let dtb = tree.to_dtb();
+
+        assert_eq!(word(&dtb, 0), FDT_MAGIC);
+        assert_eq!(word(&dtb, 4) as usize, dtb.len());
+        assert_eq!(word(&dtb, 20), FDT_VERSION);
+
+        // Property names are only stored once
+        let strings = &dtb[word(&dtb, 12) as usize..];
+        assert_eq!(strings.len(), word(&dtb, 32) as usize);
+        let names: Vec<_> = strings.split(|&byte| byte == 0).collect();
+        assert_eq!(names.iter().filter(|&&name| name == b"x").count(), 1);
+
+        // The structure block ends with FDT_END_NODE for the root and then FDT_END
+        let end = word(&dtb, 8) as usize + word(&dtb, 36) as usize;
+        assert_eq!(word(&dtb, end - 8), FDT_END_NODE);
+        assert_eq!(word(&dtb, end - 4), FDT_END);
+    }
+
+    #[test]
+    fn properties() {
+        let mut node = Node::new("test");
+        node.set_strs("compatible", &["a,b", "c"]);
+        node.set_reg(0x1_0000_2000, 0x1000);
+        assert_eq!(node.get("compatible"), Some(b"a,b\0c\0".as_slice()));
+        assert!(node.has_str("compatible", "c"));
+        assert!(!node.has_str("compatible", "a"));
+        assert_eq!(
+            node.get("reg"),
+            Some([0, 0, 0, 1, 0, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0x10, 0].as_slice())
+        );
+
+        node.set_u32("compatible", 1);
+        assert_eq!(node.get("compatible"), Some([0, 0, 0, 1].as_slice()));
+    }
+
+    #[test]
+    fn machine() {
+        let mut memory = Memory::new(0x10000);
+        let uart = Uart::new(Box::new(BufferedInput::new(&[])), Box::new(std::io::sink()));
+        memory
+            .attach(DEFAULT_UART_BASE, UART_SIZE, Rc::new(RefCell::new(uart)))
+            .unwrap();
+
+        let config = MachineConfig {
+            isa: "rv32i".to_string(),
+            harts: 1,
+            bootargs: Some("console=ttyS0".to_string()),
+            initrd: None,
+        };
+        let tree = DeviceTree::for_machine(&memory, &config);
+        let root = tree.root();
+
+        let cpu = root.child("cpus").unwrap().child("cpu@0").unwrap();
+        assert_eq!(cpu.get("riscv,isa"), Some(b"rv32i\0".as_slice()));
+
+        let ram = root.child("memory@0").unwrap();
+        assert_eq!(ram.get("reg").unwrap()[12..], [0, 1, 0, 0]);
+
+        assert!(root
+            .child("soc")
+            .unwrap()
+            .child("serial@10000000")
+            .is_some());
+        let chosen = root.child("chosen").unwrap();
+        assert_eq!(
+            chosen.get("stdout-path"),
+            Some(b"/soc/serial@10000000\0".as_slice())
+        );
+    }
+}
@@ -0,0 +1,185 @@
+/**
+ * The SiFive core-local interruptor (CLINT) for a single hart. It provides the machine timer
+ * (mtime and mtimecmp) and the machine software interrupt (msip):
+ * 0x0000 msip
+ * 0x4000 mtimecmp
+ * 0xBFF8 mtime
+ * mtime counts at the same rate as the time CSR, see TIMEBASE_FREQUENCY.
+ *
+ * The CLINT drives the hart's timer and software interrupt lines directly rather than going
+ * through the PLIC, so they are exposed through timer_interrupt and software_interrupt. The hart
+ * doesn't take interrupts yet, so nothing reads them and guests must poll mtime (or msip)
+ * instead of waiting for an interrupt.
+ */
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::Memory;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of bytes of address space the CLINT occupies.
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+/// The address QEMU's virt machine places its CLINT at.
+pub const DEFAULT_CLINT_BASE: usize = 0x200_0000;
+
+/// The machine software and machine timer interrupt numbers in mip.
+const MACHINE_SOFTWARE_INTERRUPT: u32 = 3;
+const MACHINE_TIMER_INTERRUPT: u32 = 7;
+
+mod reg {
+    pub const MSIP: usize = 0x0000;
+    pub const MTIMECMP_LOW: usize = 0x4000;
+    pub const MTIMECMP_HIGH: usize = 0x4004;
+    pub const MTIME_LOW: usize = 0xBFF8;
+    pub const MTIME_HIGH: usize = 0xBFFC;
+}
+
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    /// Added to the host clock when the guest has set mtime.
+    offset: u64,
+    timer_pending: bool,
+}
+
+/// The host clock in milliseconds, the same source as the time CSR.
+fn host_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: false,
+            mtimecmp: u64::MAX,
+            offset: 0,
+            timer_pending: false,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        host_time().wrapping_add(self.offset)
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.offset = mtime.wrapping_sub(host_time());
+    }
+
+    /// The level of the machine timer interrupt (MTIP).
+    pub fn timer_interrupt(&self) -> bool {
+        self.timer_pending
+    }
+
+    /// The level of the machine software interrupt (MSIP).
+    pub fn software_interrupt(&self) -> bool {
+        self.msip
+    }
+
+    fn update_timer(&mut self) {
+        self.timer_pending = self.mtime() >= self.mtimecmp;
+    }
+}
+
+fn set_low(target: u64, value: u32) -> u64 {
+    (target & !0xFFFF_FFFF) | value as u64
+}
+
+fn set_high(target: u64, value: u32) -> u64 {
+    (target & 0xFFFF_FFFF) | ((value as u64) << 32)
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: usize, _width: usize) -> u32 {
+        match offset {
+            reg::MSIP => self.msip as u32,
+            reg::MTIMECMP_LOW => self.mtimecmp as u32,
+            reg::MTIMECMP_HIGH => (self.mtimecmp >> 32) as u32,
+            reg::MTIME_LOW => self.mtime() as u32,
+            reg::MTIME_HIGH => (self.mtime() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u32) {
+        match offset {
+            reg::MSIP => self.msip = value & 1 != 0,
+            reg::MTIMECMP_LOW => self.mtimecmp = set_low(self.mtimecmp, value),
+            reg::MTIMECMP_HIGH => self.mtimecmp = set_high(self.mtimecmp, value),
+            reg::MTIME_LOW => self.set_mtime(set_low(self.mtime(), value)),
+            reg::MTIME_HIGH => self.set_mtime(set_high(self.mtime(), value)),
+            _ => (),
+        }
+        self.update_timer();
+    }
+
+    fn tick(&mut self, _memory: &mut Memory) {
+        self.update_timer();
+    }
+
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let interrupts: Vec<u32> = tree
+            .hart_interrupt_controllers()
+            .iter()
+            .flat_map(|&hart| {
+                [
+                    hart,
+                    MACHINE_SOFTWARE_INTERRUPT,
+                    hart,
+                    MACHINE_TIMER_INTERRUPT,
+                ]
+            })
+            .collect();
+
+        let mut node = Node::new(&format!("clint@{base:x}"));
+        node.set_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+        node.set_reg(base, size);
+        node.set_u32s("interrupts-extended", &interrupts);
+        tree.soc().add(node);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer() {
+        let mut memory = Memory::new(0);
+        let mut clint = Clint::new();
+        clint.tick(&mut memory);
+        assert!(!clint.timer_interrupt());
+
+        // Setting mtime moves the clock, which keeps counting from there
+        clint.write(reg::MTIME_HIGH, 4, 0);
+        clint.write(reg::MTIME_LOW, 4, 1000);
+        let mtime = clint.read(reg::MTIME_LOW, 4);
+        assert!((1000..1100).contains(&mtime));
+        assert_eq!(clint.read(reg::MTIME_HIGH, 4), 0);
+
+        clint.write(reg::MTIMECMP_HIGH, 4, 0);
+        clint.write(reg::MTIMECMP_LOW, 4, 100_000);
+        assert!(!clint.timer_interrupt());
+        clint.write(reg::MTIMECMP_LOW, 4, 500);
+        assert!(clint.timer_interrupt());
+        assert_eq!(clint.read(reg::MTIMECMP_LOW, 4), 500);
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut clint = Clint::new();
+        assert!(!clint.software_interrupt());
+        clint.write(reg::MSIP, 4, 1);
+        assert!(clint.software_interrupt());
+        assert_eq!(clint.read(reg::MSIP, 4), 1);
+        clint.write(reg::MSIP, 4, 0);
+        assert!(!clint.software_interrupt());
+    }
+}
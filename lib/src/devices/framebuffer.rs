@@ -0,0 +1,413 @@
+/**
+ * A headless linear framebuffer. There is no window, instead the contents can be saved as a PNG
+ * or PPM image when the guest asks for it, every N instructions, or by the host (e.g, on exit).
+ *
+ * The first page holds the registers:
+ * 0x00 width (read only)
+ * 0x04 height (read only)
+ * 0x08 pixel format (read only, 0: XRGB8888, 1: RGB565)
+ * 0x0C stride in bytes (read only)
+ * 0x10 snapshot (write only, any write saves an image)
+ * and the pixels follow from PIXELS_OFFSET, one row after the other.
+ */
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::Memory;
+use std::io::Write;
+
+/// The address our machine places the framebuffer at.
+pub const DEFAULT_FRAMEBUFFER_BASE: usize = 0x2800_0000;
+
+/// The offset of the first pixel from the framebuffer's base address.
+pub const PIXELS_OFFSET: usize = 0x1000;
+
+mod reg {
+    pub const WIDTH: usize = 0x00;
+    pub const HEIGHT: usize = 0x04;
+    pub const FORMAT: usize = 0x08;
+    pub const STRIDE: usize = 0x0C;
+    pub const SNAPSHOT: usize = 0x10;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    /// 32 bits per pixel, 0x00RRGGBB in a little endian word
+    Xrgb8888,
+    /// 16 bits per pixel, 5 bits red, 6 bits green, 5 bits blue from the top
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    /// Convert the pixel at the start of bytes to 8 bit red, green and blue.
+    fn to_rgb(self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Xrgb8888 => [bytes[2], bytes[1], bytes[0]],
+            PixelFormat::Rgb565 => {
+                let pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let (r, g, b) = (pixel >> 11, (pixel >> 5) & 0x3F, pixel & 0x1F);
+                [
+                    ((r << 3) | (r >> 2)) as u8,
+                    ((g << 2) | (g >> 4)) as u8,
+                    ((b << 3) | (b >> 2)) as u8,
+                ]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// Where and how often snapshots are saved. Each snapshot goes to `<prefix>-<name>.<extension>`
+/// where name is the number of instructions executed so far, or the name given to
+/// save_snapshot.
+struct Snapshots {
+    prefix: String,
+    format: ImageFormat,
+    every: Option<u64>,
+}
+
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+    snapshots: Option<Snapshots>,
+    instructions: u64,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            pixels: vec![0; width * height * format.bytes_per_pixel()],
+            snapshots: None,
+            instructions: 0,
+        }
+    }
+
+    /// The number of bytes of address space the framebuffer occupies, rounded up to a page.
+    pub fn size(&self) -> usize {
+        (PIXELS_OFFSET + self.pixels.len()).next_multiple_of(0x1000)
+    }
+
+    fn stride(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Save snapshots to files starting with prefix when the guest writes the snapshot
+    /// register, and every `every` instructions if given.
+    pub fn set_snapshots(&mut self, prefix: &str, format: ImageFormat, every: Option<u64>) {
+        self.snapshots = Some(Snapshots {
+            prefix: prefix.to_string(),
+            format,
+            every,
+        });
+    }
+
+    /// The framebuffer contents as 8 bit RGB triples, row by row.
+    pub fn rgb(&self) -> Vec<u8> {
+        self.pixels
+            .chunks(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.to_rgb(pixel))
+            .collect()
+    }
+
+    /// Write the framebuffer as a binary PPM (P6) image.
+    pub fn write_ppm(&self, output: &mut dyn Write) -> std::io::Result<()> {
+        write!(output, "P6\n{} {}\n255\n", self.width, self.height)?;
+        output.write_all(&self.rgb())
+    }
+
+    /// Write the framebuffer as an 8 bit RGB PNG image.
+    pub fn write_png(&self, output: &mut dyn Write) -> std::io::Result<()> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, deflate, adaptive filtering, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        // Every row starts with its filter type, we always use 0 (none)
+        let rgb = self.rgb();
+        let mut image = Vec::with_capacity(rgb.len() + self.height);
+        for row in rgb.chunks(self.width * 3) {
+            image.push(0);
+            image.extend_from_slice(row);
+        }
+
+        output.write_all(PNG_SIGNATURE)?;
+        write_chunk(output, b"IHDR", &header)?;
+        write_chunk(output, b"IDAT", &zlib_stored(&image))?;
+        write_chunk(output, b"IEND", &[])
+    }
+
+    /// Save the framebuffer to path, as a PNG if it ends in .png and as a PPM otherwise.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        match path.ends_with(".png") {
+            true => self.write_png(&mut file)?,
+            false => self.write_ppm(&mut file)?,
+        }
+        file.flush()
+    }
+
+    /// Save a snapshot called name, if snapshots are enabled.
+    pub fn save_snapshot(&self, name: &str) {
+        let Some(snapshots) = &self.snapshots else {
+            return;
+        };
+
+        let path = format!(
+            "{}-{name}.{}",
+            snapshots.prefix,
+            snapshots.format.extension()
+        );
+        if let Err(err) = self.save(&path) {
+            eprintln!("Failed to save framebuffer snapshot {path}: {err}");
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: usize, width: usize) -> u32 {
+        match offset {
+            reg::WIDTH => self.width as u32,
+            reg::HEIGHT => self.height as u32,
+            reg::FORMAT => match self.format {
+                PixelFormat::Xrgb8888 => 0,
+                PixelFormat::Rgb565 => 1,
+            },
+            reg::STRIDE => self.stride() as u32,
+            PIXELS_OFFSET.. => {
+                let mut value = [0; 4];
+                for (i, byte) in value[..width].iter_mut().enumerate() {
+                    *byte = self
+                        .pixels
+                        .get(offset - PIXELS_OFFSET + i)
+                        .copied()
+                        .unwrap_or(0);
+                }
+                u32::from_le_bytes(value)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, width: usize, value: u32) {
+        match offset {
+            reg::SNAPSHOT => self.save_snapshot(&format!("{:010}", self.instructions)),
+            PIXELS_OFFSET.. => {
+                for (i, &byte) in value.to_le_bytes()[..width].iter().enumerate() {
+                    if let Some(pixel) = self.pixels.get_mut(offset - PIXELS_OFFSET + i) {
+                        *pixel = byte;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, _memory: &mut Memory) {
+        self.instructions += 1;
+
+        let every = self
+            .snapshots
+            .as_ref()
+            .and_then(|snapshots| snapshots.every);
+        if every.is_some_and(|every| self.instructions.is_multiple_of(every)) {
+            self.save_snapshot(&format!("{:010}", self.instructions));
+        }
+    }
+
+    /// We describe the pixels as a simple-framebuffer, which firmware and Linux can draw to
+    /// without a driver for our registers.
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, _size: usize) {
+        let pixels = base + PIXELS_OFFSET;
+        let mut node = Node::new(&format!("framebuffer@{pixels:x}"));
+        node.set_str("compatible", "simple-framebuffer");
+        node.set_reg(pixels, self.pixels.len());
+        node.set_u32("width", self.width as u32);
+        node.set_u32("height", self.height as u32);
+        node.set_u32("stride", self.stride() as u32);
+        node.set_str(
+            "format",
+            match self.format {
+                PixelFormat::Xrgb8888 => "x8r8g8b8",
+                PixelFormat::Rgb565 => "r5g6b5",
+            },
+        );
+        tree.soc().add(node);
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = match c & 1 {
+                1 => 0xEDB8_8320 ^ (c >> 1),
+                _ => c >> 1,
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// The CRC-32 used by PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc: u32, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn write_chunk(output: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    let crc = crc32(&[kind.as_slice(), data].concat());
+    output.write_all(&crc.to_be_bytes())
+}
+
+/// Wrap data in a zlib stream made of uncompressed deflate blocks. The files are bigger than
+/// they need to be but we don't have to carry a compressor around.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xFFFF;
+
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK).peekable();
+
+    // Even an empty image needs one (final) block
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        stream.push(last as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut framebuffer = Framebuffer::new(320, 200, PixelFormat::Rgb565);
+        assert_eq!(framebuffer.read(reg::WIDTH, 4), 320);
+        assert_eq!(framebuffer.read(reg::HEIGHT, 4), 200);
+        assert_eq!(framebuffer.read(reg::FORMAT, 4), 1);
+        assert_eq!(framebuffer.read(reg::STRIDE, 4), 640);
+        assert_eq!(framebuffer.size(), 0x21000);
+    }
+
+    #[test]
+    fn pixels() {
+        let mut framebuffer = Framebuffer::new(2, 1, PixelFormat::Xrgb8888);
+        framebuffer.write(PIXELS_OFFSET, 4, 0x00FF_8001);
+        framebuffer.write(PIXELS_OFFSET + 4, 2, 0x1234);
+        assert_eq!(framebuffer.read(PIXELS_OFFSET + 1, 1), 0x80);
+        assert_eq!(framebuffer.rgb(), [0xFF, 0x80, 0x01, 0x00, 0x12, 0x34]);
+
+        // Writes past the last pixel are dropped
+        framebuffer.write(PIXELS_OFFSET + 8, 4, 0xFFFF_FFFF);
+        assert_eq!(framebuffer.read(PIXELS_OFFSET + 8, 4), 0);
+
+        let mut framebuffer = Framebuffer::new(2, 1, PixelFormat::Rgb565);
+        framebuffer.write(PIXELS_OFFSET, 4, 0x07E0_F800);
+        assert_eq!(framebuffer.rgb(), [0xFF, 0, 0, 0, 0xFF, 0]);
+    }
+
+    #[test]
+    fn ppm() {
+        let mut framebuffer = Framebuffer::new(1, 2, PixelFormat::Xrgb8888);
+        framebuffer.write(PIXELS_OFFSET + 4, 4, 0x0010_2030);
+        let mut output = Vec::new();
+        framebuffer.write_ppm(&mut output).unwrap();
+        assert_eq!(output, b"P6\n1 2\n255\n\0\0\0\x10\x20\x30");
+    }
+
+    #[test]
+    fn png() {
+        let framebuffer = Framebuffer::new(3, 2, PixelFormat::Xrgb8888);
+        let mut output = Vec::new();
+        framebuffer.write_png(&mut output).unwrap();
+
+        assert_eq!(&output[..8], PNG_SIGNATURE);
+        assert_eq!(&output[12..16], b"IHDR");
+        assert_eq!(&output[16..20], 3u32.to_be_bytes());
+        assert_eq!(&output[20..24], 2u32.to_be_bytes());
+        // The IEND chunk always ends with the same CRC
+        assert_eq!(&output[output.len() - 8..], b"IEND\xAE\x42\x60\x82");
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        // Two rows of a filter byte and three black pixels, stored in a single block
+        let stream = zlib_stored(&[0; 20]);
+        assert_eq!(&stream[..7], [0x78, 0x01, 1, 20, 0, !20, 0xFF]);
+        assert_eq!(&stream[stream.len() - 4..], [0, 0x14, 0, 1]);
+    }
+
+    #[test]
+    fn periodic_snapshots() {
+        let prefix = std::env::temp_dir()
+            .join(format!("framebuffer_{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let mut memory = Memory::new(0);
+        let mut framebuffer = Framebuffer::new(4, 4, PixelFormat::Xrgb8888);
+        framebuffer.set_snapshots(&prefix, ImageFormat::Ppm, Some(3));
+
+        for _ in 0..7 {
+            framebuffer.tick(&mut memory);
+        }
+        framebuffer.write(reg::SNAPSHOT, 4, 1);
+
+        for name in ["0000000003", "0000000006", "0000000007"] {
+            let path = format!("{prefix}-{name}.ppm");
+            assert_eq!(std::fs::read(&path).unwrap().len(), 11 + 4 * 4 * 3);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
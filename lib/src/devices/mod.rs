@@ -0,0 +1,50 @@
+pub mod clint;
+pub mod framebuffer;
+pub mod plic;
+pub mod rtc;
+pub mod test_finisher;
+pub mod uart;
+pub mod virtio;
+
+use crate::device_tree::DeviceTree;
+use crate::memory::Memory;
+
+/// A request made by the guest, through a device, to stop or restart the machine.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PowerRequest {
+    Pass,
+    Fail(u16),
+    Reset,
+}
+
+/// A memory mapped device. Devices are attached to Memory at a base address and every access
+/// that falls inside their range is forwarded to them with the offset from that base, rather than
+/// going to RAM.
+pub trait Device {
+    /// Read `width` bytes (1, 2 or 4) at `offset`, zero extended to a u32.
+    fn read(&mut self, offset: usize, width: usize) -> u32;
+
+    /// Write the lowest `width` bytes (1, 2 or 4) of `value` to `offset`.
+    fn write(&mut self, offset: usize, width: usize, value: u32);
+
+    /// Called once per executed instruction so devices can make progress without being accessed
+    /// (e.g, to pull in new input). Devices that do DMA use the memory they are given here, which
+    /// is the whole address space except for the device's own registers.
+    fn tick(&mut self, _memory: &mut Memory) {}
+
+    /// The level of the device's interrupt line. An interrupt controller samples this to decide
+    /// whether to raise an external interrupt. The hart doesn't take interrupts yet, so guests
+    /// can only observe this by polling the device or the controller.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    /// Set once the guest has asked this device to power off or reset the machine.
+    fn power_request(&self) -> Option<PowerRequest> {
+        None
+    }
+
+    /// Add the nodes describing this device, mapped at [base, base + size), to a device tree.
+    /// Devices normally add a single node to tree.soc().
+    fn device_tree(&self, _tree: &mut DeviceTree, _base: usize, _size: usize) {}
+}
@@ -0,0 +1,333 @@
+/**
+ * A SiFive compatible platform-level interrupt controller (PLIC) for a single hart with a
+ * machine mode (context 0) and supervisor mode (context 1) context:
+ * 0x000000 + 4 * source: priority
+ * 0x001000: pending bits
+ * 0x002000 + 0x80 * context: enable bits
+ * 0x200000 + 0x1000 * context: priority threshold
+ * 0x200004 + 0x1000 * context: claim/complete
+ *
+ * Devices are connected as sources and their interrupt_pending line is sampled every tick. Like
+ * the real gateway a source is level triggered and can't become pending again until the
+ * interrupt it raised has been completed. The hart doesn't take interrupts yet, so the
+ * contexts' external interrupt lines go nowhere and guests must poll the pending bits or claim
+ * register instead.
+ */
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::Memory;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The number of bytes of address space the PLIC occupies.
+pub const PLIC_SIZE: usize = 0x60_0000;
+
+/// The address QEMU's virt machine places its PLIC at.
+pub const DEFAULT_PLIC_BASE: usize = 0xC00_0000;
+
+/// The number of interrupt sources, numbered from 1 as source 0 means no interrupt.
+pub const PLIC_SOURCES: usize = 95;
+
+/// The machine and supervisor contexts of our single hart.
+pub const PLIC_CONTEXTS: usize = 2;
+
+/// The machine and supervisor external interrupt numbers in mip.
+const MACHINE_EXTERNAL_INTERRUPT: u32 = 11;
+const SUPERVISOR_EXTERNAL_INTERRUPT: u32 = 9;
+
+const WORDS: usize = (PLIC_SOURCES + 1).div_ceil(32);
+
+mod reg {
+    pub const PRIORITY: usize = 0x00_0000;
+    pub const PENDING: usize = 0x00_1000;
+    pub const ENABLE: usize = 0x00_2000;
+    pub const ENABLE_STRIDE: usize = 0x80;
+    pub const CONTEXT: usize = 0x20_0000;
+    pub const CONTEXT_STRIDE: usize = 0x1000;
+    pub const THRESHOLD: usize = 0x0;
+    pub const CLAIM: usize = 0x4;
+}
+
+struct Source {
+    interrupt: u32,
+    base: usize,
+    device: Rc<RefCell<dyn Device>>,
+}
+
+pub struct Plic {
+    sources: Vec<Source>,
+    priority: [u32; PLIC_SOURCES + 1],
+    pending: [u32; WORDS],
+    /// Sources that have been claimed but not yet completed.
+    claimed: [u32; WORDS],
+    enable: [[u32; WORDS]; PLIC_CONTEXTS],
+    threshold: [u32; PLIC_CONTEXTS],
+}
+
+fn bit(bits: &[u32; WORDS], source: usize) -> bool {
+    bits[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32; WORDS], source: usize, value: bool) {
+    match value {
+        true => bits[source / 32] |= 1 << (source % 32),
+        false => bits[source / 32] &= !(1 << (source % 32)),
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            sources: Vec::new(),
+            priority: [0; PLIC_SOURCES + 1],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            enable: [[0; WORDS]; PLIC_CONTEXTS],
+            threshold: [0; PLIC_CONTEXTS],
+        }
+    }
+
+    /// Connect the interrupt line of the device mapped at base to source interrupt.
+    pub fn connect(&mut self, interrupt: u32, base: usize, device: Rc<RefCell<dyn Device>>) {
+        assert!(
+            (1..=PLIC_SOURCES as u32).contains(&interrupt),
+            "invalid PLIC source {interrupt}"
+        );
+        self.sources.push(Source {
+            interrupt,
+            base,
+            device,
+        });
+    }
+
+    /// The highest priority source that is pending and enabled for context and above its
+    /// threshold. Ties go to the lowest numbered source.
+    fn best(&self, context: usize) -> Option<usize> {
+        (1..=PLIC_SOURCES)
+            .filter(|&source| bit(&self.pending, source) && bit(&self.enable[context], source))
+            .filter(|&source| self.priority[source] > self.threshold[context])
+            .max_by_key(|&source| (self.priority[source], std::cmp::Reverse(source)))
+    }
+
+    /// The level of the external interrupt line for context (0: machine, 1: supervisor).
+    pub fn external_interrupt(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                set_bit(&mut self.pending, source, false);
+                set_bit(&mut self.claimed, source, true);
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, source: usize) {
+        if source <= PLIC_SOURCES {
+            set_bit(&mut self.claimed, source, false);
+        }
+    }
+
+    /// Split an offset in the context area into the context and the register within it.
+    fn context_register(offset: usize) -> Option<(usize, usize)> {
+        let context = (offset - reg::CONTEXT) / reg::CONTEXT_STRIDE;
+        let register = (offset - reg::CONTEXT) % reg::CONTEXT_STRIDE;
+        (context < PLIC_CONTEXTS).then_some((context, register))
+    }
+
+    /// Split an offset in the enable area into the context and the word of enable bits.
+    fn enable_word(offset: usize) -> Option<(usize, usize)> {
+        let context = (offset - reg::ENABLE) / reg::ENABLE_STRIDE;
+        let word = (offset - reg::ENABLE) % reg::ENABLE_STRIDE / 4;
+        (context < PLIC_CONTEXTS && word < WORDS).then_some((context, word))
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: usize, _width: usize) -> u32 {
+        match offset {
+            reg::PRIORITY..reg::PENDING => self
+                .priority
+                .get((offset - reg::PRIORITY) / 4)
+                .copied()
+                .unwrap_or(0),
+            reg::PENDING..reg::ENABLE => self
+                .pending
+                .get((offset - reg::PENDING) / 4)
+                .copied()
+                .unwrap_or(0),
+            reg::ENABLE..reg::CONTEXT => match Self::enable_word(offset) {
+                Some((context, word)) => self.enable[context][word],
+                None => 0,
+            },
+            reg::CONTEXT.. => match Self::context_register(offset) {
+                Some((context, reg::THRESHOLD)) => self.threshold[context],
+                Some((context, reg::CLAIM)) => self.claim(context),
+                _ => 0,
+            },
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u32) {
+        match offset {
+            reg::PRIORITY..reg::PENDING => {
+                // Source 0 doesn't exist so its priority is always 0
+                let source = (offset - reg::PRIORITY) / 4;
+                if (1..=PLIC_SOURCES).contains(&source) {
+                    self.priority[source] = value;
+                }
+            }
+            reg::ENABLE..reg::CONTEXT => {
+                if let Some((context, word)) = Self::enable_word(offset) {
+                    self.enable[context][word] = value;
+                    // Again, source 0 doesn't exist
+                    self.enable[context][0] &= !1;
+                }
+            }
+            reg::CONTEXT.. => match Self::context_register(offset) {
+                Some((context, reg::THRESHOLD)) => self.threshold[context] = value,
+                Some((_, reg::CLAIM)) => self.complete(value as usize),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, _memory: &mut Memory) {
+        for source in &self.sources {
+            let interrupt = source.interrupt as usize;
+            if bit(&self.claimed, interrupt) {
+                continue;
+            }
+
+            // A source that is busy (e.g, it is ticking and we are inside its DMA) keeps its
+            // current level
+            if let Ok(device) = source.device.try_borrow() {
+                set_bit(&mut self.pending, interrupt, device.interrupt_pending());
+            }
+        }
+    }
+
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let phandle = tree.phandle();
+        let interrupts: Vec<u32> = tree
+            .hart_interrupt_controllers()
+            .iter()
+            .flat_map(|&hart| {
+                [
+                    hart,
+                    MACHINE_EXTERNAL_INTERRUPT,
+                    hart,
+                    SUPERVISOR_EXTERNAL_INTERRUPT,
+                ]
+            })
+            .collect();
+
+        let mut node = Node::new(&format!("plic@{base:x}"));
+        node.set_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        node.set_reg(base, size);
+        node.set_u32("#address-cells", 0);
+        node.set_u32("#interrupt-cells", 1);
+        node.set_empty("interrupt-controller");
+        node.set_u32("riscv,ndev", PLIC_SOURCES as u32);
+        node.set_u32s("interrupts-extended", &interrupts);
+        node.set_u32("phandle", phandle);
+        tree.soc().add(node);
+
+        for source in &self.sources {
+            tree.connect_interrupt(source.base, phandle, source.interrupt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A device whose interrupt line we can set directly.
+    struct Line(bool);
+
+    impl Device for Line {
+        fn read(&mut self, _offset: usize, _width: usize) -> u32 {
+            0
+        }
+
+        fn write(&mut self, _offset: usize, _width: usize, _value: u32) {}
+
+        fn interrupt_pending(&self) -> bool {
+            self.0
+        }
+    }
+
+    fn claim_register(context: usize) -> usize {
+        reg::CONTEXT + context * reg::CONTEXT_STRIDE + reg::CLAIM
+    }
+
+    #[test]
+    fn claim_and_complete() {
+        let mut memory = Memory::new(0);
+        let mut plic = Plic::new();
+        let uart = Rc::new(RefCell::new(Line(false)));
+        let disk = Rc::new(RefCell::new(Line(false)));
+        plic.connect(10, 0x1000_0000, uart.clone());
+        plic.connect(1, 0x1000_1000, disk.clone());
+
+        plic.write(reg::PRIORITY + 10 * 4, 4, 1);
+        plic.write(reg::PRIORITY + 4, 4, 1);
+        plic.write(reg::ENABLE + reg::ENABLE_STRIDE, 4, (1 << 10) | (1 << 1));
+
+        uart.borrow_mut().0 = true;
+        disk.borrow_mut().0 = true;
+        plic.tick(&mut memory);
+        assert_eq!(plic.read(reg::PENDING, 4), (1 << 10) | (1 << 1));
+
+        // Only the supervisor context has the sources enabled
+        assert!(!plic.external_interrupt(0));
+        assert!(plic.external_interrupt(1));
+        assert_eq!(plic.read(claim_register(0), 4), 0);
+
+        // Equal priorities go to the lowest source, then the other one
+        assert_eq!(plic.read(claim_register(1), 4), 1);
+        assert_eq!(plic.read(claim_register(1), 4), 10);
+        assert!(!plic.external_interrupt(1));
+
+        // A claimed source stays quiet until it is completed, even though its line is high
+        plic.tick(&mut memory);
+        assert!(!plic.external_interrupt(1));
+        plic.write(claim_register(1), 4, 10);
+        uart.borrow_mut().0 = false;
+        plic.write(claim_register(1), 4, 1);
+        plic.tick(&mut memory);
+        assert_eq!(plic.read(reg::PENDING, 4), 1 << 1);
+    }
+
+    #[test]
+    fn priority_and_threshold() {
+        let mut memory = Memory::new(0);
+        let mut plic = Plic::new();
+        let low = Rc::new(RefCell::new(Line(true)));
+        let high = Rc::new(RefCell::new(Line(true)));
+        plic.connect(3, 0x1000, low);
+        plic.connect(4, 0x2000, high);
+        plic.write(reg::PRIORITY + 3 * 4, 4, 2);
+        plic.write(reg::PRIORITY + 4 * 4, 4, 5);
+        plic.write(reg::ENABLE, 4, 0b11001);
+        assert_eq!(plic.read(reg::ENABLE, 4), 0b11000);
+        plic.tick(&mut memory);
+
+        plic.write(reg::CONTEXT + reg::THRESHOLD, 4, 5);
+        assert!(!plic.external_interrupt(0));
+        plic.write(reg::CONTEXT + reg::THRESHOLD, 4, 2);
+        assert_eq!(plic.read(claim_register(0), 4), 4);
+        assert_eq!(plic.read(claim_register(0), 4), 0);
+    }
+}
@@ -0,0 +1,236 @@
+/**
+ * A Goldfish real-time clock, as found on QEMU's virt machine. Time is counted in nanoseconds
+ * since the Unix epoch and comes either from the host clock or from a fixed epoch that advances
+ * by a set amount every instruction, so tests see the same time on every run.
+ *
+ * 0x00 time low (reading it latches time high)
+ * 0x04 time high
+ * 0x08 alarm low (writing it arms the alarm)
+ * 0x0C alarm high
+ * 0x10 interrupt enabled
+ * 0x14 clear alarm
+ * 0x18 alarm status (1 while the alarm is armed)
+ * 0x1C clear interrupt
+ *
+ * A firing alarm raises the interrupt line, but the hart doesn't take interrupts yet, so guests
+ * must poll the alarm status to see that it has fired.
+ */
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::Memory;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of bytes of address space the RTC occupies.
+pub const RTC_SIZE: usize = 0x1000;
+
+/// The address QEMU's virt machine places its RTC at.
+pub const DEFAULT_RTC_BASE: usize = 0x10_1000;
+
+/// How far a fixed clock advances every instruction, as if we ran at 100 MIPS.
+pub const FIXED_NS_PER_INSTRUCTION: u64 = 10;
+
+mod reg {
+    pub const TIME_LOW: usize = 0x00;
+    pub const TIME_HIGH: usize = 0x04;
+    pub const ALARM_LOW: usize = 0x08;
+    pub const ALARM_HIGH: usize = 0x0C;
+    pub const IRQ_ENABLED: usize = 0x10;
+    pub const CLEAR_ALARM: usize = 0x14;
+    pub const ALARM_STATUS: usize = 0x18;
+    pub const CLEAR_INTERRUPT: usize = 0x1C;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtcClock {
+    /// The host's wall-clock time
+    Host,
+    /// Starts at the given number of nanoseconds since the Unix epoch and advances by
+    /// FIXED_NS_PER_INSTRUCTION every instruction
+    Fixed(u64),
+}
+
+pub struct Rtc {
+    clock: RtcClock,
+    /// Time elapsed on a fixed clock.
+    elapsed: u64,
+    /// Added to the clock when the guest has set the time.
+    offset: u64,
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Self {
+        Self {
+            clock,
+            elapsed: 0,
+            offset: 0,
+            time_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn clock(&self) -> u64 {
+        match self.clock {
+            RtcClock::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+            RtcClock::Fixed(epoch) => epoch.wrapping_add(self.elapsed),
+        }
+    }
+
+    /// The guest visible time in nanoseconds since the Unix epoch.
+    pub fn now(&self) -> u64 {
+        self.clock().wrapping_add(self.offset)
+    }
+
+    fn set_time(&mut self, time: u64) {
+        self.offset = time.wrapping_sub(self.clock());
+    }
+
+    fn check_alarm(&mut self) {
+        if self.alarm.is_some_and(|alarm| self.now() >= alarm) {
+            self.alarm = None;
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn read(&mut self, offset: usize, _width: usize) -> u32 {
+        match offset {
+            reg::TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            reg::TIME_HIGH => self.time_high,
+            reg::ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            reg::ALARM_HIGH => (self.alarm.unwrap_or(0) >> 32) as u32,
+            reg::IRQ_ENABLED => self.irq_enabled as u32,
+            reg::ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u32) {
+        match offset {
+            // As with reads the high half goes first and the low half completes the write
+            reg::TIME_LOW => self.set_time(((self.time_high as u64) << 32) | value as u64),
+            reg::TIME_HIGH => self.time_high = value,
+            reg::ALARM_LOW => {
+                self.alarm = Some(((self.alarm_high as u64) << 32) | value as u64);
+                self.check_alarm();
+            }
+            reg::ALARM_HIGH => self.alarm_high = value,
+            reg::IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            reg::CLEAR_ALARM => self.alarm = None,
+            reg::CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, _memory: &mut Memory) {
+        if let RtcClock::Fixed(_) = self.clock {
+            self.elapsed += FIXED_NS_PER_INSTRUCTION;
+        }
+
+        if self.alarm.is_some() {
+            self.check_alarm();
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let mut node = Node::new(&format!("rtc@{base:x}"));
+        node.set_str("compatible", "google,goldfish-rtc");
+        node.set_reg(base, size);
+        tree.soc().add(node);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EPOCH: u64 = 0x1234_5678_9ABC_DEF0;
+
+    fn read_time(rtc: &mut Rtc) -> u64 {
+        let low = rtc.read(reg::TIME_LOW, 4) as u64;
+        low | ((rtc.read(reg::TIME_HIGH, 4) as u64) << 32)
+    }
+
+    #[test]
+    fn fixed_clock() {
+        let mut memory = Memory::new(0);
+        let mut rtc = Rtc::new(RtcClock::Fixed(EPOCH));
+        assert_eq!(read_time(&mut rtc), EPOCH);
+        for _ in 0..5 {
+            rtc.tick(&mut memory);
+        }
+        assert_eq!(read_time(&mut rtc), EPOCH + 5 * FIXED_NS_PER_INSTRUCTION);
+
+        // The guest can set the time, which keeps advancing from there
+        rtc.write(reg::TIME_HIGH, 4, 0);
+        rtc.write(reg::TIME_LOW, 4, 1000);
+        assert_eq!(read_time(&mut rtc), 1000);
+        rtc.tick(&mut memory);
+        assert_eq!(read_time(&mut rtc), 1000 + FIXED_NS_PER_INSTRUCTION);
+    }
+
+    #[test]
+    fn host_clock() {
+        let mut rtc = Rtc::new(RtcClock::Host);
+        let host = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let time = read_time(&mut rtc);
+        assert!(time >= host && time - host < 1_000_000_000);
+    }
+
+    #[test]
+    fn alarm() {
+        let mut memory = Memory::new(0);
+        let mut rtc = Rtc::new(RtcClock::Fixed(EPOCH));
+        let alarm = EPOCH + 3 * FIXED_NS_PER_INSTRUCTION;
+        rtc.write(reg::IRQ_ENABLED, 4, 1);
+        rtc.write(reg::ALARM_HIGH, 4, (alarm >> 32) as u32);
+        rtc.write(reg::ALARM_LOW, 4, alarm as u32);
+        assert_eq!(rtc.read(reg::ALARM_STATUS, 4), 1);
+
+        for _ in 0..2 {
+            rtc.tick(&mut memory);
+            assert!(!rtc.interrupt_pending());
+        }
+        rtc.tick(&mut memory);
+        assert!(rtc.interrupt_pending());
+        assert_eq!(rtc.read(reg::ALARM_STATUS, 4), 0);
+
+        rtc.write(reg::CLEAR_INTERRUPT, 4, 1);
+        assert!(!rtc.interrupt_pending());
+
+        // An alarm in the past fires straight away, but only interrupts when enabled
+        rtc.write(reg::IRQ_ENABLED, 4, 0);
+        rtc.write(reg::ALARM_LOW, 4, 0);
+        assert_eq!(rtc.read(reg::ALARM_STATUS, 4), 0);
+        assert!(!rtc.interrupt_pending());
+        rtc.write(reg::IRQ_ENABLED, 4, 1);
+        assert!(rtc.interrupt_pending());
+
+        // A cleared alarm never fires
+        rtc.write(reg::CLEAR_INTERRUPT, 4, 1);
+        rtc.write(reg::ALARM_LOW, 4, u32::MAX);
+        rtc.write(reg::CLEAR_ALARM, 4, 1);
+        assert_eq!(rtc.read(reg::ALARM_STATUS, 4), 0);
+    }
+}
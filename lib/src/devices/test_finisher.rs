@@ -0,0 +1,158 @@
+/**
+ * A model of the SiFive test finisher that QEMU's virt machine exposes (also used as the
+ * syscon-poweroff and syscon-reboot device). Guests stop the machine by writing a single word:
+ * 0x5555: pass, power off with exit status 0
+ * 0x3333 | code << 16: fail with the given exit status
+ * 0x7777: reset
+ * Any other value is ignored.
+ */
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::{Device, PowerRequest};
+
+/// The number of bytes of address space the finisher occupies.
+pub const TEST_FINISHER_SIZE: usize = 0x1000;
+
+/// The address QEMU's virt machine places its test finisher at.
+pub const DEFAULT_TEST_FINISHER_BASE: usize = 0x10_0000;
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// The process exit status for a fail code. Only the low byte of a status is kept, so a code
+/// such as 0x100 fails with 1 rather than looking like a pass.
+pub fn exit_status(code: u16) -> u8 {
+    match code as u8 {
+        0 if code != 0 => 1,
+        status => status,
+    }
+}
+
+#[derive(Default)]
+pub struct TestFinisher {
+    request: Option<PowerRequest>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self { request: None }
+    }
+}
+
+impl Device for TestFinisher {
+    fn read(&mut self, _offset: usize, _width: usize) -> u32 {
+        0
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u32) {
+        if offset != 0 {
+            return;
+        }
+
+        let request = match value & 0xFFFF {
+            FINISHER_FAIL => PowerRequest::Fail((value >> 16) as u16),
+            FINISHER_PASS => PowerRequest::Pass,
+            FINISHER_RESET => PowerRequest::Reset,
+            _ => return,
+        };
+
+        // The first request wins, the machine stops before the guest could make another.
+        self.request.get_or_insert(request);
+    }
+
+    fn power_request(&self) -> Option<PowerRequest> {
+        self.request
+    }
+
+    /// Like QEMU we describe the finisher as a syscon with poweroff and reboot nodes that say
+    /// which values to write to it.
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let phandle = tree.phandle();
+
+        let mut node = Node::new(&format!("test@{base:x}"));
+        node.set_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        node.set_reg(base, size);
+        node.set_u32("phandle", phandle);
+        tree.soc().add(node);
+
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            let mut node = Node::new(name);
+            node.set_str("compatible", &format!("syscon-{name}"));
+            node.set_u32("regmap", phandle);
+            node.set_u32("offset", 0);
+            node.set_u32("value", value);
+            tree.root_mut().add(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::rv32i::{Cpu, StepState};
+    use crate::instruction::encoder;
+    use crate::memory::Memory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn finisher_values() {
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, 4, 0x1234);
+        assert_eq!(finisher.power_request(), None);
+        finisher.write(4, 4, FINISHER_PASS);
+        assert_eq!(finisher.power_request(), None);
+        finisher.write(0, 4, (42 << 16) | FINISHER_FAIL);
+        assert_eq!(finisher.power_request(), Some(PowerRequest::Fail(42)));
+
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, 4, FINISHER_PASS);
+        assert_eq!(finisher.power_request(), Some(PowerRequest::Pass));
+
+        let mut finisher = TestFinisher::new();
+        finisher.write(0, 4, FINISHER_RESET);
+        assert_eq!(finisher.power_request(), Some(PowerRequest::Reset));
+    }
+
+    #[test]
+    fn fail_exit_status() {
+        assert_eq!(exit_status(42), 42);
+        assert_eq!(exit_status(0x1ff), 0xff);
+        assert_eq!(exit_status(0x100), 1);
+        assert_eq!(exit_status(0), 0);
+    }
+
+    #[test]
+    fn finisher_stops_cpu() {
+        let mut memory = Memory::new(32);
+        let finisher = Rc::new(RefCell::new(TestFinisher::new()));
+        memory
+            .attach(DEFAULT_TEST_FINISHER_BASE, TEST_FINISHER_SIZE, finisher)
+            .unwrap();
+
+        // x1 = 0x100000, x2 = 7 << 16 | 0x3333, [x1] = x2
+        let program = [
+            encoder::lui(1, DEFAULT_TEST_FINISHER_BASE as u32),
+            encoder::lui(2, 0x7_3000),
+            encoder::addi(2, 2, 0x333),
+            encoder::sw(1, 2, 0),
+            encoder::no_op(),
+        ];
+
+        for (index, instruction) in program.iter().enumerate() {
+            memory.set32(index * 4, instruction.encode()).unwrap();
+        }
+
+        let mut cpu = Cpu::new();
+
+        for _ in 0..4 {
+            assert!(matches!(cpu.step(&mut memory), StepState::Continue));
+        }
+
+        assert!(matches!(
+            cpu.step(&mut memory),
+            StepState::Power(PowerRequest::Fail(7))
+        ));
+        assert_eq!(cpu.state.registers.pc, 16);
+    }
+}
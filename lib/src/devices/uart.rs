@@ -0,0 +1,450 @@
+/**
+ * A model of the NS16550A UART. Firmware and kernels expect a serial port here rather than an
+ * ecall. We model the register interface rather than the line itself, so transmitted bytes go
+ * straight to the output and the divisor latch only exists so that drivers can program it.
+ *
+ * The interrupts enabled in IER are reported through IIR and the interrupt line, but the hart
+ * doesn't take interrupts yet, so drivers must poll LSR (or IIR) rather than wait for one.
+ *
+ * Registers (offsets from the base address, DLAB is bit 7 of LCR):
+ * 0: RBR (read) / THR (write), or DLL when DLAB is set
+ * 1: IER, or DLM when DLAB is set
+ * 2: IIR (read) / FCR (write)
+ * 3: LCR
+ * 4: MCR
+ * 5: LSR
+ * 6: MSR
+ * 7: SCR
+ */
+use crate::console::ConsoleInput;
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::Memory;
+use std::collections::VecDeque;
+use std::io::Write;
+
+/// The number of bytes of address space the UART occupies.
+pub const UART_SIZE: usize = 0x100;
+
+/// The address QEMU's virt machine places its UART at.
+pub const DEFAULT_UART_BASE: usize = 0x1000_0000;
+
+/// The input clock we tell drivers we have, the usual 1.8432MHz crystal doubled, as on QEMU.
+const CLOCK_FREQUENCY: u32 = 3_686_400;
+
+const FIFO_SIZE: usize = 16;
+
+/// How many ticks the receive FIFO can sit below its trigger level before a character timeout
+/// interrupt is raised.
+const RX_TIMEOUT_TICKS: usize = 1024;
+
+mod reg {
+    pub const RBR_THR_DLL: usize = 0;
+    pub const IER_DLM: usize = 1;
+    pub const IIR_FCR: usize = 2;
+    pub const LCR: usize = 3;
+    pub const MCR: usize = 4;
+    pub const LSR: usize = 5;
+    pub const MSR: usize = 6;
+    pub const SCR: usize = 7;
+}
+
+mod ier {
+    pub const RX_AVAILABLE: u8 = 0b0001;
+    pub const THR_EMPTY: u8 = 0b0010;
+    pub const MASK: u8 = 0b1111;
+}
+
+mod iir {
+    pub const NO_INTERRUPT: u8 = 0b0001;
+    pub const THR_EMPTY: u8 = 0b0010;
+    pub const RX_AVAILABLE: u8 = 0b0100;
+    pub const RX_TIMEOUT: u8 = 0b1100;
+    pub const FIFOS_ENABLED: u8 = 0b1100_0000;
+}
+
+mod fcr {
+    pub const ENABLE_FIFO: u8 = 0b0001;
+    pub const CLEAR_RX: u8 = 0b0010;
+}
+
+mod lsr {
+    pub const DATA_READY: u8 = 0b0000_0001;
+    pub const THR_EMPTY: u8 = 0b0010_0000;
+    pub const TRANSMITTER_EMPTY: u8 = 0b0100_0000;
+}
+
+const LCR_DLAB: u8 = 0b1000_0000;
+const MCR_LOOPBACK: u8 = 0b0001_0000;
+
+/// With loopback off the modem lines report CTS, DSR and DCD so that drivers will transmit.
+const MSR_CONNECTED: u8 = 0b1011_0000;
+
+pub struct Uart {
+    input: Box<dyn ConsoleInput>,
+    output: Box<dyn Write>,
+    rx_fifo: VecDeque<u8>,
+    rx_idle_ticks: usize,
+    thr_empty_pending: bool,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+}
+
+impl Uart {
+    pub fn new(input: Box<dyn ConsoleInput>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            rx_idle_ticks: 0,
+            thr_empty_pending: false,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr & MCR_LOOPBACK != 0
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & fcr::ENABLE_FIFO != 0
+    }
+
+    /// The number of bytes that must be waiting before a received data interrupt is raised.
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() < self.rx_capacity() {
+            self.rx_fifo.push_back(byte);
+            self.rx_idle_ticks = 0;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.loopback() {
+            self.receive(byte);
+        } else {
+            self.output.write_all(&[byte]).unwrap();
+            self.output.flush().unwrap();
+        }
+
+        // Transmission is instant so the holding register is immediately empty again.
+        self.thr_empty_pending = true;
+    }
+
+    /// The highest priority pending interrupt as an IIR interrupt ID, if any.
+    fn pending_interrupt(&self) -> Option<u8> {
+        if self.ier & ier::RX_AVAILABLE != 0 && !self.rx_fifo.is_empty() {
+            if self.rx_fifo.len() >= self.rx_trigger_level() {
+                return Some(iir::RX_AVAILABLE);
+            } else if self.rx_idle_ticks >= RX_TIMEOUT_TICKS {
+                return Some(iir::RX_TIMEOUT);
+            }
+        }
+
+        if self.ier & ier::THR_EMPTY != 0 && self.thr_empty_pending {
+            return Some(iir::THR_EMPTY);
+        }
+
+        None
+    }
+
+    fn read_iir(&mut self) -> u8 {
+        let fifo_bits = if self.fifo_enabled() {
+            iir::FIFOS_ENABLED
+        } else {
+            0
+        };
+
+        match self.pending_interrupt() {
+            Some(id) => {
+                // Reading IIR while it reports THR empty acknowledges that interrupt.
+                if id == iir::THR_EMPTY {
+                    self.thr_empty_pending = false;
+                }
+                id | fifo_bits
+            }
+            None => iir::NO_INTERRUPT | fifo_bits,
+        }
+    }
+
+    fn read_lsr(&self) -> u8 {
+        let data_ready = if self.rx_fifo.is_empty() {
+            0
+        } else {
+            lsr::DATA_READY
+        };
+        data_ready | lsr::THR_EMPTY | lsr::TRANSMITTER_EMPTY
+    }
+
+    fn read_msr(&self) -> u8 {
+        if self.loopback() {
+            // In loopback the modem control outputs are wired back to the status inputs:
+            // DTR -> DSR, RTS -> CTS, OUT1 -> RI, OUT2 -> DCD.
+            let mcr = self.mcr;
+            ((mcr & 0b0001) << 5)
+                | ((mcr & 0b0010) << 3)
+                | ((mcr & 0b0100) << 4)
+                | ((mcr & 0b1000) << 4)
+        } else {
+            MSR_CONNECTED
+        }
+    }
+
+    fn read_register(&mut self, register: usize) -> u8 {
+        match register {
+            reg::RBR_THR_DLL if self.dlab() => self.dll,
+            reg::RBR_THR_DLL => {
+                self.rx_idle_ticks = 0;
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            reg::IER_DLM if self.dlab() => self.dlm,
+            reg::IER_DLM => self.ier,
+            reg::IIR_FCR => self.read_iir(),
+            reg::LCR => self.lcr,
+            reg::MCR => self.mcr,
+            reg::LSR => self.read_lsr(),
+            reg::MSR => self.read_msr(),
+            reg::SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u8) {
+        match register {
+            reg::RBR_THR_DLL if self.dlab() => self.dll = value,
+            reg::RBR_THR_DLL => self.transmit(value),
+            reg::IER_DLM if self.dlab() => self.dlm = value,
+            reg::IER_DLM => {
+                // Enabling the THR empty interrupt while the THR is empty raises it immediately.
+                if self.ier & ier::THR_EMPTY == 0 && value & ier::THR_EMPTY != 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & ier::MASK;
+            }
+            reg::IIR_FCR => {
+                if value & fcr::CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+                // The clear bits are self-clearing so we don't keep them.
+                self.fcr = value & !0b0110;
+            }
+            reg::LCR => self.lcr = value,
+            reg::MCR => self.mcr = value & 0b1_1111,
+            reg::SCR => self.scr = value,
+            _ => (),
+        }
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: usize, _width: usize) -> u32 {
+        // The UART only has byte wide registers so wider accesses read the addressed register.
+        self.read_register(offset) as u32
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: u32) {
+        self.write_register(offset, value as u8)
+    }
+
+    fn tick(&mut self, _memory: &mut Memory) {
+        if !self.loopback() && self.rx_fifo.len() < self.rx_capacity() {
+            if let Some(byte) = self.input.read_nonblocking() {
+                self.receive(byte);
+                return;
+            }
+        }
+
+        if !self.rx_fifo.is_empty() {
+            self.rx_idle_ticks = self.rx_idle_ticks.saturating_add(1);
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.pending_interrupt().is_some()
+    }
+
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let mut node = Node::new(&format!("serial@{base:x}"));
+        node.set_str("compatible", "ns16550a");
+        node.set_reg(base, size);
+        node.set_u32("clock-frequency", CLOCK_FREQUENCY);
+        tree.soc().add(node);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferedInput;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A Write that can be inspected after it has been handed to the UART.
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(input: &[u8]) -> (Uart, SharedOutput) {
+        let output = SharedOutput(Rc::new(RefCell::new(Vec::new())));
+        let uart = Uart::new(
+            Box::new(BufferedInput::new(input)),
+            Box::new(output.clone()),
+        );
+        (uart, output)
+    }
+
+    #[test]
+    fn transmit() {
+        let (mut uart, output) = setup(b"");
+        for &byte in b"hi" {
+            uart.write(reg::RBR_THR_DLL, 1, byte as u32);
+        }
+        assert_eq!(*output.0.borrow(), b"hi");
+        assert_eq!(
+            uart.read(reg::LSR, 1) as u8,
+            lsr::THR_EMPTY | lsr::TRANSMITTER_EMPTY
+        );
+    }
+
+    #[test]
+    fn receive() {
+        let (mut uart, _output) = setup(b"ab");
+        let mut memory = Memory::new(0);
+        uart.write(reg::IIR_FCR, 1, fcr::ENABLE_FIFO as u32);
+        assert_eq!(uart.read(reg::LSR, 1) as u8 & lsr::DATA_READY, 0);
+
+        uart.tick(&mut memory);
+        uart.tick(&mut memory);
+        assert_eq!(
+            uart.read(reg::LSR, 1) as u8 & lsr::DATA_READY,
+            lsr::DATA_READY
+        );
+        assert_eq!(uart.read(reg::RBR_THR_DLL, 1), b'a' as u32);
+        assert_eq!(uart.read(reg::RBR_THR_DLL, 1), b'b' as u32);
+        assert_eq!(uart.read(reg::LSR, 1) as u8 & lsr::DATA_READY, 0);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (mut uart, output) = setup(b"");
+        uart.write(reg::IER_DLM, 1, 0b11);
+        uart.write(reg::LCR, 1, (LCR_DLAB | 0b11) as u32);
+        uart.write(reg::RBR_THR_DLL, 1, 0x12);
+        uart.write(reg::IER_DLM, 1, 0x34);
+        assert_eq!(uart.read(reg::RBR_THR_DLL, 1), 0x12);
+        assert_eq!(uart.read(reg::IER_DLM, 1), 0x34);
+
+        uart.write(reg::LCR, 1, 0b11);
+        assert_eq!(uart.read(reg::IER_DLM, 1), 0b11);
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn rx_interrupt() {
+        let (mut uart, _output) = setup(b"abcd");
+        let mut memory = Memory::new(0);
+        // FIFO enabled with a trigger level of 4 bytes
+        uart.write(reg::IIR_FCR, 1, (0b0100_0000 | fcr::ENABLE_FIFO) as u32);
+        uart.write(reg::IER_DLM, 1, ier::RX_AVAILABLE as u32);
+
+        for _ in 0..3 {
+            uart.tick(&mut memory);
+        }
+        assert!(!uart.interrupt_pending());
+        assert_eq!(
+            uart.read(reg::IIR_FCR, 1) as u8,
+            iir::NO_INTERRUPT | iir::FIFOS_ENABLED
+        );
+
+        uart.tick(&mut memory);
+        assert!(uart.interrupt_pending());
+        assert_eq!(
+            uart.read(reg::IIR_FCR, 1) as u8,
+            iir::RX_AVAILABLE | iir::FIFOS_ENABLED
+        );
+
+        // Draining below the trigger level times out after a while rather than clearing
+        uart.read(reg::RBR_THR_DLL, 1);
+        assert!(!uart.interrupt_pending());
+        for _ in 0..RX_TIMEOUT_TICKS {
+            uart.tick(&mut memory);
+        }
+        assert_eq!(
+            uart.read(reg::IIR_FCR, 1) as u8,
+            iir::RX_TIMEOUT | iir::FIFOS_ENABLED
+        );
+
+        for _ in 0..3 {
+            uart.read(reg::RBR_THR_DLL, 1);
+        }
+        assert!(!uart.interrupt_pending());
+    }
+
+    #[test]
+    fn thr_empty_interrupt() {
+        let (mut uart, _output) = setup(b"");
+        uart.write(reg::IER_DLM, 1, ier::THR_EMPTY as u32);
+        assert!(uart.interrupt_pending());
+        assert_eq!(uart.read(reg::IIR_FCR, 1) as u8, iir::THR_EMPTY);
+
+        // Reading IIR acknowledged the interrupt
+        assert!(!uart.interrupt_pending());
+
+        uart.write(reg::RBR_THR_DLL, 1, b'x' as u32);
+        assert!(uart.interrupt_pending());
+    }
+
+    #[test]
+    fn loopback() {
+        let (mut uart, output) = setup(b"");
+        uart.write(reg::MCR, 1, MCR_LOOPBACK as u32);
+        uart.write(reg::RBR_THR_DLL, 1, b'z' as u32);
+        assert!(output.0.borrow().is_empty());
+        assert_eq!(uart.read(reg::RBR_THR_DLL, 1), b'z' as u32);
+    }
+}
@@ -0,0 +1,407 @@
+/**
+ * A virtio block device (section 5.2 of the virtio 1.1 specification) backed by a host disk
+ * image. The image can be attached read-write, read-only, or with a copy-on-write overlay that
+ * keeps every write in memory so the image on disk is never modified.
+ */
+use crate::devices::virtio::queue::{DescriptorChain, Queue};
+use crate::devices::virtio::VirtioDevice;
+use crate::memory::{Memory, MemoryError};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const VIRTIO_BLK_DEVICE_ID: u32 = 2;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the request header (type, reserved, sector) at the start of every request.
+const HEADER_SIZE: usize = 16;
+
+/// The length of the string returned by a GET_ID request.
+const ID_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockMode {
+    /// Guest writes go to the image file
+    ReadWrite,
+    /// The device is advertised as read-only and writes fail
+    ReadOnly,
+    /// Guest writes are kept in memory and discarded when the emulator exits
+    CopyOnWrite,
+}
+
+pub struct Block {
+    image: File,
+    sectors: u64,
+    mode: BlockMode,
+    overlay: HashMap<u64, Box<[u8; SECTOR_SIZE]>>,
+}
+
+impl Block {
+    pub fn open(path: &str, mode: BlockMode) -> std::io::Result<Self> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == BlockMode::ReadWrite)
+            .open(path)?;
+        let sectors = image.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(Self {
+            image,
+            sectors,
+            mode,
+            overlay: HashMap::new(),
+        })
+    }
+
+    fn read_sector(&mut self, sector: u64, data: &mut [u8]) -> std::io::Result<()> {
+        if let Some(overlay) = self.overlay.get(&sector) {
+            data.copy_from_slice(overlay.as_slice());
+            return Ok(());
+        }
+
+        self.image
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.image.read_exact(data)
+    }
+
+    fn write_sector(&mut self, sector: u64, data: &[u8]) -> std::io::Result<()> {
+        match self.mode {
+            BlockMode::ReadWrite => {
+                self.image
+                    .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.image.write_all(data)
+            }
+            BlockMode::CopyOnWrite => {
+                let mut copy = Box::new([0; SECTOR_SIZE]);
+                copy.copy_from_slice(data);
+                self.overlay.insert(sector, copy);
+                Ok(())
+            }
+            BlockMode::ReadOnly => Err(std::io::ErrorKind::PermissionDenied.into()),
+        }
+    }
+
+    /// Check that count sectors starting at sector are inside the image.
+    fn in_range(&self, sector: u64, count: u64) -> bool {
+        sector
+            .checked_add(count)
+            .is_some_and(|end| end <= self.sectors)
+    }
+
+    fn read_request(&mut self, sector: u64, len: usize) -> (Vec<u8>, u8) {
+        // Checked before allocating space for the data, as the guest chooses the length
+        let count = len / SECTOR_SIZE;
+        if !self.in_range(sector, count as u64) {
+            return (Vec::new(), VIRTIO_BLK_S_IOERR);
+        }
+
+        let mut data = vec![0; count * SECTOR_SIZE];
+        for (i, chunk) in data.chunks_mut(SECTOR_SIZE).enumerate() {
+            if self.read_sector(sector + i as u64, chunk).is_err() {
+                return (Vec::new(), VIRTIO_BLK_S_IOERR);
+            }
+        }
+
+        (data, VIRTIO_BLK_S_OK)
+    }
+
+    fn write_request(&mut self, sector: u64, data: &[u8]) -> u8 {
+        if !data.len().is_multiple_of(SECTOR_SIZE)
+            || !self.in_range(sector, (data.len() / SECTOR_SIZE) as u64)
+        {
+            return VIRTIO_BLK_S_IOERR;
+        }
+
+        for (i, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+            if self.write_sector(sector + i as u64, chunk).is_err() {
+                return VIRTIO_BLK_S_IOERR;
+            }
+        }
+
+        VIRTIO_BLK_S_OK
+    }
+
+    /// Execute a single request, returning the number of bytes written back to the driver.
+    fn handle(&mut self, chain: &DescriptorChain, memory: &mut Memory) -> Result<u32, MemoryError> {
+        let request = chain.read_all(memory)?;
+
+        // Every request ends in a one byte status, so we need at least one writable byte.
+        let writable = chain.writable_len();
+        if request.len() < HEADER_SIZE || writable == 0 {
+            return Ok(0);
+        }
+
+        let request_type = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+        let payload = &request[HEADER_SIZE..];
+
+        // The data for reads goes in front of the status byte.
+        let (mut response, status) = match request_type {
+            VIRTIO_BLK_T_IN => self.read_request(sector, writable - 1),
+            VIRTIO_BLK_T_OUT => (Vec::new(), self.write_request(sector, payload)),
+            VIRTIO_BLK_T_FLUSH => match self.image.flush() {
+                Ok(()) => (Vec::new(), VIRTIO_BLK_S_OK),
+                Err(_) => (Vec::new(), VIRTIO_BLK_S_IOERR),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = b"riscv-emulator-blk".to_vec();
+                id.resize(ID_SIZE.min(writable - 1), 0);
+                (id, VIRTIO_BLK_S_OK)
+            }
+            _ => (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        };
+
+        // The status byte always goes in the last writable byte, even if the data was short. The
+        // gap isn't padded out in memory here, as the guest chooses how big it is.
+        response.truncate(writable - 1);
+        chain.write_all(memory, &response)?;
+        chain.write_at(memory, writable - 1, &[status])?;
+
+        Ok(writable as u32)
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_id(&self) -> u32 {
+        VIRTIO_BLK_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            BlockMode::ReadOnly => VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH,
+            BlockMode::ReadWrite | BlockMode::CopyOnWrite => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    /// The only configuration we expose is the capacity in sectors.
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0..=7 => self.sectors.to_le_bytes()[offset],
+            _ => 0,
+        }
+    }
+
+    fn notify(
+        &mut self,
+        _queue: usize,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError> {
+        let queue = &mut queues[0];
+        let mut used = false;
+
+        while let Some(chain) = queue.pop(memory)? {
+            let written = self.handle(&chain, memory)?;
+            queue.add_used(memory, chain.head, written)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::virtio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const BASE: usize = 0x10000;
+    const DESC: usize = 0x1000;
+    const AVAIL: usize = 0x2000;
+    const USED: usize = 0x3000;
+    const HEADER: usize = 0x4000;
+    const DATA: usize = 0x5000;
+    const STATUS: usize = 0x6000;
+
+    /// A disk image in the temp directory where every byte of sector n is n. The file is removed
+    /// when this is dropped.
+    struct TempImage(String);
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn image(name: &str, sectors: usize) -> TempImage {
+        let path = std::env::temp_dir().join(format!("virtio_blk_{}_{name}", std::process::id()));
+        let contents: Vec<u8> = (0..sectors * SECTOR_SIZE)
+            .map(|i| (i / SECTOR_SIZE) as u8)
+            .collect();
+        std::fs::write(&path, contents).unwrap();
+        TempImage(path.to_str().unwrap().to_string())
+    }
+
+    /// Attach a block device and bring it up the way a driver would with a single queue.
+    fn setup(path: &str, mode: BlockMode) -> Memory {
+        let mut memory = Memory::new(0x8000);
+        let device = VirtioMmio::new(Box::new(Block::open(path, mode).unwrap()));
+        memory
+            .attach(BASE, VIRTIO_MMIO_SIZE, Rc::new(RefCell::new(device)))
+            .unwrap();
+
+        assert_eq!(memory.get32(BASE), Ok(MAGIC));
+        assert_eq!(memory.get32(BASE + 0x8), Ok(VIRTIO_BLK_DEVICE_ID));
+        memory.set32(BASE + 0x70, 1 | 2).unwrap();
+        memory.set32(BASE + 0x24, 1).unwrap();
+        memory.set32(BASE + 0x20, 1).unwrap();
+        memory.set32(BASE + 0x70, 1 | 2 | 8).unwrap();
+        assert_eq!(memory.get32(BASE + 0x70), Ok(1 | 2 | 8));
+        memory.set32(BASE + 0x30, 0).unwrap();
+        memory.set32(BASE + 0x38, 8).unwrap();
+        memory.set32(BASE + 0x80, DESC as u32).unwrap();
+        memory.set32(BASE + 0x90, AVAIL as u32).unwrap();
+        memory.set32(BASE + 0xA0, USED as u32).unwrap();
+        memory.set32(BASE + 0x44, 1).unwrap();
+        memory.set32(BASE + 0x70, 1 | 2 | 8 | 4).unwrap();
+        memory
+    }
+
+    const MAGIC: u32 = 0x7472_6976;
+
+    fn set_descriptor(memory: &mut Memory, index: usize, addr: usize, len: u32, flags: u16) {
+        let descriptor = DESC + index * 16;
+        memory.set32(descriptor, addr as u32).unwrap();
+        memory.set32(descriptor + 4, 0).unwrap();
+        memory.set32(descriptor + 8, len).unwrap();
+        memory.set16(descriptor + 12, flags).unwrap();
+        memory.set16(descriptor + 14, index as u16 + 1).unwrap();
+    }
+
+    /// Submit a three descriptor request and run the device until it completes. Returns the
+    /// status byte.
+    fn request(memory: &mut Memory, request_type: u32, sector: u64) -> u8 {
+        sized_request(memory, request_type, sector, SECTOR_SIZE as u32)
+    }
+
+    /// As request, with a data buffer of len bytes.
+    fn sized_request(memory: &mut Memory, request_type: u32, sector: u64, len: u32) -> u8 {
+        memory.set32(HEADER, request_type).unwrap();
+        memory.set32(HEADER + 8, sector as u32).unwrap();
+        memory.set32(HEADER + 12, (sector >> 32) as u32).unwrap();
+
+        let data_flags = if request_type == VIRTIO_BLK_T_IN {
+            1 | 2
+        } else {
+            1
+        };
+        set_descriptor(memory, 0, HEADER, 16, 1);
+        set_descriptor(memory, 1, DATA, len, data_flags);
+        set_descriptor(memory, 2, STATUS, 1, 2);
+
+        let avail_idx = memory.get16(AVAIL + 2).unwrap();
+        memory
+            .set16(AVAIL + 4 + (avail_idx as usize % 8) * 2, 0)
+            .unwrap();
+        memory.set16(AVAIL + 2, avail_idx + 1).unwrap();
+        memory.set32(BASE + 0x50, 0).unwrap();
+        memory.tick();
+
+        assert_eq!(memory.get16(USED + 2), Ok(avail_idx + 1));
+        assert_eq!(memory.get32(BASE + 0x60), Ok(1));
+        memory.set32(BASE + 0x64, 1).unwrap();
+        memory.get8(STATUS).unwrap()
+    }
+
+    #[test]
+    fn capacity() {
+        let path = image("capacity", 4);
+        let memory = setup(&path.0, BlockMode::ReadOnly);
+        assert_eq!(memory.get32(BASE + 0x100), Ok(4));
+        assert_eq!(memory.get32(BASE + 0x104), Ok(0));
+    }
+
+    #[test]
+    fn read() {
+        let path = image("read", 4);
+        let mut memory = setup(&path.0, BlockMode::ReadOnly);
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_IN, 2), VIRTIO_BLK_S_OK);
+        assert_eq!(memory.get8(DATA), Ok(2));
+        assert_eq!(memory.get8(DATA + SECTOR_SIZE - 1), Ok(2));
+        assert_eq!(memory.get32(USED + 8), Ok(SECTOR_SIZE as u32 + 1));
+
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_IN, 4), VIRTIO_BLK_S_IOERR);
+
+        // A read bigger than the image fails without the device allocating a buffer for it
+        let len = u32::MAX - 1;
+        let status = sized_request(&mut memory, VIRTIO_BLK_T_IN, 0, len);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+        // The third used element, after those of the two reads above
+        assert_eq!(memory.get32(USED + 4 + 2 * 8 + 4), Ok(len + 1));
+    }
+
+    #[test]
+    fn read_only() {
+        let path = image("read_only", 4);
+        let mut memory = setup(&path.0, BlockMode::ReadOnly);
+        assert_eq!(
+            memory.get32(BASE + 0x10),
+            Ok((VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH) as u32)
+        );
+        assert_eq!(
+            request(&mut memory, VIRTIO_BLK_T_OUT, 1),
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let path = image("copy_on_write", 4);
+        let mut memory = setup(&path.0, BlockMode::CopyOnWrite);
+        for i in 0..SECTOR_SIZE {
+            memory.set8(DATA + i, 0xAA).unwrap();
+        }
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_OUT, 1), VIRTIO_BLK_S_OK);
+
+        for i in 0..SECTOR_SIZE {
+            memory.set8(DATA + i, 0).unwrap();
+        }
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_IN, 1), VIRTIO_BLK_S_OK);
+        assert_eq!(memory.get8(DATA + 100), Ok(0xAA));
+
+        // The image itself is untouched
+        assert_eq!(std::fs::read(&path.0).unwrap()[SECTOR_SIZE + 100], 1);
+    }
+
+    #[test]
+    fn read_write() {
+        let path = image("read_write", 4);
+        let mut memory = setup(&path.0, BlockMode::ReadWrite);
+        for i in 0..SECTOR_SIZE {
+            memory.set8(DATA + i, 0xAA).unwrap();
+        }
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_OUT, 3), VIRTIO_BLK_S_OK);
+        assert_eq!(request(&mut memory, VIRTIO_BLK_T_FLUSH, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(std::fs::read(&path.0).unwrap()[3 * SECTOR_SIZE + 100], 0xAA);
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let path = image("feature_negotiation", 1);
+        let mut memory = setup(&path.0, BlockMode::ReadWrite);
+
+        // Reset the device and try to accept a feature that we don't offer
+        memory.set32(BASE + 0x70, 0).unwrap();
+        memory.set32(BASE + 0x24, 0).unwrap();
+        memory.set32(BASE + 0x20, VIRTIO_BLK_F_RO as u32).unwrap();
+        memory.set32(BASE + 0x70, 1 | 2 | 8).unwrap();
+        assert_eq!(memory.get32(BASE + 0x70), Ok(1 | 2));
+    }
+}
@@ -0,0 +1,433 @@
+/**
+ * A virtio console device (section 5.3 of the virtio 1.1 specification). Port 0 is always
+ * present and is normally wired to host stdio. Additional ports are offered through the
+ * multiport feature, which adds a pair of control queues the driver uses to discover ports:
+ *
+ * q0/q1: port 0 receive/transmit
+ * q2/q3: control receive/transmit (multiport only)
+ * q4/q5, q6/q7, ...: port 1, 2, ... receive/transmit (multiport only)
+ */
+use crate::console::ConsoleInput;
+use crate::devices::virtio::queue::Queue;
+use crate::devices::virtio::VirtioDevice;
+use crate::memory::{Memory, MemoryError};
+use std::collections::VecDeque;
+use std::io::Write;
+
+pub const VIRTIO_CONSOLE_DEVICE_ID: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const CONTROL_RECEIVEQ: usize = 2;
+const CONTROL_TRANSMITQ: usize = 3;
+
+/// The size of a control message: id (u32), event (u16), value (u16).
+const CONTROL_SIZE: usize = 8;
+
+mod event {
+    pub const DEVICE_READY: u16 = 0;
+    pub const DEVICE_ADD: u16 = 1;
+    pub const PORT_READY: u16 = 3;
+    pub const CONSOLE_PORT: u16 = 4;
+    pub const PORT_OPEN: u16 = 6;
+}
+
+/// The host side of a console port.
+pub struct ConsolePort {
+    input: Box<dyn ConsoleInput>,
+    output: Box<dyn Write>,
+}
+
+impl ConsolePort {
+    pub fn new(input: Box<dyn ConsoleInput>, output: Box<dyn Write>) -> Self {
+        Self { input, output }
+    }
+}
+
+pub struct Console {
+    ports: Vec<ConsolePort>,
+    multiport: bool,
+    /// Control messages waiting for the driver to give us a buffer on the control receiveq.
+    control: VecDeque<[u8; CONTROL_SIZE]>,
+}
+
+/// The receiveq for a port.
+fn receive_queue(port: usize) -> usize {
+    match port {
+        0 => 0,
+        _ => 2 + port * 2,
+    }
+}
+
+/// The port that owns a transmitq, if the queue is one.
+fn transmit_port(queue: usize) -> Option<usize> {
+    match queue {
+        1 => Some(0),
+        CONTROL_RECEIVEQ | CONTROL_TRANSMITQ => None,
+        _ if queue % 2 == 1 => Some((queue - 3) / 2),
+        _ => None,
+    }
+}
+
+fn control_message(id: u32, event: u16, value: u16) -> [u8; CONTROL_SIZE] {
+    let mut message = [0; CONTROL_SIZE];
+    message[0..4].copy_from_slice(&id.to_le_bytes());
+    message[4..6].copy_from_slice(&event.to_le_bytes());
+    message[6..8].copy_from_slice(&value.to_le_bytes());
+    message
+}
+
+impl Console {
+    /// Create a console with the given ports. The first port is the console, any others are
+    /// only visible to drivers that negotiate the multiport feature.
+    pub fn new(ports: Vec<ConsolePort>) -> Self {
+        assert!(!ports.is_empty(), "a console needs at least one port");
+        Self {
+            ports,
+            multiport: false,
+            control: VecDeque::new(),
+        }
+    }
+
+    /// The number of ports the driver can currently see.
+    fn active_ports(&self) -> usize {
+        match self.multiport {
+            true => self.ports.len(),
+            false => 1,
+        }
+    }
+
+    /// Act on a control message sent by the driver.
+    fn control_event(&mut self, message: &[u8]) {
+        if message.len() < CONTROL_SIZE {
+            return;
+        }
+
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+
+        match event {
+            // Once the driver is ready we tell it about every port
+            event::DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() {
+                    self.control
+                        .push_back(control_message(port as u32, event::DEVICE_ADD, 1));
+                }
+            }
+            // Then, as each port comes up, we mark port 0 as the console and open it
+            event::PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.control
+                        .push_back(control_message(id, event::CONSOLE_PORT, 1));
+                }
+                self.control
+                    .push_back(control_message(id, event::PORT_OPEN, 1));
+            }
+            _ => (),
+        }
+    }
+
+    /// Send as many queued control messages as the driver has given us buffers for.
+    fn flush_control(
+        &mut self,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError> {
+        let queue = &mut queues[CONTROL_RECEIVEQ];
+        let mut used = false;
+
+        while !self.control.is_empty() && queue.available(memory)? {
+            let chain = queue.pop(memory)?.unwrap();
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write_all(memory, &message)?;
+            queue.add_used(memory, chain.head, written as u32)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    /// Move any pending host input for a port into the next buffer on its receiveq.
+    fn receive(
+        &mut self,
+        port: usize,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError> {
+        let queue = &mut queues[receive_queue(port)];
+
+        // Only take input once we have somewhere to put it
+        if !queue.available(memory)? {
+            return Ok(false);
+        }
+
+        let input = &mut self.ports[port].input;
+        let Some(first) = input.read_nonblocking() else {
+            return Ok(false);
+        };
+
+        let chain = queue.pop(memory)?.unwrap();
+        let mut data = vec![first];
+        while data.len() < chain.writable_len() {
+            match input.read_nonblocking() {
+                Some(byte) => data.push(byte),
+                None => break,
+            }
+        }
+
+        let written = chain.write_all(memory, &data)?;
+        queue.add_used(memory, chain.head, written as u32)?;
+        Ok(true)
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_id(&self) -> u32 {
+        VIRTIO_CONSOLE_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        match self.ports.len() {
+            1 => VIRTIO_CONSOLE_F_EMERG_WRITE,
+            _ => VIRTIO_CONSOLE_F_EMERG_WRITE | VIRTIO_CONSOLE_F_MULTIPORT,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        match self.ports.len() {
+            1 => 2,
+            ports => 2 * (ports + 1),
+        }
+    }
+
+    /// The configuration holds cols (u16), rows (u16), max_nr_ports (u32) and emerg_wr (u32).
+    /// We don't report a size, so only max_nr_ports is ever non-zero.
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            4..=7 => (self.ports.len() as u32).to_le_bytes()[offset - 4],
+            _ => 0,
+        }
+    }
+
+    /// Writing emerg_wr outputs a character on port 0 without going through the queues.
+    fn write_config(&mut self, offset: usize, value: u8) {
+        if offset == 8 {
+            let output = &mut self.ports[0].output;
+            let _ = output.write_all(&[value]);
+            let _ = output.flush();
+        }
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError> {
+        if queue == CONTROL_TRANSMITQ && self.multiport {
+            let mut used = false;
+            while let Some(chain) = queues[queue].pop(memory)? {
+                let message = chain.read_all(memory)?;
+                self.control_event(&message);
+                queues[queue].add_used(memory, chain.head, 0)?;
+                used = true;
+            }
+            return Ok(self.flush_control(queues, memory)? || used);
+        }
+
+        let Some(port) = transmit_port(queue).filter(|&port| port < self.active_ports()) else {
+            return Ok(false);
+        };
+
+        let mut used = false;
+        while let Some(chain) = queues[queue].pop(memory)? {
+            let data = chain.read_all(memory)?;
+            let output = &mut self.ports[port].output;
+            let _ = output.write_all(&data);
+            let _ = output.flush();
+            queues[queue].add_used(memory, chain.head, 0)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    fn tick(&mut self, queues: &mut [Queue], memory: &mut Memory) -> Result<bool, MemoryError> {
+        let mut used = false;
+
+        if self.multiport {
+            used |= self.flush_control(queues, memory)?;
+        }
+
+        for port in 0..self.active_ports() {
+            used |= self.receive(port, queues, memory)?;
+        }
+
+        Ok(used)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::console::BufferedInput;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const DESC: usize = 0x1000;
+    const AVAIL: usize = 0x2000;
+    const USED: usize = 0x3000;
+    const DATA: usize = 0x4000;
+
+    /// A writer that can be inspected after it has been handed to a port.
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Lay out every queue in its own 0x100 byte slice of the descriptor, avail and used areas.
+    fn queues(count: usize) -> Vec<Queue> {
+        (0..count)
+            .map(|i| {
+                let mut queue = Queue::new();
+                queue.size = 4;
+                queue.ready = true;
+                queue.desc = (DESC + i * 0x100) as u64;
+                queue.driver = (AVAIL + i * 0x100) as u64;
+                queue.device = (USED + i * 0x100) as u64;
+                queue
+            })
+            .collect()
+    }
+
+    /// Make a single descriptor buffer of len bytes at DATA + queue * 0x100 available on queue.
+    fn offer(memory: &mut Memory, queue: usize, len: u32, writable: bool) -> usize {
+        let (desc, avail) = (DESC + queue * 0x100, AVAIL + queue * 0x100);
+        let data = DATA + queue * 0x100;
+        let avail_idx = memory.get16(avail + 2).unwrap();
+        let descriptor = desc + (avail_idx as usize % 4) * 16;
+        memory.set32(descriptor, data as u32).unwrap();
+        memory.set32(descriptor + 8, len).unwrap();
+        memory
+            .set16(descriptor + 12, if writable { 2 } else { 0 })
+            .unwrap();
+        memory
+            .set16(avail + 4 + (avail_idx as usize % 4) * 2, avail_idx % 4)
+            .unwrap();
+        memory.set16(avail + 2, avail_idx + 1).unwrap();
+        data
+    }
+
+    fn used_count(memory: &Memory, queue: usize) -> u16 {
+        memory.get16(USED + queue * 0x100 + 2).unwrap()
+    }
+
+    #[test]
+    fn transmit_and_receive() {
+        let mut memory = Memory::new(0x8000);
+        let output = SharedOutput(Rc::new(RefCell::new(Vec::new())));
+        let port = ConsolePort::new(
+            Box::new(BufferedInput::new(b"hi")),
+            Box::new(output.clone()),
+        );
+        let mut console = Console::new(vec![port]);
+        let mut queues = queues(console.queue_count());
+        assert_eq!(console.features(), VIRTIO_CONSOLE_F_EMERG_WRITE);
+
+        // Nothing is received until the driver provides a buffer
+        assert_eq!(console.tick(&mut queues, &mut memory), Ok(false));
+        let rx = offer(&mut memory, 0, 16, true);
+        assert_eq!(console.tick(&mut queues, &mut memory), Ok(true));
+        assert_eq!(used_count(&memory, 0), 1);
+        assert_eq!(memory.get32(USED + 8), Ok(2));
+        assert_eq!(memory.get16(rx), Ok(u16::from_le_bytes(*b"hi")));
+
+        let tx = offer(&mut memory, 1, 3, false);
+        for (i, &byte) in b"abc".iter().enumerate() {
+            memory.set8(tx + i, byte).unwrap();
+        }
+        assert_eq!(console.notify(1, &mut queues, &mut memory), Ok(true));
+        assert_eq!(used_count(&memory, 1), 1);
+
+        console.write_config(8, b'!');
+        assert_eq!(output.0.borrow().as_slice(), b"abc!");
+    }
+
+    #[test]
+    fn multiport() {
+        let mut memory = Memory::new(0x8000);
+        let outputs: Vec<_> = (0..2)
+            .map(|_| SharedOutput(Rc::new(RefCell::new(Vec::new()))))
+            .collect();
+        let ports = outputs
+            .iter()
+            .map(|output| {
+                ConsolePort::new(Box::new(BufferedInput::new(b"")), Box::new(output.clone()))
+            })
+            .collect();
+        let mut console = Console::new(ports);
+        assert_eq!(console.queue_count(), 6);
+        assert_eq!(console.read_config(4), 2);
+        let mut queues = queues(console.queue_count());
+
+        // Port 1 stays hidden from a driver that doesn't negotiate multiport
+        let tx = offer(&mut memory, 5, 1, false);
+        memory.set8(tx, b'x').unwrap();
+        assert_eq!(console.notify(5, &mut queues, &mut memory), Ok(false));
+
+        console.set_driver_features(VIRTIO_CONSOLE_F_MULTIPORT);
+        for _ in 0..4 {
+            offer(&mut memory, CONTROL_RECEIVEQ, CONTROL_SIZE as u32, true);
+        }
+
+        let send = |memory: &mut Memory, console: &mut Console, queues: &mut [Queue], id, event| {
+            let message = offer(memory, CONTROL_TRANSMITQ, CONTROL_SIZE as u32, false);
+            for (i, &byte) in control_message(id, event, 1).iter().enumerate() {
+                memory.set8(message + i, byte).unwrap();
+            }
+            console.notify(CONTROL_TRANSMITQ, queues, memory).unwrap();
+        };
+
+        // DEVICE_READY is answered with a DEVICE_ADD for each port
+        send(
+            &mut memory,
+            &mut console,
+            &mut queues,
+            0,
+            event::DEVICE_READY,
+        );
+        assert_eq!(used_count(&memory, CONTROL_RECEIVEQ), 2);
+        let control = DATA + CONTROL_RECEIVEQ * 0x100;
+        assert_eq!(memory.get16(control + 4), Ok(event::DEVICE_ADD));
+
+        // PORT_READY on port 1 opens it, and the earlier transmit can now be handled
+        send(&mut memory, &mut console, &mut queues, 1, event::PORT_READY);
+        assert_eq!(used_count(&memory, CONTROL_RECEIVEQ), 3);
+        assert_eq!(memory.get32(control), Ok(1));
+        assert_eq!(memory.get16(control + 4), Ok(event::PORT_OPEN));
+
+        assert_eq!(console.notify(5, &mut queues, &mut memory), Ok(true));
+        assert_eq!(outputs[1].0.borrow().as_slice(), b"x");
+        assert!(outputs[0].0.borrow().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+/**
+ * The virtio-mmio transport (version 2, see section 4.2 of the virtio 1.1 specification). The
+ * transport owns the register interface, feature negotiation and the queues, and hands queue
+ * processing off to a VirtioDevice implementation such as the block device.
+ *
+ * Used buffer and configuration change notifications set InterruptStatus and the interrupt line,
+ * but the hart doesn't take interrupts yet, so drivers must poll InterruptStatus or the used
+ * rings instead of waiting for an interrupt.
+ */
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::Device;
+use crate::memory::{Memory, MemoryError};
+use queue::{Queue, MAX_QUEUE_SIZE};
+
+/// The number of bytes of address space each virtio-mmio device occupies.
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+/// The address QEMU's virt machine places its first virtio-mmio device at. Subsequent devices
+/// follow every VIRTIO_MMIO_SIZE bytes.
+pub const DEFAULT_VIRTIO_MMIO_BASE: usize = 0x1000_1000;
+
+/// Every device we implement follows the version 1 (non-legacy) interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554D_4551;
+
+mod reg {
+    pub const MAGIC_VALUE: usize = 0x000;
+    pub const VERSION: usize = 0x004;
+    pub const DEVICE_ID: usize = 0x008;
+    pub const VENDOR_ID: usize = 0x00C;
+    pub const DEVICE_FEATURES: usize = 0x010;
+    pub const DEVICE_FEATURES_SEL: usize = 0x014;
+    pub const DRIVER_FEATURES: usize = 0x020;
+    pub const DRIVER_FEATURES_SEL: usize = 0x024;
+    pub const QUEUE_SEL: usize = 0x030;
+    pub const QUEUE_NUM_MAX: usize = 0x034;
+    pub const QUEUE_NUM: usize = 0x038;
+    pub const QUEUE_READY: usize = 0x044;
+    pub const QUEUE_NOTIFY: usize = 0x050;
+    pub const INTERRUPT_STATUS: usize = 0x060;
+    pub const INTERRUPT_ACK: usize = 0x064;
+    pub const STATUS: usize = 0x070;
+    pub const QUEUE_DESC_LOW: usize = 0x080;
+    pub const QUEUE_DESC_HIGH: usize = 0x084;
+    pub const QUEUE_DRIVER_LOW: usize = 0x090;
+    pub const QUEUE_DRIVER_HIGH: usize = 0x094;
+    pub const QUEUE_DEVICE_LOW: usize = 0x0A0;
+    pub const QUEUE_DEVICE_HIGH: usize = 0x0A4;
+    pub const CONFIG_GENERATION: usize = 0x0FC;
+    pub const CONFIG: usize = 0x100;
+}
+
+mod status {
+    pub const FEATURES_OK: u32 = 8;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
+}
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// A device type that sits behind the virtio-mmio transport.
+pub trait VirtioDevice {
+    /// The virtio device ID (e.g, 2 for a block device).
+    fn device_id(&self) -> u32;
+
+    /// The device specific feature bits we offer. VIRTIO_F_VERSION_1 is added by the transport.
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    /// Read a byte of the device specific configuration space.
+    fn read_config(&self, offset: usize) -> u8;
+
+    /// Write a byte of the device specific configuration space.
+    fn write_config(&mut self, _offset: usize, _value: u8) {}
+
+    /// Called once the driver has settled on the features it will use.
+    fn set_driver_features(&mut self, _features: u64) {}
+
+    /// Called after the driver notifies us that queue has new buffers. Returns true if any
+    /// buffers were used.
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError>;
+
+    /// Called once per instruction while the driver is running so the device can fill buffers
+    /// from the host (e.g, received input). Returns true if any buffers were used.
+    fn tick(&mut self, _queues: &mut [Queue], _memory: &mut Memory) -> Result<bool, MemoryError> {
+        Ok(false)
+    }
+
+    /// Called when the driver resets the device.
+    fn reset(&mut self) {}
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    queues: Vec<Queue>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    interrupt_status: u32,
+    status: u32,
+    config_generation: u32,
+    pending_notifications: Vec<usize>,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.queue_count()).map(|_| Queue::new()).collect();
+        Self {
+            device,
+            queues,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+            pending_notifications: Vec::new(),
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.queues
+            .iter_mut()
+            .for_each(|queue| *queue = Queue::new());
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.pending_notifications.clear();
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn write_status(&mut self, value: u32) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+
+        // The driver can only accept features we offered, otherwise we refuse FEATURES_OK.
+        if value & status::FEATURES_OK != 0 && self.status & status::FEATURES_OK == 0 {
+            if self.driver_features & !self.features() != 0 {
+                self.status = value & !status::FEATURES_OK;
+                return;
+            }
+
+            self.device.set_driver_features(self.driver_features);
+        }
+
+        self.status = value;
+    }
+
+    /// Called when processing a queue fails because the driver handed us a bad address. The
+    /// spec asks us to flag that we need a reset and raise a configuration change interrupt.
+    fn fail(&mut self) {
+        self.status |= status::DEVICE_NEEDS_RESET;
+        self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        match offset {
+            reg::MAGIC_VALUE => MAGIC_VALUE,
+            reg::VERSION => VERSION,
+            reg::DEVICE_ID => self.device.device_id(),
+            reg::VENDOR_ID => VENDOR_ID,
+            reg::DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            reg::QUEUE_NUM_MAX => match self.selected_queue() {
+                Some(_) => MAX_QUEUE_SIZE as u32,
+                None => 0,
+            },
+            reg::QUEUE_READY => match self.selected_queue() {
+                Some(queue) => queue.ready as u32,
+                None => 0,
+            },
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::STATUS => self.status,
+            reg::CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        fn set_low(target: &mut u64, value: u32) {
+            *target = (*target & !0xFFFF_FFFF) | value as u64;
+        }
+
+        fn set_high(target: &mut u64, value: u32) {
+            *target = (*target & 0xFFFF_FFFF) | ((value as u64) << 32);
+        }
+
+        match offset {
+            reg::DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            reg::DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            reg::DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, value),
+                1 => set_high(&mut self.driver_features, value),
+                _ => (),
+            },
+            reg::QUEUE_SEL => self.queue_sel = value,
+            reg::QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.size = (value as u16).min(MAX_QUEUE_SIZE);
+                }
+            }
+            reg::QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            reg::QUEUE_NOTIFY => {
+                let queue = value as usize;
+                if queue < self.queues.len() && !self.pending_notifications.contains(&queue) {
+                    self.pending_notifications.push(queue);
+                }
+            }
+            reg::INTERRUPT_ACK => self.interrupt_status &= !value,
+            reg::STATUS => self.write_status(value),
+            reg::QUEUE_DESC_LOW => self
+                .selected_queue()
+                .map_or((), |q| set_low(&mut q.desc, value)),
+            reg::QUEUE_DESC_HIGH => self
+                .selected_queue()
+                .map_or((), |q| set_high(&mut q.desc, value)),
+            reg::QUEUE_DRIVER_LOW => self
+                .selected_queue()
+                .map_or((), |q| set_low(&mut q.driver, value)),
+            reg::QUEUE_DRIVER_HIGH => self
+                .selected_queue()
+                .map_or((), |q| set_high(&mut q.driver, value)),
+            reg::QUEUE_DEVICE_LOW => self
+                .selected_queue()
+                .map_or((), |q| set_low(&mut q.device, value)),
+            reg::QUEUE_DEVICE_HIGH => self
+                .selected_queue()
+                .map_or((), |q| set_high(&mut q.device, value)),
+            _ => (),
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn read(&mut self, offset: usize, width: usize) -> u32 {
+        if offset >= reg::CONFIG {
+            let offset = offset - reg::CONFIG;
+            let bytes: Vec<u8> = (0..width)
+                .map(|i| self.device.read_config(offset + i))
+                .collect();
+            let mut value = [0; 4];
+            value[..width].copy_from_slice(&bytes);
+            return u32::from_le_bytes(value);
+        }
+
+        self.read_register(offset)
+    }
+
+    fn write(&mut self, offset: usize, width: usize, value: u32) {
+        if offset >= reg::CONFIG {
+            let offset = offset - reg::CONFIG;
+            for (i, &byte) in value.to_le_bytes()[..width].iter().enumerate() {
+                self.device.write_config(offset + i, byte);
+            }
+            self.config_generation = self.config_generation.wrapping_add(1);
+            return;
+        }
+
+        self.write_register(offset, value)
+    }
+
+    fn tick(&mut self, memory: &mut Memory) {
+        if self.status & status::DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+
+        let mut used = false;
+
+        for queue in std::mem::take(&mut self.pending_notifications) {
+            match self.device.notify(queue, &mut self.queues, memory) {
+                Ok(queue_used) => used |= queue_used,
+                Err(_) => return self.fail(),
+            }
+        }
+
+        match self.device.tick(&mut self.queues, memory) {
+            Ok(tick_used) => used |= tick_used,
+            Err(_) => return self.fail(),
+        }
+
+        if used {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn device_tree(&self, tree: &mut DeviceTree, base: usize, size: usize) {
+        let mut node = Node::new(&format!("virtio_mmio@{base:x}"));
+        node.set_str("compatible", "virtio,mmio");
+        node.set_reg(base, size);
+        tree.soc().add(node);
+    }
+}
@@ -0,0 +1,404 @@
+/**
+ * A virtio network device (section 5.1 of the virtio 1.1 specification). Frames never reach a
+ * real network, they go to a NetBackend instead:
+ * Reflector: sends every frame straight back to the guest with the addresses swapped
+ * UnixSocket: exchanges frames with another emulator over a Unix socket
+ * Pcap: records every frame the guest sends in a pcap file
+ */
+use crate::devices::virtio::queue::Queue;
+use crate::devices::virtio::VirtioDevice;
+use crate::memory::{Memory, MemoryError};
+use std::collections::VecDeque;
+use std::io::Write;
+
+pub const VIRTIO_NET_DEVICE_ID: u32 = 1;
+
+/// QEMU's default MAC address for the first network card.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// The size of struct virtio_net_hdr, which comes before every frame. We don't offer any
+/// offloads so on receive it is all zeroes except for num_buffers.
+const HEADER_SIZE: usize = 12;
+
+/// The largest Ethernet frame we pass around (without a frame check sequence).
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// Where frames sent by the guest go and frames for the guest come from.
+pub trait NetBackend {
+    /// Send a frame from the guest.
+    fn send(&mut self, frame: &[u8]);
+
+    /// Take the next frame for the guest, if one has arrived.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Sends every frame back to the guest with the source and destination addresses swapped, so
+/// it looks like it came from the host the guest was talking to.
+#[derive(Default)]
+pub struct Reflector {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Reflector {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+        }
+    }
+}
+
+impl NetBackend for Reflector {
+    fn send(&mut self, frame: &[u8]) {
+        // Anything shorter than the destination and source addresses isn't a frame
+        if frame.len() < 12 {
+            return;
+        }
+
+        let mut reflected = frame.to_vec();
+        reflected[0..6].copy_from_slice(&frame[6..12]);
+        reflected[6..12].copy_from_slice(&frame[0..6]);
+        self.frames.push_back(reflected);
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Connects two emulators over a Unix stream socket. Each frame is sent as a 4 byte big endian
+/// length followed by the frame, the same framing QEMU's stream netdev uses.
+#[cfg(unix)]
+pub struct UnixSocket {
+    stream: std::os::unix::net::UnixStream,
+    receiver: std::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Listen on path and wait for the other emulator to connect.
+    pub fn listen(path: &str) -> std::io::Result<Self> {
+        // A socket left behind by an earlier run would stop us binding
+        let _ = std::fs::remove_file(path);
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        Self::from_stream(stream)
+    }
+
+    /// Connect to an emulator listening on path.
+    pub fn connect(path: &str) -> std::io::Result<Self> {
+        Self::from_stream(std::os::unix::net::UnixStream::connect(path)?)
+    }
+
+    /// Use an already connected stream. Incoming frames are read on a separate thread so
+    /// receive never blocks the emulator.
+    pub fn from_stream(stream: std::os::unix::net::UnixStream) -> std::io::Result<Self> {
+        use std::io::Read;
+
+        let mut reader = stream.try_clone()?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || loop {
+            let mut length = [0; 4];
+            if reader.read_exact(&mut length).is_err() {
+                return;
+            }
+
+            let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+            if reader.read_exact(&mut frame).is_err() || sender.send(frame).is_err() {
+                return;
+            }
+        });
+
+        Ok(Self { stream, receiver })
+    }
+}
+
+#[cfg(unix)]
+impl NetBackend for UnixSocket {
+    fn send(&mut self, frame: &[u8]) {
+        // If the other side has gone away the frame is lost, just like on a real network
+        let _ = self
+            .stream
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .and_then(|_| self.stream.write_all(frame));
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Records every frame the guest sends in pcap format for inspection with tools such as
+/// Wireshark or tcpdump. Nothing is ever received.
+pub struct Pcap {
+    output: Box<dyn Write>,
+}
+
+impl Pcap {
+    /// Write the pcap file header to output.
+    pub fn new(mut output: Box<dyn Write>) -> std::io::Result<Self> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xA1B2_C3D4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Timezone offset and timestamp accuracy, both always 0
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&(MAX_FRAME_SIZE as u32).to_le_bytes());
+        // Link type 1 is Ethernet
+        header.extend_from_slice(&1u32.to_le_bytes());
+        output.write_all(&header)?;
+        Ok(Self { output })
+    }
+}
+
+impl NetBackend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        let _ = self.output.write_all(&record);
+        let _ = self.output.flush();
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+pub struct Net {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    /// A frame that arrived while the driver had no receive buffers available.
+    pending: Option<Vec<u8>>,
+}
+
+impl Net {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            pending: None,
+        }
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_id(&self) -> u32 {
+        VIRTIO_NET_DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    /// The configuration holds the MAC address followed by the link status, which is always up.
+    fn read_config(&self, offset: usize) -> u8 {
+        match offset {
+            0..=5 => self.mac[offset],
+            6..=7 => VIRTIO_NET_S_LINK_UP.to_le_bytes()[offset - 6],
+            _ => 0,
+        }
+    }
+
+    fn notify(
+        &mut self,
+        queue: usize,
+        queues: &mut [Queue],
+        memory: &mut Memory,
+    ) -> Result<bool, MemoryError> {
+        // New receive buffers are picked up on the next tick
+        if queue != TRANSMITQ {
+            return Ok(false);
+        }
+
+        let mut used = false;
+        while let Some(chain) = queues[TRANSMITQ].pop(memory)? {
+            let packet = chain.read_all(memory)?;
+            if packet.len() > HEADER_SIZE {
+                self.backend.send(&packet[HEADER_SIZE..]);
+            }
+            queues[TRANSMITQ].add_used(memory, chain.head, 0)?;
+            used = true;
+        }
+
+        Ok(used)
+    }
+
+    fn tick(&mut self, queues: &mut [Queue], memory: &mut Memory) -> Result<bool, MemoryError> {
+        let queue = &mut queues[RECEIVEQ];
+        let mut used = false;
+
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.backend.receive();
+            }
+
+            if self.pending.is_none() || !queue.available(memory)? {
+                return Ok(used);
+            }
+
+            let frame = self.pending.take().unwrap();
+            let chain = queue.pop(memory)?.unwrap();
+
+            // A single buffer always holds a whole packet, so num_buffers is 1
+            let mut packet = vec![0; HEADER_SIZE];
+            packet[10] = 1;
+            packet.extend_from_slice(&frame);
+
+            // Frames that don't fit in the buffer are dropped
+            let written = match packet.len() <= chain.writable_len() {
+                true => chain.write_all(memory, &packet)?,
+                false => 0,
+            };
+            queue.add_used(memory, chain.head, written as u32)?;
+            used = true;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const DESC: usize = 0x1000;
+    const AVAIL: usize = 0x2000;
+    const USED: usize = 0x3000;
+    const DATA: usize = 0x4000;
+
+    /// A writer that can be inspected after it has been handed to a backend.
+    #[derive(Clone)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![0xAA; 6];
+        frame.extend_from_slice(&DEFAULT_MAC);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(b"payload");
+        frame
+    }
+
+    /// Give each queue its own 0x100 byte slice of the descriptor, avail and used areas, and
+    /// its own buffer at DATA + queue * 0x800.
+    fn queues() -> Vec<Queue> {
+        (0..2)
+            .map(|i| {
+                let mut queue = Queue::new();
+                queue.size = 4;
+                queue.ready = true;
+                queue.desc = (DESC + i * 0x100) as u64;
+                queue.driver = (AVAIL + i * 0x100) as u64;
+                queue.device = (USED + i * 0x100) as u64;
+                queue
+            })
+            .collect()
+    }
+
+    fn offer(memory: &mut Memory, queue: usize, data: &[u8], len: u32) -> usize {
+        let (desc, avail) = (DESC + queue * 0x100, AVAIL + queue * 0x100);
+        let buffer = DATA + queue * 0x800;
+        for (i, &byte) in data.iter().enumerate() {
+            memory.set8(buffer + i, byte).unwrap();
+        }
+        memory.set32(desc, buffer as u32).unwrap();
+        memory.set32(desc + 8, len).unwrap();
+        memory
+            .set16(desc + 12, if queue == RECEIVEQ { 2 } else { 0 })
+            .unwrap();
+        let avail_idx = memory.get16(avail + 2).unwrap();
+        memory.set16(avail + 2, avail_idx + 1).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn reflector() {
+        let mut memory = Memory::new(0x8000);
+        let mut queues = queues();
+        let mut net = Net::new(DEFAULT_MAC, Box::new(Reflector::new()));
+        assert_eq!(net.read_config(5), 0x56);
+        assert_eq!(net.read_config(6), 1);
+
+        let mut packet = vec![0; HEADER_SIZE];
+        packet.extend_from_slice(&frame());
+        offer(&mut memory, TRANSMITQ, &packet, packet.len() as u32);
+        assert_eq!(net.notify(TRANSMITQ, &mut queues, &mut memory), Ok(true));
+
+        // The reflected frame waits for a receive buffer
+        assert_eq!(net.tick(&mut queues, &mut memory), Ok(false));
+        let buffer = offer(&mut memory, RECEIVEQ, &[], 0x800);
+        assert_eq!(net.tick(&mut queues, &mut memory), Ok(true));
+        assert_eq!(memory.get32(USED + 8), Ok(packet.len() as u32));
+        assert_eq!(memory.get16(buffer + 10), Ok(1));
+        for (i, &byte) in DEFAULT_MAC.iter().enumerate() {
+            assert_eq!(memory.get8(buffer + HEADER_SIZE + i), Ok(byte));
+            assert_eq!(memory.get8(buffer + HEADER_SIZE + 6 + i), Ok(0xAA));
+        }
+    }
+
+    #[test]
+    fn pcap() {
+        let output = SharedOutput(Rc::new(RefCell::new(Vec::new())));
+        let mut pcap = Pcap::new(Box::new(output.clone())).unwrap();
+        assert_eq!(output.0.borrow().len(), 24);
+        assert_eq!(output.0.borrow()[0..4], [0xD4, 0xC3, 0xB2, 0xA1]);
+
+        pcap.send(&frame());
+        let written = output.0.borrow();
+        assert_eq!(written.len(), 24 + 16 + frame().len());
+        assert_eq!(written[32..36], (frame().len() as u32).to_le_bytes());
+        assert_eq!(written[40..], frame());
+        assert_eq!(pcap.receive(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        let (left, right) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut left = UnixSocket::from_stream(left).unwrap();
+        let mut right = UnixSocket::from_stream(right).unwrap();
+
+        left.send(&frame());
+        left.send(b"second");
+
+        let received: Vec<_> = std::iter::from_fn(|| right.receiver.recv().ok())
+            .take(2)
+            .collect();
+        assert_eq!(received, [frame(), b"second".to_vec()]);
+        assert_eq!(right.receive(), None);
+    }
+}
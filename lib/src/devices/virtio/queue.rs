@@ -0,0 +1,195 @@
+/**
+ * Split virtqueues as described in section 2.7 of the virtio 1.1 specification. The driver
+ * places descriptor chains in the available ring and we hand them back through the used ring
+ * once we have consumed them.
+ */
+use crate::memory::{Memory, MemoryError};
+
+const DESCRIPTOR_SIZE: u64 = 16;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// The largest queue we offer to drivers.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+fn get64(memory: &Memory, addr: u64) -> Result<u64, MemoryError> {
+    let low = memory.get32(addr as usize)? as u64;
+    let high = memory.get32(addr as usize + 4)? as u64;
+    Ok(low | (high << 32))
+}
+
+/// A buffer in guest memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+}
+
+/// A descriptor chain taken from the available ring. The device readable buffers always come
+/// before the device writable ones, so we keep them apart and treat each side as one stream.
+#[derive(Debug)]
+pub struct DescriptorChain {
+    pub head: u16,
+    pub readable: Vec<Buffer>,
+    pub writable: Vec<Buffer>,
+}
+
+impl DescriptorChain {
+    /// Read every device readable buffer in the chain into a single Vec.
+    pub fn read_all(&self, memory: &Memory) -> Result<Vec<u8>, MemoryError> {
+        let mut data = Vec::new();
+        for buffer in &self.readable {
+            // Checked before allocating space for it, as the guest chooses the length
+            if buffer.len as usize > memory.ram_size() {
+                return Err(MemoryError::OutOfBounds);
+            }
+            let start = data.len();
+            data.resize(start + buffer.len as usize, 0);
+            memory.read_slice(buffer.addr as usize, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// The total size of the device writable buffers in the chain.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|buffer| buffer.len as usize).sum()
+    }
+
+    /// Write data across the device writable buffers in order. Anything that does not fit is
+    /// dropped. Returns the number of bytes written.
+    pub fn write_all(&self, memory: &mut Memory, data: &[u8]) -> Result<usize, MemoryError> {
+        self.write_at(memory, 0, data)
+    }
+
+    /// As write_all, but starting offset bytes into the device writable buffers.
+    pub fn write_at(
+        &self,
+        memory: &mut Memory,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, MemoryError> {
+        let (mut skip, mut written) = (offset, 0);
+        for buffer in &self.writable {
+            let len = buffer.len as usize;
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let remaining = &data[written..];
+            let count = remaining.len().min(len - skip);
+            memory.load_slice(buffer.addr as usize + skip, &remaining[..count])?;
+            written += count;
+            skip = 0;
+        }
+        Ok(written)
+    }
+}
+
+#[derive(Debug)]
+pub struct Queue {
+    pub size: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    last_avail: u16,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self {
+            size: MAX_QUEUE_SIZE,
+            ready: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail: 0,
+        }
+    }
+
+    /// Check whether the driver has made a descriptor chain available without taking it.
+    pub fn available(&self, memory: &Memory) -> Result<bool, MemoryError> {
+        if !self.ready || self.size == 0 {
+            return Ok(false);
+        }
+
+        Ok(memory.get16(self.driver as usize + 2)? != self.last_avail)
+    }
+
+    /// Take the next descriptor chain the driver has made available, if there is one.
+    pub fn pop(&mut self, memory: &Memory) -> Result<Option<DescriptorChain>, MemoryError> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+
+        let avail_idx = memory.get16(self.driver as usize + 2)?;
+
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+
+        let slot = (self.last_avail % self.size) as u64;
+        let head = memory.get16((self.driver + 4 + slot * 2) as usize)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        let mut index = head;
+
+        // A well behaved driver never builds a chain longer than the queue, so we use that to
+        // stop a looping chain from hanging the emulator.
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(MemoryError::OutOfBounds);
+            }
+
+            let descriptor = self.desc + index as u64 * DESCRIPTOR_SIZE;
+            let buffer = Buffer {
+                addr: get64(memory, descriptor)?,
+                len: memory.get32(descriptor as usize + 8)?,
+            };
+            let flags = memory.get16(descriptor as usize + 12)?;
+            let next = memory.get16(descriptor as usize + 14)?;
+
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else {
+                chain.readable.push(buffer);
+            }
+
+            if flags & DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+
+            index = next;
+        }
+
+        Err(MemoryError::OutOfBounds)
+    }
+
+    /// Hand a chain back to the driver, recording that we wrote len bytes into it.
+    pub fn add_used(
+        &mut self,
+        memory: &mut Memory,
+        head: u16,
+        len: u32,
+    ) -> Result<(), MemoryError> {
+        let used_idx = memory.get16(self.device as usize + 2)?;
+        let slot = (used_idx % self.size) as u64;
+        let element = (self.device + 4 + slot * 8) as usize;
+        memory.set32(element, head as u32)?;
+        memory.set32(element + 4, len)?;
+        memory.set16(self.device as usize + 2, used_idx.wrapping_add(1))
+    }
+}
@@ -0,0 +1,430 @@
+/**
+ * A reader for 32 bit little-endian RISC-V ELF files. It extracts the loadable segments, the
+ * sections and the symbol table, which is all the debugger and disassembler need.
+ */
+use std::ops::Range;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHF_EXECINSTR: u32 = 4;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    NotElf,
+    /// A valid ELF file we can't use, e.g, one for another architecture
+    Unsupported(&'static str),
+    Truncated,
+}
+
+/// A segment to be loaded into memory. Any of memory_size beyond data is zero filled.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub memory_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub executable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub function: bool,
+}
+
+/// The function and object symbols of a program, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable(Vec<Symbol>);
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: SymbolTable,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self(symbols)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.0.iter()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.0.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The symbol covering address and the offset of address into it. Symbols without a size
+    /// cover everything up to the next symbol.
+    pub fn containing(&self, address: u32) -> Option<(&Symbol, u32)> {
+        let index = self.0.partition_point(|symbol| symbol.address <= address);
+        let symbol = self.0[..index]
+            .iter()
+            .rev()
+            .find(|symbol| symbol.size == 0 || address - symbol.address < symbol.size)?;
+        Some((symbol, address - symbol.address))
+    }
+
+    /// The addresses a symbol covers. A symbol without a size runs up to the next symbol.
+    pub fn range(&self, name: &str) -> Option<Range<u32>> {
+        let symbol = self.lookup(name)?;
+        let end = match symbol.size {
+            0 => self
+                .0
+                .iter()
+                .map(|other| other.address)
+                .find(|&address| address > symbol.address)
+                .unwrap_or(u32::MAX),
+            size => symbol.address.wrapping_add(size),
+        };
+        Some(symbol.address..end)
+    }
+
+    /// Describe address as symbol or symbol+offset.
+    pub fn describe(&self, address: u32) -> Option<String> {
+        match self.containing(address)? {
+            (symbol, 0) => Some(symbol.name.clone()),
+            (symbol, offset) => Some(format!("{}+{offset:#x}", symbol.name)),
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn slice(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    bytes
+        .get(start..start + size as usize)
+        .ok_or(ElfError::Truncated)
+}
+
+/// Read the NUL terminated string at offset in a string table.
+fn string(table: &[u8], offset: u32) -> String {
+    let bytes = table.get(offset as usize..).unwrap_or(&[]);
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    address: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+impl SectionHeader {
+    fn parse(bytes: &[u8], offset: usize) -> Result<Self, ElfError> {
+        Ok(Self {
+            name: u32_at(bytes, offset)?,
+            kind: u32_at(bytes, offset + 4)?,
+            flags: u32_at(bytes, offset + 8)?,
+            address: u32_at(bytes, offset + 12)?,
+            offset: u32_at(bytes, offset + 16)?,
+            size: u32_at(bytes, offset + 20)?,
+            link: u32_at(bytes, offset + 24)?,
+        })
+    }
+
+    fn data<'a>(&self, bytes: &'a [u8]) -> Result<&'a [u8], ElfError> {
+        match self.kind {
+            SHT_NOBITS => Ok(&[]),
+            _ => slice(bytes, self.offset, self.size),
+        }
+    }
+}
+
+/// Whether bytes look like an ELF file of any kind.
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        if !is_elf(bytes) {
+            return Err(ElfError::NotElf);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[4] != CLASS_32 {
+            return Err(ElfError::Unsupported("only 32 bit ELF files are supported"));
+        }
+        if bytes[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::Unsupported(
+                "only little-endian ELF files are supported",
+            ));
+        }
+        if u16_at(bytes, 18)? != MACHINE_RISCV {
+            return Err(ElfError::Unsupported("not a RISC-V ELF file"));
+        }
+
+        let entry = u32_at(bytes, 24)?;
+        let program_headers = u32_at(bytes, 28)? as usize;
+        let section_headers = u32_at(bytes, 32)? as usize;
+        let program_header_count = u16_at(bytes, 44)? as usize;
+        let section_header_count = u16_at(bytes, 48)? as usize;
+        let section_names = u16_at(bytes, 50)? as usize;
+
+        let mut segments = Vec::new();
+        for index in 0..program_header_count {
+            let offset = program_headers + index * PROGRAM_HEADER_SIZE;
+            if u32_at(bytes, offset)? != PT_LOAD {
+                continue;
+            }
+            let file_offset = u32_at(bytes, offset + 4)?;
+            let file_size = u32_at(bytes, offset + 16)?;
+            segments.push(Segment {
+                address: u32_at(bytes, offset + 12)?,
+                data: slice(bytes, file_offset, file_size)?.to_vec(),
+                memory_size: u32_at(bytes, offset + 20)?,
+            });
+        }
+
+        let headers = (0..section_header_count)
+            .map(|index| SectionHeader::parse(bytes, section_headers + index * SECTION_HEADER_SIZE))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let names = match headers.get(section_names) {
+            Some(header) => header.data(bytes)?,
+            None => &[],
+        };
+
+        let mut sections = Vec::new();
+        let mut symbols = Vec::new();
+        for header in &headers {
+            sections.push(Section {
+                name: string(names, header.name),
+                address: header.address,
+                data: header.data(bytes)?.to_vec(),
+                executable: header.flags & SHF_EXECINSTR != 0,
+            });
+
+            if header.kind != SHT_SYMTAB {
+                continue;
+            }
+
+            let strings = match headers.get(header.link as usize) {
+                Some(strings) => strings.data(bytes)?,
+                None => &[],
+            };
+            for symbol in header.data(bytes)?.chunks_exact(SYMBOL_SIZE) {
+                let kind = symbol[12] & 0xF;
+                let section = u16_at(symbol, 14)?;
+                let name = string(strings, u32_at(symbol, 0)?);
+
+                // Skip undefined symbols, sections, files and mapping symbols like $x
+                if section == 0 || !matches!(kind, 0 | STT_OBJECT | STT_FUNC) {
+                    continue;
+                }
+                if name.is_empty() || name.starts_with('$') {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name,
+                    address: u32_at(symbol, 4)?,
+                    size: u32_at(symbol, 8)?,
+                    function: kind == STT_FUNC,
+                });
+            }
+        }
+
+        Ok(Self {
+            entry,
+            segments,
+            sections,
+            symbols: SymbolTable::new(symbols),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn push16(bytes: &mut Vec<u8>, value: u16) {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    fn push32(bytes: &mut Vec<u8>, value: u32) {
+        bytes.extend(value.to_le_bytes());
+    }
+
+    /// Build an ELF file with a .text section and segment at address holding text, and the given
+    /// (name, address, size, type) symbols.
+    pub(crate) fn build(address: u32, text: &[u8], symbols: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYMBOL_SIZE];
+        for &(name, value, size, kind) in symbols {
+            push32(&mut symtab, strtab.len() as u32);
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+            push32(&mut symtab, value);
+            push32(&mut symtab, size);
+            symtab.extend([0x10 | kind, 0]);
+            push16(&mut symtab, 1);
+        }
+        let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+        let text_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u32;
+        let symtab_offset = text_offset + text.len() as u32;
+        let strtab_offset = symtab_offset + symtab.len() as u32;
+        let shstrtab_offset = strtab_offset + strtab.len() as u32;
+        let section_headers = shstrtab_offset + shstrtab.len() as u32;
+
+        let mut elf = MAGIC.to_vec();
+        elf.extend([CLASS_32, DATA_LITTLE_ENDIAN, 1]);
+        elf.resize(16, 0);
+        push16(&mut elf, 2);
+        push16(&mut elf, MACHINE_RISCV);
+        push32(&mut elf, 1);
+        push32(&mut elf, address);
+        push32(&mut elf, HEADER_SIZE as u32);
+        push32(&mut elf, section_headers);
+        push32(&mut elf, 0);
+        for value in [
+            HEADER_SIZE,
+            PROGRAM_HEADER_SIZE,
+            1,
+            SECTION_HEADER_SIZE,
+            5,
+            4,
+        ] {
+            push16(&mut elf, value as u16);
+        }
+
+        for value in [PT_LOAD, text_offset, address, address] {
+            push32(&mut elf, value);
+        }
+        for value in [text.len() as u32, text.len() as u32 + 0x10, 5, 4] {
+            push32(&mut elf, value);
+        }
+
+        elf.extend(text);
+        elf.extend(&symtab);
+        elf.extend(&strtab);
+        elf.extend(&shstrtab);
+
+        let sections = [
+            [0; 10],
+            [1, 1, 6, address, text_offset, text.len() as u32, 0, 0, 4, 0],
+            [
+                7,
+                SHT_SYMTAB,
+                0,
+                0,
+                symtab_offset,
+                symtab.len() as u32,
+                3,
+                1,
+                4,
+                16,
+            ],
+            [15, 3, 0, 0, strtab_offset, strtab.len() as u32, 0, 0, 1, 0],
+            [
+                23,
+                3,
+                0,
+                0,
+                shstrtab_offset,
+                shstrtab.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+        ];
+        for section in sections {
+            for value in section {
+                push32(&mut elf, value);
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn parse() {
+        let text = [0x13, 0, 0, 0, 0x73, 0, 0, 0];
+        let symbols = [
+            ("_start", 0x1000, 4, STT_FUNC),
+            ("exit", 0x1004, 0, 0),
+            ("$x", 0x1000, 0, 0),
+            ("data", 0x2000, 4, STT_OBJECT),
+        ];
+        let elf = Elf::parse(&build(0x1000, &text, &symbols)).unwrap();
+
+        assert_eq!(elf.entry, 0x1000);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segments[0].address, 0x1000);
+        assert_eq!(elf.segments[0].data, text);
+        assert_eq!(elf.segments[0].memory_size, 0x18);
+
+        let text_section = elf.sections.iter().find(|s| s.name == ".text").unwrap();
+        assert!(text_section.executable);
+        assert_eq!(text_section.data, text);
+
+        let names: Vec<_> = elf.symbols.iter().map(|symbol| &symbol.name).collect();
+        assert_eq!(names, ["_start", "exit", "data"]);
+        assert!(elf.symbols.lookup("_start").unwrap().function);
+        assert_eq!(elf.symbols.lookup("data").unwrap().address, 0x2000);
+        assert_eq!(elf.symbols.describe(0x1000), Some("_start".to_string()));
+        assert_eq!(elf.symbols.describe(0x1008), Some("exit+0x4".to_string()));
+        assert_eq!(
+            elf.symbols.describe(0x2004),
+            Some("exit+0x1000".to_string())
+        );
+        assert_eq!(elf.symbols.describe(0xFFF), None);
+        assert_eq!(elf.symbols.range("_start"), Some(0x1000..0x1004));
+        assert_eq!(elf.symbols.range("exit"), Some(0x1004..0x2000));
+        assert_eq!(elf.symbols.range("missing"), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Elf::parse(b"hello").err(), Some(ElfError::NotElf));
+        let elf = build(0, &[], &[]);
+        assert_eq!(Elf::parse(&elf[..40]).err(), Some(ElfError::Truncated));
+
+        let mut elf64 = elf.clone();
+        elf64[4] = 2;
+        assert!(matches!(Elf::parse(&elf64), Err(ElfError::Unsupported(_))));
+    }
+}
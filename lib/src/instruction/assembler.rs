@@ -0,0 +1,1219 @@
+/**
+ * An assembler for GNU-style RISC-V assembly, producing a flat image that can be loaded into
+ * memory and run. It is meant for tests and small programs rather than as a replacement for
+ * binutils, so there are no relocations or object files: everything is assembled at a fixed base
+ * address in two passes, the first to find the labels and the second to encode with them.
+ *
+ * Supported are labels, the RV32I and Zicsr instructions, MRET, `%hi`/`%lo`, the directives
+ * `.text`, `.data`, `.section`, `.word`, `.half`, `.byte`, `.ascii`, `.asciz`, `.string`,
+ * `.zero`, `.space`, `.align`, `.p2align`, `.balign` and `.equ`/`.set`, and the pseudo-
+ * instructions nop, li, la, mv, not, neg, seqz, snez, sltz, sgtz, beqz and friends, bgt, ble,
+ * bgtu, bleu, j, jr, jal with one operand, jalr with one operand, call, tail, ret, csrr, csrw,
+ * csrs, csrc (and their immediate forms) and rdcycle and friends.
+ *
+ * The .data section is placed after the .text section. Operands that are addresses, like branch
+ * targets, are absolute: `beq a0, a1, loop` or `beq a0, a1, 0x80000010`.
+ */
+use super::disassembler::CSR_NAMES;
+use super::encoder::{self, Instruction};
+use crate::cpu::registers::ABI_NAMES;
+use crate::elf::{Symbol, SymbolTable};
+use crate::memory::{Memory, MemoryError};
+use std::collections::HashMap;
+use std::fmt;
+
+type OpConstructor = fn(usize, usize, usize) -> Instruction;
+type ImmediateConstructor = fn(usize, usize, i16) -> Instruction;
+
+const OPS: [(&str, OpConstructor); 10] = [
+    ("add", encoder::add),
+    ("sub", encoder::sub),
+    ("sll", encoder::sll),
+    ("slt", encoder::slt),
+    ("sltu", encoder::sltu),
+    ("xor", encoder::xor),
+    ("srl", encoder::srl),
+    ("sra", encoder::sra),
+    ("or", encoder::or),
+    ("and", encoder::and),
+];
+
+const OP_IMMS: [(&str, ImmediateConstructor); 6] = [
+    ("addi", encoder::addi),
+    ("slti", encoder::slti),
+    ("sltiu", encoder::sltiu),
+    ("xori", encoder::xori),
+    ("ori", encoder::ori),
+    ("andi", encoder::andi),
+];
+
+const SHIFTS: [(&str, ImmediateConstructor); 3] = [
+    ("slli", encoder::slli),
+    ("srli", encoder::srli),
+    ("srai", encoder::srai),
+];
+
+/// The loads take the base register, then the destination register.
+const LOADS: [(&str, ImmediateConstructor); 5] = [
+    ("lb", encoder::lb),
+    ("lh", encoder::lh),
+    ("lw", encoder::lw),
+    ("lbu", encoder::lbu),
+    ("lhu", encoder::lhu),
+];
+
+/// The stores take the base register, then the register to store.
+const STORES: [(&str, ImmediateConstructor); 3] = [
+    ("sb", encoder::sb),
+    ("sh", encoder::sh),
+    ("sw", encoder::sw),
+];
+
+const BRANCHES: [(&str, ImmediateConstructor); 6] = [
+    ("beq", encoder::beq),
+    ("bne", encoder::bne),
+    ("blt", encoder::blt),
+    ("bge", encoder::bge),
+    ("bltu", encoder::bltu),
+    ("bgeu", encoder::bgeu),
+];
+
+/// Branches that compare with the operands swapped, e.g, bgt a0, a1 is blt a1, a0.
+const SWAPPED_BRANCHES: [(&str, ImmediateConstructor); 4] = [
+    ("bgt", encoder::blt),
+    ("ble", encoder::bge),
+    ("bgtu", encoder::bltu),
+    ("bleu", encoder::bgeu),
+];
+
+/// Branches that compare with zero. The flag says whether the register is the second operand.
+const ZERO_BRANCHES: [(&str, ImmediateConstructor, bool); 6] = [
+    ("beqz", encoder::beq, false),
+    ("bnez", encoder::bne, false),
+    ("blez", encoder::bge, true),
+    ("bgez", encoder::bge, false),
+    ("bltz", encoder::blt, false),
+    ("bgtz", encoder::blt, true),
+];
+
+const CSRS: [(&str, ImmediateConstructor); 6] = [
+    ("csrrw", csr_constructor::<0>),
+    ("csrrs", csr_constructor::<1>),
+    ("csrrc", csr_constructor::<2>),
+    ("csrrwi", csr_constructor::<3>),
+    ("csrrsi", csr_constructor::<4>),
+    ("csrrci", csr_constructor::<5>),
+];
+
+const COUNTERS: [(&str, usize); 6] = [
+    ("rdcycle", 0xC00),
+    ("rdtime", 0xC01),
+    ("rdinstret", 0xC02),
+    ("rdcycleh", 0xC80),
+    ("rdtimeh", 0xC81),
+    ("rdinstreth", 0xC82),
+];
+
+/// The CSR instructions in the same shape as the others: the source (a register or a 5 bit
+/// value), the destination and the CSR.
+fn csr_constructor<const KIND: usize>(source: usize, rd: usize, csr: i16) -> Instruction {
+    let csr = csr as u16 as usize;
+    match KIND {
+        0 => encoder::csrrw(source, rd, csr),
+        1 => encoder::csrrs(source, rd, csr),
+        2 => encoder::csrrc(source, rd, csr),
+        3 => encoder::csrrwi(source, rd, csr),
+        4 => encoder::csrrsi(source, rd, csr),
+        _ => encoder::csrrci(source, rd, csr),
+    }
+}
+
+/// A program assembled at base, ready to be loaded.
+#[derive(Debug)]
+pub struct Image {
+    pub base: u32,
+    /// The address of _start if there is one, otherwise the base
+    pub entry: u32,
+    pub bytes: Vec<u8>,
+    pub symbols: SymbolTable,
+}
+
+impl Image {
+    /// Copy the image into memory at its base address.
+    pub fn load(&self, memory: &mut Memory) -> Result<(), MemoryError> {
+        memory.load_slice(self.base as usize, &self.bytes)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AssemblerError {
+    /// The line the error is on, counting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Data,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Definition {
+    /// A label at an offset into a section
+    Label(Section, u32),
+    /// A value given with .equ or .set
+    Constant(i64),
+}
+
+/// The high 20 bits of value, rounded so that adding the sign extended %lo gives value back.
+fn hi(value: i64) -> i64 {
+    ((value + 0x800) >> 12) & 0xFFFFF
+}
+
+/// The low 12 bits of value, sign extended.
+fn lo(value: i64) -> i64 {
+    ((value & 0xFFF) ^ 0x800) - 0x800
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$'
+}
+
+fn is_symbol(text: &str) -> bool {
+    text.starts_with(is_symbol_start)
+        && text
+            .chars()
+            .all(|c| is_symbol_start(c) || c.is_ascii_digit())
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (digits, radix) = match text.get(..2) {
+        Some("0x" | "0X") => (&text[2..], 16),
+        Some("0b" | "0B") => (&text[2..], 2),
+        _ => (text, 10),
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+fn register(text: &str) -> Result<usize, String> {
+    let index = match text {
+        "fp" => Some(8),
+        _ => text
+            .strip_prefix('x')
+            .and_then(|number| number.parse().ok())
+            .filter(|&number| number < 32)
+            .or_else(|| ABI_NAMES.iter().position(|&name| name == text)),
+    };
+    index.ok_or_else(|| format!("unknown register {text}"))
+}
+
+/// Read one escaped character out of a string or character literal.
+fn escape(chars: &mut std::str::Chars) -> Result<u8, String> {
+    match chars.next() {
+        Some('n') => Ok(b'\n'),
+        Some('t') => Ok(b'\t'),
+        Some('r') => Ok(b'\r'),
+        Some('0') => Ok(0),
+        Some(c @ ('\\' | '"' | '\'')) => Ok(c as u8),
+        Some(c) => Err(format!("unknown escape \\{c}")),
+        None => Err("unterminated string".to_string()),
+    }
+}
+
+/// Parse a comma separated list of string literals.
+fn strings(text: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut strings = Vec::new();
+    let mut chars = text.trim().chars();
+    loop {
+        if chars.next() != Some('"') {
+            return Err(format!("expected a string in {text}"));
+        }
+        let mut bytes = Vec::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => bytes.push(escape(&mut chars)?),
+                Some(c) => {
+                    let mut buffer = [0; 4];
+                    bytes.extend(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        strings.push(bytes);
+
+        let rest = chars.as_str().trim();
+        match rest.strip_prefix(',') {
+            Some(rest) => chars = rest.trim_start().chars(),
+            None if rest.is_empty() => return Ok(strings),
+            None => return Err(format!("unexpected {rest} after string")),
+        }
+    }
+}
+
+/// Remove a # comment, leaving any # inside a string or character literal alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Split operands on the commas that are not inside parentheses.
+fn operands(text: &str) -> Vec<&str> {
+    if text.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+/// A recursive descent parser for operand expressions: numbers, character literals, symbols,
+/// %hi(...), %lo(...) and parentheses, combined with + and -.
+struct Expression<'a, F> {
+    text: &'a str,
+    position: usize,
+    symbol: F,
+}
+
+impl<F: FnMut(&str) -> Result<i64, String>> Expression<'_, F> {
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.position..];
+        self.position += rest.len() - rest.trim_start().len();
+        self.text[self.position..].chars().next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(format!("expected {expected} in {}", self.text)),
+        }
+    }
+
+    /// Take the characters that could make up a number or symbol.
+    fn word(&mut self) -> &str {
+        let rest = &self.text[self.position..];
+        let length = rest
+            .find(|c: char| !is_symbol_start(c) && !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        self.position += length;
+        &rest[..length]
+    }
+
+    fn expression(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.position += 1;
+                    value = value.wrapping_add(self.term()?);
+                }
+                Some('-') => {
+                    self.position += 1;
+                    value = value.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.position += 1;
+                Ok(self.term()?.wrapping_neg())
+            }
+            Some('(') => {
+                self.position += 1;
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some('%') => {
+                self.position += 1;
+                let function = self.word().to_string();
+                self.expect('(')?;
+                let value = self.expression()?;
+                self.expect(')')?;
+                match function.as_str() {
+                    "hi" => Ok(hi(value)),
+                    "lo" => Ok(lo(value)),
+                    _ => Err(format!("unknown function %{function}")),
+                }
+            }
+            Some('\'') => {
+                let mut chars = self.text[self.position + 1..].chars();
+                let value = match chars.next() {
+                    Some('\\') => escape(&mut chars)?,
+                    Some(c) if c.is_ascii() => c as u8,
+                    _ => return Err(format!("invalid character in {}", self.text)),
+                };
+                if chars.next() != Some('\'') {
+                    return Err(format!("unterminated character in {}", self.text));
+                }
+                self.position = self.text.len() - chars.as_str().len();
+                Ok(value as i64)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let word = self.word();
+                parse_number(word).ok_or_else(|| format!("invalid number {word}"))
+            }
+            Some(c) if is_symbol_start(c) => {
+                let word = self.word().to_string();
+                (self.symbol)(&word)
+            }
+            _ => Err(format!("invalid expression {}", self.text)),
+        }
+    }
+}
+
+fn evaluate(text: &str, symbol: impl FnMut(&str) -> Result<i64, String>) -> Result<i64, String> {
+    let mut expression = Expression {
+        text,
+        position: 0,
+        symbol,
+    };
+    let value = expression.expression()?;
+    match expression.peek() {
+        None => Ok(value),
+        Some(_) => Err(format!("invalid expression {text}")),
+    }
+}
+
+fn expect_operands(mnemonic: &str, operands: &[&str], count: usize) -> Result<(), String> {
+    match operands.len() == count {
+        true => Ok(()),
+        false => Err(format!("{mnemonic} takes {count} operands")),
+    }
+}
+
+fn find<T: Copy>(table: &[(&str, T)], mnemonic: &str) -> Option<T> {
+    table
+        .iter()
+        .find(|&&(name, _)| name == mnemonic)
+        .map(|&(_, value)| value)
+}
+
+struct Assembler {
+    base: u32,
+    /// Where .data starts, which is only known after the first pass
+    data_base: u32,
+    /// On the first pass labels may not be defined yet, so undefined symbols are taken as zero
+    /// and values are not range checked.
+    final_pass: bool,
+    definitions: HashMap<String, Definition>,
+    section: Section,
+    text: Vec<u8>,
+    data: Vec<u8>,
+    data_alignment: u32,
+}
+
+impl Assembler {
+    fn new(base: u32) -> Self {
+        Self {
+            base,
+            data_base: 0,
+            final_pass: false,
+            definitions: HashMap::new(),
+            section: Section::Text,
+            text: Vec::new(),
+            data: Vec::new(),
+            data_alignment: 4,
+        }
+    }
+
+    fn bytes(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Text => &mut self.text,
+            Section::Data => &mut self.data,
+        }
+    }
+
+    fn pc(&self) -> u32 {
+        match self.section {
+            Section::Text => self.base.wrapping_add(self.text.len() as u32),
+            Section::Data => self.data_base.wrapping_add(self.data.len() as u32),
+        }
+    }
+
+    fn address(&self, definition: Definition) -> i64 {
+        match definition {
+            Definition::Label(Section::Text, offset) => self.base.wrapping_add(offset) as i64,
+            Definition::Label(Section::Data, offset) => self.data_base.wrapping_add(offset) as i64,
+            Definition::Constant(value) => value,
+        }
+    }
+
+    fn define(&mut self, name: &str, definition: Definition) -> Result<(), String> {
+        // The second pass already has everything from the first
+        if self.final_pass {
+            return Ok(());
+        }
+        match self.definitions.insert(name.to_string(), definition) {
+            Some(_) => Err(format!("{name} is already defined")),
+            None => Ok(()),
+        }
+    }
+
+    fn value(&self, text: &str) -> Result<i64, String> {
+        evaluate(text, |name| match self.definitions.get(name) {
+            Some(&definition) => Ok(self.address(definition)),
+            None if self.final_pass => Err(format!("undefined symbol {name}")),
+            None => Ok(0),
+        })
+    }
+
+    /// Evaluate an expression that can't refer to labels, as the size of what it is used for
+    /// depends on its value.
+    fn constant(&self, text: &str) -> Result<i64, String> {
+        evaluate(text, |name| match self.definitions.get(name) {
+            Some(&Definition::Constant(value)) => Ok(value),
+            _ => Err(format!("{name} is not a constant")),
+        })
+    }
+
+    /// Check that value is in min..=max.
+    fn range(&self, value: i64, min: i64, max: i64) -> Result<i64, String> {
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false if !self.final_pass => Ok(0),
+            false => Err(format!("{value} is out of range {min} to {max}")),
+        }
+    }
+
+    fn signed(&self, value: i64, bits: u32) -> Result<i64, String> {
+        self.range(value, -(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    }
+
+    /// The offset from the PC to the address in text, for a branch or jump with bits of range.
+    fn offset(&self, text: &str, bits: u32) -> Result<i64, String> {
+        let offset = self.value(text)?.wrapping_sub(self.pc() as i64) as i32 as i64;
+        match offset % 2 {
+            0 => self.signed(offset, bits),
+            _ if !self.final_pass => Ok(0),
+            _ => Err(format!("branch target {text} is not aligned")),
+        }
+    }
+
+    /// A 12 bit immediate.
+    fn immediate(&self, text: &str) -> Result<i16, String> {
+        Ok(self.signed(self.value(text)?, 12)? as i16)
+    }
+
+    /// A 20 bit upper immediate, either signed or unsigned.
+    fn upper(&self, text: &str) -> Result<u32, String> {
+        let value = self.range(self.value(text)?, -0x80000, 0xFFFFF)?;
+        Ok(((value as u32) & 0xFFFFF) << 12)
+    }
+
+    /// A memory operand, offset(register), where the offset is optional.
+    fn memory_operand(&self, text: &str) -> Result<(usize, i16), String> {
+        let invalid = || format!("expected offset(register), not {text}");
+        let (offset, base) = text
+            .strip_suffix(')')
+            .and_then(|text| text.rsplit_once('('))
+            .ok_or_else(invalid)?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.immediate(offset)?,
+        };
+        Ok((register(base.trim())?, offset))
+    }
+
+    fn csr(&self, text: &str) -> Result<i16, String> {
+        match CSR_NAMES.iter().find(|&&(_, name)| name == text) {
+            Some(&(csr, _)) => Ok(csr as i16),
+            None => Ok(self.range(self.constant(text)?, 0, 0xFFF)? as i16),
+        }
+    }
+
+    /// A FENCE predecessor or successor set, e.g, rw.
+    fn fence_set(text: &str) -> Result<u8, String> {
+        text.chars().try_fold(0, |set, c| match c {
+            'i' => Ok(set | 8),
+            'o' => Ok(set | 4),
+            'r' => Ok(set | 2),
+            'w' => Ok(set | 1),
+            _ if text == "0" => Ok(0),
+            _ => Err(format!("invalid fence set {text}")),
+        })
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let word = instruction.encode();
+        self.bytes().extend(word.to_le_bytes());
+    }
+
+    /// Emit an AUIPC and the instruction built from the low part of the offset to target, as
+    /// used by la, call and tail.
+    fn pc_relative(
+        &mut self,
+        rd: usize,
+        target: &str,
+        low: impl FnOnce(i16) -> Instruction,
+    ) -> Result<(), String> {
+        let offset = self.value(target)?.wrapping_sub(self.pc() as i64) as i32 as i64;
+        self.emit(encoder::auipc(rd, (hi(offset) as u32) << 12));
+        self.emit(low(lo(offset) as i16));
+        Ok(())
+    }
+
+    fn align(&mut self, alignment: u32) -> Result<(), String> {
+        if alignment == 0 || !alignment.is_power_of_two() {
+            return Err(format!("invalid alignment {alignment}"));
+        }
+        if self.section == Section::Data {
+            self.data_alignment = self.data_alignment.max(alignment);
+        }
+
+        let section = self.section;
+        let bytes = self.bytes();
+        while !bytes.len().is_multiple_of(alignment as usize) {
+            // Code is padded with NOPs where it can be
+            match section == Section::Text && bytes.len().is_multiple_of(4) {
+                true => bytes.extend(encoder::no_op().encode().to_le_bytes()),
+                false => bytes.push(0),
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit .byte, .half or .word values.
+    fn values(&mut self, operands: &[&str], size: usize) -> Result<(), String> {
+        let bits = size as u32 * 8;
+        for operand in operands {
+            let value = self.range(self.value(operand)?, -(1 << (bits - 1)), (1 << bits) - 1)?;
+            self.bytes().extend(&value.to_le_bytes()[..size]);
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &str, rest: &str) -> Result<(), String> {
+        let operands = operands(rest);
+        match directive {
+            ".text" => self.section = Section::Text,
+            ".data" | ".rodata" | ".bss" => self.section = Section::Data,
+            ".section" => match operands.first() {
+                Some(name) if name.starts_with(".text") => self.section = Section::Text,
+                Some(_) => self.section = Section::Data,
+                None => return Err(".section needs a name".to_string()),
+            },
+            ".globl" | ".global" | ".local" | ".type" | ".size" | ".file" | ".option" => (),
+            ".byte" => self.values(&operands, 1)?,
+            ".half" | ".short" | ".2byte" => self.values(&operands, 2)?,
+            ".word" | ".long" | ".4byte" => self.values(&operands, 4)?,
+            ".ascii" => {
+                for string in strings(rest)? {
+                    self.bytes().extend(string);
+                }
+            }
+            ".asciz" | ".string" => {
+                // Each string gets its own terminator
+                for string in strings(rest)? {
+                    self.bytes().extend(string);
+                    self.bytes().push(0);
+                }
+            }
+            ".zero" | ".space" => {
+                expect_operands(directive, &operands, 1)?;
+                let size = self.range(self.constant(operands[0])?, 0, 1 << 24)?;
+                let length = self.bytes().len() + size as usize;
+                self.bytes().resize(length, 0);
+            }
+            ".align" | ".p2align" => {
+                expect_operands(directive, &operands, 1)?;
+                let power = self.range(self.constant(operands[0])?, 0, 12)?;
+                self.align(1 << power)?;
+            }
+            ".balign" => {
+                expect_operands(directive, &operands, 1)?;
+                let alignment = self.range(self.constant(operands[0])?, 1, 1 << 12)?;
+                self.align(alignment as u32)?;
+            }
+            ".equ" | ".set" => {
+                expect_operands(directive, &operands, 2)?;
+                if !is_symbol(operands[0]) {
+                    return Err(format!("invalid symbol {}", operands[0]));
+                }
+                let value = self.constant(operands[1])?;
+                self.define(operands[0], Definition::Constant(value))?;
+            }
+            _ => return Err(format!("unknown directive {directive}")),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[&str]) -> Result<(), String> {
+        let count = |count| expect_operands(mnemonic, operands, count);
+
+        if let Some(constructor) = find(&OPS, mnemonic) {
+            count(3)?;
+            let [rd, rs1, rs2] = [0, 1, 2].map(|index| register(operands[index]));
+            self.emit(constructor(rd?, rs1?, rs2?));
+        } else if let Some(constructor) = find(&OP_IMMS, mnemonic) {
+            count(3)?;
+            let immediate = self.immediate(operands[2])?;
+            self.emit(constructor(
+                register(operands[0])?,
+                register(operands[1])?,
+                immediate,
+            ));
+        } else if let Some(constructor) = find(&SHIFTS, mnemonic) {
+            count(3)?;
+            let shift = self.range(self.value(operands[2])?, 0, 31)?;
+            self.emit(constructor(
+                register(operands[0])?,
+                register(operands[1])?,
+                shift as i16,
+            ));
+        } else if let Some(constructor) = find(&LOADS, mnemonic) {
+            count(2)?;
+            let (base, offset) = self.memory_operand(operands[1])?;
+            self.emit(constructor(base, register(operands[0])?, offset));
+        } else if let Some(constructor) = find(&STORES, mnemonic) {
+            count(2)?;
+            let (base, offset) = self.memory_operand(operands[1])?;
+            self.emit(constructor(base, register(operands[0])?, offset));
+        } else if let Some(constructor) = find(&BRANCHES, mnemonic) {
+            count(3)?;
+            let offset = self.offset(operands[2], 13)? as i16;
+            self.emit(constructor(
+                register(operands[0])?,
+                register(operands[1])?,
+                offset,
+            ));
+        } else if let Some(constructor) = find(&SWAPPED_BRANCHES, mnemonic) {
+            count(3)?;
+            let offset = self.offset(operands[2], 13)? as i16;
+            self.emit(constructor(
+                register(operands[1])?,
+                register(operands[0])?,
+                offset,
+            ));
+        } else if let Some(&(_, constructor, second)) =
+            ZERO_BRANCHES.iter().find(|&&(name, ..)| name == mnemonic)
+        {
+            count(2)?;
+            let rs = register(operands[0])?;
+            let offset = self.offset(operands[1], 13)? as i16;
+            self.emit(match second {
+                true => constructor(0, rs, offset),
+                false => constructor(rs, 0, offset),
+            });
+        } else if let Some(constructor) = find(&CSRS, mnemonic) {
+            count(3)?;
+            let rd = register(operands[0])?;
+            let csr = self.csr(operands[1])?;
+            let source = match mnemonic.ends_with('i') {
+                true => self.range(self.value(operands[2])?, 0, 31)? as usize,
+                false => register(operands[2])?,
+            };
+            self.emit(constructor(source, rd, csr));
+        } else if let Some(csr) = find(&COUNTERS, mnemonic) {
+            count(1)?;
+            self.emit(encoder::csrrs(0, register(operands[0])?, csr));
+        } else {
+            self.pseudo_instruction(mnemonic, operands)?;
+        }
+        Ok(())
+    }
+
+    /// The instructions that are not in one of the tables.
+    fn pseudo_instruction(&mut self, mnemonic: &str, operands: &[&str]) -> Result<(), String> {
+        let count = |count| expect_operands(mnemonic, operands, count);
+        let operand = |index: usize| register(operands[index]);
+
+        match (mnemonic, operands.len()) {
+            ("lui", _) => {
+                count(2)?;
+                let value = self.upper(operands[1])?;
+                self.emit(encoder::lui(operand(0)?, value));
+            }
+            ("auipc", _) => {
+                count(2)?;
+                let value = self.upper(operands[1])?;
+                self.emit(encoder::auipc(operand(0)?, value));
+            }
+            ("jal", 1) | ("j", _) => {
+                count(1)?;
+                let rd = match mnemonic {
+                    "jal" => 1,
+                    _ => 0,
+                };
+                let offset = self.offset(operands[0], 21)?;
+                self.emit(encoder::jal(rd, offset as i32));
+            }
+            ("jal", _) => {
+                count(2)?;
+                let offset = self.offset(operands[1], 21)?;
+                self.emit(encoder::jal(operand(0)?, offset as i32));
+            }
+            ("jalr", 1) => self.emit(encoder::jalr(1, operand(0)?, 0)),
+            ("jalr", 2) => {
+                let (base, offset) = self.memory_operand(operands[1])?;
+                self.emit(encoder::jalr(operand(0)?, base, offset));
+            }
+            ("jalr", _) => {
+                count(3)?;
+                let offset = self.immediate(operands[2])?;
+                self.emit(encoder::jalr(operand(0)?, operand(1)?, offset));
+            }
+            ("jr", _) => {
+                count(1)?;
+                self.emit(encoder::jalr(0, operand(0)?, 0));
+            }
+            ("ret", _) => {
+                count(0)?;
+                self.emit(encoder::jalr(0, 1, 0));
+            }
+            ("call", _) => {
+                count(1)?;
+                self.pc_relative(1, operands[0], |offset| encoder::jalr(1, 1, offset))?;
+            }
+            ("tail", _) => {
+                count(1)?;
+                self.pc_relative(6, operands[0], |offset| encoder::jalr(0, 6, offset))?;
+            }
+            ("la", _) => {
+                count(2)?;
+                let rd = operand(0)?;
+                self.pc_relative(rd, operands[1], |offset| encoder::addi(rd, rd, offset))?;
+            }
+            ("li", _) => {
+                count(2)?;
+                let rd = operand(0)?;
+                let value = self.constant(operands[1])?;
+                let value = self.range(value, i32::MIN as i64, u32::MAX as i64)? as i32 as i64;
+                match (-2048..2048).contains(&value) {
+                    true => self.emit(encoder::addi(rd, 0, value as i16)),
+                    false => {
+                        self.emit(encoder::lui(rd, (hi(value) as u32) << 12));
+                        if lo(value) != 0 {
+                            self.emit(encoder::addi(rd, rd, lo(value) as i16));
+                        }
+                    }
+                }
+            }
+            ("nop", _) => {
+                count(0)?;
+                self.emit(encoder::no_op());
+            }
+            ("mv", _) => {
+                count(2)?;
+                self.emit(encoder::addi(operand(0)?, operand(1)?, 0));
+            }
+            ("not", _) => {
+                count(2)?;
+                self.emit(encoder::xori(operand(0)?, operand(1)?, -1));
+            }
+            ("neg", _) => {
+                count(2)?;
+                self.emit(encoder::sub(operand(0)?, 0, operand(1)?));
+            }
+            ("seqz", _) => {
+                count(2)?;
+                self.emit(encoder::sltiu(operand(0)?, operand(1)?, 1));
+            }
+            ("snez", _) => {
+                count(2)?;
+                self.emit(encoder::sltu(operand(0)?, 0, operand(1)?));
+            }
+            ("sltz", _) => {
+                count(2)?;
+                self.emit(encoder::slt(operand(0)?, operand(1)?, 0));
+            }
+            ("sgtz", _) => {
+                count(2)?;
+                self.emit(encoder::slt(operand(0)?, 0, operand(1)?));
+            }
+            ("csrr", _) => {
+                count(2)?;
+                let csr = self.csr(operands[1])? as usize;
+                self.emit(encoder::csrrs(0, operand(0)?, csr));
+            }
+            ("csrw" | "csrs" | "csrc", _) => {
+                count(2)?;
+                let constructor = find(&CSRS, &format!("csrr{}", &mnemonic[3..])).unwrap();
+                let csr = self.csr(operands[0])?;
+                self.emit(constructor(operand(1)?, 0, csr));
+            }
+            ("csrwi" | "csrsi" | "csrci", _) => {
+                count(2)?;
+                let constructor = find(&CSRS, &format!("csrr{}", &mnemonic[3..])).unwrap();
+                let csr = self.csr(operands[0])?;
+                let value = self.range(self.value(operands[1])?, 0, 31)?;
+                self.emit(constructor(value as usize, 0, csr));
+            }
+            ("fence", 0) => self.emit(Instruction::Fence {
+                pred: 0b1111,
+                succ: 0b1111,
+            }),
+            ("fence", _) => {
+                count(2)?;
+                let pred = Self::fence_set(operands[0])?;
+                let succ = Self::fence_set(operands[1])?;
+                self.emit(Instruction::Fence { pred, succ });
+            }
+            ("fence.i", _) => {
+                count(0)?;
+                self.emit(encoder::fence_i());
+            }
+            ("ecall", _) => {
+                count(0)?;
+                self.emit(encoder::ecall());
+            }
+            ("ebreak", _) => {
+                count(0)?;
+                self.emit(encoder::ebreak());
+            }
+            ("mret", _) => {
+                count(0)?;
+                self.emit(encoder::mret());
+            }
+            _ => return Err(format!("unknown instruction {mnemonic}")),
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, line: &str) -> Result<(), String> {
+        let mut rest = strip_comment(line).trim();
+
+        // Any number of labels can come before a statement
+        while let Some((label, after)) = rest.split_once(':') {
+            if !is_symbol(label) {
+                break;
+            }
+            let offset = self.bytes().len() as u32;
+            self.define(label, Definition::Label(self.section, offset))?;
+            rest = after.trim();
+        }
+
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        match mnemonic.starts_with('.') {
+            true => self.directive(mnemonic, rest),
+            false => self.instruction(&mnemonic.to_ascii_lowercase(), &operands(rest)),
+        }
+    }
+
+    fn pass(&mut self, source: &str) -> Result<(), AssemblerError> {
+        for (index, line) in source.lines().enumerate() {
+            self.statement(line).map_err(|message| AssemblerError {
+                line: index + 1,
+                message,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Assemble source to run at base.
+pub fn assemble(source: &str, base: u32) -> Result<Image, AssemblerError> {
+    let mut first = Assembler::new(base);
+    first.pass(source)?;
+
+    // The data goes after the code, aligned to the largest alignment it asked for
+    let text_end = base.wrapping_add(first.text.len() as u32);
+    let data_base = text_end.next_multiple_of(first.data_alignment);
+    let text_size = data_base.wrapping_sub(base) as usize;
+
+    let mut assembler = Assembler::new(base);
+    assembler.data_base = data_base;
+    assembler.final_pass = true;
+    assembler.definitions = first.definitions;
+    assembler.pass(source)?;
+
+    let mut symbols = Vec::new();
+    for (name, &definition) in &assembler.definitions {
+        if let Definition::Label(section, _) = definition {
+            symbols.push(Symbol {
+                name: name.clone(),
+                address: assembler.address(definition) as u32,
+                size: 0,
+                function: section == Section::Text,
+            });
+        }
+    }
+    let entry = match assembler.definitions.get("_start") {
+        Some(&definition) => assembler.address(definition) as u32,
+        None => base,
+    };
+
+    let mut bytes = assembler.text;
+    bytes.resize(text_size, 0);
+    bytes.extend(assembler.data);
+
+    Ok(Image {
+        base,
+        entry,
+        bytes,
+        symbols: SymbolTable::new(symbols),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::rv32i::{Cpu, StepState};
+
+    fn words(source: &str, base: u32) -> Vec<u32> {
+        let image = assemble(source, base).unwrap();
+        image
+            .bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn error(source: &str) -> AssemblerError {
+        assemble(source, 0).unwrap_err()
+    }
+
+    #[test]
+    fn instructions_match_the_encoder() {
+        let source = "
+            addi sp, sp, -16
+            sw ra, 12(sp)
+            lbu a0, (a1)
+            xor t0, t1, t2
+            srai s1, s2, 31
+            lui a0, 0x12345
+            auipc x5, 1
+            jalr t0, 4(t1)
+            csrrw a1, mtvec, a0
+            csrrsi zero, 0x7C0, 3
+            ecall
+            fence
+        ";
+        let expected = [
+            encoder::addi(2, 2, -16),
+            encoder::sw(2, 1, 12),
+            encoder::lbu(11, 10, 0),
+            encoder::xor(5, 6, 7),
+            encoder::srai(9, 18, 31),
+            encoder::lui(10, 0x1234_5000),
+            encoder::auipc(5, 0x1000),
+            encoder::jalr(5, 6, 4),
+            encoder::csrrw(10, 11, 0x305),
+            encoder::csrrsi(3, 0, 0x7C0),
+            encoder::ecall(),
+            Instruction::Fence {
+                pred: 0b1111,
+                succ: 0b1111,
+            },
+        ]
+        .map(|instruction| instruction.encode());
+        assert_eq!(words(source, 0), expected);
+    }
+
+    #[test]
+    fn far_jumps_and_fences_match_binutils() {
+        let source = "
+            j 0x800
+            jal 0x1004
+            fence
+            fence.i
+        ";
+        assert_eq!(
+            words(source, 0),
+            [0x0010_006f, 0x0000_10ef, 0x0ff0_000f, 0x0000_100f]
+        );
+    }
+
+    #[test]
+    fn labels_and_pseudo_instructions() {
+        let source = "
+            _start:
+                li a0, 5
+                li a1, 0x12345678
+                li a2, 0x1000
+            loop:   addi a0, a0, -1
+                bnez a0, loop
+                j end
+                call func
+            end: ret
+            func:
+                mv a1, a0
+        ";
+        let expected = [
+            encoder::addi(10, 0, 5),
+            encoder::lui(11, 0x1234_5000),
+            encoder::addi(11, 11, 0x678),
+            encoder::lui(12, 0x1000),
+            encoder::addi(10, 10, -1),
+            encoder::bne(10, 0, -4),
+            encoder::jal(0, 12),
+            encoder::auipc(1, 0),
+            encoder::jalr(1, 1, 12),
+            encoder::jalr(0, 1, 0),
+            encoder::addi(11, 10, 0),
+        ]
+        .map(|instruction| instruction.encode());
+        assert_eq!(words(source, 0x8000_0000), expected);
+
+        let image = assemble(source, 0x8000_0000).unwrap();
+        assert_eq!(image.entry, 0x8000_0000);
+        assert_eq!(image.symbols.lookup("func").unwrap().address, 0x8000_0028);
+        assert_eq!(
+            image.symbols.describe(0x8000_0014).unwrap(),
+            "loop+0x4".to_string()
+        );
+    }
+
+    #[test]
+    fn data_and_relocations() {
+        let source = r##"
+            .equ COUNT, 2
+            .text
+            lui a0, %hi(table)
+            lw a1, %lo(table)(a0)
+            la a2, message + 1
+            .data
+            .byte 1, -1, 'A'
+            .align 3
+            table: .word COUNT, table, 0xFFFFFFFF
+            message: .asciz "hi\n", "#,"
+            .half 0x1234
+        "##;
+        let image = assemble(source, 0x1FF0).unwrap();
+
+        // Four instructions of code, then the data aligned to 8 bytes
+        let table = 0x1FF0 + 0x10 + 8;
+        assert_eq!(image.symbols.lookup("table").unwrap().address, table);
+        assert_eq!(
+            &image.bytes[..8],
+            [
+                encoder::lui(10, 0x2000).encode().to_le_bytes(),
+                encoder::lw(10, 11, 0x8).encode().to_le_bytes(),
+            ]
+            .concat()
+        );
+        // la is PC relative: table + 12 + 1 - 0x1FF8
+        assert_eq!(
+            &image.bytes[8..16],
+            [
+                encoder::auipc(12, 0).encode().to_le_bytes(),
+                encoder::addi(12, 12, 0x1D).encode().to_le_bytes(),
+            ]
+            .concat()
+        );
+        let mut data = vec![1, 0xFF, b'A', 0, 0, 0, 0, 0, 2, 0, 0, 0];
+        data.extend(table.to_le_bytes());
+        data.extend([
+            0xFF, 0xFF, 0xFF, 0xFF, b'h', b'i', b'\n', 0, b'#', b',', 0, 0x34, 0x12,
+        ]);
+        assert_eq!(&image.bytes[0x10..], data);
+    }
+
+    #[test]
+    fn runs() {
+        // Sum the bytes of a string, calling a function for each
+        let source = r#"
+            .data
+            string: .asciz "abc"
+            .text
+            .globl _start
+            _start:
+                la s0, string
+                li s1, 0
+            next:
+                lbu a0, 0(s0)
+                beqz a0, done
+                call add
+                addi s0, s0, 1
+                j next
+            add:
+                add s1, s1, a0
+                ret
+            done:
+                li t0, 0x100000
+                sw s1, 0(t0)
+                li a0, 0
+                ecall
+        "#;
+        let image = assemble(source, 0x100).unwrap();
+        let mut memory = Memory::new(0x100400);
+        image.load(&mut memory).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.state.registers.pc = image.entry;
+
+        while cpu.step(&mut memory) == StepState::Continue {}
+        assert_eq!(memory.get32(0x100000).unwrap(), 0x61 + 0x62 + 0x63);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("nop\n  frob a0"),
+            AssemblerError {
+                line: 2,
+                message: "unknown instruction frob".to_string()
+            }
+        );
+        assert_eq!(error("j nowhere").message, "undefined symbol nowhere");
+        assert_eq!(error("a:\na: nop").message, "a is already defined");
+        assert_eq!(error("add a0, a1").message, "add takes 3 operands");
+        assert_eq!(
+            error("addi a0, a1, 2048").message,
+            "2048 is out of range -2048 to 2047"
+        );
+        assert_eq!(error("lw a0, 4(q0)").message, "unknown register q0");
+        assert_eq!(
+            error("li a0, label\nlabel:").message,
+            "label is not a constant"
+        );
+        assert_eq!(
+            error(".align 1\nb: .byte 1\nbeq a0, a1, b").message,
+            "branch target b is not aligned"
+        );
+        assert_eq!(error(".frob").message, "unknown directive .frob");
+        assert_eq!(error(".asciz \"abc").message, "unterminated string");
+        assert_eq!(
+            error(".zero 4\nbeq a0, a1, 0x2000").message,
+            "8188 is out of range -4096 to 4095"
+        );
+        assert_eq!(
+            error("ret # done\n.word 1 +").to_string(),
+            "line 2: invalid expression 1 +"
+        );
+    }
+}
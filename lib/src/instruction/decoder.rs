@@ -1,3 +1,6 @@
+use super::encoder::Instruction;
+use super::funct3::{branch, load, op, op_imm, store, system};
+use super::opcodes;
 use super::util::{
     extract, C_10_BITS, C_11_BITS, C_3_BITS, C_4_BITS, C_5_BITS, C_6_BITS, C_7_BITS, C_8_BITS,
     SIGN_BIT,
@@ -110,6 +113,166 @@ pub const fn j_type_immediate_32(instruction: u32) -> i32 {
     sign_extend_32(bits_12_to_19 | bit_11 | bits_10_to_1, 20, sign_bit)
 }
 
+/// A word that is not a valid RV32I or Zicsr instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IllegalInstruction(pub u32);
+
+const FUNCT7_SWITCH: u8 = 0b0100000;
+
+/// Decode a word into an Instruction. Words with an unknown opcode or funct3, or with a
+/// reserved field (e.g, funct7 on most OP instructions or rd on a FENCE.I) that is not zero, are
+/// illegal. Decoding a word and encoding the result gives back the same word.
+pub const fn decode(instruction: u32) -> Result<Instruction, IllegalInstruction> {
+    let illegal = Err(IllegalInstruction(instruction));
+    let destination_register = rd(instruction);
+    let source_register1 = rs1(instruction);
+    let source_register2 = rs2(instruction);
+    let funct3 = funct3(instruction);
+    let funct7 = funct7(instruction);
+
+    let decoded = match opcode(instruction) {
+        opcodes::OP_IMM => {
+            let valid = match funct3 {
+                op_imm::SLLI => funct7 == 0,
+                op_imm::SRLI_OR_SRAI => funct7 == 0 || funct7 == FUNCT7_SWITCH,
+                _ => true,
+            };
+            if !valid {
+                return illegal;
+            }
+            Instruction::OpImm {
+                destination_register,
+                source_register: source_register1,
+                funct3,
+                immediate: i_type_immediate_32(instruction) as i16,
+            }
+        }
+        opcodes::OP => {
+            let valid = match funct7 {
+                0 => true,
+                FUNCT7_SWITCH => funct3 == op::ADD_OR_SUB || funct3 == op::SRL_OR_SRA,
+                _ => false,
+            };
+            if !valid {
+                return illegal;
+            }
+            Instruction::Op {
+                destination_register,
+                source_register1,
+                source_register2,
+                funct3,
+                funct7,
+            }
+        }
+        opcodes::LUI => Instruction::Lui {
+            destination_register,
+            value: u_type_immediate(instruction) as u32,
+        },
+        opcodes::AUIPC => Instruction::Auipc {
+            destination_register,
+            value: u_type_immediate(instruction) as u32,
+        },
+        opcodes::JAL => Instruction::Jal {
+            destination_register,
+            address_offset: j_type_immediate_32(instruction),
+        },
+        opcodes::JALR if funct3 == 0 => Instruction::Jalr {
+            destination_register,
+            source_register: source_register1,
+            address_offset: i_type_immediate_32(instruction) as i16,
+        },
+        opcodes::BRANCH => match funct3 {
+            branch::BEQ | branch::BNE | branch::BLT | branch::BGE | branch::BLTU | branch::BGEU => {
+                Instruction::Branch {
+                    funct3,
+                    source_register1,
+                    source_register2,
+                    branch_offset: b_type_immediate_32(instruction) as i16,
+                }
+            }
+            _ => return illegal,
+        },
+        opcodes::LOAD => match funct3 {
+            load::LB | load::LH | load::LW | load::LBU | load::LHU => Instruction::Load {
+                funct3,
+                source_register: source_register1,
+                destination_register,
+                offset: i_type_immediate_32(instruction) as i16,
+            },
+            _ => return illegal,
+        },
+        opcodes::STORE => match funct3 {
+            store::SB | store::SH | store::SW => Instruction::Store {
+                funct3,
+                source_register1,
+                source_register2,
+                offset: s_type_immediate_32(instruction) as i16,
+            },
+            _ => return illegal,
+        },
+        // FENCE.TSO and the other fence modes in the top four bits are not supported
+        opcodes::FENCE if destination_register != 0 || source_register1 != 0 => return illegal,
+        opcodes::FENCE if funct3 == 0 && instruction >> 28 == 0 => Instruction::Fence {
+            pred: extract(instruction, 24, C_4_BITS) as u8,
+            succ: extract(instruction, 20, C_4_BITS) as u8,
+        },
+        opcodes::FENCE if funct3 == 1 && instruction >> 20 == 0 => Instruction::FenceI {},
+        opcodes::SYSTEM => match funct3 {
+            system::ECALL_OR_EBREAK => match instruction {
+                0x0000_0073 => Instruction::ECall,
+                0x0010_0073 => Instruction::EBreak,
+                0x3020_0073 => Instruction::MRet,
+                _ => return illegal,
+            },
+            system::CSRRW | system::CSRRS | system::CSRRC => {
+                let source_register = source_register1;
+                let csr = csr(instruction) as usize;
+                match funct3 {
+                    system::CSRRW => Instruction::CsrRw {
+                        source_register,
+                        destination_register,
+                        csr,
+                    },
+                    system::CSRRS => Instruction::CsrRs {
+                        source_register,
+                        destination_register,
+                        csr,
+                    },
+                    _ => Instruction::CsrRc {
+                        source_register,
+                        destination_register,
+                        csr,
+                    },
+                }
+            }
+            system::CSRRWI | system::CSRRSI | system::CSRRCI => {
+                let source_value = source_register1;
+                let csr = csr(instruction) as usize;
+                match funct3 {
+                    system::CSRRWI => Instruction::CsrRwi {
+                        source_value,
+                        destination_register,
+                        csr,
+                    },
+                    system::CSRRSI => Instruction::CsrRsi {
+                        source_value,
+                        destination_register,
+                        csr,
+                    },
+                    _ => Instruction::CsrRci {
+                        source_value,
+                        destination_register,
+                        csr,
+                    },
+                }
+            }
+            _ => return illegal,
+        },
+        _ => return illegal,
+    };
+    Ok(decoded)
+}
+
 #[cfg(test)]
 mod test {
     use super::super::*;
@@ -406,4 +569,179 @@ mod test {
         assert_eq!(j_type_immediate_32(MINUS_TWO_HUNDRED_AND_FIFTY), -250);
         assert_eq!(j_type_immediate_32(MINUS_TWO), -2);
     }
+
+    /// A xorshift generator, so the property tests see the same words every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 32) as u32
+        }
+
+        fn below(&mut self, limit: u32) -> u32 {
+            self.next() % limit
+        }
+
+        fn register(&mut self) -> usize {
+            self.below(32) as usize
+        }
+
+        /// A signed value that fits in bits.
+        fn signed(&mut self, bits: u32) -> i32 {
+            (self.next() as i32) >> (32 - bits)
+        }
+    }
+
+    fn random_instruction(random: &mut Random) -> encoder::Instruction {
+        let rd = random.register();
+        let rs1 = random.register();
+        let rs2 = random.register();
+        let csr = random.below(0x1000) as usize;
+        let immediate = random.signed(12) as i16;
+        let shamt = random.below(32) as i16;
+        let branch = (random.signed(13) & !1) as i16;
+        match random.below(44) {
+            0 => encoder::addi(rd, rs1, immediate),
+            1 => encoder::slti(rd, rs1, immediate),
+            2 => encoder::sltiu(rd, rs1, immediate),
+            3 => encoder::xori(rd, rs1, immediate),
+            4 => encoder::ori(rd, rs1, immediate),
+            5 => encoder::andi(rd, rs1, immediate),
+            6 => encoder::slli(rd, rs1, shamt),
+            7 => encoder::srli(rd, rs1, shamt),
+            8 => encoder::srai(rd, rs1, shamt),
+            9 => encoder::add(rd, rs1, rs2),
+            10 => encoder::sub(rd, rs1, rs2),
+            11 => encoder::sll(rd, rs1, rs2),
+            12 => encoder::slt(rd, rs1, rs2),
+            13 => encoder::sltu(rd, rs1, rs2),
+            14 => encoder::xor(rd, rs1, rs2),
+            15 => encoder::srl(rd, rs1, rs2),
+            16 => encoder::sra(rd, rs1, rs2),
+            17 => encoder::or(rd, rs1, rs2),
+            18 => encoder::and(rd, rs1, rs2),
+            19 => encoder::lui(rd, random.next() & !0xFFF),
+            20 => encoder::auipc(rd, random.next() & !0xFFF),
+            21 => encoder::jal(rd, random.signed(21) & !1),
+            22 => encoder::jalr(rd, rs1, immediate),
+            23 => encoder::beq(rs1, rs2, branch),
+            24 => encoder::bne(rs1, rs2, branch),
+            25 => encoder::blt(rs1, rs2, branch),
+            26 => encoder::bge(rs1, rs2, branch),
+            27 => encoder::bltu(rs1, rs2, branch),
+            28 => encoder::bgeu(rs1, rs2, branch),
+            29 => encoder::lb(rs1, rd, immediate),
+            30 => encoder::lh(rs1, rd, immediate),
+            31 => encoder::lw(rs1, rd, immediate),
+            32 => encoder::lbu(rs1, rd, immediate),
+            33 => encoder::lhu(rs1, rd, immediate),
+            34 => encoder::sb(rs1, rs2, immediate),
+            35 => encoder::sh(rs1, rs2, immediate),
+            36 => encoder::sw(rs1, rs2, immediate),
+            37 => encoder::Instruction::Fence {
+                pred: random.below(16) as u8,
+                succ: random.below(16) as u8,
+            },
+            38 => encoder::csrrw(rs1, rd, csr),
+            39 => encoder::csrrs(rs1, rd, csr),
+            40 => encoder::csrrc(rs1, rd, csr),
+            41 => encoder::csrrwi(rs1, rd, csr),
+            42 => encoder::csrrsi(rs1, rd, csr),
+            _ => encoder::csrrci(rs1, rd, csr),
+        }
+    }
+
+    #[test]
+    fn decode_round_trips_instructions() {
+        let mut random = Random(0x2545_F491_4F6C_DD1D);
+        for _ in 0..0x10000 {
+            let instruction = random_instruction(&mut random);
+            assert_eq!(decode(instruction.encode()), Ok(instruction));
+        }
+
+        for instruction in [
+            encoder::fence_i(),
+            encoder::ecall(),
+            encoder::ebreak(),
+            encoder::mret(),
+        ] {
+            assert_eq!(decode(instruction.encode()), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn decode_round_trips_words() {
+        const OPCODES: [usize; 11] = [
+            opcodes::OP,
+            opcodes::OP_IMM,
+            opcodes::JAL,
+            opcodes::JALR,
+            opcodes::LUI,
+            opcodes::AUIPC,
+            opcodes::BRANCH,
+            opcodes::LOAD,
+            opcodes::STORE,
+            opcodes::FENCE,
+            opcodes::SYSTEM,
+        ];
+
+        // Random words with a valid opcode, so most of them get past the first check
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let mut legal = 0;
+        for _ in 0..0x40000 {
+            let opcode = OPCODES[random.below(OPCODES.len() as u32) as usize];
+            let word = (random.next() & !0x7F) | opcode as u32;
+            if let Ok(instruction) = decode(word) {
+                assert_eq!(instruction.encode(), word, "{word:#010x}");
+                legal += 1;
+            }
+        }
+        assert!(legal > 0x20000);
+    }
+
+    #[test]
+    fn decode_rejects_illegal_instructions() {
+        for word in [
+            0x0000_0000,
+            0xFFFF_FFFF,
+            // SUB's funct7 on an AND
+            0x40C5_F533,
+            // An SLLI with a funct7
+            0x4005_1513,
+            // JALR with funct3 set
+            0x0000_1067,
+            // BEQ's funct3 plus two
+            0x0000_2063,
+            // LD and SD are RV64 only
+            0x0001_3503,
+            0x00A1_3023,
+            // FENCE.I with rd set, and FENCE.TSO
+            0x0000_108F,
+            0x8330_000F,
+            // ECALL with rs1 set, and an unknown system instruction
+            0x0000_8073,
+            0x1050_0073,
+            // funct3 4 under SYSTEM is reserved
+            0x0000_4073,
+        ] {
+            assert_eq!(decode(word), Err(IllegalInstruction(word)), "{word:#010x}");
+        }
+    }
+
+    #[test]
+    fn decode_fields() {
+        assert_eq!(decode(0xFF01_0113), Ok(encoder::addi(2, 2, -16)));
+        assert_eq!(decode(0x0000_100F), Ok(encoder::fence_i()));
+        assert_eq!(encoder::fence_i().encode(), 0x0000_100F);
+        assert_eq!(
+            decode(0x0FF0_000F),
+            Ok(encoder::Instruction::Fence {
+                pred: 0b1111,
+                succ: 0b1111
+            })
+        );
+    }
 }
@@ -0,0 +1,588 @@
+/**
+ * Turns instruction words back into GNU assembler syntax, e.g, `addi sp, sp, -16`, using
+ * decoder::decode so that anything it rejects is shown as a .word. Registers are given their
+ * ABI names and the common pseudo-instructions (nop, li, mv, not, neg, seqz, snez, beqz and
+ * friends, j, jal, jr, ret, csrr, csrw and friends) are recognised the way objdump does. Branch
+ * and jump targets are shown as absolute addresses.
+ */
+use super::decoder;
+use super::encoder::Instruction;
+use super::funct3::{branch, load, op, op_imm, store};
+use crate::cpu::registers::ABI_NAMES;
+use crate::elf::SymbolTable;
+use std::io::{self, Write};
+
+const FUNCT7_SWITCH: u8 = 0b0100000;
+
+/// The names of the CSRs we know about.
+pub(crate) const CSR_NAMES: [(u32, &str); 25] = [
+    (0x001, "fflags"),
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    (0x180, "satp"),
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0xC00, "cycle"),
+    (0xC01, "time"),
+    (0xC02, "instret"),
+    (0xC80, "cycleh"),
+    (0xC81, "timeh"),
+    (0xC82, "instreth"),
+];
+
+fn register(index: usize) -> &'static str {
+    ABI_NAMES[index]
+}
+
+pub(crate) fn csr_name(csr: usize) -> String {
+    match CSR_NAMES
+        .iter()
+        .find(|&&(address, _)| address as usize == csr)
+    {
+        Some((_, name)) => name.to_string(),
+        None => format!("{csr:#x}"),
+    }
+}
+
+/// The predecessor or successor set of a FENCE as i, o, r and w.
+fn fence_set(bits: u8) -> String {
+    let set: String = [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')]
+        .iter()
+        .filter(|&&(bit, _)| bits & bit != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    match set.is_empty() {
+        true => "0".to_string(),
+        false => set,
+    }
+}
+
+fn illegal(instruction: u32) -> String {
+    format!(".word {instruction:#010x}")
+}
+
+/// The address a branch or JAL at address jumps to, if instruction is one.
+pub fn target(instruction: u32, address: u32) -> Option<u32> {
+    let offset = match decoder::decode(instruction) {
+        Ok(Instruction::Branch { branch_offset, .. }) => branch_offset as i32,
+        Ok(Instruction::Jal { address_offset, .. }) => address_offset,
+        _ => return None,
+    };
+    Some(address.wrapping_add(offset as u32))
+}
+
+fn op_imm(rd: usize, rs1: usize, funct3: u8, immediate: i16) -> String {
+    if (funct3, rd, rs1, immediate) == (op_imm::ADDI, 0, 0, 0) {
+        return "nop".to_string();
+    }
+
+    let (rd, source) = (register(rd), register(rs1));
+    let shamt = immediate & 0b11111;
+
+    let mnemonic = match funct3 {
+        op_imm::ADDI if rs1 == 0 => return format!("li {rd}, {immediate}"),
+        op_imm::ADDI if immediate == 0 => return format!("mv {rd}, {source}"),
+        op_imm::XORI if immediate == -1 => return format!("not {rd}, {source}"),
+        op_imm::SLTIU if immediate == 1 => return format!("seqz {rd}, {source}"),
+        op_imm::SLLI => return format!("slli {rd}, {source}, {shamt}"),
+        // SRAI has the FUNCT7_SWITCH bit set in the immediate
+        op_imm::SRLI_OR_SRAI if immediate & 0x400 == 0 => {
+            return format!("srli {rd}, {source}, {shamt}")
+        }
+        op_imm::SRLI_OR_SRAI => return format!("srai {rd}, {source}, {shamt}"),
+        op_imm::ADDI => "addi",
+        op_imm::SLTI => "slti",
+        op_imm::SLTIU => "sltiu",
+        op_imm::XORI => "xori",
+        op_imm::ORI => "ori",
+        _ => "andi",
+    };
+    format!("{mnemonic} {rd}, {source}, {immediate}")
+}
+
+fn op(rd: usize, rs1: usize, rs2: usize, funct3: u8, funct7: u8) -> String {
+    let rd = register(rd);
+    let switch = funct7 == FUNCT7_SWITCH;
+
+    let mnemonic = match (funct3, switch) {
+        (op::ADD_OR_SUB, true) if rs1 == 0 => return format!("neg {rd}, {}", register(rs2)),
+        (op::SLTU, _) if rs1 == 0 => return format!("snez {rd}, {}", register(rs2)),
+        (op::SLT, _) if rs2 == 0 => return format!("sltz {rd}, {}", register(rs1)),
+        (op::SLT, _) if rs1 == 0 => return format!("sgtz {rd}, {}", register(rs2)),
+        (op::ADD_OR_SUB, false) => "add",
+        (op::ADD_OR_SUB, true) => "sub",
+        (op::SLL, _) => "sll",
+        (op::SLT, _) => "slt",
+        (op::SLTU, _) => "sltu",
+        (op::XOR, _) => "xor",
+        (op::SRL_OR_SRA, false) => "srl",
+        (op::SRL_OR_SRA, true) => "sra",
+        (op::OR, _) => "or",
+        _ => "and",
+    };
+    format!("{mnemonic} {rd}, {}, {}", register(rs1), register(rs2))
+}
+
+fn branch(funct3: u8, rs1: usize, rs2: usize, target: u32) -> String {
+    // Comparisons against zero have their own pseudo-instructions
+    let zero = match (funct3, rs1, rs2) {
+        (branch::BEQ, rs, 0) => Some(("beqz", rs)),
+        (branch::BNE, rs, 0) => Some(("bnez", rs)),
+        (branch::BGE, 0, rs) => Some(("blez", rs)),
+        (branch::BGE, rs, 0) => Some(("bgez", rs)),
+        (branch::BLT, rs, 0) => Some(("bltz", rs)),
+        (branch::BLT, 0, rs) => Some(("bgtz", rs)),
+        _ => None,
+    };
+    if let Some((mnemonic, rs)) = zero {
+        return format!("{mnemonic} {}, {target:#x}", register(rs));
+    }
+
+    let mnemonic = match funct3 {
+        branch::BEQ => "beq",
+        branch::BNE => "bne",
+        branch::BLT => "blt",
+        branch::BGE => "bge",
+        branch::BLTU => "bltu",
+        _ => "bgeu",
+    };
+    format!(
+        "{mnemonic} {}, {}, {target:#x}",
+        register(rs1),
+        register(rs2)
+    )
+}
+
+fn load(funct3: u8, rd: usize, rs1: usize, offset: i16) -> String {
+    let mnemonic = match funct3 {
+        load::LB => "lb",
+        load::LH => "lh",
+        load::LW => "lw",
+        load::LBU => "lbu",
+        _ => "lhu",
+    };
+    format!("{mnemonic} {}, {offset}({})", register(rd), register(rs1))
+}
+
+fn store(funct3: u8, rs1: usize, rs2: usize, offset: i16) -> String {
+    let mnemonic = match funct3 {
+        store::SB => "sb",
+        store::SH => "sh",
+        _ => "sw",
+    };
+    format!("{mnemonic} {}, {offset}({})", register(rs2), register(rs1))
+}
+
+fn jal(rd: usize, target: u32) -> String {
+    match rd {
+        0 => format!("j {target:#x}"),
+        1 => format!("jal {target:#x}"),
+        rd => format!("jal {}, {target:#x}", register(rd)),
+    }
+}
+
+fn jalr(rd: usize, rs1: usize, offset: i16) -> String {
+    match (rd, rs1, offset) {
+        (0, 1, 0) => "ret".to_string(),
+        (0, rs1, 0) => format!("jr {}", register(rs1)),
+        (1, rs1, 0) => format!("jalr {}", register(rs1)),
+        (rd, rs1, offset) => format!("jalr {}, {offset}({})", register(rd), register(rs1)),
+    }
+}
+
+fn fence(pred: u8, succ: u8) -> String {
+    match (pred, succ) {
+        (0b1111, 0b1111) => "fence".to_string(),
+        _ => format!("fence {}, {}", fence_set(pred), fence_set(succ)),
+    }
+}
+
+/// A CSR instruction. Writes that discard the old value drop the r and rd, e.g, csrw mtvec, a0.
+fn csr(mnemonic: &str, rd: usize, csr: usize, source: String) -> String {
+    let name = csr_name(csr);
+    match rd {
+        0 => format!("{} {name}, {source}", mnemonic.replacen("csrr", "csr", 1)),
+        rd => format!("{mnemonic} {}, {name}, {source}", register(rd)),
+    }
+}
+
+/// CSRRS with x0 as the source only reads the CSR. The counters have their own pseudo-
+/// instructions for this.
+fn csr_read(rd: usize, csr: usize) -> String {
+    let counter = match csr {
+        0xC00 => "rdcycle",
+        0xC01 => "rdtime",
+        0xC02 => "rdinstret",
+        0xC80 => "rdcycleh",
+        0xC81 => "rdtimeh",
+        0xC82 => "rdinstreth",
+        _ => return format!("csrr {}, {}", register(rd), csr_name(csr)),
+    };
+    format!("{counter} {}", register(rd))
+}
+
+/// Disassemble the instruction found at address. Words that are not valid instructions are
+/// shown as a .word directive.
+pub fn disassemble(instruction: u32, address: u32) -> String {
+    let decoded = match decoder::decode(instruction) {
+        Ok(decoded) => decoded,
+        Err(_) => return illegal(instruction),
+    };
+    let relative = |offset: i32| address.wrapping_add(offset as u32);
+
+    match decoded {
+        Instruction::OpImm {
+            destination_register,
+            source_register,
+            funct3,
+            immediate,
+        } => op_imm(destination_register, source_register, funct3, immediate),
+        Instruction::Op {
+            destination_register,
+            source_register1,
+            source_register2,
+            funct3,
+            funct7,
+        } => op(
+            destination_register,
+            source_register1,
+            source_register2,
+            funct3,
+            funct7,
+        ),
+        Instruction::Lui {
+            destination_register,
+            value,
+        } => format!("lui {}, {:#x}", register(destination_register), value >> 12),
+        Instruction::Auipc {
+            destination_register,
+            value,
+        } => format!(
+            "auipc {}, {:#x}",
+            register(destination_register),
+            value >> 12
+        ),
+        Instruction::Jal {
+            destination_register,
+            address_offset,
+        } => jal(destination_register, relative(address_offset)),
+        Instruction::Jalr {
+            destination_register,
+            source_register,
+            address_offset,
+        } => jalr(destination_register, source_register, address_offset),
+        Instruction::Branch {
+            funct3,
+            source_register1,
+            source_register2,
+            branch_offset,
+        } => branch(
+            funct3,
+            source_register1,
+            source_register2,
+            relative(branch_offset as i32),
+        ),
+        Instruction::Load {
+            funct3,
+            source_register,
+            destination_register,
+            offset,
+        } => load(funct3, destination_register, source_register, offset),
+        Instruction::Store {
+            funct3,
+            source_register1,
+            source_register2,
+            offset,
+        } => store(funct3, source_register1, source_register2, offset),
+        Instruction::Fence { pred, succ } => fence(pred, succ),
+        Instruction::FenceI {} => "fence.i".to_string(),
+        Instruction::ECall => "ecall".to_string(),
+        Instruction::EBreak => "ebreak".to_string(),
+        Instruction::MRet => "mret".to_string(),
+        Instruction::CsrRs {
+            source_register: 0,
+            destination_register,
+            csr,
+        } => csr_read(destination_register, csr),
+        Instruction::CsrRw {
+            source_register,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrw",
+            destination_register,
+            address,
+            register(source_register).to_string(),
+        ),
+        Instruction::CsrRs {
+            source_register,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrs",
+            destination_register,
+            address,
+            register(source_register).to_string(),
+        ),
+        Instruction::CsrRc {
+            source_register,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrc",
+            destination_register,
+            address,
+            register(source_register).to_string(),
+        ),
+        Instruction::CsrRwi {
+            source_value,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrwi",
+            destination_register,
+            address,
+            source_value.to_string(),
+        ),
+        Instruction::CsrRsi {
+            source_value,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrsi",
+            destination_register,
+            address,
+            source_value.to_string(),
+        ),
+        Instruction::CsrRci {
+            source_value,
+            destination_register,
+            csr: address,
+        } => csr(
+            "csrrci",
+            destination_register,
+            address,
+            source_value.to_string(),
+        ),
+    }
+}
+
+/// Disassemble the instruction at address, naming the symbol its branch or jump target falls
+/// in, e.g, `jal 0x40 <exit>`.
+pub fn describe(instruction: u32, address: u32, symbols: &SymbolTable) -> String {
+    let text = disassemble(instruction, address);
+    match target(instruction, address).and_then(|target| symbols.describe(target)) {
+        Some(symbol) => format!("{text} <{symbol}>"),
+        None => text,
+    }
+}
+
+/// Write an objdump -d style listing of code loaded at address, with a heading at each symbol.
+/// Bytes left over at the end that do not make up a whole instruction are listed as .byte.
+pub fn listing(
+    code: &[u8],
+    address: u32,
+    symbols: &SymbolTable,
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let words = code.chunks_exact(4);
+    let remainder = words.remainder();
+
+    for (index, bytes) in words.enumerate() {
+        let address = address.wrapping_add(index as u32 * 4);
+        if let Some(symbol) = symbols.iter().find(|symbol| symbol.address == address) {
+            writeln!(output, "\n{address:08x} <{}>:", symbol.name)?;
+        }
+
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        let text = describe(word, address, symbols);
+        writeln!(output, "{address:8x}:  {word:08x}  {text}")?;
+    }
+
+    let end = address.wrapping_add((code.len() - remainder.len()) as u32);
+    for (index, byte) in remainder.iter().enumerate() {
+        let address = end.wrapping_add(index as u32);
+        writeln!(output, "{address:8x}:  {byte:02x}        .byte {byte:#04x}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::Symbol;
+    use crate::instruction::encoder::{self, Instruction};
+
+    fn check(instruction: Instruction, expected: &str) {
+        assert_eq!(disassemble(instruction.encode(), 0x100), expected);
+    }
+
+    #[test]
+    fn arithmetic() {
+        check(encoder::addi(2, 2, -16), "addi sp, sp, -16");
+        check(encoder::slti(10, 11, 5), "slti a0, a1, 5");
+        check(encoder::sltiu(10, 11, 5), "sltiu a0, a1, 5");
+        check(encoder::xori(10, 11, 5), "xori a0, a1, 5");
+        check(encoder::ori(10, 11, 5), "ori a0, a1, 5");
+        check(encoder::andi(10, 11, 5), "andi a0, a1, 5");
+        check(encoder::slli(10, 11, 3), "slli a0, a1, 3");
+        check(encoder::srli(10, 11, 3), "srli a0, a1, 3");
+        check(encoder::srai(10, 11, 31), "srai a0, a1, 31");
+        check(encoder::add(10, 11, 12), "add a0, a1, a2");
+        check(encoder::sub(10, 11, 12), "sub a0, a1, a2");
+        check(encoder::sll(10, 11, 12), "sll a0, a1, a2");
+        check(encoder::slt(10, 11, 12), "slt a0, a1, a2");
+        check(encoder::sltu(10, 11, 12), "sltu a0, a1, a2");
+        check(encoder::xor(10, 11, 12), "xor a0, a1, a2");
+        check(encoder::srl(10, 11, 12), "srl a0, a1, a2");
+        check(encoder::sra(10, 11, 12), "sra a0, a1, a2");
+        check(encoder::or(10, 11, 12), "or a0, a1, a2");
+        check(encoder::and(10, 11, 12), "and a0, a1, a2");
+        check(encoder::lui(5, 0x1234_5000), "lui t0, 0x12345");
+        check(encoder::auipc(5, 0xFFFF_F000), "auipc t0, 0xfffff");
+    }
+
+    #[test]
+    fn memory() {
+        check(encoder::lb(2, 10, 8), "lb a0, 8(sp)");
+        check(encoder::lh(2, 10, -2), "lh a0, -2(sp)");
+        check(encoder::lw(2, 10, 0), "lw a0, 0(sp)");
+        check(encoder::lbu(2, 10, 1), "lbu a0, 1(sp)");
+        check(encoder::lhu(2, 10, 2), "lhu a0, 2(sp)");
+        check(encoder::sb(2, 10, 8), "sb a0, 8(sp)");
+        check(encoder::sh(2, 10, -8), "sh a0, -8(sp)");
+        check(encoder::sw(2, 1, 12), "sw ra, 12(sp)");
+    }
+
+    #[test]
+    fn control_flow() {
+        check(encoder::beq(10, 11, 8), "beq a0, a1, 0x108");
+        check(encoder::bne(10, 11, -8), "bne a0, a1, 0xf8");
+        check(encoder::blt(10, 11, 8), "blt a0, a1, 0x108");
+        check(encoder::bge(10, 11, 8), "bge a0, a1, 0x108");
+        check(encoder::bltu(10, 11, 8), "bltu a0, a1, 0x108");
+        check(encoder::bgeu(10, 11, 8), "bgeu a0, a1, 0x108");
+        check(encoder::jal(5, 0x10), "jal t0, 0x110");
+        check(encoder::jalr(5, 6, 4), "jalr t0, 4(t1)");
+        assert_eq!(target(encoder::jal(0, -0x100).encode(), 0x100), Some(0));
+        assert_eq!(target(encoder::add(1, 2, 3).encode(), 0x100), None);
+    }
+
+    #[test]
+    fn system_instructions() {
+        check(encoder::ecall(), "ecall");
+        check(encoder::ebreak(), "ebreak");
+        check(encoder::mret(), "mret");
+        check(encoder::csrrw(10, 11, 0x305), "csrrw a1, mtvec, a0");
+        check(encoder::csrrs(10, 11, 0x300), "csrrs a1, mstatus, a0");
+        check(encoder::csrrc(10, 11, 0x123), "csrrc a1, 0x123, a0");
+        check(encoder::csrrwi(5, 11, 0x340), "csrrwi a1, mscratch, 5");
+        check(encoder::csrrsi(5, 11, 0x344), "csrrsi a1, mip, 5");
+        check(encoder::csrrci(5, 11, 0x304), "csrrci a1, mie, 5");
+        check(encoder::fence(), "fence 0, 0");
+        assert_eq!(disassemble(0x0FF0_000F, 0), "fence");
+        assert_eq!(disassemble(0x0330_000F, 0), "fence rw, rw");
+        assert_eq!(disassemble(0x0000_100F, 0), "fence.i");
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        check(encoder::no_op(), "nop");
+        check(encoder::addi(10, 0, -1), "li a0, -1");
+        check(encoder::addi(10, 11, 0), "mv a0, a1");
+        check(encoder::xori(10, 11, -1), "not a0, a1");
+        check(encoder::sltiu(10, 11, 1), "seqz a0, a1");
+        check(encoder::sub(10, 0, 11), "neg a0, a1");
+        check(encoder::sltu(10, 0, 11), "snez a0, a1");
+        check(encoder::slt(10, 11, 0), "sltz a0, a1");
+        check(encoder::slt(10, 0, 11), "sgtz a0, a1");
+        check(encoder::beq(10, 0, 8), "beqz a0, 0x108");
+        check(encoder::bne(10, 0, 8), "bnez a0, 0x108");
+        check(encoder::bge(0, 10, 8), "blez a0, 0x108");
+        check(encoder::bge(10, 0, 8), "bgez a0, 0x108");
+        check(encoder::blt(10, 0, 8), "bltz a0, 0x108");
+        check(encoder::blt(0, 10, 8), "bgtz a0, 0x108");
+        check(encoder::jal(0, -0x10), "j 0xf0");
+        check(encoder::jal(1, 0x10), "jal 0x110");
+        check(encoder::jalr(0, 1, 0), "ret");
+        check(encoder::jalr(0, 5, 0), "jr t0");
+        check(encoder::jalr(1, 5, 0), "jalr t0");
+        check(encoder::csrrs(0, 10, 0x341), "csrr a0, mepc");
+        check(encoder::csrrs(0, 10, 0xC00), "rdcycle a0");
+        check(encoder::csrrs(0, 10, 0xC81), "rdtimeh a0");
+        check(encoder::csrrw(10, 0, 0x305), "csrw mtvec, a0");
+        check(encoder::csrrs(10, 0, 0x300), "csrs mstatus, a0");
+        check(encoder::csrrc(10, 0, 0x300), "csrc mstatus, a0");
+        check(encoder::csrrwi(1, 0, 0x340), "csrwi mscratch, 1");
+        check(encoder::csrrsi(8, 0, 0x300), "csrsi mstatus, 8");
+        check(encoder::csrrci(8, 0, 0x300), "csrci mstatus, 8");
+    }
+
+    #[test]
+    fn listings() {
+        let code: Vec<u8> = [
+            encoder::jal(1, 8),
+            encoder::jal(0, 0),
+            encoder::addi(10, 0, 7),
+            encoder::jalr(0, 1, 0),
+        ]
+        .iter()
+        .flat_map(|instruction| instruction.encode().to_le_bytes())
+        .chain([0xAA, 0xBB])
+        .collect();
+        let symbols = SymbolTable::new(vec![
+            Symbol {
+                name: "_start".to_string(),
+                address: 0x1000,
+                size: 8,
+                function: true,
+            },
+            Symbol {
+                name: "seven".to_string(),
+                address: 0x1008,
+                size: 8,
+                function: true,
+            },
+        ]);
+
+        let mut output = Vec::new();
+        listing(&code, 0x1000, &symbols, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "
+00001000 <_start>:
+    1000:  008000ef  jal 0x1008 <seven>
+    1004:  0000006f  j 0x1004 <_start+0x4>
+
+00001008 <seven>:
+    1008:  00700513  li a0, 7
+    100c:  00008067  ret
+    1010:  aa        .byte 0xaa
+    1011:  bb        .byte 0xbb
+"
+        );
+    }
+
+    #[test]
+    fn illegal_instructions() {
+        assert_eq!(disassemble(0, 0), ".word 0x00000000");
+        assert_eq!(disassemble(0xFFFF_FFFF, 0), ".word 0xffffffff");
+        // SUB's funct7 on an AND
+        assert_eq!(disassemble(0x40C5_F533, 0), ".word 0x40c5f533");
+        // LD is RV64 only
+        assert_eq!(disassemble(0x0001_3503, 0), ".word 0x00013503");
+    }
+}
@@ -119,7 +119,7 @@ const fn encode_auipc(destination_register: usize, value: u32) -> u32 {
 }
 
 const fn encode_j_type_immediate(offset: i32) -> u32 {
-    let value = match checked_int_downcast(offset as i64, 22) {
+    let value = match checked_int_downcast(offset as i64, 21) {
         Ok(value) => value as u32,
         Err(()) => panic!("j-type immediate is out of range"),
     };
@@ -129,10 +129,10 @@ const fn encode_j_type_immediate(offset: i32) -> u32 {
         panic!("j-type immediate cannot have the lsb set");
     }
 
-    let bit_twenty = value >> 21;
-    let bit_eleven = (value & 0b0000_0000_0100_0000_0000) >> 10;
-    let bits_ten_to_one = (value & 0b111_1111_1110) >> 1;
-    let bits_19_to_12 = (value & 0b0111_1111_1000_0000_0000) >> 11;
+    let bit_twenty = (value >> 20) & 0b1;
+    let bit_eleven = (value >> 11) & 0b1;
+    let bits_ten_to_one = (value >> 1) & 0b11_1111_1111;
+    let bits_19_to_12 = (value >> 12) & 0b1111_1111;
 
     (bit_twenty << 31) | (bit_eleven << 20) | (bits_ten_to_one << 21) | (bits_19_to_12 << 12)
 }
@@ -263,7 +263,7 @@ const fn encode_fence(funct3: u8, pred: u8, succ: u8) -> u32 {
         panic!("succ cannot be > 0b1111");
     }
 
-    (FENCE as u32) | ((funct3 as u32) << 7) | ((pred as u32) << 24) | ((succ as u32) << 20)
+    (FENCE as u32) | ((funct3 as u32) << 12) | ((pred as u32) << 24) | ((succ as u32) << 20)
 }
 
 const fn encode_ecall() -> u32 {
@@ -274,6 +274,10 @@ const fn encode_ebreak() -> u32 {
     (SYSTEM as u32) | ((ECALL_OR_EBREAK as u32) << 7) | (1 << 20)
 }
 
+const fn encode_mret() -> u32 {
+    (SYSTEM as u32) | (0x302 << 20)
+}
+
 const fn encode_csr(
     funct3: u8,
     csr: usize,
@@ -303,6 +307,7 @@ const fn encode_csr(
         | ((csr as u32) << 20)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     OpImm {
         destination_register: usize,
@@ -359,6 +364,7 @@ pub enum Instruction {
     FenceI {},
     ECall,
     EBreak,
+    MRet,
     CsrRw {
         source_register: usize,
         destination_register: usize,
@@ -466,6 +472,7 @@ impl Instruction {
             &Instruction::FenceI {} => encode_fence(1, 0, 0),
             &Instruction::ECall {} => encode_ecall(),
             &Instruction::EBreak {} => encode_ebreak(),
+            &Instruction::MRet => encode_mret(),
             &Instruction::CsrRw {
                 csr,
                 source_register,
@@ -1009,6 +1016,11 @@ pub const fn ebreak() -> Instruction {
     Instruction::EBreak {}
 }
 
+/// Construct a mret operation, which returns from a machine mode trap handler to mepc.
+pub const fn mret() -> Instruction {
+    Instruction::MRet
+}
+
 /// Construct an atomic read/write CSR instruction
 pub const fn csrrw(source_register: usize, destination_register: usize, csr: usize) -> Instruction {
     Instruction::CsrRw {
@@ -1401,6 +1413,14 @@ mod test {
         assert_eq!(i_type_immediate_32(op), 1);
     }
 
+    #[test]
+    fn test_mret() {
+        let op = Instruction::MRet.encode();
+        assert_eq!(op, 0x3020_0073);
+        assert_eq!(funct3(op), ECALL_OR_EBREAK);
+        assert_eq!(i_type_immediate_32(op), 0x302);
+    }
+
     #[test]
     fn test_csrrw() {
         let op = csrrw(5, 31, 2048).encode();
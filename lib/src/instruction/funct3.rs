@@ -60,3 +60,8 @@ pub mod system {
     pub const CSRRSI: u8 = 0b110;
     pub const CSRRCI: u8 = 0b111;
 }
+
+pub mod fence {
+    pub const FENCE: u8 = 0b000;
+    pub const FENCE_I: u8 = 0b001;
+}
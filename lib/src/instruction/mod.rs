@@ -1,4 +1,6 @@
+pub mod assembler;
 pub mod decoder;
+pub mod disassembler;
 pub mod encoder;
 pub mod funct3;
 pub mod opcodes;
@@ -1,11 +1,17 @@
 #![allow(internal_features)]
 #![feature(const_mut_refs)]
 #![feature(generic_arg_infer)]
-#![feature(core_intrinsics)]
 #![feature(const_try)]
 #![feature(const_trait_impl)]
 #![feature(effects)]
+pub mod benchmark;
+pub mod console;
 pub mod cpu;
+pub mod debugger;
+pub mod device_tree;
+pub mod devices;
+pub mod elf;
 pub mod instruction;
+pub mod machine;
 pub mod memory;
 pub mod util;
@@ -0,0 +1,286 @@
+/**
+ * The virt machine profile: a memory map and interrupt wiring modelled on QEMU's virt machine so
+ * that firmware and kernels built for it find everything where they expect, plus the boot
+ * protocol used to hand over to OpenSBI or a kernel. The hart itself can't run those yet, see
+ * ISA.
+ *
+ * 0x0010_0000 test finisher
+ * 0x0010_1000 RTC (source 11)
+ * 0x0200_0000 CLINT
+ * 0x0C00_0000 PLIC
+ * 0x1000_0000 UART (source 10)
+ * 0x1000_1000 virtio-mmio devices, every 0x1000 (sources 1 to 8)
+ * 0x8000_0000 RAM
+ */
+use crate::device_tree::{DeviceTree, MachineConfig};
+use crate::devices::clint::{Clint, CLINT_SIZE, DEFAULT_CLINT_BASE};
+use crate::devices::plic::{Plic, DEFAULT_PLIC_BASE, PLIC_SIZE};
+use crate::devices::virtio::{DEFAULT_VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE};
+use crate::devices::Device;
+use crate::memory::{Memory, MemoryError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const RAM_BASE: usize = 0x8000_0000;
+
+pub const UART_INTERRUPT: u32 = 10;
+pub const RTC_INTERRUPT: u32 = 11;
+pub const VIRTIO_INTERRUPT: u32 = 1;
+pub const VIRTIO_COUNT: usize = 8;
+
+/// The ISA our hart implements, as it appears in the device tree. OpenSBI and Linux need at least
+/// rv32ima with the privileged architecture, which the hart doesn't have yet.
+pub const ISA: &str = "rv32i";
+
+/// Where the kernel goes when there is firmware in front of it. 32 bit kernels need to be 4MiB
+/// aligned (a megapage).
+pub const KERNEL_OFFSET: usize = 0x40_0000;
+
+/// The space at the top of RAM set aside for the boot information and device tree.
+const BOOT_INFO_SIZE: usize = 0x1_0000;
+
+/// The fw_dynamic_info structure OpenSBI's fw_dynamic firmware expects a pointer to in a2.
+const FW_DYNAMIC_INFO_MAGIC: u32 = 0x4942_534F;
+const FW_DYNAMIC_INFO_VERSION: u32 = 2;
+/// The privilege mode OpenSBI should start the next stage in.
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u32 = 1;
+
+/// The interrupt controllers of the virt machine, which other devices are wired to.
+pub struct Virt {
+    pub clint: Rc<RefCell<Clint>>,
+    pub plic: Rc<RefCell<Plic>>,
+}
+
+/// The images to boot and the kernel command line.
+#[derive(Debug, Clone, Default)]
+pub struct BootImages {
+    /// Firmware such as OpenSBI's fw_dynamic.bin, which hands over to the kernel
+    pub bios: Option<Vec<u8>>,
+    pub kernel: Option<Vec<u8>>,
+    pub initrd: Option<Vec<u8>>,
+    pub bootargs: Option<String>,
+}
+
+/// The state the hart starts in: the PC and the values of a0 to a2.
+#[derive(Debug, PartialEq)]
+pub struct BootState {
+    pub pc: u32,
+    pub a0: u32,
+    pub a1: u32,
+    pub a2: u32,
+}
+
+/// The address and PLIC source of the virtio-mmio device in slot index.
+pub fn virtio_slot(index: usize) -> (usize, u32) {
+    assert!(
+        index < VIRTIO_COUNT,
+        "only {VIRTIO_COUNT} virtio devices fit"
+    );
+    (
+        DEFAULT_VIRTIO_MMIO_BASE + index * VIRTIO_MMIO_SIZE,
+        VIRTIO_INTERRUPT + index as u32,
+    )
+}
+
+impl Virt {
+    /// Attach the CLINT and PLIC to memory, which should have its RAM at RAM_BASE.
+    pub fn new(memory: &mut Memory) -> Self {
+        let clint = Rc::new(RefCell::new(Clint::new()));
+        let plic = Rc::new(RefCell::new(Plic::new()));
+        memory
+            .attach(DEFAULT_CLINT_BASE, CLINT_SIZE, clint.clone())
+            .unwrap();
+        memory
+            .attach(DEFAULT_PLIC_BASE, PLIC_SIZE, plic.clone())
+            .unwrap();
+
+        Self { clint, plic }
+    }
+
+    /// Attach a device and, if interrupt is given, connect it to that PLIC source.
+    pub fn attach(
+        &self,
+        memory: &mut Memory,
+        base: usize,
+        size: usize,
+        interrupt: Option<u32>,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MemoryError> {
+        memory.attach(base, size, device.clone())?;
+        if let Some(interrupt) = interrupt {
+            self.plic.borrow_mut().connect(interrupt, base, device);
+        }
+        Ok(())
+    }
+}
+
+/// Load the images into RAM along with a device tree describing the machine, and return the
+/// state the hart should start in. Firmware goes at the start of RAM with the kernel
+/// KERNEL_OFFSET after it, the device tree at the top of RAM and the initrd just below it.
+///
+/// Firmware is started with the OpenSBI fw_dynamic protocol: a0 holds the hart ID, a1 the device
+/// tree and a2 the fw_dynamic_info telling it where the kernel is. Without firmware the kernel
+/// is started directly with a0 and a1 set the same way.
+pub fn boot(memory: &mut Memory, images: &BootImages) -> Result<BootState, MemoryError> {
+    let ram_end = memory.ram_base() + memory.ram_size();
+    let boot_info = ram_end
+        .checked_sub(BOOT_INFO_SIZE)
+        .filter(|&address| address >= memory.ram_base())
+        .ok_or(MemoryError::OutOfBounds)?;
+
+    let kernel_address = match images.bios {
+        Some(_) => memory.ram_base() + KERNEL_OFFSET,
+        None => memory.ram_base(),
+    };
+
+    if let Some(bios) = &images.bios {
+        memory.load_slice(memory.ram_base(), bios)?;
+    }
+
+    if let Some(kernel) = &images.kernel {
+        memory.load_slice(kernel_address, kernel)?;
+    }
+
+    let initrd = match &images.initrd {
+        Some(initrd) => {
+            let start = boot_info
+                .checked_sub(initrd.len())
+                .ok_or(MemoryError::OutOfBounds)?
+                & !0xFFF;
+            memory.load_slice(start, initrd)?;
+            Some((start, start + initrd.len()))
+        }
+        None => None,
+    };
+
+    let config = MachineConfig {
+        isa: ISA.to_string(),
+        harts: 1,
+        bootargs: images.bootargs.clone(),
+        initrd,
+    };
+    let dtb = DeviceTree::for_machine(memory, &config).to_dtb();
+
+    // The fw_dynamic_info goes first, followed by the 8 byte aligned device tree
+    let dtb_address = boot_info + 0x40;
+    if dtb_address + dtb.len() > ram_end {
+        return Err(MemoryError::OutOfBounds);
+    }
+    memory.load_slice(dtb_address, &dtb)?;
+
+    let info = [
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        kernel_address as u32,
+        FW_DYNAMIC_INFO_NEXT_MODE_S,
+        // No options, and boot hart -1 lets OpenSBI pick
+        0,
+        u32::MAX,
+    ];
+    for (index, &word) in info.iter().enumerate() {
+        memory.set32(boot_info + index * 4, word)?;
+    }
+
+    Ok(BootState {
+        pc: memory.ram_base() as u32,
+        a0: 0,
+        a1: dtb_address as u32,
+        a2: match images.bios {
+            Some(_) => boot_info as u32,
+            None => 0,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::virtio::rng::Rng;
+    use crate::devices::virtio::VirtioMmio;
+
+    fn virt(ram_size: usize) -> (Memory, Virt) {
+        let mut memory = Memory::with_base(RAM_BASE, ram_size);
+        let virt = Virt::new(&mut memory);
+        (memory, virt)
+    }
+
+    #[test]
+    fn boot_layout() {
+        let (mut memory, _virt) = virt(0x80_0000);
+        let images = BootImages {
+            bios: Some(vec![0x11; 16]),
+            kernel: Some(vec![0x22; 16]),
+            initrd: Some(vec![0x33; 0x1800]),
+            bootargs: Some("console=ttyS0".to_string()),
+        };
+        let state = boot(&mut memory, &images).unwrap();
+
+        let boot_info = RAM_BASE + 0x80_0000 - BOOT_INFO_SIZE;
+        assert_eq!(
+            state,
+            BootState {
+                pc: RAM_BASE as u32,
+                a0: 0,
+                a1: boot_info as u32 + 0x40,
+                a2: boot_info as u32,
+            }
+        );
+
+        assert_eq!(memory.get8(RAM_BASE), Ok(0x11));
+        assert_eq!(memory.get8(RAM_BASE + KERNEL_OFFSET), Ok(0x22));
+        assert_eq!(memory.get8(boot_info - 0x2000), Ok(0x33));
+        assert_eq!(memory.get32(state.a1 as usize), Ok(0xEDFE_0DD0));
+        assert_eq!(memory.get32(boot_info), Ok(FW_DYNAMIC_INFO_MAGIC));
+        assert_eq!(
+            memory.get32(boot_info + 8),
+            Ok((RAM_BASE + KERNEL_OFFSET) as u32)
+        );
+    }
+
+    #[test]
+    fn kernel_without_firmware() {
+        let (mut memory, _virt) = virt(0x2_0000);
+        let images = BootImages {
+            kernel: Some(vec![0x22; 16]),
+            ..BootImages::default()
+        };
+        let state = boot(&mut memory, &images).unwrap();
+        assert_eq!(state.pc, RAM_BASE as u32);
+        assert_eq!(state.a2, 0);
+        assert_eq!(memory.get8(RAM_BASE), Ok(0x22));
+
+        // There has to be room for the device tree
+        let (mut memory, _virt) = virt(0x1000);
+        assert_eq!(boot(&mut memory, &images), Err(MemoryError::OutOfBounds));
+    }
+
+    #[test]
+    fn interrupts_in_device_tree() {
+        let (mut memory, virt) = virt(0x2_0000);
+        let rng = VirtioMmio::new(Box::new(Rng::new(0)));
+        let (base, interrupt) = virtio_slot(0);
+        virt.attach(
+            &mut memory,
+            base,
+            VIRTIO_MMIO_SIZE,
+            Some(interrupt),
+            Rc::new(RefCell::new(rng)),
+        )
+        .unwrap();
+
+        let config = MachineConfig {
+            isa: ISA.to_string(),
+            harts: 1,
+            bootargs: None,
+            initrd: None,
+        };
+        let tree = DeviceTree::for_machine(&memory, &config);
+        let soc = tree.root().child("soc").unwrap();
+        let plic = soc.child("plic@c000000").unwrap();
+        let virtio = soc.child("virtio_mmio@10001000").unwrap();
+        assert_eq!(virtio.get("interrupt-parent"), plic.get("phandle"));
+        assert_eq!(virtio.get("interrupts"), Some([0, 0, 0, 1].as_slice()));
+        assert!(soc.child("clint@2000000").is_some());
+        assert!(tree.root().child("memory@80000000").is_some());
+    }
+}
@@ -0,0 +1,846 @@
+/**
+ * The guest's physical address space: RAM, with devices mapped over it. Accesses that are plain
+ * RAM, by far the most common, are checked once and go straight to a page of it; anything else
+ * (a device, or an access spanning RAM and a device or the end of RAM) takes a slower path.
+ *
+ * Pages of RAM can be protected from the hart (see Memory::protect), e.g, to make code read-only
+ * or put guard pages around a stack. The hart checks its own accesses with check_access, while
+ * loaders, devices and debuggers read and write memory regardless, as they would physical memory.
+ */
+mod ram;
+
+use crate::device_tree::{DeviceTree, Node};
+use crate::devices::{Device, PowerRequest};
+pub use ram::{Ram, PAGE_SIZE};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryError {
+    OutOfBounds,
+    DeviceOverlap,
+    /// The hart isn't permitted the access (see Memory::protect)
+    PermissionDenied,
+    /// A range that has to start on a page boundary doesn't
+    Misaligned,
+}
+
+/// The kinds of access the hart makes to memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What the hart may do with a page of RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const ALL: Self = Self::new(true, true, true);
+    /// For guard pages, which fault on any access
+    pub const NONE: Self = Self::new(false, false, false);
+    pub const READ_EXECUTE: Self = Self::new(true, false, true);
+    pub const READ_WRITE: Self = Self::new(true, true, false);
+    pub const READ_ONLY: Self = Self::new(true, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+/// The granularity writes to code are tracked at. This is smaller than a real page so that data
+/// next to code, common in small programs, doesn't invalidate much of it when written.
+pub const CODE_PAGE_SIZE: usize = 256;
+
+struct MappedDevice {
+    base: usize,
+    size: usize,
+    device: Rc<RefCell<dyn Device>>,
+}
+
+pub struct Memory {
+    ram_base: usize,
+    ram: Ram,
+    /// The size of the RAM below the first device mapped over it, which can be accessed directly
+    direct_size: usize,
+    devices: Vec<MappedDevice>,
+    /// The RAM pages instructions have been decoded from, so that writes to them can be noticed
+    code_pages: Vec<bool>,
+    /// The addresses of the code pages written since take_written_code was last called
+    written_code: Vec<usize>,
+    /// The permissions of each page of RAM
+    permissions: Vec<Permissions>,
+    /// Whether any page has permissions other than Permissions::ALL
+    protected: bool,
+}
+
+impl Memory {
+    pub fn new(sz: usize) -> Self {
+        Self::with_base(0, sz)
+    }
+
+    /// Create memory with sz bytes of RAM starting at ram_base rather than address 0.
+    pub fn with_base(ram_base: usize, sz: usize) -> Self {
+        Self {
+            ram_base,
+            ram: Ram::new(sz),
+            direct_size: sz,
+            devices: Vec::new(),
+            code_pages: vec![false; sz.div_ceil(CODE_PAGE_SIZE)],
+            written_code: Vec::new(),
+            permissions: vec![Permissions::ALL; sz.div_ceil(PAGE_SIZE)],
+            protected: false,
+        }
+    }
+
+    pub fn ram_base(&self) -> usize {
+        self.ram_base
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram.size()
+    }
+
+    /// The RAM, which can be cloned cheaply to take a snapshot of it.
+    pub fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    /// Replace the RAM with a snapshot of the same size taken by cloning ram(). Every code page
+    /// is reported as written (see take_written_code).
+    pub fn restore_ram(&mut self, ram: Ram) {
+        assert_eq!(
+            ram.size(),
+            self.ram.size(),
+            "restored RAM of the wrong size"
+        );
+        self.ram = ram;
+        // The snapshot may have been taken before pages were protected
+        #[cfg(feature = "jit")]
+        for (page, permissions) in self.permissions.iter().enumerate() {
+            self.ram
+                .set_indirect(page, *permissions != Permissions::ALL);
+        }
+        self.wrote(0, self.ram.size());
+    }
+
+    /// Set what the hart may do with the RAM in [base, base + size). The range has to start on a
+    /// page boundary of RAM and is rounded up to whole pages. Any code in it is reported as
+    /// written (see take_written_code), so that nothing decoded from it before runs unchecked.
+    pub fn protect(
+        &mut self,
+        base: usize,
+        size: usize,
+        permissions: Permissions,
+    ) -> Result<(), MemoryError> {
+        let offset = base.wrapping_sub(self.ram_base);
+        if offset > self.ram.size() || size > self.ram.size() - offset {
+            return Err(MemoryError::OutOfBounds);
+        }
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(MemoryError::Misaligned);
+        }
+
+        let end = (offset + size)
+            .next_multiple_of(PAGE_SIZE)
+            .min(self.ram.size());
+        for page in offset / PAGE_SIZE..end.div_ceil(PAGE_SIZE) {
+            self.permissions[page] = permissions;
+            #[cfg(feature = "jit")]
+            self.ram.set_indirect(page, permissions != Permissions::ALL);
+        }
+        self.protected = self
+            .permissions
+            .iter()
+            .any(|page| *page != Permissions::ALL);
+        self.wrote(offset, end - offset);
+        Ok(())
+    }
+
+    /// Whether addr is RAM or belongs to a device, so that accesses to it can succeed.
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.device(addr).is_some() || addr.wrapping_sub(self.ram_base) < self.ram.size()
+    }
+
+    /// Check the hart may make an access of width bytes at addr. Only RAM is protected: accesses
+    /// to devices and outside RAM are left to succeed or fail by themselves.
+    pub fn check_access(
+        &self,
+        addr: usize,
+        width: usize,
+        access: Access,
+    ) -> Result<(), MemoryError> {
+        if !self.protected {
+            return Ok(());
+        }
+
+        // An access is never more than a page, so its first and last bytes cover every page of it
+        for byte in [addr, addr.wrapping_add(width - 1)] {
+            let offset = byte.wrapping_sub(self.ram_base);
+            let allowed = match self.permissions.get(offset / PAGE_SIZE) {
+                Some(permissions) if offset < self.ram.size() => {
+                    permissions.allows(access) || self.device(byte).is_some()
+                }
+                _ => true,
+            };
+            if !allowed {
+                return Err(MemoryError::PermissionDenied);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map a device into the address range [base, base + size). Device mappings take priority
+    /// over RAM, but two devices cannot overlap each other.
+    pub fn attach(
+        &mut self,
+        base: usize,
+        size: usize,
+        device: Rc<RefCell<dyn Device>>,
+    ) -> Result<(), MemoryError> {
+        let overlaps = self
+            .devices
+            .iter()
+            .any(|mapped| base < mapped.base + mapped.size && mapped.base < base + size);
+
+        if overlaps {
+            return Err(MemoryError::DeviceOverlap);
+        }
+
+        self.devices.push(MappedDevice { base, size, device });
+        if base + size > self.ram_base {
+            let below = base.saturating_sub(self.ram_base);
+            self.direct_size = self.direct_size.min(below);
+        }
+        Ok(())
+    }
+
+    /// Advance every attached device by one instruction, returning the first power request made
+    /// by any of them.
+    pub fn tick(&mut self) -> Option<PowerRequest> {
+        let mut request = None;
+        for index in 0..self.devices.len() {
+            // We hold our own reference to the device so that we can lend it the rest of memory.
+            let device = self.devices[index].device.clone();
+            let mut device = device.borrow_mut();
+            device.tick(self);
+            request = request.or(device.power_request());
+        }
+        request
+    }
+
+    /// Add nodes describing RAM and every attached device to a device tree.
+    pub fn describe(&self, tree: &mut DeviceTree) {
+        let mut ram = Node::new(&format!("memory@{:x}", self.ram_base));
+        ram.set_str("device_type", "memory");
+        ram.set_reg(self.ram_base, self.ram.size());
+        tree.root_mut().add(ram);
+
+        for mapped in &self.devices {
+            mapped
+                .device
+                .borrow()
+                .device_tree(tree, mapped.base, mapped.size);
+        }
+    }
+
+    /// Find the device mapped at addr, returning it along with the offset of addr into it.
+    fn device(&self, addr: usize) -> Option<(&RefCell<dyn Device>, usize)> {
+        self.devices
+            .iter()
+            .find(|mapped| addr >= mapped.base && addr - mapped.base < mapped.size)
+            .map(|mapped| (mapped.device.as_ref(), addr - mapped.base))
+    }
+
+    // A device that is busy in tick cannot be accessed (e.g, by DMA aimed at its own registers)
+    // so we treat it as out of bounds.
+    fn device_read(&self, addr: usize, width: usize) -> Option<Result<u32, MemoryError>> {
+        self.device(addr)
+            .map(|(device, offset)| match device.try_borrow_mut() {
+                Ok(mut device) => Ok(device.read(offset, width)),
+                Err(_) => Err(MemoryError::OutOfBounds),
+            })
+    }
+
+    fn device_write(&self, addr: usize, width: usize, val: u32) -> Option<Result<(), MemoryError>> {
+        self.device(addr)
+            .map(|(device, offset)| match device.try_borrow_mut() {
+                Ok(mut device) => {
+                    device.write(offset, width, val);
+                    Ok(())
+                }
+                Err(_) => Err(MemoryError::OutOfBounds),
+            })
+    }
+
+    /// Note that an instruction at addr has been decoded, so that a write to its page is reported
+    /// by take_written_code. Returns false if addr isn't RAM, as writes to it can't be tracked.
+    pub fn mark_code(&mut self, addr: usize) -> bool {
+        if self.device(addr).is_some() {
+            return false;
+        }
+        let offset = addr.wrapping_sub(self.ram_base);
+        match self.code_pages.get_mut(offset / CODE_PAGE_SIZE) {
+            Some(page) if offset < self.ram.size() => {
+                *page = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The addresses of the code pages (see mark_code) written since this was last called. A
+    /// page is reported once, and has to be marked again to be reported for later writes.
+    pub fn take_written_code(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.written_code)
+    }
+
+    /// Whether take_written_code has pages to report.
+    pub fn code_written(&self) -> bool {
+        !self.written_code.is_empty()
+    }
+
+    /// RAM for code that reads and writes it directly rather than through get8 and friends (the
+    /// JIT): a pointer to the data of each page that can be written in place (see
+    /// Ram::writable_pages), and the size of RAM up to the first device mapped over it. Writes
+    /// through the pointers have to check code_pages themselves.
+    #[cfg(feature = "jit")]
+    pub(crate) fn direct_ram(&self) -> (*const *mut u8, usize) {
+        (self.ram.writable_pages(), self.direct_size)
+    }
+
+    /// A flag for each CODE_PAGE_SIZE bytes of RAM, set while instructions decoded from the page
+    /// are tracked (see mark_code).
+    #[cfg(feature = "jit")]
+    pub(crate) fn code_pages(&self) -> *const bool {
+        self.code_pages.as_ptr()
+    }
+
+    /// The RAM offset of an access of width bytes at addr, if it's all RAM below any device.
+    fn direct(&self, addr: usize, width: usize) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.ram_base);
+        (offset < self.direct_size && width <= self.direct_size - offset).then_some(offset)
+    }
+
+    /// Note a write of width bytes at a RAM offset, reporting any code it wrote.
+    fn wrote(&mut self, offset: usize, width: usize) {
+        for page in offset / CODE_PAGE_SIZE..(offset + width).div_ceil(CODE_PAGE_SIZE) {
+            if self.code_pages[page] {
+                self.code_pages[page] = false;
+                self.written_code
+                    .push(self.ram_base + page * CODE_PAGE_SIZE);
+            }
+        }
+    }
+
+    /// Read an access that isn't all RAM below any device: from the device mapped at addr, or
+    /// else a byte at a time.
+    fn read_slow(&self, addr: usize, width: usize) -> Result<u32, MemoryError> {
+        if let Some(val) = self.device_read(addr, width) {
+            return val;
+        }
+
+        // Addresses below the base wrap around to somewhere far past the end of RAM
+        let offset = addr.wrapping_sub(self.ram_base);
+        if width == 1 {
+            return match offset < self.ram.size() {
+                true => Ok(self.ram.read::<1>(offset)[0] as u32),
+                false => Err(MemoryError::OutOfBounds),
+            };
+        }
+
+        let mut bytes = [0; 4];
+        for (index, byte) in bytes[..width].iter_mut().enumerate() {
+            *byte = self.get8(addr + index)?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Write an access that isn't all RAM below any device, as read_slow reads one.
+    fn write_slow(&mut self, addr: usize, width: usize, val: u32) -> Result<(), MemoryError> {
+        if let Some(result) = self.device_write(addr, width, val) {
+            return result;
+        }
+
+        let offset = addr.wrapping_sub(self.ram_base);
+        if width == 1 {
+            if offset >= self.ram.size() {
+                return Err(MemoryError::OutOfBounds);
+            }
+            self.ram.write(offset, [val as u8]);
+            self.wrote(offset, 1);
+            return Ok(());
+        }
+
+        for (index, byte) in val.to_le_bytes()[..width].iter().enumerate() {
+            self.set8(addr + index, *byte)?;
+        }
+        Ok(())
+    }
+
+    pub fn get8(&self, addr: usize) -> Result<u8, MemoryError> {
+        match self.direct(addr, 1) {
+            Some(offset) => Ok(self.ram.read::<1>(offset)[0]),
+            None => self.read_slow(addr, 1).map(|val| val as u8),
+        }
+    }
+
+    pub fn set8(&mut self, addr: usize, val: u8) -> Result<(), MemoryError> {
+        match self.direct(addr, 1) {
+            Some(offset) => {
+                self.ram.write(offset, [val]);
+                self.wrote(offset, 1);
+                Ok(())
+            }
+            None => self.write_slow(addr, 1, val as u32),
+        }
+    }
+
+    pub fn get16(&self, addr: usize) -> Result<u16, MemoryError> {
+        match self.direct(addr, 2) {
+            Some(offset) => Ok(u16::from_le_bytes(self.ram.read(offset))),
+            None => self.read_slow(addr, 2).map(|val| val as u16),
+        }
+    }
+
+    pub fn set16(&mut self, addr: usize, val: u16) -> Result<(), MemoryError> {
+        match self.direct(addr, 2) {
+            Some(offset) => {
+                self.ram.write(offset, val.to_le_bytes());
+                self.wrote(offset, 2);
+                Ok(())
+            }
+            None => self.write_slow(addr, 2, val as u32),
+        }
+    }
+
+    pub fn get32(&self, addr: usize) -> Result<u32, MemoryError> {
+        match self.direct(addr, 4) {
+            Some(offset) => Ok(u32::from_le_bytes(self.ram.read(offset))),
+            None => self.read_slow(addr, 4),
+        }
+    }
+
+    pub fn set32(&mut self, addr: usize, val: u32) -> Result<(), MemoryError> {
+        match self.direct(addr, 4) {
+            Some(offset) => {
+                self.ram.write(offset, val.to_le_bytes());
+                self.wrote(offset, 4);
+                Ok(())
+            }
+            None => self.write_slow(addr, 4, val),
+        }
+    }
+
+    /// Fill buffer from memory at addr, as DMA and debuggers do. Plain RAM is copied in one go,
+    /// anything else a byte at a time.
+    pub fn read_slice(&self, addr: usize, buffer: &mut [u8]) -> Result<(), MemoryError> {
+        match self.direct(addr, buffer.len()) {
+            Some(offset) => self.ram.read_slice(offset, buffer),
+            None => {
+                for (index, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.get8(addr + index)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy data into memory at addr, as loaders and DMA do. Plain RAM is copied in one go,
+    /// anything else a byte at a time.
+    pub fn load_slice(&mut self, addr: usize, data: &[u8]) -> Result<(), MemoryError> {
+        match self.direct(addr, data.len()) {
+            Some(offset) => {
+                self.ram.load_slice(offset, data)?;
+                self.wrote(offset, data.len());
+                Ok(())
+            }
+            _ => {
+                for (index, &byte) in data.iter().enumerate() {
+                    self.set8(addr + index, byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simple_8_bit_memory_tests() {
+        let mut mem = Memory::new(256);
+
+        for i in 0..256 {
+            assert_eq!(mem.get8(i), Ok(0));
+        }
+
+        for i in 0..256 {
+            mem.set8(i, 255u8 - (i as u8)).unwrap();
+        }
+
+        for i in 0..256 {
+            assert_eq!(mem.get8(i), Ok(255u8 - (i as u8)));
+        }
+
+        for i in 0..256 {
+            mem.set8(i, 0).unwrap();
+        }
+
+        for i in 0..256 {
+            assert_eq!(mem.get8(i), Ok(0));
+        }
+    }
+
+    #[test]
+    fn simple_8_bit_memory_boundry_tests() {
+        let mut mem = Memory::new(256);
+
+        if let Err(_) = mem.set8(256, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+        if let Err(_) = mem.get8(256) {
+        } else {
+            panic!("expected read to fail");
+        }
+
+        if let Err(_) = mem.set8(4096, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+        if let Err(_) = mem.get8(4096) {
+        } else {
+            panic!("expected read to fail");
+        }
+    }
+
+    #[test]
+    fn simple_16_bit_memory_tests() {
+        let mut mem = Memory::new(256);
+
+        for i in (0..256).step_by(2) {
+            assert_eq!(mem.get16(i), Ok(0));
+        }
+
+        for i in (0..256).step_by(2) {
+            mem.set16(i, 4096_u16 - (i as u16)).unwrap();
+        }
+
+        for i in (0..256).step_by(2) {
+            assert_eq!(mem.get16(i), Ok(4096_u16 - (i as u16)));
+        }
+
+        for i in (0..256).step_by(2) {
+            mem.set16(i, 0).unwrap();
+        }
+
+        for i in (0..256).step_by(2) {
+            assert_eq!(mem.get16(i), Ok(0));
+        }
+    }
+
+    #[test]
+    fn simple_16_bit_memory_boundry_tests() {
+        let mut mem = Memory::new(256);
+
+        if let Err(_) = mem.set16(255, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+
+        if let Err(_) = mem.get16(255) {
+        } else {
+            panic!("expected read to fail");
+        }
+
+        if let Err(_) = mem.set16(4096, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+        if let Err(_) = mem.get16(4096) {
+        } else {
+            panic!("expected read to fail");
+        }
+    }
+
+    #[test]
+    fn simple_32_bit_memory_tests() {
+        let mut mem = Memory::new(256);
+        for i in (0..256).step_by(4) {
+            assert_eq!(mem.get32(i), Ok(0));
+        }
+
+        for i in (0..256).step_by(4) {
+            mem.set32(i, 4096_u32 - (i as u32)).unwrap();
+        }
+
+        for i in (0..256).step_by(4) {
+            assert_eq!(mem.get32(i), Ok(4096_u32 - (i as u32)));
+        }
+
+        for i in (0..256).step_by(4) {
+            mem.set32(i, 0).unwrap();
+        }
+
+        for i in (0..256).step_by(4) {
+            assert_eq!(mem.get32(i), Ok(0));
+        }
+    }
+
+    #[test]
+    fn simple_32_bit_memory_boundry_tests() {
+        let mut mem = Memory::new(256);
+
+        if let Err(_) = mem.set32(254, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+
+        if let Err(_) = mem.get32(254) {
+        } else {
+            panic!("expected read to fail");
+        }
+
+        if let Err(_) = mem.set32(4096, 0) {
+        } else {
+            panic!("expected write to fail");
+        }
+        if let Err(_) = mem.get32(4096) {
+        } else {
+            panic!("expected read to fail");
+        }
+    }
+
+    /// A device that records the last write and returns offset + width on reads.
+    struct TestDevice {
+        last_write: Option<(usize, usize, u32)>,
+    }
+
+    impl Device for TestDevice {
+        fn read(&mut self, offset: usize, width: usize) -> u32 {
+            (offset + width) as u32
+        }
+
+        fn write(&mut self, offset: usize, width: usize, value: u32) {
+            self.last_write = Some((offset, width, value));
+        }
+    }
+
+    #[test]
+    fn device_accesses() {
+        let mut mem = Memory::new(256);
+        let device = Rc::new(RefCell::new(TestDevice { last_write: None }));
+        mem.attach(0x1000, 0x100, device.clone()).unwrap();
+
+        assert_eq!(mem.get8(0x1000), Ok(1));
+        assert_eq!(mem.get16(0x1010), Ok(0x12));
+        assert_eq!(mem.get32(0x10FC), Ok(0x100));
+        assert_eq!(mem.get8(0x1100), Err(MemoryError::OutOfBounds));
+
+        mem.set32(0x1004, 0xDEADBEEF).unwrap();
+        assert_eq!(device.borrow().last_write, Some((4, 4, 0xDEADBEEF)));
+        mem.set8(0x1001, 0xAB).unwrap();
+        assert_eq!(device.borrow().last_write, Some((1, 1, 0xAB)));
+
+        // RAM is unaffected by device writes
+        for i in 0..256 {
+            assert_eq!(mem.get8(i), Ok(0));
+        }
+    }
+
+    #[test]
+    fn ram_base() {
+        let mut mem = Memory::with_base(0x8000_0000, 16);
+        mem.set32(0x8000_000C, 0x1234_5678).unwrap();
+        assert_eq!(mem.get32(0x8000_000C), Ok(0x1234_5678));
+        assert_eq!(mem.get8(0x8000_0010), Err(MemoryError::OutOfBounds));
+        assert_eq!(mem.get8(0x7FFF_FFFF), Err(MemoryError::OutOfBounds));
+        assert_eq!(mem.set8(0, 1), Err(MemoryError::OutOfBounds));
+    }
+
+    #[test]
+    fn written_code() {
+        let mut mem = Memory::with_base(0x8000_0000, 4 * CODE_PAGE_SIZE);
+        assert!(mem.mark_code(0x8000_0000 + CODE_PAGE_SIZE + 8));
+        assert!(!mem.mark_code(0x8000_0000 + 4 * CODE_PAGE_SIZE));
+        assert!(!mem.mark_code(0));
+
+        // Only writes to marked pages are reported, and only the first
+        mem.set32(0x8000_0000, 1).unwrap();
+        mem.set8(0x8000_0000 + CODE_PAGE_SIZE + 1, 1).unwrap();
+        mem.set16(0x8000_0000 + CODE_PAGE_SIZE + 2, 1).unwrap();
+        assert_eq!(mem.take_written_code(), vec![0x8000_0000 + CODE_PAGE_SIZE]);
+        assert_eq!(mem.take_written_code(), Vec::<usize>::new());
+
+        mem.mark_code(0x8000_0000 + CODE_PAGE_SIZE);
+        mem.set32(0x8000_0000 + 2 * CODE_PAGE_SIZE - 2, 1).unwrap();
+        assert_eq!(mem.take_written_code(), vec![0x8000_0000 + CODE_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn unaligned_accesses_span_pages() {
+        let mut mem = Memory::new(2 * PAGE_SIZE);
+        mem.set32(PAGE_SIZE - 1, 0x1234_5678).unwrap();
+        assert_eq!(mem.get32(PAGE_SIZE - 1), Ok(0x1234_5678));
+        assert_eq!(mem.get16(PAGE_SIZE - 1), Ok(0x5678));
+        assert_eq!(mem.get8(PAGE_SIZE + 2), Ok(0x12));
+        assert_eq!(mem.get32(2 * PAGE_SIZE - 3), Err(MemoryError::OutOfBounds));
+    }
+
+    #[test]
+    fn slices() {
+        let mut mem = Memory::with_base(0x8000_0000, 2 * PAGE_SIZE);
+        let data: Vec<u8> = (0..PAGE_SIZE + 3).map(|i| i as u8).collect();
+        mem.mark_code(0x8000_0000 + PAGE_SIZE + 1);
+        mem.load_slice(0x8000_0000 + 1, &data).unwrap();
+        assert_eq!(mem.take_written_code(), vec![0x8000_0000 + PAGE_SIZE]);
+
+        let mut buffer = vec![0; data.len()];
+        mem.read_slice(0x8000_0000 + 1, &mut buffer).unwrap();
+        assert_eq!(buffer, data);
+        assert_eq!(mem.get8(0x8000_0000 + PAGE_SIZE), Ok(0xFF));
+
+        assert_eq!(
+            mem.load_slice(0x8000_0000 + PAGE_SIZE, &data),
+            Err(MemoryError::OutOfBounds)
+        );
+        assert_eq!(
+            mem.read_slice(0x7FFF_FFFF, &mut buffer[..2]),
+            Err(MemoryError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn slices_and_devices() {
+        let mut mem = Memory::new(256);
+        let device = Rc::new(RefCell::new(TestDevice { last_write: None }));
+        mem.attach(0x80, 0x10, device.clone()).unwrap();
+
+        // The parts of a slice over a device go to the device a byte at a time
+        mem.load_slice(0x7E, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(device.borrow().last_write, Some((1, 1, 4)));
+        let mut buffer = [0; 4];
+        mem.read_slice(0x7E, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 1, 2]);
+
+        // RAM past the device is still there
+        mem.set32(0x90, 0xDEADBEEF).unwrap();
+        assert_eq!(mem.get32(0x90), Ok(0xDEADBEEF));
+
+        // As are accesses spanning RAM and a device, a byte at a time
+        assert_eq!(mem.get16(0x7F), Ok(0x0102));
+    }
+
+    #[test]
+    fn restore_ram() {
+        let mut mem = Memory::new(2 * PAGE_SIZE);
+        mem.set32(0, 1).unwrap();
+        let snapshot = mem.ram().clone();
+        mem.set32(0, 2).unwrap();
+        mem.set32(PAGE_SIZE, 3).unwrap();
+
+        mem.mark_code(PAGE_SIZE + 4);
+        mem.restore_ram(snapshot);
+        assert_eq!(mem.get32(0), Ok(1));
+        assert_eq!(mem.get32(PAGE_SIZE), Ok(0));
+        assert_eq!(mem.take_written_code(), vec![PAGE_SIZE]);
+    }
+
+    #[test]
+    fn protection() {
+        let mut mem = Memory::with_base(0x1000, 4 * PAGE_SIZE);
+        let page = |index: usize| 0x1000 + index * PAGE_SIZE;
+        assert_eq!(mem.check_access(page(1), 4, Access::Write), Ok(()));
+        assert_eq!(
+            mem.protect(page(1) + 4, PAGE_SIZE, Permissions::NONE),
+            Err(MemoryError::Misaligned)
+        );
+        assert_eq!(
+            mem.protect(page(3), PAGE_SIZE + 1, Permissions::NONE),
+            Err(MemoryError::OutOfBounds)
+        );
+
+        // Sizes are rounded up to whole pages
+        mem.protect(page(0), 100, Permissions::READ_EXECUTE)
+            .unwrap();
+        mem.protect(page(1), PAGE_SIZE, Permissions::READ_WRITE)
+            .unwrap();
+        mem.protect(page(2), PAGE_SIZE, Permissions::NONE).unwrap();
+        let allowed =
+            |mem: &Memory, addr, width, access| mem.check_access(addr, width, access).is_ok();
+        assert!(allowed(&mem, page(1) - 4, 4, Access::Execute));
+        assert!(!allowed(&mem, page(1) - 4, 4, Access::Write));
+        assert!(allowed(&mem, page(1), 4, Access::Write));
+        assert!(!allowed(&mem, page(1), 4, Access::Execute));
+        assert!(allowed(&mem, page(2) - 1, 1, Access::Read));
+        assert!(!allowed(&mem, page(2) + 100, 1, Access::Read));
+        assert!(allowed(&mem, page(3), 4, Access::Execute));
+
+        // An access spanning two pages needs both to allow it
+        assert!(allowed(&mem, page(1) - 2, 4, Access::Read));
+        assert!(!allowed(&mem, page(1) - 2, 4, Access::Write));
+        assert!(!allowed(&mem, page(2) - 2, 4, Access::Read));
+
+        // Outside RAM and devices are left to the access itself
+        assert!(allowed(&mem, 0, 4, Access::Write));
+        assert!(allowed(&mem, page(4), 4, Access::Write));
+        let device = Rc::new(RefCell::new(TestDevice { last_write: None }));
+        mem.attach(page(2), 0x100, device).unwrap();
+        assert!(allowed(&mem, page(2), 4, Access::Write));
+        assert!(!allowed(&mem, page(2) + 0x100, 4, Access::Read));
+
+        // Only the hart is restricted
+        mem.set32(page(0), 5).unwrap();
+        assert_eq!(mem.get32(page(2) + 0x100), Ok(0));
+
+        mem.protect(page(0), 4 * PAGE_SIZE, Permissions::ALL)
+            .unwrap();
+        assert!(allowed(&mem, page(2) + 0x100, 4, Access::Read));
+    }
+
+    #[test]
+    fn protecting_code_reports_it_written() {
+        let mut mem = Memory::new(2 * PAGE_SIZE);
+        mem.mark_code(0);
+        mem.mark_code(PAGE_SIZE + 8);
+        mem.protect(PAGE_SIZE, 4, Permissions::READ_ONLY).unwrap();
+        assert_eq!(mem.take_written_code(), vec![PAGE_SIZE]);
+    }
+
+    #[test]
+    fn device_overlap() {
+        let mut mem = Memory::new(256);
+        let device = Rc::new(RefCell::new(TestDevice { last_write: None }));
+        mem.attach(0x1000, 0x100, device.clone()).unwrap();
+        assert_eq!(
+            mem.attach(0x10FF, 0x100, device.clone()),
+            Err(MemoryError::DeviceOverlap)
+        );
+        assert_eq!(
+            mem.attach(0xF01, 0x100, device.clone()),
+            Err(MemoryError::DeviceOverlap)
+        );
+        mem.attach(0x1100, 0x100, device).unwrap();
+    }
+}
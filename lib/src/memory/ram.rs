@@ -0,0 +1,242 @@
+/**
+ * The backing store for RAM: a table of fixed size pages, shared copy on write. A page is only
+ * allocated when it's first written, and reading one that hasn't been gives zeros, so RAM costs
+ * little more than its table however large it is. Cloning it (e.g, for a snapshot) only copies
+ * the table.
+ */
+use super::MemoryError;
+#[cfg(feature = "jit")]
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub const PAGE_SIZE: usize = 4096;
+
+type Page = [u8; PAGE_SIZE];
+
+pub struct Ram {
+    /// None for a page that has never been written
+    pages: Vec<Option<Rc<Page>>>,
+    size: usize,
+    /// For the JIT: the data of each page this RAM alone holds, which can be written in place,
+    /// or null. Clearing these has to be possible through a shared reference, as clone does.
+    #[cfg(feature = "jit")]
+    writable: Vec<Cell<*mut u8>>,
+    /// For the JIT: the pages left null in writable however they're held, so that every access
+    /// to them goes through Memory (e.g, to check their permissions)
+    #[cfg(feature = "jit")]
+    indirect: Vec<bool>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        let pages = size.div_ceil(PAGE_SIZE);
+        Self {
+            pages: vec![None; pages],
+            size,
+            #[cfg(feature = "jit")]
+            writable: vec![Cell::new(std::ptr::null_mut()); pages],
+            #[cfg(feature = "jit")]
+            indirect: vec![false; pages],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The bytes allocated for the pages written so far. Pages shared with a clone count for
+    /// both.
+    pub fn footprint(&self) -> usize {
+        self.pages.iter().flatten().count() * PAGE_SIZE
+    }
+
+    /// The page at index, allocated if it hasn't been and made writable.
+    fn page_mut(&mut self, index: usize) -> &mut Page {
+        let page = self.pages[index].get_or_insert_with(|| Rc::new([0; PAGE_SIZE]));
+        let page = Rc::make_mut(page);
+        #[cfg(feature = "jit")]
+        if !self.indirect[index] {
+            self.writable[index].set(page.as_mut_ptr());
+        }
+        page
+    }
+
+    /// Read N bytes at offset. The caller checks that they are in range.
+    pub(super) fn read<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let start = offset % PAGE_SIZE;
+        match &self.pages[offset / PAGE_SIZE] {
+            Some(page) if start + N <= PAGE_SIZE => page[start..start + N].try_into().unwrap(),
+            // A page that hasn't been written, or spanning two pages
+            _ => {
+                let mut bytes = [0; N];
+                self.read_slice(offset, &mut bytes).unwrap();
+                bytes
+            }
+        }
+    }
+
+    /// Write N bytes at offset. The caller checks that they are in range.
+    pub(super) fn write<const N: usize>(&mut self, offset: usize, bytes: [u8; N]) {
+        let start = offset % PAGE_SIZE;
+        if start + N <= PAGE_SIZE {
+            self.page_mut(offset / PAGE_SIZE)[start..start + N].copy_from_slice(&bytes);
+        } else {
+            self.load_slice(offset, &bytes).unwrap();
+        }
+    }
+
+    /// Check that length bytes at offset are in range.
+    fn check(&self, offset: usize, length: usize) -> Result<(), MemoryError> {
+        match offset <= self.size && length <= self.size - offset {
+            true => Ok(()),
+            false => Err(MemoryError::OutOfBounds),
+        }
+    }
+
+    /// Split the range of length bytes at offset into the part in each page: the page's index,
+    /// the range within it and where the part starts in the whole.
+    fn parts(
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (usize, std::ops::Range<usize>, usize)> {
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            (done < length).then(|| {
+                let address = offset + done;
+                let start = address % PAGE_SIZE;
+                let count = (PAGE_SIZE - start).min(length - done);
+                let part = (address / PAGE_SIZE, start..start + count, done);
+                done += count;
+                part
+            })
+        })
+    }
+
+    /// Fill buffer from the RAM at offset.
+    pub fn read_slice(&self, offset: usize, buffer: &mut [u8]) -> Result<(), MemoryError> {
+        self.check(offset, buffer.len())?;
+        for (page, range, done) in Self::parts(offset, buffer.len()) {
+            let part = &mut buffer[done..done + range.len()];
+            match &self.pages[page] {
+                Some(page) => part.copy_from_slice(&page[range]),
+                None => part.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy data into the RAM at offset.
+    pub fn load_slice(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        self.check(offset, data.len())?;
+        for (page, range, done) in Self::parts(offset, data.len()) {
+            let length = range.len();
+            self.page_mut(page)[range].copy_from_slice(&data[done..done + length]);
+        }
+        Ok(())
+    }
+
+    /// A pointer to the data of each page, or null where the page is shared or not allocated and
+    /// has to be written through page_mut first. Entries are only set as pages are written.
+    #[cfg(feature = "jit")]
+    pub(crate) fn writable_pages(&self) -> *const *mut u8 {
+        // Cell<T> has the same layout as T
+        self.writable.as_ptr() as *const *mut u8
+    }
+
+    /// Keep the page at index out of writable_pages, or let it back in once it's next written.
+    #[cfg(feature = "jit")]
+    pub(super) fn set_indirect(&mut self, index: usize, indirect: bool) {
+        self.indirect[index] = indirect;
+        if indirect {
+            self.writable[index].set(std::ptr::null_mut());
+        }
+    }
+}
+
+impl Clone for Ram {
+    fn clone(&self) -> Self {
+        // Every page is shared now, so neither copy can write one in place
+        #[cfg(feature = "jit")]
+        for page in &self.writable {
+            page.set(std::ptr::null_mut());
+        }
+        Self {
+            pages: self.pages.clone(),
+            size: self.size,
+            #[cfg(feature = "jit")]
+            writable: vec![Cell::new(std::ptr::null_mut()); self.pages.len()],
+            #[cfg(feature = "jit")]
+            indirect: self.indirect.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slices_span_pages() {
+        let mut ram = Ram::new(4 * PAGE_SIZE);
+        let data: Vec<u8> = (0..2 * PAGE_SIZE + 10).map(|i| i as u8).collect();
+        ram.load_slice(PAGE_SIZE - 5, &data).unwrap();
+
+        let mut buffer = vec![0; data.len() + 2];
+        ram.read_slice(PAGE_SIZE - 6, &mut buffer).unwrap();
+        assert_eq!(buffer[0], 0);
+        assert_eq!(&buffer[1..data.len() + 1], &data[..]);
+        assert_eq!(buffer[data.len() + 1], 0);
+
+        assert_eq!(ram.read::<4>(PAGE_SIZE - 2), [3, 4, 5, 6]);
+        ram.write(2 * PAGE_SIZE - 1, [0xAA, 0xBB]);
+        assert_eq!(ram.read::<2>(2 * PAGE_SIZE - 1), [0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn slices_out_of_range() {
+        let mut ram = Ram::new(100);
+        assert_eq!(ram.load_slice(96, &[1; 4]), Ok(()));
+        assert_eq!(ram.load_slice(97, &[1; 4]), Err(MemoryError::OutOfBounds));
+        assert_eq!(ram.read_slice(101, &mut []), Err(MemoryError::OutOfBounds));
+        assert_eq!(
+            ram.read_slice(usize::MAX, &mut [0; 2]),
+            Err(MemoryError::OutOfBounds)
+        );
+        assert_eq!(ram.read_slice(100, &mut []), Ok(()));
+    }
+
+    #[test]
+    fn pages_are_allocated_when_written() {
+        // More than a 32-bit guest can address, which costs only the page table
+        let mut ram = Ram::new(1 << 33);
+        assert_eq!(ram.footprint(), 0);
+        assert_eq!(ram.read::<4>((1 << 33) - 4), [0; 4]);
+        let mut buffer = [1; 2 * PAGE_SIZE];
+        ram.read_slice(1 << 32, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; 2 * PAGE_SIZE]);
+        assert_eq!(ram.footprint(), 0);
+
+        ram.write(0, [1]);
+        ram.write(PAGE_SIZE - 1, [2, 3]);
+        ram.load_slice((1 << 33) - 1, &[4]).unwrap();
+        assert_eq!(ram.footprint(), 3 * PAGE_SIZE);
+        assert_eq!(ram.read::<4>(PAGE_SIZE - 2), [0, 2, 3, 0]);
+
+        let clone = ram.clone();
+        assert_eq!(clone.footprint(), 3 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn clones_are_copy_on_write() {
+        let mut ram = Ram::new(2 * PAGE_SIZE);
+        ram.write(10, [1, 2, 3, 4]);
+        let mut clone = ram.clone();
+        clone.write(10, [5]);
+        ram.write(PAGE_SIZE, [6]);
+
+        assert_eq!(ram.read::<2>(10), [1, 2]);
+        assert_eq!(clone.read::<2>(10), [5, 2]);
+        assert_eq!(ram.read::<1>(PAGE_SIZE), [6]);
+        assert_eq!(clone.read::<1>(PAGE_SIZE), [0]);
+    }
+}